@@ -0,0 +1,757 @@
+//! Wire types shared by the server, storage and push crates.
+//!
+//! Everything here is plain serde data: no I/O and no server state, so clients
+//! and tests can depend on it without pulling in the rest of the stack.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+
+pub const MAX_MESSAGE_ID_LEN: usize = 128;
+
+/// A mailbox id that has passed [`check_message_id`]. Deserializing one
+/// validates it, so request types holding `MessageId`s reject malformed ids
+/// before any handler runs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct MessageId(String);
+
+impl MessageId {
+    pub fn parse(value: impl Into<String>) -> Result<Self, ValidationIssue> {
+        let value = value.into();
+        match check_message_id(&value) {
+            Some(issue) => Err(issue),
+            None => Ok(Self(value)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl TryFrom<String> for MessageId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(value).map_err(|issue| issue.message)
+    }
+}
+
+impl From<MessageId> for String {
+    fn from(id: MessageId) -> Self {
+        id.0
+    }
+}
+
+impl AsRef<str> for MessageId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutMessageRequest {
+    pub message_id: MessageId,
+    pub message: String,
+    // Retries carrying the same key for the same message_id store nothing new;
+    // the Idempotency-Key header works too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    // Holds the message back from polls and pushes until this time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deliver_at: Option<DateTime<Utc>>,
+    // Deletes the message this long after it is stored (or delivered, when
+    // scheduled), acked or not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    // Timestamp of an earlier message in this mailbox that the new one replaces
+    // in the same transaction, e.g. an edit or a status update.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<DateTime<Utc>>,
+    // Mailbox that gets a `DeliveryReceipt` once the recipient acks the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt_channel_id: Option<MessageId>,
+    // Sync storage to disk before answering, rather than within the server's
+    // persist interval.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub durable: bool,
+    // Make this the channel's retained value instead of queueing it: it
+    // replaces the previous one and every poll gets it until it is replaced.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retain: bool,
+}
+
+/// One message delivered to several mailboxes in a single transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutMultiRequest {
+    pub message_ids: Vec<MessageId>,
+    pub message: String,
+}
+
+/// Body of `POST /api/put-message`: a single put, or one message fanned out to
+/// several mailboxes when `message_ids` is given instead of `message_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PutMessageBody {
+    Single(PutMessageRequest),
+    Broadcast(PutMultiRequest),
+}
+
+/// Different messages for different mailboxes, stored in one transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutMessagesRequest {
+    pub entries: Vec<PutMessageRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutResult {
+    pub message_id: MessageId,
+    pub timestamp: DateTime<Utc>,
+    // Secret proving the caller put this message, for /api/cancel-message.
+    // Only single puts that stored something return one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutMultiResponse {
+    pub results: Vec<PutResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PushSubscriptionInfo {
+    pub endpoint: String, // The push service URL, or "apns:" and an iOS device token
+    // Empty for APNs, which encrypts in transit itself
+    #[serde(default)]
+    pub keys: SubscriptionKeysInfo,
+    // The device's BCP 47 language tag, e.g. "pt-BR", for notification text.
+    // English when absent or not translated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    // Which of the server's VAPID keys the browser subscribed with, from
+    // ServerInfo::vapid_key_id. Absent means the server's first key, "1".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vapid_key_id: Option<String>,
+}
+
+// Represents the 'keys' object within the PushSubscription
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubscriptionKeysInfo {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// Order of `results` in a [`GetMessagesResponse`].
+///
+/// Messages from all requested ids are interleaved by timestamp. Ties are broken
+/// by message_id and then by storage order, so the same stored state always
+/// produces the same response.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+}
+
+/// What a poll returns once any requested id has messages.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PollMode {
+    /// The messages themselves.
+    #[default]
+    Messages,
+    /// Only the ids holding messages, in `pending_ids`, so bandwidth-constrained
+    /// clients can wake up and then fetch selectively.
+    Notify,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetMessagesRequest {
+    /// Repeated ids count once: each message is returned once and the id
+    /// appears once in `pending_ids`.
+    pub message_ids: Vec<MessageId>,
+    pub timeout_ms: Option<u64>,
+    /// Deprecated in favour of `/api/subscribe`. Still saved, but after the
+    /// poll starts, so a failure no longer shows up as `resubscribe_required`.
+    pub push_subscription: Option<PushSubscriptionInfo>,
+    #[serde(default)]
+    pub sort: SortOrder,
+    // Ask for whitespace heartbeats while the poll waits, for clients behind NATs
+    // that drop idle connections. Ignored unless the server enables keepalives.
+    #[serde(default)]
+    pub keepalive: bool,
+    // Opaque token shared by a client's tabs; a second poll for the same ids in
+    // the same session is rejected with 409 while the first is still waiting.
+    #[serde(default)]
+    pub poll_session: Option<String>,
+    // When only some ids have messages, also return a continuation token for the
+    // rest. Ignored by NDJSON streams.
+    #[serde(default)]
+    pub continue_pending: bool,
+    // Token from an earlier response; resumes the wait on the ids it stands for,
+    // in addition to any listed in message_ids, and implies continue_pending.
+    #[serde(default)]
+    pub continuation: Option<String>,
+    // Return at most this many messages per id; ids with more get a cursor in the
+    // response. Ignored by NDJSON streams.
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    // Cursors from the previous page's response; each id resumes after its cursor.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cursors: BTreeMap<MessageId, String>,
+    // Optional behaviours the client understands, from ServerInfo::capabilities.
+    // Left empty, the server assumes a client predating negotiation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    // Only return messages stored at or after `since` and before `until`, for
+    // catching up after downtime without re-downloading everything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+    // Notify mode ignores paging, continuations and NDJSON.
+    #[serde(default)]
+    pub mode: PollMode,
+    // Hide each returned message from every poll for this long, or until it is
+    // acked, so devices sharing an id don't both process it. Messages left
+    // unacked are returned again once the lease runs out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_secs: Option<u64>,
+    // Named reader with its own ack cursor, e.g. one per device. Messages it has
+    // acked are hidden from its polls but kept until every consumer of the id
+    // has acked them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumer: Option<String>,
+    // Also poll every id under each pattern, written `<root>/*` where the root
+    // is a secret of at least MIN_PATTERN_ROOT_LEN bytes; `<root>/*` covers
+    // `<root>/inbox` and `<root>/inbox/2`. Ids are picked up as they appear.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_id_patterns: Vec<String>,
+    // Also return the retained value of each id; see PutMessageRequest::retain.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retained: bool,
+    // Timestamps of the retained values the client already holds, by id; those
+    // are left out of the response and the poll waits for newer ones.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub retained_seen: BTreeMap<MessageId, DateTime<Utc>>,
+    // Also wake for signals sent to the ids while this poll waits.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub signals: bool,
+}
+
+/// Shortest root a `message_id_patterns` entry may have: 256 bits in base64,
+/// so nobody can sweep up mailboxes by guessing a short prefix.
+pub const MIN_PATTERN_ROOT_LEN: usize = 43;
+
+/// Body of `GET /api/info`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerInfo {
+    pub capabilities: Vec<String>,
+    // Prefix every route is served under, e.g. "/relay"; empty at the root.
+    #[serde(default)]
+    pub base_path: String,
+    // The VAPID public key new Web Push subscriptions should be made with, as
+    // their applicationServerKey, and its id to send as `vapid_key_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vapid_public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vapid_key_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FoundMessage {
+    pub message_id: MessageId,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+    // Per-channel number, one higher for each message put to the id, so a
+    // client can spot messages it never saw. Absent on retained values and on
+    // messages stored before sequencing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+impl FoundMessage {
+    pub fn ack_token(&self) -> AckToken {
+        AckToken {
+            message_id: self.message_id.clone(),
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetMessagesResponse {
+    pub results: Vec<FoundMessage>,
+    // Set when the push service reported the subscription for one of the requested
+    // ids as gone; the client should call pushManager.subscribe() and send the new
+    // subscription, which clears the flag.
+    #[serde(default)]
+    pub resubscribe_required: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resubscribe_ids: Vec<MessageId>,
+    // Stands for the requested ids that had no messages, when continue_pending was
+    // set; valid once, for a few minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+    // Opaque cursor for each id whose page_size limit left messages unreturned.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cursors: BTreeMap<MessageId, String>,
+    // Requested ids holding messages, filled instead of results in notify mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_ids: Vec<MessageId>,
+    // Current retained value of each requested id that has one newer than
+    // retained_seen. Not acked: they stay until replaced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retained: Vec<FoundMessage>,
+    // Signals sent while the poll waited, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signals: Vec<Signal>,
+    // Set when newer messages were left out to keep the response under the
+    // server's size cap. They are still stored, so poll again without waiting.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// Asks which mailboxes have pending messages without fetching or waiting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HasMessagesRequest {
+    pub message_ids: Vec<MessageId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingCount {
+    pub message_id: MessageId,
+    pub has_messages: bool,
+    pub count: usize,
+    // Sequence number of the last message ever put to the id, acked or not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latest_sequence: Option<u64>,
+}
+
+/// One entry per requested id, in request order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HasMessagesResponse {
+    pub results: Vec<PendingCount>,
+}
+
+/// Content type a get-messages client sends in `Accept` to receive
+/// [`GetMessagesStreamLine`]s instead of one [`GetMessagesResponse`].
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// One line of a streamed get-messages response: messages in storage order
+/// (grouped by message_id, oldest first; `sort` is ignored), then one `end`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GetMessagesStreamLine {
+    Message(FoundMessage),
+    End {
+        resubscribe_required: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        resubscribe_ids: Vec<MessageId>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidatePutRequest {
+    pub message_id: String,
+    pub message_size: usize, // Length in bytes of the (base64) message the client intends to put
+}
+
+/// Whether retrying a failed request can succeed, so clients apply one retry
+/// policy instead of guessing from status codes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryClass {
+    /// A transient fault; retry with the client's own backoff.
+    Retryable,
+    /// Retry no sooner than `retry_after_secs`.
+    RetryAfter,
+    /// The same request will fail again.
+    Permanent,
+}
+
+/// JSON body of every error response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub message: String,
+    pub error_code: String,
+    pub retry: RetryClass,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    // The server's clock when it answered, so clients can measure their skew.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_time: Option<DateTime<Utc>>,
+    // For CLOCK_SKEW errors, how far the rejected timestamp was from
+    // `server_time`, in seconds; positive means ahead of the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skew_secs: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationIssue {
+    pub field: String,
+    pub error_code: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidatePutResponse {
+    pub valid: bool,
+    pub max_message_size: usize,
+    pub issues: Vec<ValidationIssue>,
+}
+
+/// Names one stored message for deletion: the mailbox it was delivered to and
+/// the timestamp it was stored under, as returned in [`FoundMessage`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AckToken {
+    pub message_id: MessageId,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckMessagesPayload {
+    pub acks: Vec<AckToken>,
+    // Advance this consumer's cursor past each acked message, and everything
+    // stored before it, instead of deleting them outright.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumer: Option<String>,
+}
+
+/// Acknowledges every message of `message_id` stored before `before`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckBeforeRequest {
+    pub message_id: MessageId,
+    pub before: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckBeforeResponse {
+    pub deleted: usize,
+}
+
+/// Retracts one message a sender put, named by the `message_id` and
+/// `timestamp` its put returned.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevokeMessageRequest {
+    pub message_id: MessageId,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevokeMessageResponse {
+    // False if the message was already acked, revoked or never existed.
+    pub revoked: bool,
+}
+
+/// Registers one device's push subscription for each of `message_ids`.
+/// Subscriptions are one-shot: a push consumes them, so clients subscribe again
+/// after a poll returns messages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubscribeRequest {
+    pub message_ids: Vec<MessageId>,
+    pub push_subscription: PushSubscriptionInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubscribeResponse {
+    // False if every id already had exactly this subscription.
+    pub saved: bool,
+}
+
+/// Removes one device's push subscription from a mailbox, for a client that
+/// turned notifications off or logged out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsubscribeRequest {
+    pub message_id: MessageId,
+    pub endpoint: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsubscribeResponse {
+    // False if no subscription with that endpoint was registered.
+    pub removed: bool,
+}
+
+/// Deletes a message nobody has fetched yet, on behalf of the sender holding
+/// the `handle` its put returned.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CancelMessageRequest {
+    pub message_id: MessageId,
+    pub timestamp: DateTime<Utc>,
+    pub handle: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CancelMessageResponse {
+    // False if the message is gone or the handle doesn't match it.
+    pub cancelled: bool,
+}
+
+/// A payload for whoever is polling `message_id` right now, such as a typing
+/// indicator. It is never stored: with nobody waiting it is dropped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignalRequest {
+    pub message_id: MessageId,
+    pub payload: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignalResponse {
+    // False if nobody was waiting, so the signal went nowhere.
+    pub delivered: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signal {
+    pub message_id: MessageId,
+    pub payload: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Body of the message stored in a put's `receipt_channel_id` when the
+/// recipient acks it. Unlike other messages it is plaintext JSON written by
+/// the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryReceipt {
+    pub message_id: MessageId,
+    pub timestamp: DateTime<Utc>,
+    pub acked_at: DateTime<Utc>,
+}
+
+/// Asks how far one message a sender put has got, named like a revoke.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageStateRequest {
+    pub message_id: MessageId,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Stored (or scheduled) but not yet returned to any poll.
+    Stored,
+    /// Returned to a poll at least once but not acked.
+    Delivered,
+    /// No longer stored: acked, revoked, expired or never put.
+    Acked,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageState {
+    pub state: DeliveryState,
+    // When a poll first returned the message; set only while `Delivered`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Deletes everything stored for `message_id`: messages and push subscription.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurgeChannelRequest {
+    pub message_id: MessageId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurgeChannelResponse {
+    pub deleted_messages: usize,
+}
+
+/// Asks for a one-shot, expiring link that reads `message_id` once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssueShareLinkRequest {
+    pub message_id: MessageId,
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssueShareLinkResponse {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Sets the address emailed about new messages for `message_id` when no push
+/// reaches a device, or removes it when `email` is None.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailFallbackRequest {
+    pub message_id: MessageId,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailFallbackResponse {
+    pub registered: bool,
+}
+
+/// Opts `message_id` in to SMS alerts at `phone`, in E.164 form such as
+/// "+14155550123", or opts it out when `phone` is None.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SmsAlertRequest {
+    pub message_id: MessageId,
+    pub phone: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SmsAlertResponse {
+    pub registered: bool,
+}
+
+/// Redeems a share link; answered with a [`GetMessagesResponse`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedeemShareLinkRequest {
+    pub token: String,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationPayload {
+    pub title: String,
+    pub body: String,
+    pub icon: Option<String>,
+    pub url: Option<String>, // URL to open on click
+    // Messages waiting in the mailbox when the push was built.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub badge: Option<usize>,
+    // The newest of them, still end-to-end encrypted, when the server passes
+    // messages through and it fits in the push.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<FoundMessage>,
+}
+
+/// Header carrying a redeemable rate-limit token on puts: base64url (no padding)
+/// of the 32 byte nonce, the 32 byte message randomizer and the RSA signature.
+pub const PRIVATE_TOKEN_HEADER: &str = "private-token";
+
+/// Header naming the rate-limit budget a poll is charged to when the server
+/// keys gets by client-provided poll key: up to 128 bytes of the client's choosing.
+pub const POLL_KEY_HEADER: &str = "poll-key";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HintKind {
+    Deprecation,
+    UpgradeRequired,
+    Maintenance,
+}
+
+/// An operational notice from the server, signed but not encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemHint {
+    pub kind: HintKind,
+    pub text: String,
+    // The window the hint is about, e.g. a maintenance window or the date a
+    // deprecated behaviour goes away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+/// The body of every message in a mailbox's system channel. `hint` holds the
+/// exact JSON of a [`SystemHint`] that `signature` covers, so clients verify
+/// before parsing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedHint {
+    pub hint: String,
+    pub signature: String,
+}
+
+/// Blinded token nonces, base64 encoded, for the issuer to sign.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssueTokensRequest {
+    pub blinded_messages: Vec<String>,
+}
+
+/// Blind signatures, base64 encoded, in the order of the request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssueTokensResponse {
+    pub blind_signatures: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsPeriod {
+    Hour,
+    Day,
+}
+
+/// Delivery totals for one hour or one day, starting at `start`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalyticsBucket {
+    pub period: AnalyticsPeriod,
+    pub start: DateTime<Utc>,
+    pub messages: u64,
+    pub bytes: u64,
+    pub push_sends: u64,
+    pub active_mailboxes: u64, // Distinct message_ids written to during the period
+}
+
+/// One request seen during a trace capture: its shape and timing, never its
+/// ids or bodies.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraceEvent {
+    // When the request arrived, counted from the start of the capture.
+    pub offset_ms: u64,
+    pub route: String,
+    // The mailboxes it named, numbered from 0 in order of first appearance
+    // within the capture, so requests sharing a mailbox still share a number.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mailboxes: Vec<u32>,
+    pub body_len: usize,
+    // Length of the put's message field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_len: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    pub status: u16,
+    pub duration_ms: u64,
+}
+
+/// A finished trace capture, in arrival order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trace {
+    pub started_at: DateTime<Utc>,
+    pub events: Vec<TraceEvent>,
+    // Requests past the capture's limit, counted but not recorded.
+    pub dropped: u64,
+}
+
+// Message ids are base64 (standard or URL-safe) encodings of a hash derived from the
+// shared contact key, so anything else is a client bug rather than a real mailbox.
+pub fn check_message_id(message_id: &str) -> Option<ValidationIssue> {
+    let issue = |error_code: &str, message: String| ValidationIssue {
+        field: "message_id".to_string(),
+        error_code: error_code.to_string(),
+        message,
+    };
+    if message_id.is_empty() {
+        return Some(issue("EMPTY", "message_id must not be empty".to_string()));
+    }
+    if message_id.len() > MAX_MESSAGE_ID_LEN {
+        return Some(issue(
+            "TOO_LONG",
+            format!("message_id exceeds {} bytes", MAX_MESSAGE_ID_LEN),
+        ));
+    }
+    if !message_id
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_'))
+    {
+        return Some(issue(
+            "BAD_FORMAT",
+            "message_id must be base64 encoded".to_string(),
+        ));
+    }
+    None
+}
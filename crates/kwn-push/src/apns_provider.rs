@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kwn_protocol::{NotificationPayload, PushSubscriptionInfo};
+use p256::{
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    pkcs8::DecodePrivateKey,
+};
+use serde_json::json;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+
+use crate::{endpoint_address, EndpointHash, PushError, PushProvider, PushTopic, APNS_SCHEME};
+
+const PRODUCTION_HOST: &str = "https://api.push.apple.com";
+const SANDBOX_HOST: &str = "https://api.sandbox.push.apple.com";
+// APNs rejects provider tokens older than an hour, and refreshing more often
+// than every 20 minutes
+const TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+// Largest payload APNs accepts for an alert
+const MAX_PAYLOAD: usize = 4096;
+// Matching the Web Push TTL
+const EXPIRATION: Duration = Duration::from_secs(3600 * 48);
+// Device tokens are 32 bytes today; Apple reserves the right to lengthen them
+const MAX_TOKEN_LEN: usize = 200;
+
+/// Sends notifications to iOS devices through the Apple Push Notification
+/// service, over HTTP/2 with token-based authentication.
+///
+/// Configured by `APNS_KEY_FILE` (the `.p8` signing key from the developer
+/// account), `APNS_KEY_ID`, `APNS_TEAM_ID` and `APNS_TOPIC` (the app's bundle
+/// id). `APNS_SANDBOX=1` sends to the development environment instead.
+pub struct ApnsProvider {
+    client: reqwest::Client,
+    host: &'static str,
+    key: SigningKey,
+    key_id: String,
+    team_id: String,
+    topic: String,
+    // The signed provider token and when it was issued
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl ApnsProvider {
+    /// None unless `APNS_KEY_FILE` is set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(key_file) = std::env::var("APNS_KEY_FILE") else {
+            return Ok(None);
+        };
+        let pem = std::fs::read_to_string(&key_file)
+            .map_err(|e| format!("Failed to read APNS_KEY_FILE {}: {}", key_file, e))?;
+        let key = SigningKey::from_pkcs8_pem(&pem)
+            .map_err(|e| format!("APNS_KEY_FILE {} is not a P-256 key: {}", key_file, e))?;
+        let required = |name: &str| {
+            std::env::var(name).map_err(|_| format!("{} must be set with APNS_KEY_FILE", name))
+        };
+        let client = reqwest::Client::builder().http2_prior_knowledge().build()?;
+        Ok(Some(Self {
+            client,
+            host: match std::env::var("APNS_SANDBOX").as_deref() {
+                Ok("1") | Ok("true") => SANDBOX_HOST,
+                _ => PRODUCTION_HOST,
+            },
+            key,
+            key_id: required("APNS_KEY_ID")?,
+            team_id: required("APNS_TEAM_ID")?,
+            topic: required("APNS_TOPIC")?,
+            token: Mutex::new(None),
+        }))
+    }
+
+    // The current provider token, signing a fresh one once it nears expiry.
+    fn provider_token(&self) -> Result<String, PushError> {
+        let mut cached = self.token.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((token, issued)) = cached.as_ref() {
+            if issued.elapsed() < TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+        let issued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| PushError::Failed(format!("System clock is before 1970: {}", e)))?
+            .as_secs();
+        let header =
+            URL_SAFE_NO_PAD.encode(json!({"alg": "ES256", "kid": self.key_id}).to_string());
+        let claims =
+            URL_SAFE_NO_PAD.encode(json!({"iss": self.team_id, "iat": issued_at}).to_string());
+        let signing_input = format!("{}.{}", header, claims);
+        let signature: Signature = self.key.sign(signing_input.as_bytes());
+        let token = format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        );
+        *cached = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+
+    fn forget_provider_token(&self) {
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+// The APNs body: the alert in `aps`, with the rest of the payload beside it for
+// the app. The message is dropped if it would take the body past APNs' limit.
+fn apns_body(payload: &NotificationPayload) -> Result<Vec<u8>, serde_json::Error> {
+    let body = |message| {
+        let mut body = json!({
+            "aps": {
+                "alert": {"title": payload.title, "body": payload.body},
+                "sound": "default",
+            },
+            "url": payload.url,
+            "message": message,
+        });
+        if let Some(badge) = payload.badge {
+            body["aps"]["badge"] = json!(badge);
+        }
+        serde_json::to_vec(&body)
+    };
+    let full = body(&payload.message)?;
+    if full.len() > MAX_PAYLOAD && payload.message.is_some() {
+        return body(&None);
+    }
+    Ok(full)
+}
+
+// The hex device token an `apns:` endpoint names.
+fn device_token(endpoint: &str) -> Option<&str> {
+    endpoint_address(endpoint, APNS_SCHEME).filter(|token| {
+        !token.is_empty()
+            && token.len() <= MAX_TOKEN_LEN
+            && token.len() % 2 == 0
+            && token.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+#[async_trait]
+impl PushProvider for ApnsProvider {
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError> {
+        let Some(device_token) = device_token(&subscription.endpoint) else {
+            return Err(PushError::EndpointGone);
+        };
+        let body = apns_body(payload).map_err(|e| {
+            error!("Failed to serialize notification payload: {}", e);
+            PushError::Failed(format!("Failed to serialize notification payload: {}", e))
+        })?;
+
+        let endpoint = EndpointHash::of(subscription);
+        info!(
+            "Attempting to send APNs notification to device {}",
+            endpoint
+        );
+
+        let expiration = SystemTime::now()
+            .checked_add(EXPIRATION)
+            .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |at| at.as_secs());
+        let mut request = self
+            .client
+            .post(format!("{}/3/device/{}", self.host, device_token))
+            .bearer_auth(self.provider_token()?)
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "alert")
+            .header("apns-priority", "10")
+            .header("apns-expiration", expiration.to_string())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(topic) = topic {
+            // Like the Web Push topic, replaces an undelivered notification
+            request = request.header("apns-collapse-id", topic.as_str());
+        }
+
+        let response = request.send().await.map_err(|e| {
+            error!("Failed to send APNs request: {}", e);
+            PushError::Failed(format!("Failed to send APNs request: {}", e))
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            info!("APNs notification sent successfully!");
+            return Ok(());
+        }
+        // APNs explains a rejection in a JSON body's `reason`
+        let reason = response
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|body| body["reason"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        error!("APNs rejected notification: {} {}", status, reason);
+        match (status.as_u16(), reason.as_str()) {
+            (410, _) | (400, "BadDeviceToken" | "DeviceTokenNotForTopic") => {
+                warn!("APNs device {} is no longer registered", endpoint);
+                Err(PushError::EndpointGone)
+            }
+            (403, "ExpiredProviderToken") => {
+                self.forget_provider_token();
+                Err(PushError::Failed(format!("APNs: {}", reason)))
+            }
+            (403, _) => {
+                error!("APNs authorization failed - check the APNs key and team!");
+                Err(PushError::Unauthorized)
+            }
+            (429, _) => Err(PushError::RateLimited),
+            _ => Err(PushError::Failed(format!("APNs: {} {}", status, reason))),
+        }
+    }
+
+    fn check(&self, subscription: &PushSubscriptionInfo) -> Result<(), String> {
+        match device_token(&subscription.endpoint) {
+            Some(_) => Ok(()),
+            None => {
+                Err("push_subscription.endpoint must be apns: and a hex device token".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kwn_protocol::{NotificationPayload, PushSubscriptionInfo};
+use rsa::{
+    pkcs1v15::SigningKey,
+    pkcs8::DecodePrivateKey,
+    sha2::Sha256,
+    signature::{SignatureEncoding, Signer},
+    RsaPrivateKey,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{endpoint_address, EndpointHash, PushError, PushProvider, PushTopic, FCM_SCHEME};
+
+const SEND_HOST: &str = "https://fcm.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+// Google's access tokens last an hour; a new one is fetched this long before
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+// Largest message FCM accepts
+const MAX_PAYLOAD: usize = 4096;
+// Matching the Web Push TTL
+const TTL: &str = "172800s";
+// Registration tokens run to about 160 characters; Google documents no bound
+const MAX_TOKEN_LEN: usize = 1024;
+
+// The fields of a Google service account key file that signing needs.
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Sends notifications to Android devices through Firebase Cloud Messaging's
+/// HTTP v1 API, for clients whose browsers don't deliver Web Push reliably.
+///
+/// Configured by `FCM_SERVICE_ACCOUNT_FILE`, the JSON key of a service account
+/// allowed to send for the Firebase project. Its OAuth access token is fetched
+/// with a signed JWT and reused until shortly before it expires.
+pub struct FcmProvider {
+    client: reqwest::Client,
+    key: SigningKey<Sha256>,
+    client_email: String,
+    token_uri: String,
+    send_url: String,
+    // The access token and when it stops being used
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl FcmProvider {
+    /// None unless `FCM_SERVICE_ACCOUNT_FILE` is set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(account_file) = std::env::var("FCM_SERVICE_ACCOUNT_FILE") else {
+            return Ok(None);
+        };
+        let account: ServiceAccount =
+            serde_json::from_str(&std::fs::read_to_string(&account_file).map_err(|e| {
+                format!(
+                    "Failed to read FCM_SERVICE_ACCOUNT_FILE {}: {}",
+                    account_file, e
+                )
+            })?)
+            .map_err(|e| {
+                format!(
+                    "FCM_SERVICE_ACCOUNT_FILE {} is not a service account key: {}",
+                    account_file, e
+                )
+            })?;
+        let key = RsaPrivateKey::from_pkcs8_pem(&account.private_key).map_err(|e| {
+            format!(
+                "FCM_SERVICE_ACCOUNT_FILE {} holds no RSA private key: {}",
+                account_file, e
+            )
+        })?;
+        Ok(Some(Self {
+            client: reqwest::Client::new(),
+            key: SigningKey::new(key),
+            client_email: account.client_email,
+            token_uri: account.token_uri,
+            send_url: format!(
+                "{}/v1/projects/{}/messages:send",
+                SEND_HOST, account.project_id
+            ),
+            token: Mutex::new(None),
+        }))
+    }
+
+    // The current access token, exchanging a freshly signed assertion for a new
+    // one once it nears expiry. Held locked meanwhile so concurrent sends wait
+    // for the one exchange.
+    async fn access_token(&self) -> Result<String, PushError> {
+        let mut cached = self.token.lock().await;
+        if let Some((token, refresh_at)) = cached.as_ref() {
+            if Instant::now() < *refresh_at {
+                return Ok(token.clone());
+            }
+        }
+        let issued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| PushError::Failed(format!("System clock is before 1970: {}", e)))?
+            .as_secs();
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256", "typ": "JWT"}).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "iss": self.client_email,
+                "scope": SCOPE,
+                "aud": self.token_uri,
+                "iat": issued_at,
+                "exp": issued_at + 3600,
+            })
+            .to_string(),
+        );
+        let signing_input = format!("{}.{}", header, claims);
+        let signature = self.key.sign(signing_input.as_bytes());
+        let assertion = format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        );
+
+        // The assertion is base64url and dots, so needs no form encoding
+        let response = self
+            .client
+            .post(&self.token_uri)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(format!(
+                "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}",
+                assertion
+            ))
+            .send()
+            .await
+            .map_err(|e| {
+                PushError::Failed(format!("Failed to fetch an FCM access token: {}", e))
+            })?;
+        if !response.status().is_success() {
+            error!(
+                "Google refused the FCM service account: {}",
+                response.status()
+            );
+            return Err(PushError::Unauthorized);
+        }
+        let token: AccessToken = response
+            .json()
+            .await
+            .map_err(|e| PushError::Failed(format!("Malformed FCM access token: {}", e)))?;
+        let lifetime = Duration::from_secs(token.expires_in).saturating_sub(TOKEN_REFRESH_MARGIN);
+        *cached = Some((token.access_token.clone(), Instant::now() + lifetime));
+        Ok(token.access_token)
+    }
+
+    async fn forget_access_token(&self) {
+        *self.token.lock().await = None;
+    }
+}
+
+// The FCM message. FCM data values are strings only, so the badge and the
+// message go as text; the message is dropped if it would pass FCM's limit.
+fn fcm_body(
+    registration_token: &str,
+    payload: &NotificationPayload,
+    topic: Option<&PushTopic>,
+) -> Result<Vec<u8>, serde_json::Error> {
+    let body = |message: Option<String>| {
+        let mut data = json!({});
+        if let Some(url) = &payload.url {
+            data["url"] = json!(url);
+        }
+        if let Some(badge) = payload.badge {
+            data["badge"] = json!(badge.to_string());
+        }
+        if let Some(message) = message {
+            data["message"] = json!(message);
+        }
+        let mut android = json!({
+            "priority": "high",
+            "ttl": TTL,
+            "notification": {},
+        });
+        if let Some(topic) = topic {
+            // Like the Web Push topic, replaces an undelivered notification
+            android["collapse_key"] = json!(topic.as_str());
+            android["notification"]["tag"] = json!(topic.as_str());
+        }
+        if let Some(badge) = payload.badge {
+            android["notification"]["notification_count"] = json!(badge);
+        }
+        serde_json::to_vec(&json!({
+            "message": {
+                "token": registration_token,
+                "notification": {"title": payload.title, "body": payload.body},
+                "data": data,
+                "android": android,
+            }
+        }))
+    };
+    let message = payload
+        .message
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let dropped = message.is_none();
+    let full = body(message)?;
+    if full.len() > MAX_PAYLOAD && !dropped {
+        return body(None);
+    }
+    Ok(full)
+}
+
+// The registration token an `fcm:` endpoint names.
+fn registration_token(endpoint: &str) -> Option<&str> {
+    endpoint_address(endpoint, FCM_SCHEME).filter(|token| {
+        !token.is_empty()
+            && token.len() <= MAX_TOKEN_LEN
+            && token
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b':'))
+    })
+}
+
+// Whether an FCM error names the registration token as no longer valid.
+fn is_unregistered(error: &serde_json::Value) -> bool {
+    error["error"]["details"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|detail| detail["errorCode"] == "UNREGISTERED")
+}
+
+#[async_trait]
+impl PushProvider for FcmProvider {
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError> {
+        let Some(registration_token) = registration_token(&subscription.endpoint) else {
+            return Err(PushError::EndpointGone);
+        };
+        let body = fcm_body(registration_token, payload, topic).map_err(|e| {
+            error!("Failed to serialize notification payload: {}", e);
+            PushError::Failed(format!("Failed to serialize notification payload: {}", e))
+        })?;
+
+        let endpoint = EndpointHash::of(subscription);
+        info!("Attempting to send FCM notification to device {}", endpoint);
+
+        let response = self
+            .client
+            .post(&self.send_url)
+            .bearer_auth(self.access_token().await?)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| {
+                error!("Failed to send FCM request: {}", e);
+                PushError::Failed(format!("Failed to send FCM request: {}", e))
+            })?;
+        let status = response.status();
+        if status.is_success() {
+            info!("FCM notification sent successfully!");
+            return Ok(());
+        }
+        let error = response
+            .json::<serde_json::Value>()
+            .await
+            .unwrap_or_default();
+        let message = error["error"]["message"].as_str().unwrap_or_default();
+        error!("FCM rejected notification: {} {}", status, message);
+        match status.as_u16() {
+            404 => {
+                warn!("FCM device {} is no longer registered", endpoint);
+                Err(PushError::EndpointGone)
+            }
+            400 if is_unregistered(&error) => {
+                warn!("FCM device {} is no longer registered", endpoint);
+                Err(PushError::EndpointGone)
+            }
+            401 => {
+                // Revoked or expired early; the next send fetches another
+                self.forget_access_token().await;
+                Err(PushError::Failed(format!("FCM: {}", message)))
+            }
+            403 => {
+                error!("FCM authorization failed - check the service account!");
+                Err(PushError::Unauthorized)
+            }
+            429 => Err(PushError::RateLimited),
+            _ => Err(PushError::Failed(format!("FCM: {} {}", status, message))),
+        }
+    }
+
+    fn check(&self, subscription: &PushSubscriptionInfo) -> Result<(), String> {
+        match registration_token(&subscription.endpoint) {
+            Some(_) => Ok(()),
+            None => {
+                Err("push_subscription.endpoint must be fcm: and a registration token".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Push delivery boundary.
+//!
+//! [`PushProvider`] sends one notification to one subscription; deciding which
+//! subscriptions to notify and when is left to the server. [`PushRouter`] picks
+//! the provider by the scheme of the subscription's endpoint: an `https` URL
+//! is for Web Push, an `apns:` endpoint names an iOS device token for
+//! [`ApnsProvider`], an `fcm:` one an Android registration token for
+//! [`FcmProvider`], and a `webhook:` one an HTTPS URL for [`WebhookProvider`].
+
+mod apns_provider;
+mod fcm_provider;
+mod web_push_provider;
+mod webhook_provider;
+
+use async_trait::async_trait;
+use kwn_protocol::{MessageId, NotificationPayload, PushSubscriptionInfo};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt, sync::Arc};
+
+pub use apns_provider::ApnsProvider;
+pub use fcm_provider::FcmProvider;
+pub use web_push_provider::{
+    vapid_private_key, VapidKeyInfo, WebPushProvider, LEGACY_VAPID_KEY_ID,
+};
+pub use webhook_provider::{WebhookProvider, SIGNATURE_HEADER, TIMESTAMP_HEADER, TOPIC_HEADER};
+
+/// Scheme of APNs endpoints, `apns:` and the hex device token.
+pub const APNS_SCHEME: &str = "apns";
+/// Scheme of FCM endpoints, `fcm:` and the registration token.
+pub const FCM_SCHEME: &str = "fcm";
+/// Scheme of webhook endpoints, `webhook:` and the HTTPS URL.
+pub const WEBHOOK_SCHEME: &str = "webhook";
+
+/// The scheme an endpoint names its transport by: the text before its first
+/// `:`. Web Push endpoints are plain URLs, so theirs is `https`.
+pub fn endpoint_scheme(endpoint: &str) -> &str {
+    endpoint.split_once(':').map_or("", |(scheme, _)| scheme)
+}
+
+// What follows `scheme:` in `endpoint`, if it has that scheme.
+fn endpoint_address<'a>(endpoint: &'a str, scheme: &str) -> Option<&'a str> {
+    endpoint.strip_prefix(scheme)?.strip_prefix(':')
+}
+
+/// Truncated SHA-256 of a subscription endpoint. The endpoint URL is itself a
+/// capability to push to the device, so logs identify it by this instead.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EndpointHash(String);
+
+impl EndpointHash {
+    pub fn of(subscription: &PushSubscriptionInfo) -> Self {
+        Self(hex::encode(
+            &Sha256::digest(subscription.endpoint.as_bytes())[..8],
+        ))
+    }
+}
+
+impl fmt::Display for EndpointHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Web Push `Topic` for one mailbox's notifications. A push service holding an
+/// undelivered push replaces it with a newer one of the same topic, so a device
+/// coming back online gets one notification instead of a stack. Derived by
+/// hashing, so the push service never learns the message_id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushTopic(String);
+
+impl PushTopic {
+    pub fn for_mailbox(message_id: &MessageId) -> Self {
+        // 32 hex characters: the longest topic allowed, and all URL-safe
+        Self(hex::encode(&Sha256::digest(message_id.as_bytes())[..16]))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PushError {
+    #[error("Subscription endpoint is gone or invalid.")]
+    EndpointGone,
+    #[error("Push service authorization failed.")]
+    Unauthorized,
+    #[error("Push service rate limited the request.")]
+    RateLimited,
+    #[error("{0}")]
+    Failed(String),
+}
+
+#[async_trait]
+pub trait PushProvider: Send + Sync {
+    /// Sends `payload`, replacing any still-undelivered push with the same `topic`.
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError>;
+
+    /// Why `subscription` could never be delivered, checked before it is
+    /// stored so it isn't pushed to.
+    fn check(&self, _subscription: &PushSubscriptionInfo) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Whether subscriptions stay registered after a push. Devices re-register
+    /// on their next poll; integrations that don't poll need this.
+    fn persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Sends each subscription through the provider registered for its endpoint's
+/// scheme, so a new transport needs only a provider and a [`register`] call.
+///
+/// [`register`]: PushRouter::register
+pub struct PushRouter {
+    web_push: Arc<dyn PushProvider>,
+    by_scheme: HashMap<&'static str, Arc<dyn PushProvider>>,
+}
+
+impl PushRouter {
+    /// Routes `https` endpoints to `web_push` and nothing else yet.
+    pub fn new(web_push: Arc<dyn PushProvider>) -> Self {
+        Self {
+            web_push,
+            by_scheme: HashMap::new(),
+        }
+    }
+
+    /// Routes endpoints of `scheme` to `provider`.
+    pub fn register(&mut self, scheme: &'static str, provider: Arc<dyn PushProvider>) {
+        self.by_scheme.insert(scheme, provider);
+    }
+
+    fn provider(&self, endpoint: &str) -> Option<&dyn PushProvider> {
+        match endpoint_scheme(endpoint) {
+            "https" => Some(self.web_push.as_ref()),
+            scheme => self.by_scheme.get(scheme).map(Arc::as_ref),
+        }
+    }
+
+    /// Why `subscription` could never be delivered: no provider is registered
+    /// for its endpoint, or that provider rejects it.
+    pub fn check(&self, subscription: &PushSubscriptionInfo) -> Result<(), String> {
+        match self.provider(&subscription.endpoint) {
+            Some(provider) => provider.check(subscription),
+            None => {
+                let mut schemes: Vec<_> = self.by_scheme.keys().copied().collect();
+                schemes.sort_unstable();
+                Err(format!(
+                    "push_subscription.endpoint must be an https URL{}",
+                    schemes
+                        .iter()
+                        .map(|scheme| format!(" or {}:", scheme))
+                        .collect::<String>()
+                ))
+            }
+        }
+    }
+
+    /// Whether `subscription` stays registered after a push.
+    pub fn persistent(&self, subscription: &PushSubscriptionInfo) -> bool {
+        self.provider(&subscription.endpoint)
+            .is_some_and(|provider| provider.persistent())
+    }
+}
+
+#[async_trait]
+impl PushProvider for PushRouter {
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError> {
+        match self.provider(&subscription.endpoint) {
+            Some(provider) => provider.send(subscription, payload, topic).await,
+            // Stored before its transport was turned off; nothing can
+            // deliver it now
+            None => Err(PushError::EndpointGone),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kwn_protocol::{NotificationPayload, PushSubscriptionInfo};
+use serde::Serialize;
+use std::{sync::RwLock, time::Duration};
+use tracing::{error, info, warn};
+use web_push::{
+    ContentEncoding, IsahcWebPushClient, PartialVapidSignatureBuilder, SubscriptionInfo,
+    VapidSignatureBuilder, WebPushClient, WebPushError, WebPushMessageBuilder,
+};
+
+use crate::{EndpointHash, PushError, PushProvider, PushTopic};
+
+const MAX_ENDPOINT_LEN: usize = 2048;
+const MAX_KEY_ID_LEN: usize = 32;
+/// Id of `VAPID_PRIVATE_KEY` unless `VAPID_KEY_ID` says otherwise, and so of
+/// the key subscriptions naming none were made with.
+pub const LEGACY_VAPID_KEY_ID: &str = "1";
+
+/// One VAPID key pair, by the id subscriptions name it with.
+#[derive(Serialize, Debug, Clone)]
+pub struct VapidKeyInfo {
+    pub id: String,
+    /// Uncompressed P-256 point, base64url, as browsers take it for
+    /// `applicationServerKey`.
+    pub public_key: String,
+    /// Whether new subscriptions should be made with this key.
+    pub current: bool,
+}
+
+/// The current VAPID private key, base64url: `VAPID_PRIVATE_KEY`, or the
+/// contents of the file `VAPID_PRIVATE_KEY_FILE` names.
+pub fn vapid_private_key() -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(key) = std::env::var("VAPID_PRIVATE_KEY") {
+        return Ok(key);
+    }
+    let path = std::env::var("VAPID_PRIVATE_KEY_FILE")
+        .map_err(|_| "neither VAPID_PRIVATE_KEY nor VAPID_PRIVATE_KEY_FILE is set")?;
+    let key = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read VAPID_PRIVATE_KEY_FILE {}: {}", path, e))?;
+    Ok(key.trim().to_string())
+}
+
+struct VapidKey {
+    id: String,
+    public_key: String,
+    signer: PartialVapidSignatureBuilder,
+}
+
+impl VapidKey {
+    fn parse(id: &str, encoded: &str, var: &str) -> Result<Self, String> {
+        if id.is_empty()
+            || id.len() > MAX_KEY_ID_LEN
+            || !id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(format!(
+                "{} key id {:?} must be 1 to {} letters, digits, - or _",
+                var, id, MAX_KEY_ID_LEN
+            ));
+        }
+        let signer = VapidSignatureBuilder::from_base64_no_sub(encoded.trim())
+            .map_err(|e| format!("{} key {} is not a VAPID key: {}", var, id, e))?;
+        Ok(Self {
+            id: id.to_string(),
+            public_key: URL_SAFE_NO_PAD.encode(signer.get_public_key()),
+            signer,
+        })
+    }
+}
+
+/// Sends notifications through the browser's Web Push service, signed with
+/// the VAPID key each subscription was made with.
+///
+/// `VAPID_PRIVATE_KEY` (or the file `VAPID_PRIVATE_KEY_FILE`) is the current
+/// key, which new subscriptions should use, with id `VAPID_KEY_ID` (default
+/// `1`). Keys it replaced go in `VAPID_PREVIOUS_KEYS` as `id=key` pairs
+/// separated by commas, so subscriptions made with them keep working until
+/// their devices resubscribe.
+/// A subscription names its key by `vapid_key_id`, or is taken to use key `1`.
+///
+/// The keys are parsed and the HTTP client built once, so sends share its
+/// connection pool instead of each paying for a key parse and a TLS handshake.
+pub struct WebPushProvider {
+    client: IsahcWebPushClient,
+    // The current key first
+    keys: RwLock<Vec<VapidKey>>,
+}
+
+impl WebPushProvider {
+    /// Loads the VAPID private keys; startup has already checked the current
+    /// one is set.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let current_id =
+            std::env::var("VAPID_KEY_ID").unwrap_or_else(|_| LEGACY_VAPID_KEY_ID.to_string());
+        let mut keys = vec![VapidKey::parse(
+            current_id.trim(),
+            &vapid_private_key()?,
+            "VAPID_PRIVATE_KEY",
+        )?];
+        if let Ok(previous) = std::env::var("VAPID_PREVIOUS_KEYS") {
+            for entry in previous.split(',').filter(|entry| !entry.trim().is_empty()) {
+                let (id, encoded) = entry
+                    .split_once('=')
+                    .ok_or("VAPID_PREVIOUS_KEYS entries must be id=key")?;
+                let key = VapidKey::parse(id.trim(), encoded, "VAPID_PREVIOUS_KEYS")?;
+                if keys.iter().any(|known| known.id == key.id) {
+                    return Err(format!("VAPID key id {} is used twice", key.id).into());
+                }
+                keys.push(key);
+            }
+        }
+        Ok(Self {
+            client: IsahcWebPushClient::new()?,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Every key still signing, the current one first.
+    pub fn keys(&self) -> Vec<VapidKeyInfo> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        keys.iter()
+            .enumerate()
+            .map(|(i, key)| VapidKeyInfo {
+                id: key.id.clone(),
+                public_key: key.public_key.clone(),
+                current: i == 0,
+            })
+            .collect()
+    }
+
+    /// Stops signing with the previous key `id`, after a compromise or once its
+    /// devices have moved on. Pushes to subscriptions made with it fail as gone
+    /// from then on, and new ones naming it are refused. Returns whether there
+    /// was such a key; the current key can't be retired, only replaced.
+    pub fn retire(&self, id: &str) -> Result<bool, String> {
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        match keys.iter().position(|key| key.id == id) {
+            Some(0) => Err(format!(
+                "VAPID key {} is current; replace it before retiring it",
+                id
+            )),
+            Some(i) => {
+                keys.remove(i);
+                warn!("Retired VAPID key {}", id);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // The signer for the key `subscription` was made with, if still in use.
+    fn signer(&self, subscription: &PushSubscriptionInfo) -> Option<PartialVapidSignatureBuilder> {
+        let id = subscription
+            .vapid_key_id
+            .as_deref()
+            .unwrap_or(LEGACY_VAPID_KEY_ID);
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        keys.iter()
+            .find(|key| key.id == id)
+            .map(|key| key.signer.clone())
+    }
+}
+
+#[async_trait]
+impl PushProvider for WebPushProvider {
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError> {
+        let payload_json_bytes = serde_json::to_vec(payload).map_err(|e| {
+            error!("Failed to serialize notification payload: {}", e);
+            PushError::Failed(format!("Failed to serialize notification payload: {}", e))
+        })?;
+
+        let endpoint = EndpointHash::of(subscription);
+        info!("Attempting to send notification to endpoint {}", endpoint);
+
+        // 1. Convert our stored info to the web_push crate's format
+        let push_crate_sub_info = SubscriptionInfo::new(
+            subscription.endpoint.clone(),
+            subscription.keys.p256dh.clone(),
+            subscription.keys.auth.clone(),
+        );
+
+        // 2. Prepare the message builder
+        let Some(signer) = self.signer(subscription) else {
+            // Made with a retired key, so no push service would accept it
+            warn!("Subscription {} uses a retired VAPID key", endpoint);
+            return Err(PushError::EndpointGone);
+        };
+        let signature = signer
+            .add_sub_info(&push_crate_sub_info)
+            .build()
+            .map_err(|e| {
+                error!("Failed to build VAPID signature: {}", e);
+                PushError::Failed(format!("Failed to build VAPID signature: {}", e))
+            })?;
+
+        // Build the message
+        let mut message_builder = WebPushMessageBuilder::new(&push_crate_sub_info);
+
+        message_builder.set_payload(ContentEncoding::Aes128Gcm, &payload_json_bytes);
+        message_builder.set_vapid_signature(signature);
+        message_builder.set_ttl(Duration::from_secs(3600 * 48).as_secs() as u32);
+        if let Some(topic) = topic {
+            message_builder.set_topic(topic.as_str().to_string());
+        }
+
+        let message = message_builder.build().map_err(|e| {
+            error!("Failed to build web push message: {}", e);
+            PushError::Failed(format!("Failed building push message: {}", e))
+        })?;
+
+        // 3. Send the message using the shared web_push client
+        info!("Sending push message.");
+
+        match self.client.send(message).await {
+            Ok(()) => {
+                info!("Push message sent successfully!");
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to send push message: {}", e);
+                match e {
+                    WebPushError::EndpointNotValid(_) | WebPushError::EndpointNotFound(_) => {
+                        warn!("Subscription endpoint {} invalid or not found", endpoint);
+                        Err(PushError::EndpointGone)
+                    }
+                    WebPushError::Unauthorized(_) => {
+                        error!("Push service authorization failed - check VAPID keys!");
+                        Err(PushError::Unauthorized)
+                    }
+                    _ => Err(PushError::Failed(format!("Failed to send push: {}", e))),
+                }
+            }
+        }
+    }
+
+    fn check(&self, subscription: &PushSubscriptionInfo) -> Result<(), String> {
+        if !subscription.endpoint.starts_with("https://") {
+            return Err("push_subscription.endpoint must be an https URL".to_string());
+        }
+        if subscription.endpoint.len() > MAX_ENDPOINT_LEN {
+            return Err(format!(
+                "push_subscription.endpoint exceeds {} bytes",
+                MAX_ENDPOINT_LEN
+            ));
+        }
+        if self.signer(subscription).is_none() {
+            return Err(
+                "push_subscription.vapid_key_id names no VAPID key this server signs with"
+                    .to_string(),
+            );
+        }
+        // An uncompressed P-256 point and a 16-byte auth secret, per RFC 8291
+        for (field, value, len) in [
+            ("p256dh", &subscription.keys.p256dh, 65),
+            ("auth", &subscription.keys.auth, 16),
+        ] {
+            let decoded = URL_SAFE_NO_PAD.decode(value.trim_end_matches('='));
+            if !decoded.is_ok_and(|bytes| bytes.len() == len) {
+                return Err(format!(
+                    "push_subscription.keys.{} must be {} bytes of base64url",
+                    field, len
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use kwn_protocol::{NotificationPayload, PushSubscriptionInfo};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+use crate::{endpoint_address, EndpointHash, PushError, PushProvider, PushTopic, WEBHOOK_SCHEME};
+
+/// Hex HMAC-SHA256, as `sha256=<hex>`, of the timestamp, a `.` and the body.
+pub const SIGNATURE_HEADER: &str = "x-kwn-signature";
+/// Unix seconds when the request was signed, for receivers to reject replays.
+pub const TIMESTAMP_HEADER: &str = "x-kwn-timestamp";
+/// The channel's [`PushTopic`], for receivers matching one URL to many channels.
+pub const TOPIC_HEADER: &str = "x-kwn-topic";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POSTs the notification payload to integrations' HTTPS URLs, signed with
+/// the secret each registered as its subscription's `auth` key.
+///
+/// The server makes requests to URLs clients choose, so webhooks are off
+/// unless `WEBHOOK_ALLOWED_HOSTS` lists the hosts they may name, separated by
+/// commas, or is `*` for any. Redirects aren't followed.
+pub struct WebhookProvider {
+    client: reqwest::Client,
+    // None allows every host
+    allowed_hosts: Option<Vec<String>>,
+}
+
+impl WebhookProvider {
+    /// None unless `WEBHOOK_ALLOWED_HOSTS` is set.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(hosts) = std::env::var("WEBHOOK_ALLOWED_HOSTS") else {
+            return Ok(None);
+        };
+        let allowed_hosts = match hosts.trim() {
+            "*" => None,
+            hosts => Some(
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_ascii_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect(),
+            ),
+        };
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(TIMEOUT)
+            .build()?;
+        Ok(Some(Self {
+            client,
+            allowed_hosts,
+        }))
+    }
+
+    // Whether `url` names a host webhooks may be sent to.
+    fn allows(&self, url: &str) -> bool {
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return false;
+        };
+        match (&self.allowed_hosts, url.host_str()) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(allowed), Some(host)) => allowed.iter().any(|allowed| allowed == host),
+        }
+    }
+}
+
+// The https URL a `webhook:` endpoint names.
+fn webhook_url(endpoint: &str) -> Option<&str> {
+    endpoint_address(endpoint, WEBHOOK_SCHEME).filter(|url| url.starts_with("https://"))
+}
+
+// The HMAC key a webhook subscription registered, at least 16 bytes.
+fn webhook_secret(subscription: &PushSubscriptionInfo) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(subscription.keys.auth.trim_end_matches('='))
+        .ok()
+        .filter(|secret| secret.len() >= 16)
+}
+
+#[async_trait]
+impl PushProvider for WebhookProvider {
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError> {
+        let Some(url) = webhook_url(&subscription.endpoint) else {
+            return Err(PushError::EndpointGone);
+        };
+        // Hosts may have been dropped from the list since it subscribed
+        if !self.allows(url) {
+            return Err(PushError::EndpointGone);
+        }
+        let Some(secret) = webhook_secret(subscription) else {
+            return Err(PushError::EndpointGone);
+        };
+        let body = serde_json::to_vec(payload).map_err(|e| {
+            error!("Failed to serialize notification payload: {}", e);
+            PushError::Failed(format!("Failed to serialize notification payload: {}", e))
+        })?;
+
+        let endpoint = EndpointHash::of(subscription);
+        info!("Attempting to send webhook to endpoint {}", endpoint);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| PushError::Failed(format!("System clock is before 1970: {}", e)))?
+            .as_secs()
+            .to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret)
+            .map_err(|e| PushError::Failed(format!("Bad webhook secret: {}", e)))?;
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(&body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, signature)
+            .body(body);
+        if let Some(topic) = topic {
+            request = request.header(TOPIC_HEADER, topic.as_str());
+        }
+        // The URL may carry the integration's own credentials, so isn't logged
+        let response = request.send().await.map_err(|e| {
+            let e = e.without_url();
+            error!("Failed to send webhook: {}", e);
+            PushError::Failed(format!("Failed to send webhook: {}", e))
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            info!("Webhook sent successfully!");
+            return Ok(());
+        }
+        error!("Webhook endpoint {} answered {}", endpoint, status);
+        match status.as_u16() {
+            404 | 410 => {
+                warn!("Webhook endpoint {} is gone", endpoint);
+                Err(PushError::EndpointGone)
+            }
+            429 => Err(PushError::RateLimited),
+            _ => Err(PushError::Failed(format!("Webhook answered {}", status))),
+        }
+    }
+
+    fn check(&self, subscription: &PushSubscriptionInfo) -> Result<(), String> {
+        let Some(url) = webhook_url(&subscription.endpoint) else {
+            return Err("push_subscription.endpoint must be webhook: and an https URL".to_string());
+        };
+        if !self.allows(url) {
+            return Err("this server does not send webhooks to that host".to_string());
+        }
+        if webhook_secret(subscription).is_none() {
+            return Err(
+                "push_subscription.keys.auth must be a webhook secret of at least 16 bytes of base64url"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    // Integrations don't poll, so have nothing to re-register them
+    fn persistent(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,240 @@
+//! On-disk encoding of message values.
+//!
+//! Current values are a version byte, a flags byte, the big-endian expiry
+//! millis when [`FLAG_EXPIRES`] is set, the big-endian channel sequence number
+//! when [`FLAG_SEQUENCE`] is set, and the raw message body; the timestamp lives
+//! only in the key. Values written before this format are
+//! JSON `MessageRecord`s and always start with `{`, which never collides with a
+//! version byte, so both decode side by side until old records are acked away.
+//! Under [`DeletionPolicy::Overwrite`](crate::DeletionPolicy) a value is zeroed
+//! before it is removed; see [`is_overwritten`].
+
+use chrono::{DateTime, Utc};
+use kwn_protocol::MessageId;
+use serde::Deserialize;
+use smallvec::SmallVec;
+
+use crate::{Result, StorageError};
+
+const VERSION_1: u8 = 1;
+const TIMESTAMP_LEN: usize = 8;
+/// The value carries an expiry after the flags byte.
+pub const FLAG_EXPIRES: u8 = 1;
+/// The value carries its sequence number after the expiry, if any.
+pub const FLAG_SEQUENCE: u8 = 2;
+const SEQUENCE_LEN: usize = 8;
+// Covers the default put size limit, so ordinary puts encode on the stack;
+// fjall copies the value into its own buffer regardless.
+const INLINE_VALUE_LEN: usize = 3072;
+
+pub type EncodedMessage = SmallVec<[u8; INLINE_VALUE_LEN]>;
+
+pub struct DecodedMessage {
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+    pub sequence: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct LegacyRecord {
+    message: String,
+    timestamp: DateTime<Utc>,
+}
+
+pub fn encode_message(message: &str, expires_at: Option<DateTime<Utc>>) -> EncodedMessage {
+    encode_sequenced(message, expires_at, None)
+}
+
+pub fn encode_sequenced(
+    message: &str,
+    expires_at: Option<DateTime<Utc>>,
+    sequence: Option<u64>,
+) -> EncodedMessage {
+    let mut value = EncodedMessage::with_capacity(2 + TIMESTAMP_LEN + SEQUENCE_LEN + message.len());
+    value.push(VERSION_1);
+    let mut flags = 0;
+    if expires_at.is_some() {
+        flags |= FLAG_EXPIRES;
+    }
+    if sequence.is_some() {
+        flags |= FLAG_SEQUENCE;
+    }
+    value.push(flags);
+    if let Some(expires_at) = expires_at {
+        value.extend_from_slice(&expires_at.timestamp_millis().to_be_bytes());
+    }
+    if let Some(sequence) = sequence {
+        value.extend_from_slice(&sequence.to_be_bytes());
+    }
+    value.extend_from_slice(message.as_bytes());
+    value
+}
+
+// Re-encodes a current-format value written without a sequence number, such
+// as a scheduled message coming due, with `sequence`.
+pub fn with_sequence(value: &[u8], sequence: u64) -> Result<EncodedMessage> {
+    if value.first() != Some(&VERSION_1) || value.len() < body_offset(value) {
+        return Err(StorageError::Corrupt(
+            "can't sequence a value in an old format".to_string(),
+        ));
+    }
+    let body = std::str::from_utf8(&value[body_offset(value)..])
+        .map_err(|e| StorageError::Corrupt(format!("message body not UTF-8: {}", e)))?;
+    Ok(encode_sequenced(body, value_expiry(value), Some(sequence)))
+}
+
+fn flags(value: &[u8]) -> u8 {
+    value.get(1).copied().unwrap_or(0)
+}
+
+// Where the body starts in a current-format value.
+fn body_offset(value: &[u8]) -> usize {
+    let flags = flags(value);
+    let mut offset = 2;
+    if flags & FLAG_EXPIRES != 0 {
+        offset += TIMESTAMP_LEN;
+    }
+    if flags & FLAG_SEQUENCE != 0 {
+        offset += SEQUENCE_LEN;
+    }
+    offset
+}
+
+// Body length of a value written by `encode_message`, without copying it out.
+pub fn encoded_body_len(value: &[u8]) -> usize {
+    value.len().saturating_sub(body_offset(value))
+}
+
+// Reads a current-format value's expiry without touching the body. Legacy
+// values never expire.
+pub fn value_expiry(value: &[u8]) -> Option<DateTime<Utc>> {
+    if value.first() != Some(&VERSION_1) || flags(value) & FLAG_EXPIRES == 0 {
+        return None;
+    }
+    value
+        .get(2..2 + TIMESTAMP_LEN)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(i64::from_be_bytes)
+        .and_then(DateTime::from_timestamp_millis)
+}
+
+// The channel sequence number of a current-format value, if it was given one.
+fn value_sequence(value: &[u8]) -> Option<u64> {
+    let flags = flags(value);
+    if value.first() != Some(&VERSION_1) || flags & FLAG_SEQUENCE == 0 {
+        return None;
+    }
+    let start = if flags & FLAG_EXPIRES != 0 {
+        2 + TIMESTAMP_LEN
+    } else {
+        2
+    };
+    value
+        .get(start..start + SEQUENCE_LEN)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_be_bytes)
+}
+
+// Expired values wait for the sweeper; readers skip them meanwhile.
+pub fn is_expired(value: &[u8], now: DateTime<Utc>) -> bool {
+    value_expiry(value).is_some_and(|expires_at| expires_at <= now)
+}
+
+// Zeroed values are acked messages between their overwrite and removal
+// commits; readers skip them rather than treating them as corrupt.
+pub fn is_overwritten(value: &[u8]) -> bool {
+    value.first() == Some(&0)
+}
+
+// Whether a key found by a prefix scan for `message_id` is one of its own
+// rather than a longer id's that starts the same way, e.g. `a/b` under `a/`.
+pub fn key_is_for(key: &[u8], message_id: &MessageId) -> bool {
+    key.len() == message_id.as_bytes().len() + TIMESTAMP_LEN
+}
+
+// The id a message key belongs to.
+pub fn key_message_id(key: &[u8]) -> Result<MessageId> {
+    key.len()
+        .checked_sub(TIMESTAMP_LEN)
+        .and_then(|end| std::str::from_utf8(&key[..end]).ok())
+        .and_then(|id| MessageId::parse(id).ok())
+        .ok_or_else(|| StorageError::Corrupt("bad message_id in message key".to_string()))
+}
+
+// Keys end with the big-endian millisecond timestamp (see `message_key`).
+pub fn key_timestamp(key: &[u8]) -> Result<DateTime<Utc>> {
+    let millis = key
+        .len()
+        .checked_sub(TIMESTAMP_LEN)
+        .and_then(|start| key[start..].try_into().ok())
+        .map(i64::from_be_bytes)
+        .ok_or_else(|| StorageError::Corrupt("message key too short".to_string()))?;
+    DateTime::from_timestamp_millis(millis)
+        .ok_or_else(|| StorageError::Corrupt(format!("timestamp {} out of range", millis)))
+}
+
+pub fn decode_message(key: &[u8], value: &[u8]) -> Result<DecodedMessage> {
+    match value.first() {
+        Some(&VERSION_1) if value.len() >= body_offset(value) => Ok(DecodedMessage {
+            message: String::from_utf8(value[body_offset(value)..].to_vec())
+                .map_err(|e| StorageError::Corrupt(format!("message body not UTF-8: {}", e)))?,
+            timestamp: key_timestamp(key)?,
+            sequence: value_sequence(value),
+        }),
+        Some(b'{') => {
+            let record: LegacyRecord = serde_json::from_slice(value)?;
+            Ok(DecodedMessage {
+                message: record.message,
+                timestamp: record.timestamp,
+                sequence: None,
+            })
+        }
+        _ => Err(StorageError::Corrupt(
+            "unrecognized message value format".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // A legacy value as the single-binary server wrote it, with the
+    // nanosecond timestamp `Utc::now()` gives on Linux.
+    fn legacy_value(message: &str, timestamp: DateTime<Utc>) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "message": message,
+            "timestamp": timestamp,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn binary_values_are_smaller_than_legacy_json_by_a_fixed_overhead() {
+        let timestamp = Utc
+            .timestamp_opt(1_760_572_800, 123_456_789)
+            .single()
+            .unwrap();
+        let expires_at = timestamp + chrono::Duration::days(7);
+        let key = {
+            let mut key = b"inbox".to_vec();
+            key.extend_from_slice(&timestamp.timestamp_millis().to_be_bytes());
+            key
+        };
+        // Bodies are base64 ciphertext, which JSON never needs to escape.
+        for body_len in [64, 256, 1024, 4096] {
+            let body: String = "AbC+/9xY".chars().cycle().take(body_len).collect();
+            let legacy = legacy_value(&body, timestamp);
+            let plain = encode_message(&body, None);
+            let expiring = encode_message(&body, Some(expires_at));
+
+            assert_eq!(legacy.len(), body_len + 59);
+            assert_eq!(plain.len(), body_len + 2);
+            assert_eq!(expiring.len(), body_len + 10);
+            for value in [&legacy[..], &plain[..], &expiring[..]] {
+                assert_eq!(decode_message(&key, value).unwrap().message, body);
+            }
+        }
+    }
+}
@@ -0,0 +1,2443 @@
+use chrono::{DateTime, Utc};
+use fjall::{
+    Config, PartitionCreateOptions, PersistMode, ReadTransaction, Slice, TransactionalKeyspace,
+    TransactionalPartitionHandle, WriteTransaction,
+};
+use kwn_protocol::{
+    AckToken, AnalyticsBucket, AnalyticsPeriod, DeliveryReceipt, DeliveryState, FoundMessage,
+    MessageId, MessageState, PushSubscriptionInfo, PutMessageRequest,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::Range,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    codec::{
+        decode_message, encode_message, encode_sequenced, encoded_body_len, is_expired,
+        is_overwritten, key_is_for, key_message_id, key_timestamp, value_expiry, with_sequence,
+    },
+    email_key, message_key,
+    persistence::{PersistPacer, PersistPolicy, PersistReason},
+    phone_key, AnalyticsStore, CancelOutcome, DeadLetter, DeletionPolicy, DeliveryAnomalies,
+    EmailStore, MessageKey, MessageStore, OutboxJob, OutboxStore, Result, ShareLinkStore, SmsStore,
+    StorageError, StorageHealth, SubscriptionStore, TokenStore, MAX_SUBSCRIPTIONS_PER_ID,
+};
+
+/// fjall-backed store holding the `messages`, `subscriptions`,
+/// `subscription_blobs`, `resubscribe`, `tokens`, `share_links`, `idempotency`, `scheduled`, `delivered`,
+/// `receipts`, `leases`, `cursors`, `handles`, `retained`, `sequences`, `counts`,
+/// `push_outbox`, `push_dead_letters`, `email_fallbacks`, `sms_numbers` and
+/// `analytics` partitions.
+pub struct FjallStore {
+    keyspace: TransactionalKeyspace,
+    messages: TransactionalPartitionHandle,
+    subscriptions: TransactionalPartitionHandle,
+    // Each device's latest subscription JSON keyed by the SHA-256 of its
+    // endpoint and prefixed by how many subscription keys refer to it, since
+    // one device often subscribes thousands of mailboxes
+    subscription_blobs: TransactionalPartitionHandle,
+    resubscribe: TransactionalPartitionHandle,
+    tokens: TransactionalPartitionHandle,
+    share_links: TransactionalPartitionHandle,
+    idempotency: TransactionalPartitionHandle,
+    scheduled: TransactionalPartitionHandle,
+    delivered: TransactionalPartitionHandle,
+    receipts: TransactionalPartitionHandle,
+    leases: TransactionalPartitionHandle,
+    cursors: TransactionalPartitionHandle,
+    handles: TransactionalPartitionHandle,
+    retained: TransactionalPartitionHandle,
+    // Last sequence number handed out per message_id, kept after its messages
+    // are acked so numbers never repeat
+    sequences: TransactionalPartitionHandle,
+    // Per message_id, how many live records `messages` holds and how many of
+    // those carry an expiry, kept by every write there so counting skips a scan
+    counts: TransactionalPartitionHandle,
+    // Pushes not yet sent, keyed like scheduled messages: by when they're due,
+    // then the message_id
+    push_outbox: TransactionalPartitionHandle,
+    // Pushes that used up their retries, keyed by when they failed and then the
+    // message_id; values are the attempt count and the last error
+    push_dead_letters: TransactionalPartitionHandle,
+    // Fallback email addresses, keyed by a hash of their mailbox's id
+    email_fallbacks: TransactionalPartitionHandle,
+    // Numbers opted in to SMS alerts, keyed by a hash of their mailbox's id
+    sms_numbers: TransactionalPartitionHandle,
+    analytics: TransactionalPartitionHandle,
+    compacting: AtomicBool,
+    deletion_policy: DeletionPolicy,
+    pacer: PersistPacer,
+}
+
+// fjall's defaults, set explicitly so health checks know where stalls begin.
+const MAX_WRITE_BUFFER_BYTES: u64 = 64 * 1024 * 1024;
+const MAX_JOURNALING_BYTES: u64 = 512 * 1024 * 1024;
+// Sealed journals beyond this mean flushes are falling behind writes.
+const JOURNAL_COUNT_LIMIT: usize = 8;
+// Fraction of a limit at which readiness is withdrawn.
+const STALL_RISK_RATIO: f64 = 0.9;
+
+impl FjallStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let keyspace = Config::new(path)
+            .max_write_buffer_size(MAX_WRITE_BUFFER_BYTES)
+            .max_journaling_size(MAX_JOURNALING_BYTES)
+            .open_transactional()?;
+        let messages = keyspace.open_partition("messages", PartitionCreateOptions::default())?;
+        let subscriptions =
+            keyspace.open_partition("subscriptions", PartitionCreateOptions::default())?;
+        let subscription_blobs =
+            keyspace.open_partition("subscription_blobs", PartitionCreateOptions::default())?;
+        let resubscribe =
+            keyspace.open_partition("resubscribe", PartitionCreateOptions::default())?;
+        let tokens = keyspace.open_partition("tokens", PartitionCreateOptions::default())?;
+        let share_links =
+            keyspace.open_partition("share_links", PartitionCreateOptions::default())?;
+        let idempotency =
+            keyspace.open_partition("idempotency", PartitionCreateOptions::default())?;
+        let scheduled = keyspace.open_partition("scheduled", PartitionCreateOptions::default())?;
+        let delivered = keyspace.open_partition("delivered", PartitionCreateOptions::default())?;
+        let receipts = keyspace.open_partition("receipts", PartitionCreateOptions::default())?;
+        let leases = keyspace.open_partition("leases", PartitionCreateOptions::default())?;
+        let cursors = keyspace.open_partition("cursors", PartitionCreateOptions::default())?;
+        let handles = keyspace.open_partition("handles", PartitionCreateOptions::default())?;
+        let retained = keyspace.open_partition("retained", PartitionCreateOptions::default())?;
+        let sequences = keyspace.open_partition("sequences", PartitionCreateOptions::default())?;
+        let counts = keyspace.open_partition("counts", PartitionCreateOptions::default())?;
+        let push_outbox =
+            keyspace.open_partition("push_outbox", PartitionCreateOptions::default())?;
+        let push_dead_letters =
+            keyspace.open_partition("push_dead_letters", PartitionCreateOptions::default())?;
+        let email_fallbacks =
+            keyspace.open_partition("email_fallbacks", PartitionCreateOptions::default())?;
+        let sms_numbers =
+            keyspace.open_partition("sms_numbers", PartitionCreateOptions::default())?;
+        let analytics = keyspace.open_partition("analytics", PartitionCreateOptions::default())?;
+        let store = Self {
+            keyspace,
+            messages,
+            subscriptions,
+            subscription_blobs,
+            resubscribe,
+            tokens,
+            share_links,
+            idempotency,
+            scheduled,
+            delivered,
+            receipts,
+            leases,
+            cursors,
+            handles,
+            retained,
+            sequences,
+            counts,
+            push_outbox,
+            push_dead_letters,
+            email_fallbacks,
+            sms_numbers,
+            analytics,
+            compacting: AtomicBool::new(false),
+            deletion_policy: DeletionPolicy::default(),
+            pacer: PersistPacer::new(PersistPolicy::default()),
+        };
+        store.backfill_counts()?;
+        Ok(store)
+    }
+
+    // Databases from before the counts partition open with it empty, as does
+    // one with no live messages, for which recounting is just as cheap.
+    fn backfill_counts(&self) -> Result<()> {
+        let read_tx = self.keyspace.read_tx();
+        if !read_tx.is_empty(&self.counts)? {
+            return Ok(());
+        }
+        let mut tallies: BTreeMap<MessageId, (u64, u64)> = BTreeMap::new();
+        for result in read_tx.iter(&self.messages) {
+            let (key, value) = result?;
+            let (live, expiring) = tally_weight(Some(&value));
+            if live > 0 {
+                let tally = tallies.entry(key_message_id(&key)?).or_default();
+                tally.0 += live;
+                tally.1 += expiring;
+            }
+        }
+        drop(read_tx);
+        if tallies.is_empty() {
+            return Ok(());
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for (message_id, (count, expiring)) in &tallies {
+            write_tx.insert(
+                &self.counts,
+                message_id.as_bytes(),
+                encode_tally(*count, *expiring),
+            );
+        }
+        write_tx.commit()?;
+        info!("Counted messages of {} mailboxes", tallies.len());
+        Ok(())
+    }
+
+    // Writes a message record, keeping its mailbox's tally in step.
+    fn insert_message(
+        &self,
+        write_tx: &mut WriteTransaction,
+        message_id: &MessageId,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        let old = write_tx.get(&self.messages, key)?;
+        self.tally(write_tx, message_id, old.as_deref(), Some(value))?;
+        write_tx.insert(&self.messages, key, value);
+        Ok(())
+    }
+
+    // Removes a message record, keeping its mailbox's tally in step.
+    fn remove_message(
+        &self,
+        write_tx: &mut WriteTransaction,
+        message_id: &MessageId,
+        key: &[u8],
+    ) -> Result<()> {
+        if let Some(old) = write_tx.get(&self.messages, key)? {
+            self.tally(write_tx, message_id, Some(&old), None)?;
+            write_tx.remove(&self.messages, key);
+        }
+        Ok(())
+    }
+
+    // Moves `message_id`'s tally from counting `old` to counting `new`, where
+    // either is a record value or None for no record.
+    fn tally(
+        &self,
+        write_tx: &mut WriteTransaction,
+        message_id: &MessageId,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<()> {
+        let (old, new) = (tally_weight(old), tally_weight(new));
+        if old == new {
+            return Ok(());
+        }
+        let (count, expiring) = match write_tx.get(&self.counts, message_id.as_bytes())? {
+            Some(value) => decode_tally(&value)?,
+            None => (0, 0),
+        };
+        let count = (count + new.0).saturating_sub(old.0);
+        let expiring = (expiring + new.1).saturating_sub(old.1);
+        if count == 0 {
+            write_tx.remove(&self.counts, message_id.as_bytes());
+        } else {
+            write_tx.insert(
+                &self.counts,
+                message_id.as_bytes(),
+                encode_tally(count, expiring),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn with_deletion_policy(mut self, deletion_policy: DeletionPolicy) -> Self {
+        self.deletion_policy = deletion_policy;
+        self
+    }
+
+    pub fn with_persist_policy(mut self, persist_policy: PersistPolicy) -> Self {
+        self.pacer = PersistPacer::new(persist_policy);
+        self
+    }
+
+    // Counts writes toward the persist policy, syncing once enough are pending.
+    fn mutated(&self, mutations: usize) -> Result<()> {
+        if self.pacer.record(mutations as u64) {
+            self.pacer
+                .persist(PersistReason::Threshold, || self.sync_journal())?;
+        }
+        Ok(())
+    }
+
+    fn sync_journal(&self) -> Result<()> {
+        self.keyspace.persist(PersistMode::SyncAll)?;
+        Ok(())
+    }
+
+    // Removes the per-mailbox bookkeeping both reaping and forgetting drop.
+    // The sequence counter stays while messages do, so numbering never
+    // restarts under messages a client has already seen numbered.
+    fn remove_idle_state(
+        &self,
+        write_tx: &mut WriteTransaction,
+        message_id: &MessageId,
+    ) -> Result<()> {
+        let mut cursor_prefix = message_id.as_bytes().to_vec();
+        cursor_prefix.push(b'\n');
+        let mut doomed = Vec::new();
+        for result in write_tx.prefix(&self.cursors, &cursor_prefix) {
+            doomed.push((&self.cursors, result?.0));
+        }
+        for result in write_tx.prefix(&self.leases, message_id.as_bytes()) {
+            let (key, _) = result?;
+            if key_is_for(&key, message_id) {
+                doomed.push((&self.leases, key));
+            }
+        }
+        let mut holds_messages = false;
+        for result in write_tx.prefix(&self.messages, message_id.as_bytes()) {
+            if key_is_for(&result?.0, message_id) {
+                holds_messages = true;
+                break;
+            }
+        }
+        for (partition, key) in doomed {
+            write_tx.remove(partition, key);
+        }
+        if !holds_messages {
+            write_tx.remove(&self.sequences, message_id.as_bytes());
+        }
+        write_tx.remove(&self.resubscribe, message_id.as_bytes());
+        Ok(())
+    }
+
+    // Drops the still-scheduled message `token` names with its handle and
+    // receipt request, in one transaction. Returns false if it isn't waiting.
+    fn unschedule(&self, token: &AckToken) -> Result<bool> {
+        let scheduled = scheduled_key(&token.message_id, token.timestamp);
+        let key = message_key(&token.message_id, token.timestamp);
+        let mut write_tx = self.keyspace.write_tx();
+        if !write_tx.contains_key(&self.scheduled, &scheduled)? {
+            return Ok(false);
+        }
+        write_tx.remove(&self.scheduled, scheduled);
+        write_tx.remove(&self.handles, key.as_slice());
+        write_tx.remove(&self.receipts, key.as_slice());
+        write_tx.commit()?;
+        self.mutated(1)?;
+        Ok(true)
+    }
+
+    // Hands out `message_id`'s next sequence number inside `write_tx`, so the
+    // number commits or rolls back with the message carrying it.
+    fn next_sequence(
+        &self,
+        write_tx: &mut WriteTransaction,
+        message_id: &MessageId,
+    ) -> Result<u64> {
+        let sequence = match write_tx.get(&self.sequences, message_id.as_bytes())? {
+            Some(value) => {
+                u64::from_be_bytes(
+                    value
+                        .as_ref()
+                        .try_into()
+                        .map_err(|_| StorageError::Corrupt("bad sequence number".to_string()))?,
+                ) + 1
+            }
+            None => 1,
+        };
+        write_tx.insert(
+            &self.sequences,
+            message_id.as_bytes(),
+            sequence.to_be_bytes(),
+        );
+        Ok(sequence)
+    }
+
+    // Replaces each acked value with zeros of the same length and syncs the
+    // journal, so the plaintext's most recent copy is gone before the key is.
+    fn overwrite_acked(&self, acks: &[AckToken]) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        for ack in acks {
+            let key = message_key(&ack.message_id, ack.timestamp);
+            if let Some(value) = write_tx.get(&self.messages, &key)? {
+                self.insert_message(
+                    &mut write_tx,
+                    &ack.message_id,
+                    &key,
+                    &vec![0u8; value.len()],
+                )?;
+            }
+        }
+        write_tx.commit()?;
+        self.keyspace.persist(PersistMode::SyncAll)?;
+        Ok(())
+    }
+}
+
+impl MessageStore for FjallStore {
+    fn put(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        let sequence = self.next_sequence(&mut write_tx, message_id)?;
+        self.insert_message(
+            &mut write_tx,
+            message_id,
+            &message_key(message_id, timestamp),
+            &encode_sequenced(message, expires_at, Some(sequence)),
+        )?;
+        write_tx.commit()?;
+        self.mutated(1)
+    }
+
+    fn supersede(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        supersedes: DateTime<Utc>,
+    ) -> Result<()> {
+        let old = AckToken {
+            message_id: message_id.clone(),
+            timestamp: supersedes,
+        };
+        if self.deletion_policy == DeletionPolicy::Overwrite {
+            self.overwrite_acked(std::slice::from_ref(&old))?;
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        let old_key = message_key(&old.message_id, old.timestamp);
+        self.remove_message(&mut write_tx, message_id, &old_key)?;
+        write_tx.remove(&self.delivered, old_key.as_slice());
+        write_tx.remove(&self.receipts, old_key.as_slice());
+        write_tx.remove(&self.leases, old_key.as_slice());
+        write_tx.remove(&self.handles, old_key.as_slice());
+        let sequence = self.next_sequence(&mut write_tx, message_id)?;
+        self.insert_message(
+            &mut write_tx,
+            message_id,
+            &message_key(message_id, timestamp),
+            &encode_sequenced(message, expires_at, Some(sequence)),
+        )?;
+        write_tx.commit()?;
+        self.mutated(1)
+    }
+
+    fn put_idempotent(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        idempotency_key: &str,
+        key_expires_at: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>> {
+        let dedup_key = idempotency_record_key(message_id, idempotency_key);
+        // One transaction, so concurrent retries can't both miss the record
+        let mut write_tx = self.keyspace.write_tx();
+        if let Some(value) = write_tx.get(&self.idempotency, &dedup_key)? {
+            if value_millis(&value, 0)? > timestamp {
+                return value_millis(&value, 8);
+            }
+        }
+        let sequence = self.next_sequence(&mut write_tx, message_id)?;
+        self.insert_message(
+            &mut write_tx,
+            message_id,
+            &message_key(message_id, timestamp),
+            &encode_sequenced(message, expires_at, Some(sequence)),
+        )?;
+        let mut value = key_expires_at.timestamp_millis().to_be_bytes().to_vec();
+        value.extend_from_slice(&timestamp.timestamp_millis().to_be_bytes());
+        write_tx.insert(&self.idempotency, dedup_key, value);
+        write_tx.commit()?;
+        self.mutated(1)?;
+        Ok(timestamp)
+    }
+
+    fn prune_idempotency_keys(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut expired = Vec::new();
+        for result in self.keyspace.read_tx().iter(&self.idempotency) {
+            let (key, value) = result?;
+            if value_millis(&value, 0)? <= now {
+                expired.push(key);
+            }
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for key in &expired {
+            write_tx.remove(&self.idempotency, key.clone());
+        }
+        write_tx.commit()?;
+        Ok(expired.len())
+    }
+
+    fn schedule(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        deliver_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.scheduled.insert(
+            scheduled_key(message_id, deliver_at),
+            encode_message(message, expires_at).as_slice(),
+        )?;
+        self.mutated(1)
+    }
+
+    // Scheduled keys lead with the due time, so everything due is one range
+    // read from the start of the partition.
+    fn release_due(&self, now: DateTime<Utc>) -> Result<Vec<(MessageId, usize)>> {
+        let end = (now.timestamp_millis() + 1).to_be_bytes();
+        let mut due = Vec::new();
+        for result in self
+            .keyspace
+            .read_tx()
+            .range(&self.scheduled, ..end.as_slice())
+        {
+            let (key, value) = result?;
+            let (message_id, deliver_at) = decode_scheduled_key(&key)?;
+            due.push((key, value, message_id, deliver_at));
+        }
+        if due.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        let mut released = Vec::with_capacity(due.len());
+        // Numbered as they come due, which is when polls first see them
+        for (key, value, message_id, deliver_at) in due {
+            let sequence = self.next_sequence(&mut write_tx, &message_id)?;
+            self.insert_message(
+                &mut write_tx,
+                &message_id,
+                &message_key(&message_id, deliver_at),
+                &with_sequence(&value, sequence)?,
+            )?;
+            write_tx.remove(&self.scheduled, key);
+            released.push((message_id, encoded_body_len(&value)));
+        }
+        write_tx.commit()?;
+        self.mutated(released.len())?;
+        Ok(released)
+    }
+
+    fn put_batch(&self, entries: &[PutMessageRequest], timestamp: DateTime<Utc>) -> Result<()> {
+        // Repeats would share a key, the later silently replacing the earlier
+        let mut seen = HashSet::with_capacity(entries.len());
+        if let Some(entry) = entries.iter().find(|entry| !seen.insert(&entry.message_id)) {
+            return Err(StorageError::DuplicateId(entry.message_id.clone()));
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for entry in entries {
+            let sequence = self.next_sequence(&mut write_tx, &entry.message_id)?;
+            self.insert_message(
+                &mut write_tx,
+                &entry.message_id,
+                &message_key(&entry.message_id, timestamp),
+                &encode_sequenced(
+                    &entry.message,
+                    entry
+                        .ttl_seconds
+                        .map(|ttl| timestamp + chrono::Duration::seconds(ttl as i64)),
+                    Some(sequence),
+                ),
+            )?;
+        }
+        write_tx.commit()?;
+        self.mutated(entries.len())
+    }
+
+    fn scan(
+        &self,
+        message_ids: &[MessageId],
+        visit: &mut dyn FnMut(FoundMessage) -> bool,
+    ) -> Result<usize> {
+        let mut visited = 0;
+        // Use a read transaction so all prefixes are scanned from one snapshot
+        let read_tx = self.keyspace.read_tx();
+        let now = Utc::now();
+
+        for message_id in message_ids {
+            for result in read_tx.prefix(&self.messages, message_id.as_bytes()) {
+                let (key, value) = result.map_err(|e| {
+                    error!(
+                        "Database error during prefix scan for {}: {}",
+                        message_id, e
+                    );
+                    StorageError::Fjall(e)
+                })?;
+                if !key_is_for(&key, message_id)
+                    || is_overwritten(&value)
+                    || is_expired(&value, now)
+                {
+                    continue;
+                }
+                let record = decode_message(&key, &value).map_err(|e| {
+                    error!(
+                        "Failed to decode record for key prefix {}: {}",
+                        message_id, e
+                    );
+                    e
+                })?;
+                // Deletion happens on ACK
+                visited += 1;
+                if !visit(FoundMessage {
+                    message_id: message_id.clone(),
+                    message: record.message,
+                    timestamp: record.timestamp,
+                    sequence: record.sequence,
+                }) {
+                    return Ok(visited);
+                }
+            }
+        }
+        Ok(visited)
+    }
+
+    // Retained values are keyed and encoded like queued messages, so each id
+    // holds at most one key in its own partition.
+    fn put_retained(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        let mut previous = Vec::new();
+        for result in write_tx.prefix(&self.retained, message_id.as_bytes()) {
+            let (key, _) = result?;
+            if key_is_for(&key, message_id) {
+                previous.push(key);
+            }
+        }
+        for key in previous {
+            write_tx.remove(&self.retained, key);
+        }
+        write_tx.insert(
+            &self.retained,
+            message_key(message_id, timestamp).as_slice(),
+            encode_message(message, expires_at).as_slice(),
+        );
+        write_tx.commit()?;
+        self.mutated(1)
+    }
+
+    fn retained(&self, message_ids: &[MessageId]) -> Result<Vec<FoundMessage>> {
+        let read_tx = self.keyspace.read_tx();
+        let now = Utc::now();
+        let mut found = Vec::new();
+        for message_id in message_ids {
+            for result in read_tx.prefix(&self.retained, message_id.as_bytes()) {
+                let (key, value) = result?;
+                if !key_is_for(&key, message_id) || is_expired(&value, now) {
+                    continue;
+                }
+                let record = decode_message(&key, &value)?;
+                found.push(FoundMessage {
+                    message_id: message_id.clone(),
+                    message: record.message,
+                    timestamp: record.timestamp,
+                    sequence: record.sequence,
+                });
+            }
+        }
+        Ok(found)
+    }
+
+    // Ids sharing a prefix interleave in key order, so this walks every
+    // message under the prefix rather than seeking from id to id.
+    fn ids_with_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<MessageId>> {
+        let now = Utc::now();
+        let mut ids = BTreeSet::new();
+        for result in self.keyspace.read_tx().prefix(&self.messages, prefix) {
+            let (key, value) = result?;
+            if is_overwritten(&value) || is_expired(&value, now) {
+                continue;
+            }
+            ids.insert(key_message_id(&key)?);
+            if ids.len() == limit {
+                break;
+            }
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    // Seeks straight to the first key after the cursor instead of skipping through
+    // the older part of the backlog.
+    fn page(
+        &self,
+        message_id: &MessageId,
+        after: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<(Vec<FoundMessage>, bool)> {
+        let start = match after {
+            Some(after) => message_key(message_id, after + chrono::Duration::milliseconds(1)),
+            None => MessageKey::from_slice(message_id.as_bytes()),
+        };
+        let read_tx = self.keyspace.read_tx();
+        let now = Utc::now();
+        let mut found = Vec::new();
+        for result in read_tx.range(&self.messages, start..) {
+            let (key, value) = result?;
+            if !key.starts_with(message_id.as_bytes()) {
+                break;
+            }
+            if !key_is_for(&key, message_id) || is_overwritten(&value) || is_expired(&value, now) {
+                continue;
+            }
+            if found.len() == limit {
+                return Ok((found, true));
+            }
+            let record = decode_message(&key, &value)?;
+            found.push(FoundMessage {
+                message_id: message_id.clone(),
+                message: record.message,
+                timestamp: record.timestamp,
+                sequence: record.sequence,
+            });
+        }
+        Ok((found, false))
+    }
+
+    fn latest_sequences(&self, message_ids: &[MessageId]) -> Result<Vec<Option<u64>>> {
+        let read_tx = self.keyspace.read_tx();
+        message_ids
+            .iter()
+            .map(|message_id| {
+                read_tx
+                    .get(&self.sequences, message_id.as_bytes())?
+                    .map(|value| {
+                        value
+                            .as_ref()
+                            .try_into()
+                            .map(u64::from_be_bytes)
+                            .map_err(|_| StorageError::Corrupt("bad sequence number".to_string()))
+                    })
+                    .transpose()
+            })
+            .collect()
+    }
+
+    fn pending_counts(&self, message_ids: &[MessageId]) -> Result<Vec<usize>> {
+        message_ids
+            .iter()
+            .map(|message_id| Ok(self.count_and_latest(message_id)?.0))
+            .collect()
+    }
+
+    // The tally answers unless some of the mailbox's messages carry an expiry,
+    // since only reading each can tell whether it has lapsed unswept. The
+    // newest timestamp then comes from the last key, without decoding bodies.
+    fn count_and_latest(&self, message_id: &MessageId) -> Result<(usize, Option<DateTime<Utc>>)> {
+        let read_tx = self.keyspace.read_tx();
+        let (count, expiring) = match read_tx.get(&self.counts, message_id.as_bytes())? {
+            Some(value) => decode_tally(&value)?,
+            None => return Ok((0, None)),
+        };
+        if expiring == 0 {
+            // Stored millis start with a zero byte, which no id holds, so longer
+            // ids sharing the prefix sort past the end of this range
+            let range = message_key(message_id, DateTime::<Utc>::UNIX_EPOCH)
+                ..=message_key(message_id, DateTime::<Utc>::MAX_UTC);
+            for result in read_tx.range(&self.messages, range).rev() {
+                let (key, value) = result?;
+                if !is_overwritten(&value) {
+                    return Ok((count as usize, Some(key_timestamp(&key)?)));
+                }
+            }
+            return Ok((count as usize, None));
+        }
+        let now = Utc::now();
+        let mut count = 0;
+        let mut latest = None;
+        for result in read_tx.prefix(&self.messages, message_id.as_bytes()) {
+            let (key, value) = result?;
+            if key_is_for(&key, message_id) && !is_overwritten(&value) && !is_expired(&value, now) {
+                count += 1;
+                latest = Some(key_timestamp(&key)?);
+            }
+        }
+        Ok((count, latest))
+    }
+
+    fn ack(&self, acks: &[AckToken]) -> Result<()> {
+        if self.deletion_policy == DeletionPolicy::Overwrite {
+            self.overwrite_acked(acks)?;
+        }
+        // Use a transaction for batch deletion efficiency
+        let mut write_tx = self.keyspace.write_tx();
+        for ack in acks {
+            let key = message_key(&ack.message_id, ack.timestamp);
+            self.remove_message(&mut write_tx, &ack.message_id, &key)?;
+            write_tx.remove(&self.delivered, key.as_slice());
+            write_tx.remove(&self.receipts, key.as_slice());
+            write_tx.remove(&self.leases, key.as_slice());
+            write_tx.remove(&self.handles, key.as_slice());
+            tracing::debug!(message_id = %ack.message_id, timestamp = %ack.timestamp, "Acknowledged and marked message for deletion in transaction");
+        }
+        write_tx.commit()?;
+        self.mutated(acks.len())
+    }
+
+    // Receipt requests share the message's key and hold the channel's id.
+    fn request_receipt(&self, token: &AckToken, receipt_channel: &MessageId) -> Result<()> {
+        self.receipts.insert(
+            message_key(&token.message_id, token.timestamp).as_slice(),
+            receipt_channel.as_bytes(),
+        )?;
+        self.mutated(1)
+    }
+
+    fn ack_with_receipts(
+        &self,
+        acks: &[AckToken],
+        acked_at: DateTime<Utc>,
+    ) -> Result<Vec<(MessageId, usize)>> {
+        if self.deletion_policy == DeletionPolicy::Overwrite {
+            self.overwrite_acked(acks)?;
+        }
+        let mut receipts = Vec::new();
+        let mut write_tx = self.keyspace.write_tx();
+        for ack in acks {
+            let key = message_key(&ack.message_id, ack.timestamp);
+            // Only while the message is stored, so a repeated ack sends nothing
+            if let Some(channel) = write_tx.get(&self.receipts, &key)? {
+                if write_tx.get(&self.messages, &key)?.is_some() {
+                    let channel = std::str::from_utf8(&channel)
+                        .ok()
+                        .and_then(|id| MessageId::parse(id).ok())
+                        .ok_or_else(|| {
+                            StorageError::Corrupt("bad receipt channel id".to_string())
+                        })?;
+                    let body = serde_json::to_string(&DeliveryReceipt {
+                        message_id: ack.message_id.clone(),
+                        timestamp: ack.timestamp,
+                        acked_at,
+                    })?;
+                    // Step past whatever the channel already holds at this
+                    // millisecond, another receipt from this ack included;
+                    // write transactions are serialized, so the probe holds
+                    let mut stored_at = acked_at;
+                    while write_tx.contains_key(&self.messages, message_key(&channel, stored_at))? {
+                        stored_at += chrono::Duration::milliseconds(1);
+                    }
+                    let sequence = self.next_sequence(&mut write_tx, &channel)?;
+                    self.insert_message(
+                        &mut write_tx,
+                        &channel,
+                        &message_key(&channel, stored_at),
+                        &encode_sequenced(&body, None, Some(sequence)),
+                    )?;
+                    receipts.push((channel, body.len()));
+                }
+            }
+            self.remove_message(&mut write_tx, &ack.message_id, &key)?;
+            write_tx.remove(&self.delivered, key.as_slice());
+            write_tx.remove(&self.receipts, key.as_slice());
+            write_tx.remove(&self.leases, key.as_slice());
+            write_tx.remove(&self.handles, key.as_slice());
+        }
+        write_tx.commit()?;
+        self.mutated(acks.len() + receipts.len())?;
+        Ok(receipts)
+    }
+
+    // Collects the doomed keys with a range read ending at `before`, so message
+    // bodies are never decoded.
+    fn ack_before(&self, message_id: &MessageId, before: DateTime<Utc>) -> Result<usize> {
+        let mut acks = Vec::new();
+        let read_tx = self.keyspace.read_tx();
+        for result in read_tx.range(&self.messages, keys_before(message_id, before)) {
+            let (key, _) = result?;
+            if !key_is_for(&key, message_id) {
+                continue;
+            }
+            acks.push(AckToken {
+                message_id: message_id.clone(),
+                timestamp: key_timestamp(&key)?,
+            });
+        }
+        drop(read_tx);
+        self.ack(&acks)?;
+        Ok(acks.len())
+    }
+
+    // Scheduled keys lead with the due time, so finding the id's takes a read
+    // of the whole partition, which holds only what is still waiting.
+    fn purge(&self, message_id: &MessageId) -> Result<usize> {
+        let mut acks = Vec::new();
+        let mut scheduled = Vec::new();
+        let mut retained = Vec::new();
+        let read_tx = self.keyspace.read_tx();
+        for result in read_tx.prefix(&self.messages, message_id.as_bytes()) {
+            let (key, _) = result?;
+            if key_is_for(&key, message_id) {
+                acks.push(AckToken {
+                    message_id: message_id.clone(),
+                    timestamp: key_timestamp(&key)?,
+                });
+            }
+        }
+        for result in read_tx.prefix(&self.retained, message_id.as_bytes()) {
+            let (key, _) = result?;
+            if key_is_for(&key, message_id) {
+                retained.push(key);
+            }
+        }
+        for result in read_tx.iter(&self.scheduled) {
+            let (key, _) = result?;
+            let (scheduled_id, deliver_at) = decode_scheduled_key(&key)?;
+            if scheduled_id == *message_id {
+                scheduled.push((key, deliver_at));
+            }
+        }
+        drop(read_tx);
+
+        if self.deletion_policy == DeletionPolicy::Overwrite {
+            self.overwrite_acked(&acks)?;
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for ack in &acks {
+            let key = message_key(&ack.message_id, ack.timestamp);
+            self.remove_message(&mut write_tx, &ack.message_id, &key)?;
+            write_tx.remove(&self.delivered, key.as_slice());
+            write_tx.remove(&self.receipts, key.as_slice());
+            write_tx.remove(&self.leases, key.as_slice());
+            write_tx.remove(&self.handles, key.as_slice());
+        }
+        for (key, deliver_at) in &scheduled {
+            // A scheduled message's records are keyed by its delivery time
+            let record = message_key(message_id, *deliver_at);
+            write_tx.remove(&self.scheduled, key.clone());
+            write_tx.remove(&self.handles, record.as_slice());
+            write_tx.remove(&self.receipts, record.as_slice());
+        }
+        for key in &retained {
+            write_tx.remove(&self.retained, key.clone());
+        }
+        write_tx.commit()?;
+        self.mutated(acks.len() + scheduled.len() + retained.len())?;
+        Ok(acks.len())
+    }
+
+    // Delivery records share the message's key and hold the first delivery millis.
+    fn mark_delivered(&self, tokens: &[AckToken], at: DateTime<Utc>) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        for token in tokens {
+            let key = message_key(&token.message_id, token.timestamp);
+            if write_tx.get(&self.delivered, &key)?.is_none() {
+                write_tx.insert(
+                    &self.delivered,
+                    key.as_slice(),
+                    at.timestamp_millis().to_be_bytes().as_slice(),
+                );
+            }
+        }
+        write_tx.commit()?;
+        Ok(())
+    }
+
+    fn message_state(&self, token: &AckToken) -> Result<MessageState> {
+        let scheduled = scheduled_key(&token.message_id, token.timestamp);
+        if self.scheduled.contains_key(&scheduled)? {
+            return Ok(MessageState {
+                state: DeliveryState::Stored,
+                delivered_at: None,
+            });
+        }
+        let key = message_key(&token.message_id, token.timestamp);
+        let read_tx = self.keyspace.read_tx();
+        let stored = read_tx
+            .get(&self.messages, &key)?
+            .is_some_and(|value| !is_overwritten(&value) && !is_expired(&value, Utc::now()));
+        if !stored {
+            return Ok(MessageState {
+                state: DeliveryState::Acked,
+                delivered_at: None,
+            });
+        }
+        Ok(match read_tx.get(&self.delivered, &key)? {
+            Some(value) => MessageState {
+                state: DeliveryState::Delivered,
+                delivered_at: Some(value_millis(&value, 0)?),
+            },
+            None => MessageState {
+                state: DeliveryState::Stored,
+                delivered_at: None,
+            },
+        })
+    }
+
+    // Lease values are the big-endian expiry millis, under the message's key.
+    fn lease(
+        &self,
+        tokens: &[AckToken],
+        now: DateTime<Utc>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<bool>> {
+        let Some(until) = until else {
+            let read_tx = self.keyspace.read_tx();
+            return tokens
+                .iter()
+                .map(|token| {
+                    let key = message_key(&token.message_id, token.timestamp);
+                    match read_tx.get(&self.leases, &key)? {
+                        Some(value) => Ok(value_millis(&value, 0)? <= now),
+                        None => Ok(true),
+                    }
+                })
+                .collect();
+        };
+        // One transaction, so two polls can't both take the same message
+        let mut write_tx = self.keyspace.write_tx();
+        let mut granted = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            let key = message_key(&token.message_id, token.timestamp);
+            let free = match write_tx.get(&self.leases, &key)? {
+                Some(value) => value_millis(&value, 0)? <= now,
+                None => true,
+            };
+            if free {
+                write_tx.insert(
+                    &self.leases,
+                    key.as_slice(),
+                    until.timestamp_millis().to_be_bytes().as_slice(),
+                );
+            }
+            granted.push(free);
+        }
+        write_tx.commit()?;
+        self.mutated(granted.iter().filter(|&&free| free).count())?;
+        Ok(granted)
+    }
+
+    // Cursor values are the big-endian millis acked through, empty until the
+    // consumer's first ack.
+    fn join_consumer(
+        &self,
+        message_ids: &[MessageId],
+        consumer: &str,
+    ) -> Result<Vec<Option<DateTime<Utc>>>> {
+        let mut write_tx = self.keyspace.write_tx();
+        let mut cursors = Vec::with_capacity(message_ids.len());
+        let mut joined = 0;
+        for message_id in message_ids {
+            let key = cursor_key(message_id, consumer);
+            match write_tx.get(&self.cursors, &key)? {
+                Some(value) if value.is_empty() => cursors.push(None),
+                Some(value) => cursors.push(Some(value_millis(&value, 0)?)),
+                None => {
+                    write_tx.insert(&self.cursors, key.as_slice(), []);
+                    cursors.push(None);
+                    joined += 1;
+                }
+            }
+        }
+        write_tx.commit()?;
+        if joined > 0 {
+            self.mutated(joined)?;
+        }
+        Ok(cursors)
+    }
+
+    fn advance_cursor(&self, consumer: &str, acks: &[AckToken]) -> Result<Vec<AckToken>> {
+        let mut newest: BTreeMap<&MessageId, DateTime<Utc>> = BTreeMap::new();
+        // Nothing is stored before the epoch, so such acks name no message
+        for ack in acks
+            .iter()
+            .filter(|ack| ack.timestamp >= DateTime::UNIX_EPOCH)
+        {
+            let entry = newest.entry(&ack.message_id).or_insert(ack.timestamp);
+            *entry = (*entry).max(ack.timestamp);
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        let mut done = Vec::new();
+        for (message_id, acked_through) in &newest {
+            let key = cursor_key(message_id, consumer);
+            let current = match write_tx.get(&self.cursors, &key)? {
+                Some(value) if !value.is_empty() => Some(value_millis(&value, 0)?),
+                _ => None,
+            };
+            let mine = current.max(Some(*acked_through));
+            if mine != current {
+                write_tx.insert(
+                    &self.cursors,
+                    key.as_slice(),
+                    acked_through.timestamp_millis().to_be_bytes().as_slice(),
+                );
+            }
+            // The slowest consumer decides what can go
+            let mut slowest = mine;
+            let mut prefix = message_id.as_bytes().to_vec();
+            prefix.push(b'\n');
+            for result in write_tx.prefix(&self.cursors, &prefix) {
+                let (other, value) = result?;
+                if &*other == key.as_slice() {
+                    continue;
+                }
+                let cursor = if value.is_empty() {
+                    None
+                } else {
+                    Some(value_millis(&value, 0)?)
+                };
+                slowest = slowest.min(cursor);
+            }
+            let Some(slowest) = slowest else {
+                continue;
+            };
+            let range = keys_before(message_id, slowest + chrono::Duration::milliseconds(1));
+            for result in write_tx.range(&self.messages, range) {
+                let (key, _) = result?;
+                if !key_is_for(&key, message_id) {
+                    continue;
+                }
+                done.push(AckToken {
+                    message_id: (*message_id).clone(),
+                    timestamp: key_timestamp(&key)?,
+                });
+            }
+        }
+        write_tx.commit()?;
+        self.mutated(newest.len())?;
+        Ok(done)
+    }
+
+    // Handle records share the message's key and hold the handle's hash.
+    fn save_handle(&self, token: &AckToken, handle_hash: &[u8]) -> Result<()> {
+        self.handles.insert(
+            message_key(&token.message_id, token.timestamp).as_slice(),
+            handle_hash,
+        )?;
+        self.mutated(1)
+    }
+
+    fn cancel(&self, token: &AckToken, handle_hash: &[u8]) -> Result<CancelOutcome> {
+        let key = message_key(&token.message_id, token.timestamp);
+        if self
+            .handles
+            .get(&key)?
+            .is_none_or(|saved| &*saved != handle_hash)
+        {
+            return Ok(CancelOutcome::NotFound);
+        }
+        if self.unschedule(token)? {
+            return Ok(CancelOutcome::Cancelled);
+        }
+        if self.delivered.contains_key(&key)? {
+            return Ok(CancelOutcome::Fetched);
+        }
+        if !self.messages.contains_key(&key)? {
+            return Ok(CancelOutcome::NotFound);
+        }
+        // Through `ack`, so the deletion policy applies
+        self.ack(std::slice::from_ref(token))?;
+        Ok(CancelOutcome::Cancelled)
+    }
+
+    fn revoke(&self, token: &AckToken) -> Result<bool> {
+        if self.unschedule(token)? {
+            return Ok(true);
+        }
+        let key = message_key(&token.message_id, token.timestamp);
+        if !self.messages.contains_key(&key)? {
+            return Ok(false);
+        }
+        // Through `ack`, so the deletion policy applies
+        self.ack(std::slice::from_ref(token))?;
+        Ok(true)
+    }
+
+    // Reads every value but decodes only the expiry. Deletes go through `ack`,
+    // so expired bodies get the same deletion policy as acked ones.
+    fn sweep_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut acks = Vec::new();
+        for result in self.keyspace.read_tx().iter(&self.messages) {
+            let (key, value) = result?;
+            if !is_expired(&value, now) {
+                continue;
+            }
+            let message_id = key
+                .len()
+                .checked_sub(8)
+                .and_then(|end| std::str::from_utf8(&key[..end]).ok())
+                .and_then(|id| MessageId::parse(id).ok())
+                .ok_or_else(|| {
+                    StorageError::Corrupt("bad message_id in message key".to_string())
+                })?;
+            acks.push(AckToken {
+                message_id,
+                timestamp: key_timestamp(&key)?,
+            });
+        }
+        if !acks.is_empty() {
+            self.ack(&acks)?;
+        }
+        Ok(acks.len())
+    }
+
+    fn check_delivery(
+        &self,
+        from: &[u8],
+        limit: usize,
+        now: DateTime<Utc>,
+        stale_before: DateTime<Utc>,
+        swept_before: DateTime<Utc>,
+    ) -> Result<DeliveryAnomalies> {
+        let read_tx = self.keyspace.read_tx();
+        let mut anomalies = DeliveryAnomalies::default();
+        for (key, value) in sample(&read_tx, &self.messages, from, limit)? {
+            anomalies.checked += 1;
+            let stale = match read_tx.get(&self.delivered, &key)? {
+                Some(delivered) if value_millis(&delivered, 0)? < stale_before => {
+                    match read_tx.get(&self.leases, &key)? {
+                        Some(lease) => value_millis(&lease, 0)? <= now,
+                        None => true,
+                    }
+                }
+                _ => false,
+            };
+            if stale {
+                anomalies.stale_delivered += 1;
+                anomalies.note(key_message_id(&key)?);
+            }
+            if !is_overwritten(&value) && is_expired(&value, swept_before) {
+                anomalies.unswept_expired += 1;
+                anomalies.note(key_message_id(&key)?);
+            }
+        }
+        for partition in [&self.delivered, &self.leases, &self.handles, &self.receipts] {
+            for (key, _) in sample(&read_tx, partition, from, limit)? {
+                anomalies.checked += 1;
+                if read_tx.contains_key(&self.messages, &key)? {
+                    continue;
+                }
+                // Handles and receipt requests also belong to scheduled messages
+                let message_id = key_message_id(&key)?;
+                let scheduled = scheduled_key(&message_id, key_timestamp(&key)?);
+                if read_tx.contains_key(&self.scheduled, scheduled)? {
+                    continue;
+                }
+                anomalies.orphaned_records += 1;
+                anomalies.note(message_id);
+            }
+        }
+        Ok(anomalies)
+    }
+
+    fn deletion_policy(&self) -> DeletionPolicy {
+        self.deletion_policy
+    }
+
+    fn health(&self) -> StorageHealth {
+        let keyspace = self.keyspace.inner();
+        let journal_count = keyspace.journal_count();
+        let write_buffer_bytes = keyspace.write_buffer_size();
+        let stall_risk = journal_count as f64 >= JOURNAL_COUNT_LIMIT as f64 * STALL_RISK_RATIO
+            || write_buffer_bytes as f64 >= MAX_WRITE_BUFFER_BYTES as f64 * STALL_RISK_RATIO;
+        StorageHealth {
+            journal_count,
+            journal_limit: JOURNAL_COUNT_LIMIT,
+            write_buffer_bytes,
+            write_buffer_limit: MAX_WRITE_BUFFER_BYTES,
+            disk_space_bytes: keyspace.disk_space(),
+            message_segments: self.messages.inner().segment_count(),
+            stall_risk,
+        }
+    }
+
+    fn persist(&self, reason: PersistReason) -> Result<u64> {
+        self.pacer.persist(reason, || self.sync_journal())
+    }
+
+    fn compact(&self) -> Result<bool> {
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return Ok(false);
+        }
+        let started = Instant::now();
+        let result = self.messages.inner().major_compact();
+        self.compacting.store(false, Ordering::Release);
+        result?;
+        info!("Compacted messages partition in {:?}", started.elapsed());
+        Ok(true)
+    }
+}
+
+impl SubscriptionStore for FjallStore {
+    fn save_subscription(
+        &self,
+        message_ids: &[MessageId],
+        subscription: &PushSubscriptionInfo,
+    ) -> Result<bool> {
+        let subscription_bytes = serde_json::to_vec(subscription)?;
+        let reference = blob_reference(&subscription.endpoint);
+        let mut write_tx = self.keyspace.write_tx();
+        let mut changed = false;
+        for message_id in message_ids {
+            let key = subscription_key(message_id, &subscription.endpoint);
+            let stored = write_tx.get(&self.subscriptions, &key)?;
+            let referenced = stored.as_deref() == Some(reference.as_slice());
+            // Rotated keys or an invalidated blob need writing, for every id at once
+            let current = referenced
+                && write_tx
+                    .get(&self.subscription_blobs, &reference[1..])?
+                    .is_some_and(|blob| blob.get(8..) == Some(subscription_bytes.as_slice()))
+                && !write_tx.contains_key(&self.resubscribe, message_id.as_bytes())?;
+            if current {
+                continue;
+            }
+            // Move a subscription from before per-device keys under its own key
+            if let Some(legacy) = write_tx.get(&self.subscriptions, message_id.as_bytes())? {
+                let legacy_info: PushSubscriptionInfo = serde_json::from_slice(&legacy)?;
+                write_tx.remove(&self.subscriptions, message_id.as_bytes());
+                if legacy_info.endpoint != subscription.endpoint {
+                    let legacy_reference = acquire_blob(
+                        &mut write_tx,
+                        &self.subscription_blobs,
+                        &legacy_info.endpoint,
+                        &legacy,
+                        false,
+                    )?;
+                    write_tx.insert(
+                        &self.subscriptions,
+                        subscription_key(message_id, &legacy_info.endpoint),
+                        legacy_reference,
+                    );
+                }
+            }
+            let mut others = Vec::new();
+            for result in write_tx.prefix(&self.subscriptions, subscription_prefix(message_id)) {
+                let (other, value) = result?;
+                if *other != *key {
+                    others.push((other, value));
+                }
+            }
+            // Evicts whichever devices sort first, which is arbitrary but stable
+            let excess = (others.len() + 1).saturating_sub(MAX_SUBSCRIPTIONS_PER_ID);
+            for (other, value) in others.into_iter().take(excess) {
+                release_blob(&mut write_tx, &self.subscription_blobs, &value)?;
+                write_tx.remove(&self.subscriptions, other);
+            }
+            if referenced {
+                replace_blob(
+                    &mut write_tx,
+                    &self.subscription_blobs,
+                    &reference,
+                    &subscription_bytes,
+                )?;
+            } else {
+                acquire_blob(
+                    &mut write_tx,
+                    &self.subscription_blobs,
+                    &subscription.endpoint,
+                    &subscription_bytes,
+                    true,
+                )?;
+                // A blob keyed by content, from before blobs were keyed by endpoint
+                if let Some(stored) = stored {
+                    release_blob(&mut write_tx, &self.subscription_blobs, &stored)?;
+                }
+                write_tx.insert(&self.subscriptions, key, reference.as_slice());
+            }
+            write_tx.remove(&self.resubscribe, message_id.as_bytes());
+            changed = true;
+        }
+        if changed {
+            write_tx.commit()?;
+            self.mutated(message_ids.len())?;
+        }
+        Ok(changed)
+    }
+
+    fn subscriptions(&self, message_id: &MessageId) -> Result<Vec<PushSubscriptionInfo>> {
+        let read_tx = self.keyspace.read_tx();
+        let legacy = read_tx.get(&self.subscriptions, message_id.as_bytes())?;
+        let mut subscriptions = Vec::new();
+        for value in legacy.into_iter().map(Ok).chain(
+            read_tx
+                .prefix(&self.subscriptions, subscription_prefix(message_id))
+                .map(|result| result.map(|(_, value)| value)),
+        ) {
+            let value = value.map_err(|e| {
+                error!(
+                    "Database IO error reading subscriptions for {}: {}",
+                    message_id, e
+                );
+                StorageError::Fjall(e)
+            })?;
+            let parsed = match value.first() {
+                Some(&BLOB_REFERENCE) => {
+                    match read_tx.get(&self.subscription_blobs, &value[1..])? {
+                        // Emptied by older versions once the push service reported it gone
+                        Some(blob) if blob.len() > 8 => serde_json::from_slice(&blob[8..]),
+                        _ => continue,
+                    }
+                }
+                _ => serde_json::from_slice(&value),
+            };
+            subscriptions.push(parsed.map_err(|e| {
+                error!("Failed to deserialize subscription info: {}", e);
+                StorageError::SerdeJson(e)
+            })?);
+        }
+        Ok(subscriptions)
+    }
+
+    fn remove_subscription(&self, message_id: &MessageId) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        let removed = remove_subscriptions(
+            &mut write_tx,
+            &self.subscriptions,
+            &self.subscription_blobs,
+            message_id,
+        )?;
+        write_tx.commit()?;
+        self.mutated(removed)
+    }
+
+    fn remove_device_subscription(&self, message_id: &MessageId, endpoint: &str) -> Result<bool> {
+        let mut write_tx = self.keyspace.write_tx();
+        let key = subscription_key(message_id, endpoint);
+        let mut removed = false;
+        if let Some(value) = write_tx.get(&self.subscriptions, &key)? {
+            release_blob(&mut write_tx, &self.subscription_blobs, &value)?;
+            write_tx.remove(&self.subscriptions, key);
+            removed = true;
+        }
+        if let Some(legacy) = write_tx.get(&self.subscriptions, message_id.as_bytes())? {
+            let legacy_info: PushSubscriptionInfo = serde_json::from_slice(&legacy)?;
+            if legacy_info.endpoint == endpoint {
+                write_tx.remove(&self.subscriptions, message_id.as_bytes());
+                removed = true;
+            }
+        }
+        if removed {
+            write_tx.commit()?;
+            self.mutated(1)?;
+        }
+        Ok(removed)
+    }
+
+    // Nothing indexes mailboxes by endpoint, so this reads every key. Endpoints
+    // go away rarely enough for that to be cheaper than keeping an index.
+    fn remove_endpoint(&self, subscription: &PushSubscriptionInfo) -> Result<Vec<MessageId>> {
+        let endpoint_hash = Sha256::digest(subscription.endpoint.as_bytes());
+        let mut write_tx = self.keyspace.write_tx();
+        let mut doomed = Vec::new();
+        for result in write_tx.iter(&self.subscriptions) {
+            let (key, value) = result?;
+            let id = match key.iter().position(|&b| b == b'\n') {
+                Some(newline) if key[newline + 1..] == *endpoint_hash => &key[..newline],
+                Some(_) => continue,
+                // A bare key from before per-device keys holds the JSON itself
+                None => match serde_json::from_slice::<PushSubscriptionInfo>(&value) {
+                    Ok(legacy) if legacy.endpoint == subscription.endpoint => &key[..],
+                    _ => continue,
+                },
+            };
+            match MessageId::parse(String::from_utf8_lossy(id)) {
+                Ok(message_id) => doomed.push((key, message_id)),
+                Err(issue) => warn!("Skipping stored id: {}", issue.message),
+            }
+        }
+        if doomed.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Every reference goes, so the blobs go outright rather than by count
+        write_tx.remove(&self.subscription_blobs, &endpoint_hash[..]);
+        // Where it was kept before blobs were keyed by endpoint
+        let legacy_blob = Sha256::digest(serde_json::to_vec(subscription)?);
+        write_tx.remove(&self.subscription_blobs, &legacy_blob[..]);
+        let now = Utc::now().timestamp_millis().to_be_bytes();
+        let mut message_ids = Vec::with_capacity(doomed.len());
+        for (key, message_id) in doomed {
+            write_tx.remove(&self.subscriptions, key);
+            write_tx.insert(&self.resubscribe, message_id.as_bytes(), now.as_slice());
+            message_ids.push(message_id);
+        }
+        write_tx.commit()?;
+        self.mutated(message_ids.len() * 2)?;
+        message_ids.sort_unstable();
+        message_ids.dedup();
+        Ok(message_ids)
+    }
+
+    fn mark_resubscribe_required(&self, message_id: &MessageId) -> Result<()> {
+        self.resubscribe.insert(
+            message_id.as_bytes(),
+            Utc::now().timestamp_millis().to_be_bytes().as_slice(),
+        )?;
+        self.mutated(1)
+    }
+
+    fn resubscribe_required(&self, message_ids: &[MessageId]) -> Result<Vec<MessageId>> {
+        let mut flagged = Vec::new();
+        for message_id in message_ids {
+            if self.resubscribe.contains_key(message_id.as_bytes())? {
+                flagged.push(message_id.clone());
+            }
+        }
+        Ok(flagged)
+    }
+
+    fn subscribed_ids(&self) -> Result<Vec<MessageId>> {
+        let read_tx = self.keyspace.read_tx();
+        let mut ids = Vec::new();
+        for partition in [&self.subscriptions, &self.resubscribe] {
+            for key in read_tx.keys(partition) {
+                let key = key?;
+                // Subscription keys carry the endpoint hash after a newline
+                let id = key.split(|&b| b == b'\n').next().unwrap_or_default();
+                match MessageId::parse(String::from_utf8_lossy(id)) {
+                    Ok(id) => ids.push(id),
+                    // Written before ids were validated; nothing can reach them now
+                    Err(issue) => warn!("Skipping stored id: {}", issue.message),
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    fn forget_mailbox(&self, message_id: &MessageId) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        remove_subscriptions(
+            &mut write_tx,
+            &self.subscriptions,
+            &self.subscription_blobs,
+            message_id,
+        )?;
+        self.remove_idle_state(&mut write_tx, message_id)?;
+        write_tx.remove(&self.email_fallbacks, email_key(message_id));
+        write_tx.remove(&self.sms_numbers, phone_key(message_id));
+        write_tx.commit()?;
+        self.mutated(1)
+    }
+
+    fn reap_mailbox(&self, message_id: &MessageId) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        self.remove_idle_state(&mut write_tx, message_id)?;
+        write_tx.commit()?;
+        self.mutated(1)
+    }
+}
+
+impl TokenStore for FjallStore {
+    fn redeem_token(&self, nonce: &[u8]) -> Result<bool> {
+        // The write transaction serializes redemptions, so two requests carrying
+        // the same token can't both see it unspent.
+        let mut write_tx = self.keyspace.write_tx();
+        if write_tx.get(&self.tokens, nonce)?.is_some() {
+            return Ok(false);
+        }
+        write_tx.insert(
+            &self.tokens,
+            nonce,
+            Utc::now().timestamp_millis().to_be_bytes().as_slice(),
+        );
+        write_tx.commit()?;
+        self.mutated(1)?;
+        Ok(true)
+    }
+}
+
+// Up to `limit` entries of `partition` from key `from` on, continuing from the
+// start once the end is reached.
+fn sample(
+    read_tx: &ReadTransaction,
+    partition: &TransactionalPartitionHandle,
+    from: &[u8],
+    limit: usize,
+) -> Result<Vec<(Slice, Slice)>> {
+    let mut entries = Vec::with_capacity(limit);
+    for result in read_tx.range(partition, from.to_vec()..).take(limit) {
+        entries.push(result?);
+    }
+    let remaining = limit - entries.len();
+    for result in read_tx.range(partition, ..from.to_vec()).take(remaining) {
+        entries.push(result?);
+    }
+    Ok(entries)
+}
+
+// Analytics keys are a one byte period tag followed by the big-endian start
+// millis, so each period's buckets form one time-ordered range.
+fn analytics_key(period: AnalyticsPeriod, start: DateTime<Utc>) -> Vec<u8> {
+    let tag = match period {
+        AnalyticsPeriod::Hour => b'h',
+        AnalyticsPeriod::Day => b'd',
+    };
+    let mut key = Vec::with_capacity(9);
+    key.push(tag);
+    key.extend_from_slice(&start.timestamp_millis().to_be_bytes());
+    key
+}
+
+// Reads the big-endian millisecond timestamp at `offset` in `value`.
+fn value_millis(value: &[u8], offset: usize) -> Result<DateTime<Utc>> {
+    value
+        .get(offset..offset + 8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(i64::from_be_bytes)
+        .and_then(DateTime::from_timestamp_millis)
+        .ok_or_else(|| StorageError::Corrupt("timestamp missing from value".to_string()))
+}
+
+// Share link values are the big-endian expiry millis followed by the message_id.
+fn share_link_expiry(value: &[u8]) -> Result<DateTime<Utc>> {
+    value_millis(value, 0)
+}
+
+// Scheduled keys are the big-endian due millis followed by the message_id.
+fn scheduled_key(message_id: &MessageId, deliver_at: DateTime<Utc>) -> Vec<u8> {
+    let mut key = Vec::with_capacity(8 + message_id.as_bytes().len());
+    key.extend_from_slice(&deliver_at.timestamp_millis().to_be_bytes());
+    key.extend_from_slice(message_id.as_bytes());
+    key
+}
+
+// How a record counts toward its mailbox's tally: live unless overwritten,
+// and expiring if it carries an expiry.
+fn tally_weight(value: Option<&[u8]>) -> (u64, u64) {
+    match value {
+        Some(value) if !is_overwritten(value) => (1, u64::from(value_expiry(value).is_some())),
+        _ => (0, 0),
+    }
+}
+
+// Tallies are the big-endian live count and then the expiring count.
+fn encode_tally(count: u64, expiring: u64) -> [u8; 16] {
+    let mut value = [0; 16];
+    value[..8].copy_from_slice(&count.to_be_bytes());
+    value[8..].copy_from_slice(&expiring.to_be_bytes());
+    value
+}
+
+fn decode_tally(value: &[u8]) -> Result<(u64, u64)> {
+    let half = |range: Range<usize>| {
+        value
+            .get(range)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| StorageError::Corrupt("bad message tally".to_string()))
+    };
+    Ok((half(0..8)?, half(8..16)?))
+}
+
+// `message_id`'s message keys from before `before`. Keys hold the millis as a
+// big-endian i64, so a pre-epoch bound would sort after every key of the id,
+// and of longer ids sharing its prefix; nothing is stored that early, so such
+// a bound gets an empty range instead.
+fn keys_before(message_id: &MessageId, before: DateTime<Utc>) -> Range<MessageKey> {
+    let start = message_key(message_id, DateTime::<Utc>::UNIX_EPOCH);
+    let end = message_key(message_id, before.max(DateTime::<Utc>::UNIX_EPOCH));
+    start..end
+}
+
+fn decode_scheduled_key(key: &[u8]) -> Result<(MessageId, DateTime<Utc>)> {
+    let deliver_at = value_millis(key, 0)?;
+    let message_id = std::str::from_utf8(&key[8..])
+        .ok()
+        .and_then(|id| MessageId::parse(id).ok())
+        .ok_or_else(|| StorageError::Corrupt("bad message_id in scheduled key".to_string()))?;
+    Ok((message_id, deliver_at))
+}
+
+// Cursors are keyed by message_id, a newline and the consumer's name, so each
+// mailbox's consumers share a prefix.
+fn cursor_key(message_id: &MessageId, consumer: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(message_id.as_bytes().len() + 1 + consumer.len());
+    key.extend_from_slice(message_id.as_bytes());
+    key.push(b'\n');
+    key.extend_from_slice(consumer.as_bytes());
+    key
+}
+
+// One key per device: the message_id, a newline and the SHA-256 of the push
+// endpoint. Older keys are the bare message_id.
+fn subscription_key(message_id: &MessageId, endpoint: &str) -> Vec<u8> {
+    let mut key = subscription_prefix(message_id);
+    key.extend_from_slice(&Sha256::digest(endpoint.as_bytes()));
+    key
+}
+
+// Per-device values written before blobs were shared hold the JSON itself;
+// JSON never starts with this byte.
+const BLOB_REFERENCE: u8 = 0;
+
+// The subscription value pointing at a device's blob: the tag, then the blob's
+// key, the SHA-256 of the endpoint. Blobs written before they were keyed by
+// endpoint are keyed by the SHA-256 of their JSON, and are read and released
+// the same way.
+fn blob_reference(endpoint: &str) -> Vec<u8> {
+    let mut reference = Vec::with_capacity(33);
+    reference.push(BLOB_REFERENCE);
+    reference.extend_from_slice(&Sha256::digest(endpoint.as_bytes()));
+    reference
+}
+
+// Counts one more key referring to the blob of the device at `endpoint` and
+// returns the reference to store. `subscription_bytes` become its contents if
+// `replace` is set or it has none yet.
+fn acquire_blob(
+    write_tx: &mut WriteTransaction,
+    blobs: &TransactionalPartitionHandle,
+    endpoint: &str,
+    subscription_bytes: &[u8],
+    replace: bool,
+) -> Result<Vec<u8>> {
+    let reference = blob_reference(endpoint);
+    let existing = write_tx.get(blobs, &reference[1..])?;
+    let count = match &existing {
+        Some(blob) => blob_count(blob)?,
+        None => 0,
+    };
+    let contents = match &existing {
+        Some(blob) if !replace && blob.len() > 8 => &blob[8..],
+        _ => subscription_bytes,
+    };
+    let mut blob = Vec::with_capacity(8 + contents.len());
+    blob.extend_from_slice(&(count + 1).to_be_bytes());
+    blob.extend_from_slice(contents);
+    write_tx.insert(blobs, &reference[1..], blob);
+    Ok(reference)
+}
+
+// Replaces the contents of a blob, keeping its count, when a device's keys change.
+fn replace_blob(
+    write_tx: &mut WriteTransaction,
+    blobs: &TransactionalPartitionHandle,
+    reference: &[u8],
+    subscription_bytes: &[u8],
+) -> Result<()> {
+    let count = match write_tx.get(blobs, &reference[1..])? {
+        Some(blob) => blob_count(&blob)?,
+        None => 1,
+    };
+    let mut blob = Vec::with_capacity(8 + subscription_bytes.len());
+    blob.extend_from_slice(&count.to_be_bytes());
+    blob.extend_from_slice(subscription_bytes);
+    write_tx.insert(blobs, &reference[1..], blob);
+    Ok(())
+}
+
+// Drops one reference held by a subscription value, deleting the blob with
+// its last. Values holding the JSON inline refer to nothing.
+fn release_blob(
+    write_tx: &mut WriteTransaction,
+    blobs: &TransactionalPartitionHandle,
+    value: &[u8],
+) -> Result<()> {
+    if value.first() != Some(&BLOB_REFERENCE) {
+        return Ok(());
+    }
+    let Some(blob) = write_tx.get(blobs, &value[1..])? else {
+        return Ok(());
+    };
+    match blob_count(&blob)? {
+        0 | 1 => write_tx.remove(blobs, &value[1..]),
+        count => {
+            let mut blob = blob.to_vec();
+            blob[..8].copy_from_slice(&(count - 1).to_be_bytes());
+            write_tx.insert(blobs, &value[1..], blob);
+        }
+    }
+    Ok(())
+}
+
+fn blob_count(blob: &[u8]) -> Result<u64> {
+    blob.get(..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_be_bytes)
+        .ok_or_else(|| StorageError::Corrupt("subscription blob missing its count".to_string()))
+}
+
+fn subscription_prefix(message_id: &MessageId) -> Vec<u8> {
+    let mut key = Vec::with_capacity(message_id.as_bytes().len() + 33);
+    key.extend_from_slice(message_id.as_bytes());
+    key.push(b'\n');
+    key
+}
+
+// Removes the bare and per-device keys of `message_id`, returning how many.
+fn remove_subscriptions(
+    write_tx: &mut WriteTransaction,
+    subscriptions: &TransactionalPartitionHandle,
+    blobs: &TransactionalPartitionHandle,
+    message_id: &MessageId,
+) -> Result<usize> {
+    let mut entries = Vec::new();
+    for result in write_tx.prefix(subscriptions, subscription_prefix(message_id)) {
+        entries.push(result?);
+    }
+    let removed = entries.len() + 1;
+    for (key, value) in entries {
+        release_blob(write_tx, blobs, &value)?;
+        write_tx.remove(subscriptions, key);
+    }
+    write_tx.remove(subscriptions, message_id.as_bytes());
+    Ok(removed)
+}
+
+// Idempotency records are keyed by message_id, a newline (never part of an id)
+// and the client's key; values are the expiry and then the stored timestamp.
+fn idempotency_record_key(message_id: &MessageId, idempotency_key: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(message_id.as_bytes().len() + 1 + idempotency_key.len());
+    key.extend_from_slice(message_id.as_bytes());
+    key.push(b'\n');
+    key.extend_from_slice(idempotency_key.as_bytes());
+    key
+}
+
+impl ShareLinkStore for FjallStore {
+    fn save_share_link(
+        &self,
+        token_hash: &[u8],
+        message_id: &MessageId,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut value = expires_at.timestamp_millis().to_be_bytes().to_vec();
+        value.extend_from_slice(message_id.as_bytes());
+        self.share_links.insert(token_hash, value)?;
+        self.mutated(1)
+    }
+
+    fn take_share_link(&self, token_hash: &[u8], now: DateTime<Utc>) -> Result<Option<MessageId>> {
+        // The write transaction serializes redemptions, so a link reads once
+        let mut write_tx = self.keyspace.write_tx();
+        let Some(value) = write_tx.get(&self.share_links, token_hash)? else {
+            return Ok(None);
+        };
+        write_tx.remove(&self.share_links, token_hash);
+        write_tx.commit()?;
+        self.mutated(1)?;
+        if share_link_expiry(&value)? <= now {
+            return Ok(None);
+        }
+        let message_id = String::from_utf8(value[8..].to_vec())
+            .ok()
+            .and_then(|id| MessageId::parse(id).ok())
+            .ok_or_else(|| StorageError::Corrupt("share link message_id invalid".to_string()))?;
+        Ok(Some(message_id))
+    }
+
+    fn prune_share_links(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut expired = Vec::new();
+        for result in self.keyspace.read_tx().iter(&self.share_links) {
+            let (key, value) = result?;
+            if share_link_expiry(&value)? <= now {
+                expired.push(key);
+            }
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for key in &expired {
+            write_tx.remove(&self.share_links, key.clone());
+        }
+        write_tx.commit()?;
+        Ok(expired.len())
+    }
+}
+
+impl EmailStore for FjallStore {
+    fn save_email(&self, mailbox_hash: &[u8], email: Option<&str>) -> Result<()> {
+        match email {
+            Some(email) => self.email_fallbacks.insert(mailbox_hash, email)?,
+            None => self.email_fallbacks.remove(mailbox_hash)?,
+        }
+        self.mutated(1)
+    }
+
+    fn email(&self, mailbox_hash: &[u8]) -> Result<Option<String>> {
+        let Some(value) = self.email_fallbacks.get(mailbox_hash)? else {
+            return Ok(None);
+        };
+        String::from_utf8(value.to_vec())
+            .map(Some)
+            .map_err(|_| StorageError::Corrupt("fallback email is not UTF-8".to_string()))
+    }
+}
+
+impl SmsStore for FjallStore {
+    fn save_phone(&self, mailbox_hash: &[u8], phone: Option<&str>) -> Result<()> {
+        match phone {
+            Some(phone) => self.sms_numbers.insert(mailbox_hash, phone)?,
+            None => self.sms_numbers.remove(mailbox_hash)?,
+        }
+        self.mutated(1)
+    }
+
+    fn phone(&self, mailbox_hash: &[u8]) -> Result<Option<String>> {
+        let Some(value) = self.sms_numbers.get(mailbox_hash)? else {
+            return Ok(None);
+        };
+        String::from_utf8(value.to_vec())
+            .map(Some)
+            .map_err(|_| StorageError::Corrupt("SMS number is not UTF-8".to_string()))
+    }
+}
+
+impl OutboxStore for FjallStore {
+    fn enqueue_pushes(&self, message_ids: &[MessageId], due: DateTime<Utc>) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        for message_id in message_ids {
+            write_tx.insert(
+                &self.push_outbox,
+                scheduled_key(message_id, due),
+                0u32.to_be_bytes().as_slice(),
+            );
+        }
+        write_tx.commit()?;
+        self.mutated(message_ids.len())
+    }
+
+    fn due_pushes(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<OutboxJob>> {
+        let read_tx = self.keyspace.read_tx();
+        let mut jobs = Vec::new();
+        for result in read_tx.iter(&self.push_outbox) {
+            let (key, value) = result?;
+            let (message_id, due) = decode_scheduled_key(&key)?;
+            if due > now || jobs.len() == limit {
+                break;
+            }
+            let attempts = value
+                .get(..4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_be_bytes)
+                .unwrap_or(0);
+            jobs.push(OutboxJob {
+                message_id,
+                due,
+                attempts,
+            });
+        }
+        Ok(jobs)
+    }
+
+    fn finish_push(&self, job: &OutboxJob, retry_at: Option<DateTime<Utc>>) -> Result<()> {
+        let mut write_tx = self.keyspace.write_tx();
+        write_tx.remove(&self.push_outbox, scheduled_key(&job.message_id, job.due));
+        if let Some(retry_at) = retry_at {
+            write_tx.insert(
+                &self.push_outbox,
+                scheduled_key(&job.message_id, retry_at),
+                (job.attempts + 1).to_be_bytes().as_slice(),
+            );
+        }
+        write_tx.commit()?;
+        self.mutated(1)
+    }
+
+    fn dead_letter_push(
+        &self,
+        job: &OutboxJob,
+        error: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut value = (job.attempts + 1).to_be_bytes().to_vec();
+        value.extend_from_slice(error.as_bytes());
+        let mut write_tx = self.keyspace.write_tx();
+        write_tx.remove(&self.push_outbox, scheduled_key(&job.message_id, job.due));
+        write_tx.insert(
+            &self.push_dead_letters,
+            scheduled_key(&job.message_id, failed_at),
+            value,
+        );
+        write_tx.commit()?;
+        self.mutated(2)
+    }
+
+    fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>> {
+        let read_tx = self.keyspace.read_tx();
+        let mut letters = Vec::new();
+        for result in read_tx.iter(&self.push_dead_letters).take(limit) {
+            let (key, value) = result?;
+            let (message_id, failed_at) = decode_scheduled_key(&key)?;
+            let attempts = value
+                .get(..4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or_else(|| {
+                    StorageError::Corrupt("dead letter missing its attempts".to_string())
+                })?;
+            letters.push(DeadLetter {
+                message_id,
+                failed_at,
+                attempts,
+                error: String::from_utf8_lossy(&value[4..]).into_owned(),
+            });
+        }
+        Ok(letters)
+    }
+
+    fn remove_dead_letter(
+        &self,
+        letter: &DeadLetter,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<bool> {
+        let key = scheduled_key(&letter.message_id, letter.failed_at);
+        let mut write_tx = self.keyspace.write_tx();
+        if !write_tx.contains_key(&self.push_dead_letters, &key)? {
+            return Ok(false);
+        }
+        write_tx.remove(&self.push_dead_letters, key);
+        if let Some(retry_at) = retry_at {
+            write_tx.insert(
+                &self.push_outbox,
+                scheduled_key(&letter.message_id, retry_at),
+                0u32.to_be_bytes().as_slice(),
+            );
+        }
+        write_tx.commit()?;
+        self.mutated(2)?;
+        Ok(true)
+    }
+
+    fn prune_dead_letters(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut stale = Vec::new();
+        for result in self.keyspace.read_tx().iter(&self.push_dead_letters) {
+            let (key, _) = result?;
+            if value_millis(&key, 0)? >= before {
+                break;
+            }
+            stale.push(key);
+        }
+        if stale.is_empty() {
+            return Ok(0);
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for key in &stale {
+            write_tx.remove(&self.push_dead_letters, key.clone());
+        }
+        write_tx.commit()?;
+        self.mutated(stale.len())?;
+        Ok(stale.len())
+    }
+}
+
+impl AnalyticsStore for FjallStore {
+    fn save_bucket(&self, bucket: &AnalyticsBucket) -> Result<()> {
+        self.analytics.insert(
+            analytics_key(bucket.period, bucket.start),
+            serde_json::to_vec(bucket)?,
+        )?;
+        Ok(())
+    }
+
+    fn buckets(
+        &self,
+        period: AnalyticsPeriod,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<AnalyticsBucket>> {
+        let read_tx = self.keyspace.read_tx();
+        read_tx
+            .range(
+                &self.analytics,
+                analytics_key(period, from)..=analytics_key(period, to),
+            )
+            .map(|result| {
+                let (_key, value) = result?;
+                Ok(serde_json::from_slice(&value)?)
+            })
+            .collect()
+    }
+
+    fn prune_buckets(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut stale = Vec::new();
+        {
+            let read_tx = self.keyspace.read_tx();
+            for period in [AnalyticsPeriod::Hour, AnalyticsPeriod::Day] {
+                let start = analytics_key(period, DateTime::<Utc>::UNIX_EPOCH);
+                for result in read_tx.range(&self.analytics, start..analytics_key(period, before)) {
+                    let (key, _value) = result?;
+                    stale.push(key);
+                }
+            }
+        }
+        if stale.is_empty() {
+            return Ok(0);
+        }
+        let mut write_tx = self.keyspace.write_tx();
+        for key in &stale {
+            write_tx.remove(&self.analytics, key.clone());
+        }
+        write_tx.commit()?;
+        Ok(stale.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn open_store() -> (tempfile::TempDir, FjallStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FjallStore::open(dir.path()).unwrap();
+        (dir, store)
+    }
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(millis).unwrap()
+    }
+
+    #[test]
+    fn scan_returns_messages_oldest_first_whatever_the_put_order() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        for millis in [3_000, 1_000, 2_000] {
+            store
+                .put(&inbox, &format!("at {}", millis), at(millis), None)
+                .unwrap();
+        }
+
+        let found = store.fetch(std::slice::from_ref(&inbox)).unwrap();
+        let timestamps: Vec<_> = found.iter().map(|message| message.timestamp).collect();
+        assert_eq!(timestamps, vec![at(1_000), at(2_000), at(3_000)]);
+        assert_eq!(found[0].message, "at 1000");
+    }
+
+    #[test]
+    fn ack_before_deletes_only_the_ids_older_messages() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        let longer = MessageId::parse("inbox2").unwrap();
+        for millis in [1_000, 2_000, 3_000] {
+            store.put(&inbox, "hello", at(millis), None).unwrap();
+            store.put(&longer, "hello", at(millis), None).unwrap();
+        }
+
+        let before_epoch = DateTime::<Utc>::UNIX_EPOCH - chrono::Duration::days(1);
+        assert_eq!(store.ack_before(&inbox, before_epoch).unwrap(), 0);
+        assert_eq!(store.ack_before(&inbox, at(2_500)).unwrap(), 2);
+        assert_eq!(
+            store.ack_before(&inbox, DateTime::<Utc>::MAX_UTC).unwrap(),
+            1
+        );
+        assert_eq!(store.pending_counts(&[inbox, longer]).unwrap(), vec![0, 3]);
+    }
+
+    #[test]
+    fn revoking_a_scheduled_message_leaves_no_orphans() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        let receipts = MessageId::parse("receipts").unwrap();
+        let now = Utc::now();
+        let token = AckToken {
+            message_id: inbox.clone(),
+            timestamp: at((now + chrono::Duration::hours(1)).timestamp_millis()),
+        };
+        store
+            .schedule(&inbox, "later", token.timestamp, None)
+            .unwrap();
+        store.save_handle(&token, b"handle hash").unwrap();
+        store.request_receipt(&token, &receipts).unwrap();
+
+        assert!(store.revoke(&token).unwrap());
+        assert!(!store.revoke(&token).unwrap());
+        let anomalies = store.check_delivery(&[], 100, now, now, now).unwrap();
+        assert_eq!(anomalies.orphaned_records, 0);
+        assert_eq!(store.release_due(token.timestamp).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn check_delivery_reports_records_whose_message_is_gone() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        let outbox = MessageId::parse("outbox").unwrap();
+        let now = Utc::now();
+        let kept = AckToken {
+            message_id: inbox.clone(),
+            timestamp: at(1_000),
+        };
+        store.put(&inbox, "kept", kept.timestamp, None).unwrap();
+        store
+            .mark_delivered(std::slice::from_ref(&kept), now)
+            .unwrap();
+
+        // A delivery record for a message that was never stored
+        let never_stored = AckToken {
+            message_id: inbox.clone(),
+            timestamp: at(2_000),
+        };
+        store
+            .mark_delivered(std::slice::from_ref(&never_stored), now)
+            .unwrap();
+        // A handle left behind by a scheduled entry removed on its own, as
+        // revoke did before it went through unschedule
+        let revoked = AckToken {
+            message_id: outbox.clone(),
+            timestamp: at((now + chrono::Duration::hours(1)).timestamp_millis()),
+        };
+        store
+            .schedule(&outbox, "later", revoked.timestamp, None)
+            .unwrap();
+        store.save_handle(&revoked, b"handle hash").unwrap();
+        store
+            .scheduled
+            .remove(scheduled_key(&revoked.message_id, revoked.timestamp))
+            .unwrap();
+
+        let anomalies = store.check_delivery(&[], 100, now, at(0), at(0)).unwrap();
+        assert_eq!(anomalies.checked, 4);
+        assert_eq!(anomalies.orphaned_records, 2);
+        assert_eq!(anomalies.stale_delivered, 0);
+        assert!(anomalies.mailboxes.contains(&inbox));
+        assert!(anomalies.mailboxes.contains(&outbox));
+    }
+
+    #[test]
+    fn receipts_never_overwrite_messages_in_their_channel() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        let receipts = MessageId::parse("receipts").unwrap();
+        let acked_at = at(10_000);
+        store
+            .put(&receipts, "already here", acked_at, None)
+            .unwrap();
+        let mut acks = Vec::new();
+        for millis in [1_000, 2_000] {
+            let token = AckToken {
+                message_id: inbox.clone(),
+                timestamp: at(millis),
+            };
+            store.put(&inbox, "hello", token.timestamp, None).unwrap();
+            store.request_receipt(&token, &receipts).unwrap();
+            acks.push(token);
+        }
+
+        let sent = store.ack_with_receipts(&acks, acked_at).unwrap();
+        assert_eq!(sent.len(), 2);
+        let found = store.fetch(std::slice::from_ref(&receipts)).unwrap();
+        let timestamps: Vec<_> = found.iter().map(|message| message.timestamp).collect();
+        assert_eq!(timestamps, vec![at(10_000), at(10_001), at(10_002)]);
+        assert_eq!(found[0].message, "already here");
+    }
+
+    #[test]
+    fn forgetting_a_mailbox_drops_its_cursors_leases_and_counter() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        let longer = MessageId::parse("inbox2").unwrap();
+        let now = Utc::now();
+        for message_id in [&inbox, &longer] {
+            store.put(message_id, "hello", at(1_000), None).unwrap();
+            store
+                .join_consumer(std::slice::from_ref(message_id), "phone")
+                .unwrap();
+            let found = store.fetch(std::slice::from_ref(message_id)).unwrap();
+            let tokens: Vec<_> = found.iter().map(FoundMessage::ack_token).collect();
+            store
+                .lease(&tokens, now, Some(now + chrono::Duration::hours(1)))
+                .unwrap();
+        }
+        // Cursor keys are the id and a newline, leases message keys, counters the id
+        let records = |message_id: &MessageId| {
+            let id = message_id.as_bytes();
+            [&store.cursors, &store.leases, &store.sequences].map(|partition| {
+                store
+                    .keyspace
+                    .read_tx()
+                    .prefix(partition, id)
+                    .filter_map(|result| result.ok())
+                    .filter(|(key, _)| {
+                        key.len() == id.len()
+                            || key[id.len()] == b'\n'
+                            || key_is_for(key, message_id)
+                    })
+                    .count()
+            })
+        };
+        assert_eq!(records(&inbox), [1, 1, 1]);
+
+        // The counter outlives reaping while messages still carry its numbers
+        store.reap_mailbox(&inbox).unwrap();
+        assert_eq!(records(&inbox), [0, 0, 1]);
+        store.purge(&inbox).unwrap();
+        store.forget_mailbox(&inbox).unwrap();
+        assert_eq!(records(&inbox), [0, 0, 0]);
+        assert_eq!(records(&longer), [1, 1, 1]);
+    }
+
+    #[test]
+    fn tallies_stay_exact_under_concurrent_puts_and_acks() {
+        const THREADS: i64 = 4;
+        const PUTS: i64 = 50;
+        for policy in [DeletionPolicy::Remove, DeletionPolicy::Overwrite] {
+            let (_dir, store) = open_store();
+            let store = store.with_deletion_policy(policy);
+            let inbox = MessageId::parse("inbox").unwrap();
+            let token = |thread: i64, i: i64| AckToken {
+                message_id: inbox.clone(),
+                timestamp: at(1_000_000 * (thread + 1) + i),
+            };
+            std::thread::scope(|scope| {
+                for thread in 0..THREADS {
+                    let (store, token) = (&store, &token);
+                    scope.spawn(move || {
+                        for i in 0..PUTS {
+                            let put = token(thread, i);
+                            store
+                                .put(&put.message_id, "hello", put.timestamp, None)
+                                .unwrap();
+                            // Even ones are acked twice, racing the neighbour's thread
+                            if i % 2 == 0 {
+                                let acks = [token(thread, i), token((thread + 1) % THREADS, i)];
+                                store.ack(&acks).unwrap();
+                            }
+                        }
+                    });
+                }
+                scope.spawn(|| {
+                    for _ in 0..PUTS {
+                        let (count, _) = store.count_and_latest(&inbox).unwrap();
+                        assert!(count <= (THREADS * PUTS) as usize);
+                    }
+                });
+            });
+            // An ack can beat its neighbour's put, so what survives varies
+            let stored = store.fetch(std::slice::from_ref(&inbox)).unwrap();
+            let (count, latest) = store.count_and_latest(&inbox).unwrap();
+            assert_eq!(count, stored.len(), "{:?}", policy);
+            assert_eq!(latest, stored.last().map(|message| message.timestamp));
+            assert_eq!(
+                store.pending_counts(std::slice::from_ref(&inbox)).unwrap(),
+                vec![count]
+            );
+        }
+    }
+
+    #[test]
+    fn tallies_are_backfilled_for_databases_without_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = MessageId::parse("inbox").unwrap();
+        {
+            let store = FjallStore::open(dir.path()).unwrap();
+            store.put(&inbox, "first", at(1_000), None).unwrap();
+            store.put(&inbox, "second", at(2_000), None).unwrap();
+            let mut write_tx = store.keyspace.write_tx();
+            write_tx.remove(&store.counts, inbox.as_bytes());
+            write_tx.commit().unwrap();
+            assert_eq!(store.count_and_latest(&inbox).unwrap(), (0, None));
+        }
+        let store = FjallStore::open(dir.path()).unwrap();
+        assert_eq!(
+            store.count_and_latest(&inbox).unwrap(),
+            (2, Some(at(2_000)))
+        );
+    }
+
+    #[test]
+    fn advance_cursor_returns_only_what_every_consumer_acked() {
+        let (_dir, store) = open_store();
+        let inbox = MessageId::parse("inbox").unwrap();
+        let longer = MessageId::parse("inbox2").unwrap();
+        for millis in [1_000, 2_000, 3_000] {
+            store.put(&inbox, "hello", at(millis), None).unwrap();
+            store.put(&longer, "hello", at(millis), None).unwrap();
+        }
+        let ids = [inbox.clone(), longer.clone()];
+        store.join_consumer(&ids, "phone").unwrap();
+        store.join_consumer(&ids, "laptop").unwrap();
+        let ack = |message_id: &MessageId, timestamp| AckToken {
+            message_id: message_id.clone(),
+            timestamp,
+        };
+
+        let before_epoch = DateTime::<Utc>::UNIX_EPOCH - chrono::Duration::days(1);
+        for consumer in ["phone", "laptop"] {
+            let done = store
+                .advance_cursor(consumer, &[ack(&inbox, before_epoch)])
+                .unwrap();
+            assert!(done.is_empty());
+        }
+
+        assert!(store
+            .advance_cursor("phone", &[ack(&inbox, at(2_000))])
+            .unwrap()
+            .is_empty());
+        let done = store
+            .advance_cursor("laptop", &[ack(&inbox, at(3_000))])
+            .unwrap();
+        assert_eq!(done, vec![ack(&inbox, at(1_000)), ack(&inbox, at(2_000))]);
+    }
+
+    #[test]
+    fn put_batch_with_a_repeated_id_writes_nothing() {
+        let (_dir, store) = open_store();
+        let entry = |message_id: &str| PutMessageRequest {
+            message_id: MessageId::parse(message_id).unwrap(),
+            message: "hello".to_string(),
+            idempotency_key: None,
+            deliver_at: None,
+            ttl_seconds: None,
+            supersedes: None,
+            receipt_channel_id: None,
+            durable: false,
+            retain: false,
+        };
+        let entries = [entry("alice"), entry("bob"), entry("alice")];
+
+        let result = store.put_batch(&entries, at(1_000));
+        assert!(matches!(result, Err(StorageError::DuplicateId(id)) if id.as_str() == "alice"));
+        let ids: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.message_id.clone())
+            .collect();
+        assert_eq!(store.pending_counts(&ids).unwrap(), vec![0, 0, 0]);
+        assert_eq!(
+            store.latest_sequences(&ids).unwrap(),
+            vec![None, None, None]
+        );
+    }
+
+    #[test]
+    fn count_and_latest_agrees_with_separate_reads() {
+        let (_dir, store) = open_store();
+        let empty = MessageId::parse("empty").unwrap();
+        let live = MessageId::parse("live").unwrap();
+        let expired = MessageId::parse("expired").unwrap();
+        let now = at(Utc::now().timestamp_millis());
+        let past = now - chrono::Duration::hours(1);
+        let newest = now + chrono::Duration::seconds(1);
+        store.put(&live, "first", now, None).unwrap();
+        store.put(&live, "second", newest, None).unwrap();
+        store
+            .put(
+                &live,
+                "lapsed",
+                past - chrono::Duration::hours(1),
+                Some(past),
+            )
+            .unwrap();
+        store
+            .put(
+                &expired,
+                "lapsed",
+                past - chrono::Duration::hours(1),
+                Some(past),
+            )
+            .unwrap();
+
+        for message_id in [&empty, &live, &expired] {
+            let (count, latest) = store.count_and_latest(message_id).unwrap();
+            let ids = std::slice::from_ref(message_id);
+            assert_eq!(
+                count,
+                store.pending_counts(ids).unwrap()[0],
+                "{}",
+                message_id
+            );
+            let newest = store.newest_message(message_id).unwrap();
+            assert_eq!(
+                latest,
+                newest.map(|message| message.timestamp),
+                "{}",
+                message_id
+            );
+        }
+        assert_eq!(store.count_and_latest(&empty).unwrap(), (0, None));
+        assert_eq!(store.count_and_latest(&expired).unwrap(), (0, None));
+        assert_eq!(store.count_and_latest(&live).unwrap(), (2, Some(newest)));
+    }
+}
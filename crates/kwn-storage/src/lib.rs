@@ -0,0 +1,606 @@
+//! Storage boundary for the message relay.
+//!
+//! The server only talks to storage through [`MessageStore`] and
+//! [`SubscriptionStore`]; [`FjallStore`] is the production implementation.
+//! Trait methods are blocking, so async callers decide whether to run them
+//! inline or on the blocking pool.
+
+mod codec;
+mod fjall_store;
+mod persistence;
+
+use chrono::{DateTime, Utc};
+use kwn_protocol::{
+    AckToken, AnalyticsBucket, AnalyticsPeriod, FoundMessage, MessageId, MessageState,
+    PushSubscriptionInfo, PutMessageRequest, MAX_MESSAGE_ID_LEN,
+};
+use sha2::{Digest, Sha256};
+use smallvec::SmallVec;
+
+pub use fjall_store::FjallStore;
+pub use persistence::{PersistPolicy, PersistReason};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Fjall DB error: {0}")]
+    Fjall(#[from] fjall::Error),
+    #[error("JSON serialization/deserialization error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Corrupt record: {0}")]
+    Corrupt(String),
+    #[error("message_id {0} appears more than once in a batch")]
+    DuplicateId(MessageId),
+}
+
+impl StorageError {
+    /// I/O failures may clear up on their own; anything else means bad data.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            StorageError::Io(_) | StorageError::Fjall(fjall::Error::Io(_))
+        )
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// Devices that can register push subscriptions for one message_id; each is
+/// pushed on every put, so this bounds the fan-out a put can cause.
+pub const MAX_SUBSCRIPTIONS_PER_ID: usize = 8;
+
+/// Storage engine pressure indicators, cheap enough to read on every probe.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StorageHealth {
+    pub journal_count: usize,
+    pub journal_limit: usize,
+    pub write_buffer_bytes: u64,
+    pub write_buffer_limit: u64,
+    pub disk_space_bytes: u64,
+    pub message_segments: usize,
+    // True once either the journal or the write buffer is close enough to its
+    // limit that fjall will start stalling writes.
+    pub stall_risk: bool,
+}
+
+/// What one sampling pass of [`MessageStore::check_delivery`] found wrong.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct DeliveryAnomalies {
+    // Messages and side records examined
+    pub checked: usize,
+    // Delivered before the cutoff, never acked and with no lease running
+    pub stale_delivered: usize,
+    // Expired long enough ago that the sweep should have deleted them
+    pub unswept_expired: usize,
+    // Delivery, lease, handle or receipt records whose message is gone
+    pub orphaned_records: usize,
+    // A few of the mailboxes involved, to start an investigation from
+    pub mailboxes: Vec<MessageId>,
+}
+
+const MAX_ANOMALY_MAILBOXES: usize = 8;
+
+impl DeliveryAnomalies {
+    pub fn is_empty(&self) -> bool {
+        self.stale_delivered + self.unswept_expired + self.orphaned_records == 0
+    }
+
+    fn note(&mut self, message_id: MessageId) {
+        if self.mailboxes.len() < MAX_ANOMALY_MAILBOXES && !self.mailboxes.contains(&message_id) {
+            self.mailboxes.push(message_id);
+        }
+    }
+}
+
+/// What [`MessageStore::ack`] does with an acknowledged message's value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeletionPolicy {
+    /// Delete the key; the old value lingers on disk until compaction.
+    #[default]
+    Remove,
+    /// Best-effort secure deletion: overwrite the value with zeros in a synced
+    /// commit, then delete the key. Old segments still hold the original bytes
+    /// until compacted away, and SSD wear levelling may keep copies beyond
+    /// that, so this narrows the window rather than guaranteeing erasure.
+    Overwrite,
+}
+
+/// What [`MessageStore::cancel`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    Cancelled,
+    /// A poll already returned the message, so it was left alone.
+    Fetched,
+    /// No such message, or the handle doesn't match it.
+    NotFound,
+}
+
+pub trait MessageStore: Send + Sync {
+    /// Stores `message` for `message_id` at `timestamp`. Once `expires_at` has
+    /// passed the message is hidden from reads until [`Self::sweep_expired`]
+    /// deletes it.
+    fn put(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()>;
+
+    /// Like [`Self::put`], but deletes `message_id`'s message stored at
+    /// `supersedes` in the same transaction, as an ack would. Stores the new
+    /// message even if the old one is already gone.
+    fn supersede(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        supersedes: DateTime<Utc>,
+    ) -> Result<()>;
+
+    /// Like [`Self::put`], except that when `idempotency_key` was already used for
+    /// `message_id` and hasn't expired, nothing is stored. Returns the timestamp
+    /// the message is stored under: `timestamp`, or the first put's on a repeat.
+    /// The key is remembered until `key_expires_at`.
+    fn put_idempotent(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        idempotency_key: &str,
+        key_expires_at: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>>;
+
+    /// Forgets idempotency keys that expired before `now`, returning how many.
+    fn prune_idempotency_keys(&self, now: DateTime<Utc>) -> Result<usize>;
+
+    /// Holds `message` back until `deliver_at`, when [`Self::release_due`]
+    /// stores it for `message_id` with `deliver_at` as its timestamp.
+    fn schedule(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        deliver_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()>;
+
+    /// Moves every scheduled message due by `now` into its mailbox in one
+    /// transaction, returning each one's message_id and body length.
+    fn release_due(&self, now: DateTime<Utc>) -> Result<Vec<(MessageId, usize)>>;
+
+    /// Stores every entry at `timestamp` in one transaction: either all of them
+    /// are written or none are. Entries must have distinct message_ids, else
+    /// nothing is written; each one's `ttl_seconds` counts from `timestamp`.
+    fn put_batch(&self, entries: &[PutMessageRequest], timestamp: DateTime<Utc>) -> Result<()>;
+
+    /// Stores `message` for every one of `message_ids` in one transaction.
+    fn put_many(
+        &self,
+        message_ids: &[MessageId],
+        message: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let entries: Vec<PutMessageRequest> = message_ids
+            .iter()
+            .map(|message_id| PutMessageRequest {
+                message_id: message_id.clone(),
+                message: message.to_string(),
+                idempotency_key: None,
+                deliver_at: None,
+                ttl_seconds: None,
+                supersedes: None,
+                receipt_channel_id: None,
+                durable: false,
+                retain: false,
+            })
+            .collect();
+        self.put_batch(&entries, timestamp)
+    }
+
+    /// Passes every stored message for each of `message_ids` to `visit`, id by id
+    /// and oldest first within an id, stopping early once `visit` returns false.
+    /// Returns how many messages were visited.
+    fn scan(
+        &self,
+        message_ids: &[MessageId],
+        visit: &mut dyn FnMut(FoundMessage) -> bool,
+    ) -> Result<usize>;
+
+    /// Makes `message` the retained value of `message_id`, replacing any
+    /// earlier one in the same transaction. Retained values live apart from
+    /// the queue, so acks and queue scans never touch them.
+    fn put_retained(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()>;
+
+    /// Returns the unexpired retained value of each of `message_ids` that has one.
+    fn retained(&self, message_ids: &[MessageId]) -> Result<Vec<FoundMessage>>;
+
+    /// Returns up to `limit` distinct ids starting with `prefix` that hold
+    /// stored messages, in key order.
+    fn ids_with_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<MessageId>>;
+
+    /// Returns every stored message for each of `message_ids`.
+    fn fetch(&self, message_ids: &[MessageId]) -> Result<Vec<FoundMessage>> {
+        let mut found = Vec::new();
+        self.scan(message_ids, &mut |message| {
+            found.push(message);
+            true
+        })?;
+        Ok(found)
+    }
+
+    /// Returns up to `limit` of `message_id`'s messages stored after `after`, oldest
+    /// first, and whether more remain beyond them.
+    fn page(
+        &self,
+        message_id: &MessageId,
+        after: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<(Vec<FoundMessage>, bool)> {
+        let mut found = Vec::new();
+        let mut more = false;
+        self.scan(std::slice::from_ref(message_id), &mut |message| {
+            if after.is_some_and(|after| message.timestamp <= after) {
+                return true;
+            }
+            if found.len() == limit {
+                more = true;
+                return false;
+            }
+            found.push(message);
+            true
+        })?;
+        Ok((found, more))
+    }
+
+    /// Returns the last sequence number handed out for each of `message_ids`, in
+    /// order, or None for ids that never received a message.
+    fn latest_sequences(&self, message_ids: &[MessageId]) -> Result<Vec<Option<u64>>>;
+
+    /// Returns how many messages are stored for each of `message_ids`, in order.
+    fn pending_counts(&self, message_ids: &[MessageId]) -> Result<Vec<usize>> {
+        message_ids
+            .iter()
+            .map(|message_id| self.scan(std::slice::from_ref(message_id), &mut |_| true))
+            .collect()
+    }
+
+    /// Returns how many messages are stored for `message_id` and the timestamp
+    /// of the newest, in one pass over the mailbox. Stores that keep a count
+    /// per mailbox answer from it instead.
+    fn count_and_latest(&self, message_id: &MessageId) -> Result<(usize, Option<DateTime<Utc>>)> {
+        let mut count = 0;
+        let mut latest = None;
+        self.scan(std::slice::from_ref(message_id), &mut |message| {
+            count += 1;
+            latest = latest.max(Some(message.timestamp));
+            true
+        })?;
+        Ok((count, latest))
+    }
+
+    /// Returns the newest message stored for `message_id`, if any.
+    fn newest_message(&self, message_id: &MessageId) -> Result<Option<FoundMessage>> {
+        let mut newest = None;
+        self.scan(std::slice::from_ref(message_id), &mut |message| {
+            newest = Some(message);
+            true
+        })?;
+        Ok(newest)
+    }
+
+    /// Deletes the acknowledged messages in a single transaction, first zeroing
+    /// them under [`DeletionPolicy::Overwrite`].
+    fn ack(&self, acks: &[AckToken]) -> Result<()>;
+
+    /// Records that acking the message `token` names should store a
+    /// [`DeliveryReceipt`](kwn_protocol::DeliveryReceipt) in `receipt_channel`.
+    /// Called right after the put, before recipients are told about it.
+    fn request_receipt(&self, token: &AckToken, receipt_channel: &MessageId) -> Result<()>;
+
+    /// Like [`Self::ack`], but in the same transaction stores a receipt at
+    /// `acked_at`, or the first free millisecond after it in the receipt's
+    /// channel, for each acked message that requested one. Returns each
+    /// receipt's channel and body length. Other deletions, including
+    /// [`Self::ack`] itself, drop a receipt request without sending it.
+    fn ack_with_receipts(
+        &self,
+        acks: &[AckToken],
+        acked_at: DateTime<Utc>,
+    ) -> Result<Vec<(MessageId, usize)>>;
+
+    /// Acks every message of `message_id` stored before `before` in one
+    /// [`Self::ack`] call, returning how many there were.
+    fn ack_before(&self, message_id: &MessageId, before: DateTime<Utc>) -> Result<usize> {
+        let mut acks = Vec::new();
+        self.scan(std::slice::from_ref(message_id), &mut |message| {
+            if message.timestamp >= before {
+                return false; // Scans run oldest first
+            }
+            acks.push(message.ack_token());
+            true
+        })?;
+        self.ack(&acks)?;
+        Ok(acks.len())
+    }
+
+    /// Deletes every message of `message_id`, stored or still scheduled, and its
+    /// retained value, with the delivery, lease, handle and receipt records
+    /// tied to them, in one
+    /// transaction, first zeroing stored bodies under
+    /// [`DeletionPolicy::Overwrite`]. Returns how many stored messages there
+    /// were.
+    fn purge(&self, message_id: &MessageId) -> Result<usize>;
+
+    /// Records that the messages `tokens` name were returned to a recipient at
+    /// `at`, keeping the earliest time for any already recorded. Acking a
+    /// message drops its record.
+    fn mark_delivered(&self, tokens: &[AckToken], at: DateTime<Utc>) -> Result<()>;
+
+    /// Reports whether the message `token` names is still waiting, was
+    /// delivered but not acked, or is gone.
+    fn message_state(&self, token: &AckToken) -> Result<MessageState>;
+
+    /// Leases each message `tokens` names until `until`, unless a lease on it
+    /// is still running at `now`, and returns whether each was granted, in
+    /// order. With no `until` nothing is leased and each result says whether
+    /// the message is free. Acking a message drops its lease.
+    fn lease(
+        &self,
+        tokens: &[AckToken],
+        now: DateTime<Utc>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<bool>>;
+
+    /// Registers `consumer` as a reader of each of `message_ids` it hasn't read
+    /// before and returns its cursor on each, in order: the timestamp it has
+    /// acked through, or `None` if it has acked nothing there yet.
+    fn join_consumer(
+        &self,
+        message_ids: &[MessageId],
+        consumer: &str,
+    ) -> Result<Vec<Option<DateTime<Utc>>>>;
+
+    /// Moves `consumer`'s cursor on each acked message's id up to the newest
+    /// of its acks, registering the consumer where it is new, and returns the
+    /// messages every consumer of those ids has now acked through. The caller
+    /// deletes those with [`Self::ack`] or [`Self::ack_with_receipts`]; any
+    /// left behind by a failure come back on the next call. Acks dated before
+    /// the epoch name no message and are ignored.
+    fn advance_cursor(&self, consumer: &str, acks: &[AckToken]) -> Result<Vec<AckToken>>;
+
+    /// Records the hash of the sender's handle for the message `token` names.
+    /// Called right after the put, like [`Self::request_receipt`].
+    fn save_handle(&self, token: &AckToken, handle_hash: &[u8]) -> Result<()>;
+
+    /// Deletes the message `token` names if `handle_hash` matches the one
+    /// saved for it and no poll has returned it yet.
+    fn cancel(&self, token: &AckToken, handle_hash: &[u8]) -> Result<CancelOutcome>;
+
+    /// Deletes the one message `token` names, whether delivered or still
+    /// scheduled. Returns `false` if there was no such message.
+    fn revoke(&self, token: &AckToken) -> Result<bool>;
+
+    /// Deletes every message whose expiry has passed by `now`, returning how many.
+    fn sweep_expired(&self, now: DateTime<Utc>) -> Result<usize>;
+
+    /// Checks up to `limit` messages and `limit` of each kind of side record,
+    /// starting at key `from` and wrapping around, against the invariants the
+    /// delivery paths keep: a message delivered before `stale_before` has been
+    /// acked or is leased at `now`, one expired before `swept_before` is gone,
+    /// and every delivery, lease, handle and receipt record has its message.
+    /// Reads one snapshot, so concurrent acks can't cause false alarms.
+    fn check_delivery(
+        &self,
+        from: &[u8],
+        limit: usize,
+        now: DateTime<Utc>,
+        stale_before: DateTime<Utc>,
+        swept_before: DateTime<Utc>,
+    ) -> Result<DeliveryAnomalies>;
+
+    fn deletion_policy(&self) -> DeletionPolicy;
+
+    fn health(&self) -> StorageHealth;
+
+    /// Syncs every write so far to disk, returning how many mutations were
+    /// pending; nothing is synced when none were. See [`PersistPolicy`].
+    fn persist(&self, reason: PersistReason) -> Result<u64>;
+
+    /// Compacts message storage so tombstones left by deletes stop slowing reads.
+    /// Returns `false` without doing anything if a compaction is already running.
+    fn compact(&self) -> Result<bool>;
+}
+
+pub trait SubscriptionStore: Send + Sync {
+    /// Adds `subscription` to the push targets of each of `message_ids`,
+    /// replacing an earlier one with the same endpoint and clearing any
+    /// resubscribe flag on those ids, in one transaction. Each id keeps at most
+    /// [`MAX_SUBSCRIPTIONS_PER_ID`], so another device's registration may be
+    /// evicted. Ids that already have exactly this subscription and no flag are
+    /// left untouched. New keys for an endpoint replace the old ones for every
+    /// id that endpoint is registered for. Returns whether anything was written.
+    fn save_subscription(
+        &self,
+        message_ids: &[MessageId],
+        subscription: &PushSubscriptionInfo,
+    ) -> Result<bool>;
+
+    /// Returns every subscription registered for `message_id`, one per device.
+    fn subscriptions(&self, message_id: &MessageId) -> Result<Vec<PushSubscriptionInfo>>;
+
+    /// Removes every subscription registered for `message_id`.
+    fn remove_subscription(&self, message_id: &MessageId) -> Result<()>;
+
+    /// Removes the subscription of the device pushed to at `endpoint` from
+    /// `message_id`. Returns whether one was registered.
+    fn remove_device_subscription(&self, message_id: &MessageId, endpoint: &str) -> Result<bool>;
+
+    /// Removes the device pushed to at `subscription`'s endpoint from every
+    /// mailbox it was saved for, after the push service reported the endpoint
+    /// gone, and flags each of them with [`Self::mark_resubscribe_required`], in
+    /// one transaction. Returns the mailboxes it was removed from.
+    fn remove_endpoint(&self, subscription: &PushSubscriptionInfo) -> Result<Vec<MessageId>>;
+
+    /// Records that the push service rejected `message_id`'s subscription as gone.
+    fn mark_resubscribe_required(&self, message_id: &MessageId) -> Result<()>;
+
+    /// Returns those of `message_ids` flagged by [`Self::mark_resubscribe_required`].
+    fn resubscribe_required(&self, message_ids: &[MessageId]) -> Result<Vec<MessageId>>;
+
+    /// Returns every id with a stored subscription or resubscribe flag.
+    fn subscribed_ids(&self) -> Result<Vec<MessageId>>;
+
+    /// Drops the bookkeeping of a mailbox gone idle: its resubscribe flag,
+    /// consumer cursors, leases and, once it holds no messages, its sequence
+    /// counter. Keeps its subscriptions and fallback contacts.
+    fn reap_mailbox(&self, message_id: &MessageId) -> Result<()>;
+
+    /// Drops everything [`Self::reap_mailbox`] does, plus the subscriptions,
+    /// fallback email and alert number of an abandoned mailbox.
+    fn forget_mailbox(&self, message_id: &MessageId) -> Result<()>;
+}
+
+pub trait TokenStore: Send + Sync {
+    /// Marks the token `nonce` as spent. Returns `false` if it already was.
+    fn redeem_token(&self, nonce: &[u8]) -> Result<bool>;
+}
+
+pub trait ShareLinkStore: Send + Sync {
+    /// Records a one-shot read grant for `message_id` under `token_hash`.
+    fn save_share_link(
+        &self,
+        token_hash: &[u8],
+        message_id: &MessageId,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()>;
+
+    /// Removes the grant under `token_hash`, returning its id unless it had
+    /// expired by `now`.
+    fn take_share_link(&self, token_hash: &[u8], now: DateTime<Utc>) -> Result<Option<MessageId>>;
+
+    /// Deletes grants that expired before `now`, returning how many.
+    fn prune_share_links(&self, now: DateTime<Utc>) -> Result<usize>;
+}
+
+pub trait EmailStore: Send + Sync {
+    /// Records `email` as the fallback address under `mailbox_hash`, or
+    /// removes it when None.
+    fn save_email(&self, mailbox_hash: &[u8], email: Option<&str>) -> Result<()>;
+
+    fn email(&self, mailbox_hash: &[u8]) -> Result<Option<String>>;
+}
+
+pub trait SmsStore: Send + Sync {
+    /// Records `phone` as the number alerted under `mailbox_hash`, or removes
+    /// it when None.
+    fn save_phone(&self, mailbox_hash: &[u8], phone: Option<&str>) -> Result<()>;
+
+    fn phone(&self, mailbox_hash: &[u8]) -> Result<Option<String>>;
+}
+
+/// A push waiting in the outbox for `message_id`.
+#[derive(Clone, Debug)]
+pub struct OutboxJob {
+    pub message_id: MessageId,
+    pub due: DateTime<Utc>,
+    // Sends already tried and failed
+    pub attempts: u32,
+}
+
+/// A push that used up its retries, kept for an operator to retry or discard.
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    pub message_id: MessageId,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    // Why the last attempt failed
+    pub error: String,
+}
+
+pub trait OutboxStore: Send + Sync {
+    /// Queues a push to each of `message_ids`, due at `due`.
+    fn enqueue_pushes(&self, message_ids: &[MessageId], due: DateTime<Utc>) -> Result<()>;
+
+    /// Returns up to `limit` pushes due by `now`, soonest first.
+    fn due_pushes(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<OutboxJob>>;
+
+    /// Removes `job` from the outbox. With `retry_at`, queues it again for then
+    /// with one more attempt counted, in the same transaction.
+    fn finish_push(&self, job: &OutboxJob, retry_at: Option<DateTime<Utc>>) -> Result<()>;
+
+    /// Moves `job` from the outbox to the dead letters, recording the `error`
+    /// its last attempt failed with, in one transaction.
+    fn dead_letter_push(
+        &self,
+        job: &OutboxJob,
+        error: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<()>;
+
+    /// Returns up to `limit` dead letters, oldest first.
+    fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>>;
+
+    /// Removes `letter`. With `retry_at`, queues its push again for then, with
+    /// no attempts counted, in the same transaction. Returns whether it was
+    /// still there.
+    fn remove_dead_letter(
+        &self,
+        letter: &DeadLetter,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<bool>;
+
+    /// Deletes dead letters that failed before `before`, returning how many.
+    fn prune_dead_letters(&self, before: DateTime<Utc>) -> Result<usize>;
+}
+
+pub trait AnalyticsStore: Send + Sync {
+    /// Stores `bucket`, replacing any bucket with the same period and start.
+    fn save_bucket(&self, bucket: &AnalyticsBucket) -> Result<()>;
+
+    /// Returns buckets of `period` starting within `from..=to`, oldest first.
+    fn buckets(
+        &self,
+        period: AnalyticsPeriod,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<AnalyticsBucket>>;
+
+    /// Deletes buckets of every period that start before `before`, returning how many.
+    fn prune_buckets(&self, before: DateTime<Utc>) -> Result<usize>;
+}
+
+/// A message key, held inline: no valid message_id makes one spill to the heap.
+pub type MessageKey = SmallVec<[u8; MAX_MESSAGE_ID_LEN + 8]>;
+
+// Keys are the message_id bytes followed by the big-endian millisecond timestamp,
+// so a prefix scan on the id returns that mailbox's messages in time order.
+pub fn message_key(message_id: &MessageId, timestamp: DateTime<Utc>) -> MessageKey {
+    let mut key_bytes = MessageKey::from_slice(message_id.as_bytes());
+    key_bytes.extend_from_slice(&timestamp.timestamp_millis().to_be_bytes());
+    key_bytes
+}
+
+// Domain-separated, since other hashes of message_ids leave the server
+pub fn email_key(message_id: &MessageId) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"kwn-email\n");
+    hasher.update(message_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Separated from email_key so the two tables can't be joined on their keys
+pub fn phone_key(message_id: &MessageId) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"kwn-sms\n");
+    hasher.update(message_id.as_bytes());
+    hasher.finalize().to_vec()
+}
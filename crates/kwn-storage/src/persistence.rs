@@ -0,0 +1,176 @@
+//! When fjall's journal is synced to disk.
+//!
+//! Writes reach the journal's in-process buffer and are acknowledged before
+//! they are durable, so a crash loses whatever was written since the last
+//! sync. A [`PersistPolicy`] bounds that window: the store counts mutations and
+//! syncs once `max_pending` have accumulated, the server's timer syncs whatever
+//! is pending every `interval`, and a request marked durable syncs before it is
+//! answered. Each sync covers every write before it, so concurrent writers
+//! share one fsync.
+
+use metrics::{counter, histogram};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How eagerly mutations are synced.
+#[derive(Clone, Copy, Debug)]
+pub struct PersistPolicy {
+    /// Sync as soon as this many mutations are pending; 0 leaves it to the timer.
+    pub max_pending: u64,
+    /// How often the server's timer syncs pending mutations.
+    pub interval: Duration,
+}
+
+impl Default for PersistPolicy {
+    fn default() -> Self {
+        Self {
+            max_pending: 1000,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What asked for a sync, for metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PersistReason {
+    Interval,
+    Threshold,
+    Durable,
+    Shutdown,
+}
+
+impl PersistReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            PersistReason::Interval => "interval",
+            PersistReason::Threshold => "threshold",
+            PersistReason::Durable => "durable",
+            PersistReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+// Counts mutations since the last sync.
+pub(crate) struct PersistPacer {
+    policy: PersistPolicy,
+    pending: AtomicU64,
+    // Held across the count and the sync, so a caller finding nothing pending
+    // knows the sync that took its writes has finished.
+    syncing: Mutex<()>,
+}
+
+impl PersistPacer {
+    pub(crate) fn new(policy: PersistPolicy) -> Self {
+        Self {
+            policy,
+            pending: AtomicU64::new(0),
+            syncing: Mutex::new(()),
+        }
+    }
+
+    /// Counts `mutations` already written, returning whether a sync is due.
+    pub(crate) fn record(&self, mutations: u64) -> bool {
+        let pending = self.pending.fetch_add(mutations, Ordering::Relaxed) + mutations;
+        self.policy.max_pending > 0 && pending >= self.policy.max_pending
+    }
+
+    /// Runs `sync` if anything is pending, returning how many mutations it
+    /// covered. The count is taken before syncing, so writes racing the sync
+    /// stay pending for the next one. Syncs run one at a time: a caller that
+    /// arrives mid-sync waits for it, so its earlier writes are on disk by the
+    /// time it returns.
+    pub(crate) fn persist<E>(
+        &self,
+        reason: PersistReason,
+        sync: impl FnOnce() -> Result<(), E>,
+    ) -> Result<u64, E> {
+        let _syncing = self
+            .syncing
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let batch = self.pending.swap(0, Ordering::Relaxed);
+        if batch == 0 {
+            return Ok(0);
+        }
+        let started = Instant::now();
+        if let Err(e) = sync() {
+            // Still unsynced, so the next attempt covers them
+            self.pending.fetch_add(batch, Ordering::Relaxed);
+            return Err(e);
+        }
+        histogram!("kwn_storage_persist_seconds").record(started.elapsed().as_secs_f64());
+        histogram!("kwn_storage_persist_batch_mutations").record(batch as f64);
+        counter!("kwn_storage_persists_total", "reason" => reason.as_str()).increment(1);
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+    };
+
+    #[test]
+    fn persist_waits_for_a_sync_already_in_flight() {
+        let pacer = Arc::new(PersistPacer::new(PersistPolicy::default()));
+        pacer.record(1);
+
+        // The timer's sync takes the first write and stalls inside the fsync
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let timer = {
+            let pacer = pacer.clone();
+            thread::spawn(move || {
+                pacer.persist(PersistReason::Interval, || {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok::<_, ()>(())
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+
+        // A durable write lands now and must not return before a sync covers it
+        pacer.record(1);
+        let (done_tx, done_rx) = mpsc::channel();
+        let durable = {
+            let pacer = pacer.clone();
+            thread::spawn(move || {
+                let synced = pacer.persist(PersistReason::Durable, || Ok::<_, ()>(()));
+                done_tx.send(()).unwrap();
+                synced
+            })
+        };
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        release_tx.send(()).unwrap();
+        assert_eq!(timer.join().unwrap(), Ok(1));
+        assert_eq!(durable.join().unwrap(), Ok(1));
+    }
+
+    #[test]
+    fn failed_sync_leaves_mutations_pending() {
+        let pacer = PersistPacer::new(PersistPolicy::default());
+        pacer.record(3);
+        assert_eq!(
+            pacer.persist(PersistReason::Interval, || Err("disk")),
+            Err("disk")
+        );
+        assert_eq!(
+            pacer.persist(PersistReason::Interval, || Ok::<_, ()>(())),
+            Ok(3)
+        );
+        assert_eq!(
+            pacer.persist(PersistReason::Interval, || Ok::<_, ()>(())),
+            Ok(0)
+        );
+    }
+}
@@ -0,0 +1,527 @@
+//! Headless sender/recipient for the message relay.
+//!
+//! Run after a deploy as a smoke test, or point two instances at one mailbox
+//! to watch traffic by hand. Configured through the environment:
+//!
+//! - `RELAY_URL`: server to talk to, default `http://localhost:3000`.
+//! - `BOT_MODE`: `smoke` (default) puts a message to a fresh mailbox, long-polls
+//!   it back, acks it and checks the mailbox is empty again; exits non-zero on
+//!   any failure. `send` puts each stdin line to `BOT_MESSAGE_ID`. `receive`
+//!   long-polls `BOT_MESSAGE_ID`, printing and acking everything that arrives.
+//!   `latency` parks `BOT_WAITERS` (default 1000) long polls on idle mailboxes,
+//!   then over `BOT_ROUNDS` (default 50) rounds times each put until the poll
+//!   waiting on it returns, and prints the percentiles. Waiters are started at
+//!   under the server's per-IP rate limit, so a thousand take about ten seconds.
+//!   `replay` reads a trace from `/admin/trace-capture` at `BOT_TRACE_FILE` and
+//!   issues its puts, polls and acks with the same timing and overlap, sped up
+//!   `BOT_REPLAY_SPEED` times (default 1), against fresh mailboxes standing in
+//!   for the traced ones. It then reports puts never delivered and messages
+//!   returned again after their ack completed, and exits non-zero if there are
+//!   any. Dense traces need a server with a raised or per-mailbox rate limit.
+//! - `BOT_PUSH_ENDPOINT`, `BOT_PUSH_P256DH`, `BOT_PUSH_AUTH`: when all are set,
+//!   the first poll registers this push subscription for the mailbox.
+//!
+//! Servers with `PRIVATE_TOKEN_KEY_FILE` set require a token on every put,
+//! which this bot does not obtain, so smoke tests need a server without it.
+//!
+//! There is no Rust client SDK yet, so requests are built straight from the
+//! `kwn-protocol` wire types.
+
+use kwn_protocol::{
+    AckMessagesPayload, FoundMessage, GetMessagesRequest, GetMessagesResponse, MessageId,
+    PushSubscriptionInfo, PutMessageRequest, ServerInfo, SubscribeRequest, SubscriptionKeysInfo,
+    Trace,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io::BufRead,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+type BotResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const SMOKE_POLL_TIMEOUT_MS: u64 = 10_000;
+const RECEIVE_POLL_TIMEOUT_MS: u64 = 300_000;
+// Keeps waiter start-up under the default 100 requests per second per IP.
+const WAITER_START_INTERVAL: Duration = Duration::from_millis(12);
+// Time for a round's poll to reach the server and park before the put.
+const LATENCY_SETTLE: Duration = Duration::from_millis(50);
+
+struct Relay {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl Relay {
+    fn new(base_url: String) -> BotResult<Self> {
+        let http = reqwest::Client::builder()
+            // Longer than any poll we ask for, so the server always answers first
+            .timeout(Duration::from_millis(RECEIVE_POLL_TIMEOUT_MS + 30_000))
+            .build()?;
+        Ok(Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    async fn info(&self) -> BotResult<ServerInfo> {
+        let response = self
+            .http
+            .get(format!("{}/api/info", self.base_url))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    async fn put(&self, message_id: &MessageId, message: &str) -> BotResult<()> {
+        self.http
+            .post(format!("{}/api/put-message", self.base_url))
+            .json(&PutMessageRequest {
+                message_id: message_id.clone(),
+                message: message.to_string(),
+                idempotency_key: None,
+                deliver_at: None,
+                ttl_seconds: None,
+                supersedes: None,
+                receipt_channel_id: None,
+                durable: false,
+                retain: false,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        message_id: &MessageId,
+        push_subscription: PushSubscriptionInfo,
+    ) -> BotResult<()> {
+        self.http
+            .post(format!("{}/api/subscribe", self.base_url))
+            .json(&SubscribeRequest {
+                message_ids: vec![message_id.clone()],
+                push_subscription,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn poll(
+        &self,
+        message_id: &MessageId,
+        timeout_ms: u64,
+    ) -> BotResult<GetMessagesResponse> {
+        self.poll_many(std::slice::from_ref(message_id), timeout_ms)
+            .await
+    }
+
+    async fn poll_many(
+        &self,
+        message_ids: &[MessageId],
+        timeout_ms: u64,
+    ) -> BotResult<GetMessagesResponse> {
+        let request = GetMessagesRequest {
+            message_ids: message_ids.to_vec(),
+            timeout_ms: Some(timeout_ms),
+            push_subscription: None,
+            sort: Default::default(),
+            keepalive: false,
+            poll_session: None,
+            continue_pending: false,
+            continuation: None,
+            page_size: None,
+            cursors: Default::default(),
+            capabilities: Vec::new(),
+            since: None,
+            until: None,
+            mode: Default::default(),
+            lease_secs: None,
+            consumer: None,
+            message_id_patterns: Vec::new(),
+            retained: false,
+            signals: false,
+            retained_seen: Default::default(),
+        };
+        let response = self
+            .http
+            .post(format!("{}/api/get-messages", self.base_url))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    async fn ack(&self, messages: &[FoundMessage]) -> BotResult<()> {
+        self.http
+            .post(format!("{}/api/ack-messages", self.base_url))
+            .json(&AckMessagesPayload {
+                acks: messages.iter().map(FoundMessage::ack_token).collect(),
+                consumer: None,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn push_subscription_from_env() -> Option<PushSubscriptionInfo> {
+    Some(PushSubscriptionInfo {
+        endpoint: std::env::var("BOT_PUSH_ENDPOINT").ok()?,
+        keys: SubscriptionKeysInfo {
+            p256dh: std::env::var("BOT_PUSH_P256DH").ok()?,
+            auth: std::env::var("BOT_PUSH_AUTH").ok()?,
+        },
+        locale: std::env::var("BOT_PUSH_LOCALE").ok(),
+        vapid_key_id: std::env::var("BOT_PUSH_VAPID_KEY_ID").ok(),
+    })
+}
+
+fn random_message_id() -> BotResult<MessageId> {
+    MessageId::parse(hex::encode(rand::random::<[u8; 32]>())).map_err(|issue| issue.message.into())
+}
+
+fn count_from_env(name: &str, default: usize) -> BotResult<usize> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{} must be a number", name).into()),
+        Err(_) => Ok(default),
+    }
+}
+
+fn message_id_from_env() -> BotResult<MessageId> {
+    let value = std::env::var("BOT_MESSAGE_ID").map_err(|_| "BOT_MESSAGE_ID not set")?;
+    MessageId::parse(value).map_err(|issue| issue.message.into())
+}
+
+async fn smoke(relay: &Relay) -> BotResult<()> {
+    let info = relay.info().await?;
+    println!("server capabilities: {}", info.capabilities.join(", "));
+
+    let message_id = random_message_id()?;
+    let body = format!("relay-bot smoke test {}", chrono::Utc::now().to_rfc3339());
+
+    if let Some(push_subscription) = push_subscription_from_env() {
+        relay.subscribe(&message_id, push_subscription).await?;
+        println!("subscribe ok");
+    }
+
+    // Start waiting before the put so the long-poll wakeup path is exercised
+    let waiting = {
+        let relay = Relay::new(relay.base_url.clone())?;
+        let message_id = message_id.clone();
+        tokio::spawn(async move { relay.poll(&message_id, SMOKE_POLL_TIMEOUT_MS).await })
+    };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    relay.put(&message_id, &body).await?;
+    println!("put ok");
+
+    let response = waiting.await??;
+    let [message] = response.results.as_slice() else {
+        return Err(format!(
+            "expected 1 message, long poll got {}",
+            response.results.len()
+        )
+        .into());
+    };
+    if message.message != body {
+        return Err("long poll returned a different message body".into());
+    }
+    println!("long poll ok");
+
+    relay.ack(&response.results).await?;
+    let after_ack = relay.poll(&message_id, 0).await?;
+    if !after_ack.results.is_empty() {
+        return Err(format!("{} message(s) left after ack", after_ack.results.len()).into());
+    }
+    println!("ack ok");
+    Ok(())
+}
+
+async fn latency(relay: &Relay) -> BotResult<()> {
+    let waiters = count_from_env("BOT_WAITERS", 1000)?;
+    let rounds = count_from_env("BOT_ROUNDS", 50)?.max(1);
+
+    // Background load: long polls on mailboxes nobody writes to, re-armed
+    // whenever one times out
+    for _ in 0..waiters {
+        let relay = Relay::new(relay.base_url.clone())?;
+        let message_id = random_message_id()?;
+        tokio::spawn(async move {
+            while relay
+                .poll(&message_id, RECEIVE_POLL_TIMEOUT_MS)
+                .await
+                .is_ok()
+            {}
+        });
+        tokio::time::sleep(WAITER_START_INTERVAL).await;
+    }
+    println!("{} waiters parked", waiters);
+
+    let mut samples = Vec::with_capacity(rounds);
+    for _ in 0..rounds {
+        let message_id = random_message_id()?;
+        let waiting = {
+            let relay = Relay::new(relay.base_url.clone())?;
+            let message_id = message_id.clone();
+            tokio::spawn(async move {
+                let response = relay.poll(&message_id, SMOKE_POLL_TIMEOUT_MS).await;
+                // Stamped here, so the put's own response time isn't counted
+                (std::time::Instant::now(), response)
+            })
+        };
+        tokio::time::sleep(LATENCY_SETTLE).await;
+        let started = std::time::Instant::now();
+        relay.put(&message_id, "relay-bot latency probe").await?;
+        let (woken, response) = waiting.await?;
+        let response = response?;
+        if response.results.is_empty() {
+            return Err("poll timed out without seeing the put".into());
+        }
+        samples.push(woken.duration_since(started));
+        relay.ack(&response.results).await?;
+    }
+
+    samples.sort();
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    println!(
+        "put to wakeup over {} rounds: p50 {:?}, p99 {:?}, max {:?}",
+        rounds,
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+    Ok(())
+}
+
+// What a replay has seen so far. Every replayed put has a distinct body, so a
+// body names one put.
+#[derive(Default)]
+struct ReplayLog {
+    put: HashSet<String>,
+    seen: HashSet<String>,
+    // Returned by a poll and not acked yet, by mailbox
+    unacked: HashMap<MessageId, Vec<FoundMessage>>,
+    // When each ack completed
+    acked: HashMap<String, Instant>,
+    returned_after_ack: usize,
+    failed: usize,
+}
+
+async fn replay_event(
+    relay: Arc<Relay>,
+    log: Arc<Mutex<ReplayLog>>,
+    route: String,
+    message_ids: Vec<MessageId>,
+    body: String,
+    timeout_ms: u64,
+) -> BotResult<()> {
+    match route.as_str() {
+        "/api/put-message" => {
+            let Some(message_id) = message_ids.first() else {
+                return Ok(());
+            };
+            relay.put(message_id, &body).await?;
+            log.lock().unwrap().put.insert(body);
+        }
+        "/api/get-messages" | "/api/messages" => {
+            let started = Instant::now();
+            let response = relay.poll_many(&message_ids, timeout_ms).await?;
+            let mut log = log.lock().unwrap();
+            for message in response.results {
+                if log
+                    .acked
+                    .get(&message.message)
+                    .is_some_and(|acked| *acked < started)
+                {
+                    log.returned_after_ack += 1;
+                }
+                log.seen.insert(message.message.clone());
+                let unacked = log.unacked.entry(message.message_id.clone()).or_default();
+                if !unacked.iter().any(|m| m.message == message.message) {
+                    unacked.push(message);
+                }
+            }
+        }
+        "/api/ack-messages" => {
+            let messages: Vec<FoundMessage> = {
+                let mut log = log.lock().unwrap();
+                message_ids
+                    .iter()
+                    .filter_map(|message_id| log.unacked.remove(message_id))
+                    .flatten()
+                    .collect()
+            };
+            if messages.is_empty() {
+                return Ok(());
+            }
+            relay.ack(&messages).await?;
+            let mut log = log.lock().unwrap();
+            let now = Instant::now();
+            for message in messages {
+                log.acked.insert(message.message, now);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+async fn replay(relay: Relay) -> BotResult<()> {
+    let path = std::env::var("BOT_TRACE_FILE").map_err(|_| "BOT_TRACE_FILE not set")?;
+    let trace: Trace = serde_json::from_slice(&std::fs::read(path)?)?;
+    let speed: f64 = match std::env::var("BOT_REPLAY_SPEED") {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .filter(|speed: &f64| *speed > 0.0)
+            .ok_or("BOT_REPLAY_SPEED must be a positive number")?,
+        Err(_) => 1.0,
+    };
+    let mailbox_count = trace
+        .events
+        .iter()
+        .flat_map(|event| event.mailboxes.iter())
+        .max()
+        .map_or(0, |&highest| highest as usize + 1);
+    let mailboxes = (0..mailbox_count)
+        .map(|_| random_message_id())
+        .collect::<BotResult<Vec<_>>>()?;
+    println!(
+        "replaying {} requests over {} mailboxes at {}x",
+        trace.events.len(),
+        mailbox_count,
+        speed
+    );
+
+    let relay = Arc::new(relay);
+    let log = Arc::new(Mutex::new(ReplayLog::default()));
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(trace.events.len());
+    let mut skipped = 0;
+    for (n, event) in trace.events.into_iter().enumerate() {
+        if !matches!(
+            event.route.as_str(),
+            "/api/put-message" | "/api/get-messages" | "/api/messages" | "/api/ack-messages"
+        ) {
+            skipped += 1;
+            continue;
+        }
+        let due = started + Duration::from_secs_f64(event.offset_ms as f64 / 1000.0 / speed);
+        tokio::time::sleep_until(due.into()).await;
+        let message_ids = event
+            .mailboxes
+            .iter()
+            .map(|&mailbox| mailboxes[mailbox as usize].clone())
+            .collect();
+        // Distinct and as long as the traced message, so sizes match too
+        let mut body = format!("replay {}", n);
+        if let Some(len) = event.message_len {
+            while body.len() < len {
+                body.push('.');
+            }
+        }
+        let timeout_ms = (event.timeout_ms.unwrap_or(0) as f64 / speed) as u64;
+        let relay = relay.clone();
+        let log = log.clone();
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = replay_event(
+                relay,
+                log.clone(),
+                event.route,
+                message_ids,
+                body,
+                timeout_ms,
+            )
+            .await
+            {
+                eprintln!("replayed request failed: {}", e);
+                log.lock().unwrap().failed += 1;
+            }
+        }));
+    }
+    for task in tasks {
+        task.await?;
+    }
+
+    // Whatever is still stored was neither lost nor delivered yet
+    let mut stored = HashSet::new();
+    for message_id in &mailboxes {
+        for message in relay.poll(message_id, 0).await?.results {
+            stored.insert(message.message);
+        }
+    }
+    let log = log.lock().unwrap();
+    let lost = log
+        .put
+        .iter()
+        .filter(|body| !log.seen.contains(*body) && !stored.contains(*body))
+        .count();
+    println!(
+        "{} puts, {} delivered, {} still stored, {} lost, {} returned after ack, {} failed, {} skipped",
+        log.put.len(),
+        log.seen.len(),
+        stored.len(),
+        lost,
+        log.returned_after_ack,
+        log.failed,
+        skipped
+    );
+    if lost > 0 || log.returned_after_ack > 0 {
+        return Err("replay found delivery errors".into());
+    }
+    Ok(())
+}
+
+async fn send(relay: &Relay) -> BotResult<()> {
+    let message_id = message_id_from_env()?;
+    for line in std::io::stdin().lock().lines() {
+        relay.put(&message_id, &line?).await?;
+    }
+    Ok(())
+}
+
+async fn receive(relay: &Relay) -> BotResult<()> {
+    let message_id = message_id_from_env()?;
+    if let Some(push_subscription) = push_subscription_from_env() {
+        relay.subscribe(&message_id, push_subscription).await?;
+    }
+    loop {
+        let response = relay.poll(&message_id, RECEIVE_POLL_TIMEOUT_MS).await?;
+        if response.resubscribe_required {
+            eprintln!("server reports the push subscription is gone");
+        }
+        for message in &response.results {
+            println!("{} {}", message.timestamp.to_rfc3339(), message.message);
+        }
+        if !response.results.is_empty() {
+            relay.ack(&response.results).await?;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> BotResult<()> {
+    dotenvy::dotenv().ok();
+    let relay = Relay::new(
+        std::env::var("RELAY_URL").unwrap_or_else(|_| "http://localhost:3000".to_string()),
+    )?;
+    let mode = std::env::var("BOT_MODE").unwrap_or_else(|_| "smoke".to_string());
+    match mode.as_str() {
+        "smoke" => smoke(&relay).await,
+        "send" => send(&relay).await,
+        "receive" => receive(&relay).await,
+        "latency" => latency(&relay).await,
+        "replay" => replay(relay).await,
+        other => Err(format!("unknown BOT_MODE {}", other).into()),
+    }
+}
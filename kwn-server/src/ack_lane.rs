@@ -0,0 +1,68 @@
+//! Dedicated threads for ack transactions.
+//!
+//! Acks are what shrink storage, so under overload they must not queue behind
+//! puts and polls in tokio's shared blocking pool. [`AckLane`] runs them on its
+//! own few threads fed by a bounded queue; when the queue is full callers wait
+//! for room instead of spilling back into the shared pool.
+
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+const QUEUE_CAPACITY: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, thiserror::Error)]
+#[error("ack lane job did not complete")]
+pub struct AckLaneError;
+
+pub struct AckLane {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AckLane {
+    /// Starts `threads` worker threads, at least one.
+    pub fn new(threads: usize) -> std::io::Result<Self> {
+        let (jobs, rx) = mpsc::channel::<Job>(QUEUE_CAPACITY);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("ack-lane-{}", i))
+                .spawn(move || loop {
+                    // Only the idle worker holding the lock waits on the queue
+                    let job = rx.lock().unwrap().blocking_recv();
+                    match job {
+                        Some(job) => {
+                            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                error!("Ack lane job panicked");
+                            }
+                        }
+                        None => return,
+                    }
+                })?;
+        }
+        Ok(Self { jobs })
+    }
+
+    /// Runs blocking `job` on the lane and returns its result.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, AckLaneError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move || {
+                let _ = tx.send(job());
+            }))
+            .await
+            .map_err(|_| AckLaneError)?;
+        // A panicking job drops `tx` without sending
+        rx.await.map_err(|_| AckLaneError)
+    }
+}
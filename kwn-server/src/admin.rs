@@ -0,0 +1,468 @@
+//! Operator endpoints under `/admin`, mounted only when `ADMIN_TOKEN` is set.
+//! Every request must carry `Authorization: Bearer <ADMIN_TOKEN>`.
+
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{from_fn_with_state, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use kwn_protocol::{
+    AnalyticsBucket, AnalyticsPeriod, IssueTokensRequest, IssueTokensResponse, MessageId,
+    PutMessageRequest, SystemHint, Trace,
+};
+use kwn_push::VapidKeyInfo;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, warn};
+
+use crate::{
+    debug_capture::CaptureEvent,
+    error::AppError,
+    handlers::after_put_many,
+    hints::system_channel,
+    outbox::DeadLetterView,
+    push_chaos::{ActiveFault, SimulatedFault, SimulatedSend},
+    state::SharedState,
+};
+
+pub fn router(admin_token: String) -> Router<SharedState> {
+    Router::new()
+        .route("/admin/compact", post(compact_handler))
+        .route("/admin/analytics", get(analytics_handler))
+        .route("/admin/metrics", get(metrics_handler))
+        .route("/admin/debug-capture", post(start_capture_handler))
+        .route(
+            "/admin/trace-capture",
+            post(start_trace_handler).delete(stop_trace_handler),
+        )
+        .route("/admin/tokens/issue", post(issue_tokens_handler))
+        .route("/admin/hints", post(publish_hint_handler))
+        .route("/admin/push-dead-letters", get(dead_letters_handler))
+        .route(
+            "/admin/push-dead-letters/{id}",
+            delete(discard_dead_letter_handler),
+        )
+        .route(
+            "/admin/push-dead-letters/{id}/retry",
+            post(retry_dead_letter_handler),
+        )
+        .route("/admin/vapid-keys", get(vapid_keys_handler))
+        .route(
+            "/admin/vapid-keys/{id}/retire",
+            post(retire_vapid_key_handler),
+        )
+        .route(
+            "/admin/log-filter",
+            get(log_filter_handler).put(set_log_filter_handler),
+        )
+        .route(
+            "/admin/push-chaos",
+            get(push_chaos_handler).post(start_push_fault_handler),
+        )
+        .route(
+            "/admin/push-chaos/{provider}",
+            delete(stop_push_fault_handler),
+        )
+        .route(
+            "/admin/debug-capture/{mailbox_hash}",
+            get(capture_events_handler).delete(stop_capture_handler),
+        )
+        .layer(from_fn_with_state(
+            Arc::<str>::from(admin_token),
+            require_admin_token,
+        ))
+}
+
+// Compares in constant time so response timing doesn't leak the token prefix.
+fn token_matches(expected: &[u8], provided: &[u8]) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .iter()
+            .zip(provided)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn require_admin_token(
+    State(admin_token): State<Arc<str>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if token_matches(admin_token.as_bytes(), token.as_bytes()) => {
+            next.run(req).await
+        }
+        _ => {
+            warn!("Rejected admin request to {}", req.uri().path());
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct CompactResponse {
+    started: bool, // false when a compaction was already in progress
+}
+
+async fn compact_handler(
+    State(state): State<SharedState>,
+) -> Result<Json<CompactResponse>, AppError> {
+    let messages = state.messages.clone();
+    match tokio::task::spawn_blocking(move || messages.compact()).await {
+        Ok(Ok(started)) => Ok(Json(CompactResponse { started })),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute compaction task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during compaction: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+async fn metrics_handler(State(state): State<SharedState>) -> String {
+    state.metrics.render()
+}
+
+#[derive(Deserialize, Debug)]
+struct AnalyticsQuery {
+    period: AnalyticsPeriod,
+    from: Option<DateTime<Utc>>, // Defaults to seven days before `to`
+    to: Option<DateTime<Utc>>,   // Defaults to now
+}
+
+async fn analytics_handler(
+    State(state): State<SharedState>,
+    Query(query): Query<AnalyticsQuery>,
+) -> Result<Json<Vec<AnalyticsBucket>>, AppError> {
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::days(7));
+    let analytics = state.analytics.clone();
+    match tokio::task::spawn_blocking(move || analytics.query(query.period, from, to)).await {
+        Ok(Ok(buckets)) => Ok(Json(buckets)),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute analytics query task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during analytics query: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StartCaptureRequest {
+    mailbox_hash: String, // Hex SHA-256 of the message_id
+    duration_secs: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct StartCaptureResponse {
+    expires_at: DateTime<Utc>,
+}
+
+async fn start_capture_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<StartCaptureRequest>,
+) -> Result<Json<StartCaptureResponse>, AppError> {
+    let mailbox_hash = payload.mailbox_hash.to_ascii_lowercase();
+    if mailbox_hash.len() != 64 || !mailbox_hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(AppError::InvalidRequest(
+            "mailbox_hash must be a hex SHA-256 digest".to_string(),
+        ));
+    }
+    let duration = std::time::Duration::from_secs(payload.duration_secs);
+    let expires_at = state.debug.start(mailbox_hash, duration);
+    Ok(Json(StartCaptureResponse { expires_at }))
+}
+
+async fn capture_events_handler(
+    State(state): State<SharedState>,
+    Path(mailbox_hash): Path<String>,
+) -> Result<Json<Vec<CaptureEvent>>, StatusCode> {
+    state
+        .debug
+        .events(&mailbox_hash.to_ascii_lowercase())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn stop_capture_handler(
+    State(state): State<SharedState>,
+    Path(mailbox_hash): Path<String>,
+) -> Result<Json<Vec<CaptureEvent>>, StatusCode> {
+    state
+        .debug
+        .stop(&mailbox_hash.to_ascii_lowercase())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize, Debug)]
+struct StartTraceRequest {
+    duration_secs: u64,
+}
+
+// Records every mailbox's requests, so unlike debug-capture it takes no hash.
+async fn start_trace_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<StartTraceRequest>,
+) -> Json<StartCaptureResponse> {
+    let duration = std::time::Duration::from_secs(payload.duration_secs);
+    Json(StartCaptureResponse {
+        expires_at: state.traces.start(duration),
+    })
+}
+
+async fn stop_trace_handler(State(state): State<SharedState>) -> Result<Json<Trace>, StatusCode> {
+    state.traces.stop().map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// Called by the operator's attester once it has decided a client deserves tokens.
+async fn issue_tokens_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<IssueTokensRequest>,
+) -> Result<Json<IssueTokensResponse>, AppError> {
+    let Some(tokens) = state.tokens.as_ref() else {
+        return Err(AppError::InvalidRequest(
+            "private tokens are not enabled".to_string(),
+        ));
+    };
+    let blind_signatures = tokens
+        .issue(&payload.blinded_messages)
+        .map_err(AppError::InvalidRequest)?;
+    Ok(Json(IssueTokensResponse { blind_signatures }))
+}
+
+// How long a hint with no end waits for mailboxes that never poll.
+const DEFAULT_HINT_TTL_DAYS: i64 = 30;
+
+#[derive(Deserialize, Debug)]
+struct PublishHintRequest {
+    hint: SystemHint,
+    // Defaults to every mailbox with a push subscription
+    message_ids: Option<Vec<MessageId>>,
+}
+
+#[derive(Serialize, Debug)]
+struct PublishHintResponse {
+    delivered: usize,
+}
+
+// Signs the hint and puts it to the system channel of each mailbox. It expires
+// at `ends_at`, so mailboxes that poll after the window never see it.
+async fn publish_hint_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<PublishHintRequest>,
+) -> Result<Json<PublishHintResponse>, AppError> {
+    let now = Utc::now();
+    let expires_at = payload
+        .hint
+        .ends_at
+        .unwrap_or(now + Duration::days(DEFAULT_HINT_TTL_DAYS));
+    if expires_at <= now {
+        return Err(AppError::InvalidRequest(
+            "ends_at must be in the future".to_string(),
+        ));
+    }
+    let body = serde_json::to_string(&state.hints.sign(&payload.hint)?)?;
+    let message_len = body.len();
+    let messages = state.messages.clone();
+    let subscriptions = state.subscriptions.clone();
+    let stored = tokio::task::spawn_blocking(move || -> kwn_storage::Result<Vec<MessageId>> {
+        let mailboxes = match payload.message_ids {
+            Some(message_ids) => message_ids,
+            None => subscriptions.subscribed_ids()?,
+        };
+        let channels: Vec<MessageId> = mailboxes.iter().map(system_channel).collect();
+        let entries: Vec<PutMessageRequest> = channels
+            .iter()
+            .map(|channel| PutMessageRequest {
+                message_id: channel.clone(),
+                message: body.clone(),
+                idempotency_key: None,
+                deliver_at: None,
+                ttl_seconds: Some((expires_at - now).num_seconds().max(1) as u64),
+                supersedes: None,
+                receipt_channel_id: None,
+                durable: false,
+                retain: false,
+            })
+            .collect();
+        messages.put_batch(&entries, now)?;
+        Ok(channels)
+    })
+    .await;
+    match stored {
+        Ok(Ok(channels)) => {
+            let delivered = channels.len();
+            after_put_many(&state, channels, message_len);
+            Ok(Json(PublishHintResponse { delivered }))
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute hint publish task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during hint publish: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StartPushFaultRequest {
+    provider: String, // Push service host such as "fcm.googleapis.com", or "*"
+    fault: SimulatedFault,
+    duration_secs: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct StartPushFaultResponse {
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+struct PushChaosResponse {
+    faults: Vec<ActiveFault>,
+    simulated_sends: Vec<SimulatedSend>,
+}
+
+async fn start_push_fault_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<StartPushFaultRequest>,
+) -> Result<Json<StartPushFaultResponse>, AppError> {
+    let provider = payload.provider.to_ascii_lowercase();
+    if provider.is_empty() || provider.contains('/') {
+        return Err(AppError::InvalidRequest(
+            "provider must be a push service host or \"*\"".to_string(),
+        ));
+    }
+    let duration = std::time::Duration::from_secs(payload.duration_secs);
+    let expires_at = state.push_chaos.start(provider, payload.fault, duration);
+    Ok(Json(StartPushFaultResponse { expires_at }))
+}
+
+// Far more than an operator reads; the rest show up once these are resolved
+const MAX_LISTED_DEAD_LETTERS: usize = 1000;
+
+async fn dead_letters_handler(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<DeadLetterView>>, AppError> {
+    let outbox = state.outbox.clone();
+    match tokio::task::spawn_blocking(move || outbox.dead_letters(MAX_LISTED_DEAD_LETTERS)).await {
+        Ok(Ok(letters)) => Ok(Json(letters)),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute dead letter listing task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during dead letter listing: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+async fn retry_dead_letter_handler(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    resolve_dead_letter(state, id, true).await
+}
+
+async fn discard_dead_letter_handler(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    resolve_dead_letter(state, id, false).await
+}
+
+async fn resolve_dead_letter(
+    state: SharedState,
+    id: String,
+    retry: bool,
+) -> Result<StatusCode, AppError> {
+    let outbox = state.outbox.clone();
+    match tokio::task::spawn_blocking(move || outbox.resolve_dead_letter(&id, retry)).await {
+        Ok(Ok(true)) => Ok(StatusCode::NO_CONTENT),
+        Ok(Ok(false)) => Ok(StatusCode::NOT_FOUND),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute dead letter task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error resolving a dead letter: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+async fn vapid_keys_handler(State(state): State<SharedState>) -> Json<Vec<VapidKeyInfo>> {
+    Json(state.web_push.keys())
+}
+
+async fn retire_vapid_key_handler(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    match state.web_push.retire(&id) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Ok(StatusCode::NOT_FOUND),
+        Err(problem) => Err(AppError::InvalidRequest(problem)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LogFilterBody {
+    filter: String, // RUST_LOG syntax, e.g. "info,kwn_server::push=debug"
+}
+
+async fn log_filter_handler(State(state): State<SharedState>) -> Json<LogFilterBody> {
+    Json(LogFilterBody {
+        filter: state.log_filter.current(),
+    })
+}
+
+async fn set_log_filter_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<LogFilterBody>,
+) -> Result<Json<LogFilterBody>, AppError> {
+    state
+        .log_filter
+        .set(&payload.filter)
+        .map_err(|e| AppError::InvalidRequest(format!("Bad log filter: {}", e)))?;
+    warn!("Log filter set to {}", payload.filter);
+    Ok(Json(LogFilterBody {
+        filter: state.log_filter.current(),
+    }))
+}
+
+async fn push_chaos_handler(State(state): State<SharedState>) -> Json<PushChaosResponse> {
+    Json(PushChaosResponse {
+        faults: state.push_chaos.faults(),
+        simulated_sends: state.push_chaos.simulated_sends(),
+    })
+}
+
+async fn stop_push_fault_handler(
+    State(state): State<SharedState>,
+    Path(provider): Path<String>,
+) -> Result<Json<ActiveFault>, StatusCode> {
+    state
+        .push_chaos
+        .stop(&provider.to_ascii_lowercase())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
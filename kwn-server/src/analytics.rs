@@ -0,0 +1,151 @@
+//! Rolling delivery totals for capacity planning.
+//!
+//! Counters accumulate in memory for the current hour and day and are written
+//! to the `analytics` partition once their period has ended. Mailboxes are
+//! counted by hash, so the aggregates never hold message ids.
+
+use chrono::{DateTime, Duration, Utc};
+use kwn_protocol::{AnalyticsBucket, AnalyticsPeriod, MessageId};
+use kwn_storage::{AnalyticsStore, StorageError};
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+struct Window {
+    period: AnalyticsPeriod,
+    start: DateTime<Utc>,
+    messages: u64,
+    bytes: u64,
+    push_sends: u64,
+    mailboxes: HashSet<u64>,
+}
+
+impl Window {
+    fn new(period: AnalyticsPeriod, now: DateTime<Utc>) -> Self {
+        Self {
+            period,
+            start: period_start(period, now),
+            messages: 0,
+            bytes: 0,
+            push_sends: 0,
+            mailboxes: HashSet::new(),
+        }
+    }
+
+    fn bucket(&self) -> AnalyticsBucket {
+        AnalyticsBucket {
+            period: self.period,
+            start: self.start,
+            messages: self.messages,
+            bytes: self.bytes,
+            push_sends: self.push_sends,
+            active_mailboxes: self.mailboxes.len() as u64,
+        }
+    }
+}
+
+fn period_start(period: AnalyticsPeriod, now: DateTime<Utc>) -> DateTime<Utc> {
+    let period_secs = match period {
+        AnalyticsPeriod::Hour => 3600,
+        AnalyticsPeriod::Day => 86400,
+    };
+    let secs = now.timestamp();
+    DateTime::from_timestamp(secs - secs.rem_euclid(period_secs), 0).unwrap_or(now)
+}
+
+fn mailbox_hash(message_id: &MessageId) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct Analytics {
+    store: Arc<dyn AnalyticsStore>,
+    retention: Duration,
+    windows: Mutex<[Window; 2]>, // Current hour and current day
+}
+
+impl Analytics {
+    pub fn new(store: Arc<dyn AnalyticsStore>, retention: Duration) -> Self {
+        let now = Utc::now();
+        Self {
+            store,
+            retention,
+            windows: Mutex::new([
+                Window::new(AnalyticsPeriod::Hour, now),
+                Window::new(AnalyticsPeriod::Day, now),
+            ]),
+        }
+    }
+
+    pub fn record_put(&self, message_id: &MessageId, bytes: usize) {
+        let hash = mailbox_hash(message_id);
+        let mut windows = self.windows.lock().unwrap();
+        for window in windows.iter_mut() {
+            window.messages += 1;
+            window.bytes += bytes as u64;
+            window.mailboxes.insert(hash);
+        }
+    }
+
+    pub fn record_push(&self) {
+        let mut windows = self.windows.lock().unwrap();
+        for window in windows.iter_mut() {
+            window.push_sends += 1;
+        }
+    }
+
+    /// Persists and resets every window whose period has ended, then drops
+    /// buckets older than the retention period. Blocking.
+    pub fn flush(&self, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let finished: Vec<AnalyticsBucket> = {
+            let mut windows = self.windows.lock().unwrap();
+            windows
+                .iter_mut()
+                .filter(|window| period_start(window.period, now) != window.start)
+                .map(|window| {
+                    let bucket = window.bucket();
+                    *window = Window::new(window.period, now);
+                    bucket
+                })
+                .collect()
+        };
+        if finished.is_empty() {
+            return Ok(());
+        }
+        for bucket in &finished {
+            self.store.save_bucket(bucket)?;
+        }
+        let pruned = self.store.prune_buckets(now - self.retention)?;
+        info!(
+            "Persisted {} analytics bucket(s), pruned {}",
+            finished.len(),
+            pruned
+        );
+        Ok(())
+    }
+
+    /// Stored buckets in `from..=to` plus the in-progress one if it falls in range.
+    pub fn query(
+        &self,
+        period: AnalyticsPeriod,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<AnalyticsBucket>, StorageError> {
+        let mut buckets = self.store.buckets(period, from, to)?;
+        let current = {
+            let windows = self.windows.lock().unwrap();
+            windows
+                .iter()
+                .find(|window| window.period == period)
+                .map(Window::bucket)
+        };
+        if let Some(current) = current.filter(|b| b.start >= from && b.start <= to) {
+            buckets.push(current);
+        }
+        Ok(buckets)
+    }
+}
@@ -0,0 +1,88 @@
+//! Feature negotiation between clients and the server.
+//!
+//! `GET /api/info` lists the optional behaviours this server supports. Clients
+//! send the ones they understand in a request's `capabilities` field and the
+//! server uses the intersection, so new behaviours can roll out without
+//! breaking older clients. A request without `capabilities` predates
+//! negotiation and gets every behaviour it explicitly asks for, as before.
+
+use axum::{extract::State, Json};
+use kwn_protocol::ServerInfo;
+use metrics::counter;
+
+use crate::state::SharedState;
+
+pub const ACK_TOKENS: &str = "ack_tokens";
+pub const BATCH_PUT: &str = "batch_put";
+pub const CONSUMERS: &str = "consumers";
+pub const CONTINUATIONS: &str = "continuations";
+pub const CURSORS: &str = "cursors";
+pub const LEASES: &str = "leases";
+pub const NDJSON: &str = "ndjson";
+pub const NOTIFY_MODE: &str = "notify_mode";
+pub const PATTERNS: &str = "patterns";
+pub const RETAINED: &str = "retained";
+pub const SIGNALS: &str = "signals";
+
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    ACK_TOKENS,
+    BATCH_PUT,
+    CONSUMERS,
+    CONTINUATIONS,
+    CURSORS,
+    LEASES,
+    NDJSON,
+    NOTIFY_MODE,
+    PATTERNS,
+    RETAINED,
+    SIGNALS,
+];
+
+/// The capabilities both sides of one request support.
+pub struct Negotiated {
+    // None for clients that sent no capabilities at all
+    agreed: Option<Vec<&'static str>>,
+}
+
+impl Negotiated {
+    pub fn allows(&self, capability: &str) -> bool {
+        self.agreed
+            .as_ref()
+            .is_none_or(|agreed| agreed.contains(&capability))
+    }
+}
+
+/// Intersects `requested` with [`SERVER_CAPABILITIES`], counting each outcome.
+/// Names the server doesn't know share one metric label to bound cardinality.
+pub fn negotiate(requested: &[String]) -> Negotiated {
+    if requested.is_empty() {
+        counter!("kwn_capability_negotiations_total", "outcome" => "legacy").increment(1);
+        return Negotiated { agreed: None };
+    }
+    counter!("kwn_capability_negotiations_total", "outcome" => "negotiated").increment(1);
+    let mut agreed = Vec::new();
+    for name in requested {
+        match SERVER_CAPABILITIES.iter().find(|known| *known == name) {
+            Some(&known) => {
+                counter!("kwn_capability_requested_total", "capability" => known).increment(1);
+                agreed.push(known);
+            }
+            None => {
+                counter!("kwn_capability_requested_total", "capability" => "unknown").increment(1)
+            }
+        }
+    }
+    Negotiated {
+        agreed: Some(agreed),
+    }
+}
+
+pub async fn info_handler(State(state): State<SharedState>) -> Json<ServerInfo> {
+    let current_key = state.web_push.keys().into_iter().find(|key| key.current);
+    Json(ServerInfo {
+        capabilities: SERVER_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        base_path: state.base_path.clone(),
+        vapid_public_key: current_key.as_ref().map(|key| key.public_key.clone()),
+        vapid_key_id: current_key.map(|key| key.id),
+    })
+}
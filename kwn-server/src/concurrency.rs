@@ -0,0 +1,172 @@
+//! Per-route concurrency ceilings, so one hot endpoint can't take the capacity
+//! every other endpoint needs.
+//!
+//! `ROUTE_CONCURRENCY` lists `route=limit` pairs separated by `;`, for instance
+//! `/api/put-message=64;/api/ack-messages=32:128`. At most `limit` requests to a
+//! route run at once. Past that, up to the number after the colon (default: the
+//! limit again) wait their turn, and the rest are turned away with a `503` and a
+//! `Retry-After`, so a flood on one route is shed there instead of backing up
+//! the shared blocking pool. Routes left out are unlimited. Each limited route
+//! reports `kwn_route_in_flight`, `kwn_route_queued` and
+//! `kwn_route_shed_total`.
+
+use axum::{
+    body::Body,
+    extract::{Json, MatchedPath, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use kwn_protocol::{ErrorResponse, RetryClass};
+use metrics::{counter, gauge};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::state::SharedState;
+
+const SHED_RETRY_AFTER_SECS: u64 = 1;
+
+struct RouteLimit {
+    route: String,
+    permits: Semaphore,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+pub struct ConcurrencyLimits {
+    routes: HashMap<String, RouteLimit>,
+}
+
+impl ConcurrencyLimits {
+    /// None when no route is limited.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        match std::env::var("ROUTE_CONCURRENCY") {
+            Ok(spec) if !spec.trim().is_empty() => Self::parse(&spec).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Parses a `ROUTE_CONCURRENCY` value.
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut routes = HashMap::new();
+        for entry in spec.split(';').filter(|entry| !entry.trim().is_empty()) {
+            let (route, ceiling) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("ROUTE_CONCURRENCY entry {} is not route=limit", entry))?;
+            let route = route.trim();
+            if !route.starts_with('/') {
+                return Err(format!("ROUTE_CONCURRENCY route {} must start with /", route).into());
+            }
+            let (limit, queued) = match ceiling.split_once(':') {
+                Some((limit, queued)) => (limit, Some(queued)),
+                None => (ceiling, None),
+            };
+            let limit: usize = limit
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&limit| limit > 0)
+                .ok_or_else(|| format!("ROUTE_CONCURRENCY limit for {} must be positive", route))?;
+            let max_queued = match queued {
+                Some(queued) => queued
+                    .trim()
+                    .parse()
+                    .map_err(|e| format!("ROUTE_CONCURRENCY queue length for {}: {}", route, e))?,
+                None => limit,
+            };
+            routes.insert(
+                route.to_string(),
+                RouteLimit {
+                    route: route.to_string(),
+                    permits: Semaphore::new(limit),
+                    max_queued,
+                    queued: AtomicUsize::new(0),
+                },
+            );
+        }
+        Ok(Self { routes })
+    }
+}
+
+// Counts a request as waiting until it gets its permit or gives up.
+struct Queued<'a>(&'a RouteLimit);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+        gauge!("kwn_route_queued", "route" => self.0.route.clone()).decrement(1.0);
+    }
+}
+
+// Counts a request as running until its response is built.
+struct InFlight<'a>(&'a RouteLimit);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        gauge!("kwn_route_in_flight", "route" => self.0.route.clone()).decrement(1.0);
+    }
+}
+
+/// Holds each request to a limited route until the route has room for it.
+pub async fn limit_concurrency(
+    State(state): State<SharedState>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let limit = state.concurrency.as_ref().and_then(|limits| {
+        let path = req.extensions().get::<MatchedPath>()?.as_str();
+        let route = path.strip_prefix(state.base_path.as_str()).unwrap_or(path);
+        limits.routes.get(route)
+    });
+    let Some(limit) = limit else {
+        return next.run(req).await;
+    };
+
+    let permit = match limit.permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if limit.queued.fetch_add(1, Ordering::Relaxed) >= limit.max_queued {
+                limit.queued.fetch_sub(1, Ordering::Relaxed);
+                counter!("kwn_route_shed_total", "route" => limit.route.clone()).increment(1);
+                warn!("Shed a request to {}: its queue is full", limit.route);
+                return overloaded();
+            }
+            gauge!("kwn_route_queued", "route" => limit.route.clone()).increment(1.0);
+            let _queued = Queued(limit);
+            match limit.permits.acquire().await {
+                Ok(permit) => permit,
+                // Never closed
+                Err(_) => return overloaded(),
+            }
+        }
+    };
+    gauge!("kwn_route_in_flight", "route" => limit.route.clone()).increment(1.0);
+    let _in_flight = InFlight(limit);
+    let response = next.run(req).await;
+    drop(permit);
+    response
+}
+
+fn overloaded() -> Response {
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(ErrorResponse {
+            message: "This endpoint is busy.".to_string(),
+            error_code: "OVERLOADED".to_string(),
+            retry: RetryClass::RetryAfter,
+            retry_after_secs: Some(SHED_RETRY_AFTER_SECS),
+            server_time: Some(chrono::Utc::now()),
+            skew_secs: None,
+        }),
+    )
+        .into_response();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        header::HeaderValue::from(SHED_RETRY_AFTER_SECS),
+    );
+    response
+}
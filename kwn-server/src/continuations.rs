@@ -0,0 +1,75 @@
+//! Server-held continuations for partially answered long polls.
+//!
+//! A poll with `continue_pending` set that finds messages for only some of its
+//! ids returns them at once together with a token standing for the ids that
+//! came back empty. Polling with that token resumes the wait on those ids
+//! without the client re-sending them or its push subscription, so the empty
+//! ids keep the long-poll benefit instead of being re-polled alongside the busy
+//! ones. Tokens are single use and expire after [`CONTINUATION_TTL`].
+
+use dashmap::DashMap;
+use kwn_protocol::MessageId;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+pub const CONTINUATION_TTL: Duration = Duration::from_secs(600);
+// Beyond this many live tokens, new partial responses carry no continuation.
+const MAX_CONTINUATIONS: usize = 100_000;
+
+struct Continuation {
+    message_ids: Vec<MessageId>,
+    expires: Instant,
+}
+
+#[derive(Default, Clone)]
+pub struct Continuations {
+    pending: Arc<DashMap<String, Continuation>>,
+}
+
+impl Continuations {
+    /// Returns a fresh token for `message_ids`, or `None` if too many are live.
+    pub fn issue(&self, message_ids: Vec<MessageId>) -> Option<String> {
+        let now = Instant::now();
+        if self.pending.len() >= MAX_CONTINUATIONS {
+            self.pending
+                .retain(|_, continuation| continuation.expires > now);
+            if self.pending.len() >= MAX_CONTINUATIONS {
+                return None;
+            }
+        }
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        self.pending.insert(
+            token.clone(),
+            Continuation {
+                message_ids,
+                expires: now + CONTINUATION_TTL,
+            },
+        );
+        Some(token)
+    }
+
+    /// Consumes `token`, returning the ids it stood for unless it is unknown or expired.
+    pub fn redeem(&self, token: &str) -> Option<Vec<MessageId>> {
+        let (_, continuation) = self.pending.remove(token)?;
+        (continuation.expires > Instant::now()).then_some(continuation.message_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_redeem_once_and_only_before_they_expire() {
+        let continuations = Continuations::default();
+        let ids = vec![MessageId::parse("inbox").unwrap()];
+
+        let token = continuations.issue(ids.clone()).unwrap();
+        assert_eq!(continuations.redeem(&token), Some(ids.clone()));
+        assert_eq!(continuations.redeem(&token), None);
+
+        let token = continuations.issue(ids).unwrap();
+        tokio::time::advance(CONTINUATION_TTL).await;
+        assert_eq!(continuations.redeem(&token), None);
+    }
+}
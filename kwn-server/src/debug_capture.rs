@@ -0,0 +1,116 @@
+//! Time-boxed diagnostics for a single mailbox.
+//!
+//! An operator enables capture for the SHA-256 hash of a message_id (see
+//! [`mailbox_hash`]); until it expires, handler timings and push outcomes for
+//! that mailbox are logged at info level and kept in a small ring buffer. Raw
+//! message ids and bodies are never recorded.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use kwn_protocol::MessageId;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{collections::VecDeque, time::Duration};
+use tracing::info;
+
+const MAX_EVENTS: usize = 256;
+pub const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(3600);
+
+pub fn mailbox_hash(message_id: &str) -> String {
+    hex::encode(Sha256::digest(message_id.as_bytes()))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CaptureEvent {
+    pub at: DateTime<Utc>,
+    pub kind: &'static str, // "put", "get", "ack" or "push"
+    pub duration_ms: Option<u64>,
+    pub outcome: String,
+}
+
+struct Capture {
+    expires_at: DateTime<Utc>,
+    events: VecDeque<CaptureEvent>,
+}
+
+#[derive(Default)]
+pub struct DebugCapture {
+    captures: DashMap<String, Capture>, // Keyed by mailbox hash
+}
+
+impl DebugCapture {
+    pub fn start(&self, mailbox_hash: String, duration: Duration) -> DateTime<Utc> {
+        let duration = duration.min(MAX_CAPTURE_DURATION);
+        let expires_at = Utc::now()
+            + chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+        info!(mailbox = %mailbox_hash, %expires_at, "Debug capture started");
+        self.captures.insert(
+            mailbox_hash,
+            Capture {
+                expires_at,
+                events: VecDeque::new(),
+            },
+        );
+        expires_at
+    }
+
+    pub fn stop(&self, mailbox_hash: &str) -> Option<Vec<CaptureEvent>> {
+        self.captures
+            .remove(mailbox_hash)
+            .map(|(_, capture)| capture.events.into())
+    }
+
+    pub fn events(&self, mailbox_hash: &str) -> Option<Vec<CaptureEvent>> {
+        self.expire();
+        self.captures
+            .get(mailbox_hash)
+            .map(|capture| capture.events.iter().cloned().collect())
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.captures.is_empty()
+    }
+
+    /// Records an event if `message_id` is under capture. Costs one map lookup
+    /// when no capture is active.
+    pub fn record(
+        &self,
+        message_id: &MessageId,
+        kind: &'static str,
+        duration: Option<Duration>,
+        outcome: impl Into<String>,
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let hash = mailbox_hash(message_id.as_str());
+        let now = Utc::now();
+        let expired = match self.captures.get_mut(&hash) {
+            None => return,
+            Some(capture) if capture.expires_at <= now => true,
+            Some(mut capture) => {
+                let event = CaptureEvent {
+                    at: now,
+                    kind,
+                    duration_ms: duration.map(|d| d.as_millis() as u64),
+                    outcome: outcome.into(),
+                };
+                info!(mailbox = %hash, kind, duration_ms = ?event.duration_ms, outcome = %event.outcome, "Debug capture");
+                if capture.events.len() == MAX_EVENTS {
+                    capture.events.pop_front();
+                }
+                capture.events.push_back(event);
+                false
+            }
+        };
+        if expired {
+            info!(mailbox = %hash, "Debug capture expired");
+            self.captures.remove(&hash);
+        }
+    }
+
+    fn expire(&self) {
+        let now = Utc::now();
+        self.captures.retain(|_, capture| capture.expires_at > now);
+    }
+}
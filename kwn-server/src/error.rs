@@ -0,0 +1,146 @@
+use axum::{
+    extract::Json,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use kwn_protocol::{ErrorResponse, RetryClass};
+use kwn_push::PushError;
+use kwn_storage::StorageError;
+use tracing::error;
+
+// Push services don't pass their Retry-After through web-push, so rate limited
+// pushes get this fixed backoff.
+const PUSH_RATE_LIMIT_RETRY_SECS: u64 = 60;
+
+// --- Error Handling ---
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("JSON serialization/deserialization error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Rejected by put heuristic {0}")]
+    PolicyRejected(String),
+    #[error("Clock skew: {field} is {skew_secs}s off the server clock, beyond the {limit_secs}s allowed")]
+    ClockSkew {
+        field: &'static str,
+        skew_secs: i64,
+        limit_secs: i64,
+    },
+    #[error("Push error: {0}")]
+    Push(#[from] PushError),
+    #[error("Web Push error: {0}")]
+    WebPush(String), // New variant for web push errors
+}
+
+impl AppError {
+    /// Whether the same request can succeed later, and if so after how long.
+    pub fn retry(&self) -> (RetryClass, Option<u64>) {
+        match self {
+            AppError::Storage(e) if e.is_transient() => (RetryClass::Retryable, None),
+            AppError::Storage(_) | AppError::SerdeJson(_) => (RetryClass::Permanent, None),
+            AppError::PayloadTooLarge(_)
+            | AppError::InvalidRequest(_)
+            | AppError::PolicyRejected(_) => (RetryClass::Permanent, None),
+            // Only a corrected timestamp will do
+            AppError::ClockSkew { .. } => (RetryClass::Permanent, None),
+            // Another tab's poll is waiting; it will be done by the next attempt
+            AppError::Conflict(_) => (RetryClass::Retryable, None),
+            AppError::Push(PushError::RateLimited) => {
+                (RetryClass::RetryAfter, Some(PUSH_RATE_LIMIT_RETRY_SECS))
+            }
+            AppError::Push(PushError::EndpointGone | PushError::Unauthorized) => {
+                (RetryClass::Permanent, None)
+            }
+            AppError::Push(PushError::Failed(_)) => (RetryClass::Retryable, None),
+            // Task join and worker failures
+            AppError::WebPush(_) => (RetryClass::Retryable, None),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        error!("Error processing request: {:?}", self);
+        let (retry, retry_after_secs) = self.retry();
+        let skew_secs = match &self {
+            AppError::ClockSkew { skew_secs, .. } => Some(*skew_secs),
+            _ => None,
+        };
+        let (status, error_code, message) = match self {
+            AppError::Storage(e) if e.is_transient() => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "STORAGE_UNAVAILABLE",
+                "Storage temporarily unavailable".to_string(),
+            ),
+            AppError::Storage(_) | AppError::SerdeJson(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL",
+                "Internal server error".to_string(),
+            ),
+            AppError::PayloadTooLarge(details) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE", details)
+            }
+            AppError::InvalidRequest(details) => {
+                (StatusCode::BAD_REQUEST, "INVALID_REQUEST", details)
+            }
+            AppError::Conflict(details) => (StatusCode::CONFLICT, "CONFLICT", details),
+            // The rule name stays in the log; senders learn only that it was policy
+            AppError::PolicyRejected(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "POLICY_REJECTED",
+                "Put rejected by server policy".to_string(),
+            ),
+            AppError::ClockSkew {
+                field,
+                skew_secs,
+                limit_secs,
+            } => (
+                StatusCode::BAD_REQUEST,
+                "CLOCK_SKEW",
+                format!(
+                    "{} is {}s {} the server clock; at most {}s of skew is accepted",
+                    field,
+                    skew_secs.abs(),
+                    if skew_secs > 0 { "ahead of" } else { "behind" },
+                    limit_secs
+                ),
+            ),
+            AppError::Push(e) => {
+                let error_code = match e {
+                    PushError::EndpointGone => "PUSH_ENDPOINT_GONE",
+                    PushError::Unauthorized => "PUSH_UNAUTHORIZED",
+                    PushError::RateLimited => "PUSH_RATE_LIMITED",
+                    PushError::Failed(_) => "PUSH_FAILED",
+                };
+                (StatusCode::BAD_GATEWAY, error_code, e.to_string())
+            }
+            // Handle the new WebPush variant
+            AppError::WebPush(details) => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL", details),
+        };
+        let mut response = (
+            status,
+            Json(ErrorResponse {
+                message,
+                error_code: error_code.to_string(),
+                retry,
+                retry_after_secs,
+                server_time: Some(chrono::Utc::now()),
+                skew_secs,
+            }),
+        )
+            .into_response();
+        if let Some(secs) = retry_after_secs {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        }
+        response
+    }
+}
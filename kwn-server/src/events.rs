@@ -0,0 +1,145 @@
+//! Server-Sent Events fallback for clients whose proxies break long polls.
+//!
+//! `GET /api/events?message_ids=a,b` streams each stored message once as an
+//! `event: message` record whose data is a [`FoundMessage`] and whose id is the
+//! message's millisecond timestamp. EventSource resends the last id as
+//! `Last-Event-ID` when it reconnects, and only newer messages are replayed.
+//! Messages still have to be acked through `/api/ack-messages`.
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::future::select_all;
+use kwn_protocol::{AckToken, FoundMessage, MessageId, SortOrder};
+use serde::Deserialize;
+use std::{collections::HashSet, convert::Infallible};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+use tracing::error;
+
+use crate::{
+    error::AppError,
+    handlers::{dedup_message_ids, record_delivered, sort_messages},
+    notifier::Waiters,
+    state::SharedState,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize, Debug)]
+pub struct EventsQuery {
+    message_ids: String, // Comma separated
+}
+
+pub async fn events_handler(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<EventsQuery>,
+) -> Result<Response, AppError> {
+    let message_ids = query
+        .message_ids
+        .split(',')
+        .map(MessageId::parse)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|issue| AppError::InvalidRequest(issue.message))?;
+    let last_event_millis = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok());
+
+    let (tx, rx) = mpsc::channel::<FoundMessage>(16);
+    tokio::spawn(stream_messages(state, message_ids, last_event_millis, tx));
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|message| (Ok::<_, Infallible>(message_event(&message)), rx))
+    });
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+fn message_event(message: &FoundMessage) -> Event {
+    Event::default()
+        .event("message")
+        .id(message.timestamp.timestamp_millis().to_string())
+        .json_data(message)
+        .unwrap_or_else(|e| {
+            error!("Failed to serialize event: {}", e);
+            Event::default().comment("unserializable message skipped")
+        })
+}
+
+/// Sends each stored message for `message_ids` newer than `after_millis` once,
+/// then every new one as it arrives, until `tx` is closed. Shared by the SSE
+/// and gRPC streams.
+pub async fn stream_messages(
+    state: SharedState,
+    mut message_ids: Vec<MessageId>,
+    after_millis: Option<i64>,
+    tx: mpsc::Sender<FoundMessage>,
+) {
+    dedup_message_ids(&mut message_ids);
+    for message_id in &message_ids {
+        state.lifecycle.touch(message_id);
+    }
+    let notifiers = Waiters::register(&state.notifier, &message_ids, &[]);
+    // (message_id, timestamp) of everything sent on this connection and not yet acked
+    let mut sent: HashSet<(MessageId, i64)> = HashSet::new();
+
+    loop {
+        // Armed before the fetch so a put landing in between still wakes us
+        let mut notified: Vec<_> = notifiers
+            .handles()
+            .iter()
+            .map(|n| Box::pin(n.notified()))
+            .collect();
+        for future in notified.iter_mut() {
+            future.as_mut().enable();
+        }
+
+        let mut found = match state.messages.fetch(&message_ids) {
+            Ok(found) => found,
+            Err(e) => {
+                error!("Failed to fetch messages for event stream: {}", e);
+                return; // Dropping the sender ends the stream; EventSource reconnects
+            }
+        };
+        sort_messages(&mut found, SortOrder::OldestFirst);
+        let stored: HashSet<(MessageId, i64)> = found
+            .iter()
+            .map(|m| (m.message_id.clone(), m.timestamp.timestamp_millis()))
+            .collect();
+        sent.retain(|key| stored.contains(key));
+
+        for message in found {
+            let millis = message.timestamp.timestamp_millis();
+            let key = (message.message_id.clone(), millis);
+            if after_millis.is_some_and(|last| millis <= last) || sent.contains(&key) {
+                continue;
+            }
+            let token = AckToken {
+                message_id: message.message_id.clone(),
+                timestamp: message.timestamp,
+            };
+            if tx.send(message).await.is_err() {
+                return; // Client went away
+            }
+            record_delivered(&state, std::slice::from_ref(&token));
+            sent.insert(key);
+        }
+
+        tokio::select! {
+            _ = select_all(notified) => {}
+            _ = sleep(CHECK_INTERVAL) => {}
+            _ = tx.closed() => return,
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! gRPC service for native clients, listening on `GRPC_PORT` when it is set.
+//!
+//! Calls go through the same handlers and state as the REST API. The listener
+//! has no nginx or tower_governor in front of it, so it applies its own per-IP
+//! limit with the same quota, and puts pass the private token check with the
+//! token in `private-token` metadata.
+
+// tonic's API returns Status by value throughout
+#![allow(clippy::result_large_err)]
+
+use axum::extract::{Json, State};
+use chrono::DateTime;
+use futures::Stream;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use kwn_protocol::{
+    AckMessagesPayload, AckToken, MessageId, PutMessageRequest, PRIVATE_TOKEN_HEADER,
+};
+use std::{net::SocketAddr, num::NonZeroU32, pin::Pin, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    error::AppError,
+    events::stream_messages,
+    handlers::{ack_messages_handler, max_message_size, store_put, MAX_IDEMPOTENCY_KEY_LEN},
+    state::SharedState,
+    tokens::PutAdmission,
+};
+
+pub mod proto {
+    tonic::include_proto!("kwn.v1");
+}
+
+use proto::message_relay_server::{MessageRelay, MessageRelayServer};
+
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        match err {
+            AppError::InvalidRequest(details) => Status::invalid_argument(details),
+            AppError::PayloadTooLarge(details) => Status::resource_exhausted(details),
+            AppError::Conflict(details) => Status::already_exists(details),
+            AppError::PolicyRejected(_) => {
+                Status::permission_denied("Put rejected by server policy")
+            }
+            skew @ AppError::ClockSkew { .. } => Status::invalid_argument(skew.to_string()),
+            other => {
+                tracing::error!("Error processing gRPC request: {:?}", other);
+                Status::internal("Internal server error")
+            }
+        }
+    }
+}
+
+pub struct GrpcRelay {
+    state: SharedState,
+    limiter: Arc<DefaultKeyedRateLimiter<String>>,
+}
+
+impl GrpcRelay {
+    pub fn new(state: SharedState) -> Self {
+        Self {
+            state,
+            // Same budget as the HTTP governor: 100 per second with a burst of 100
+            limiter: Arc::new(RateLimiter::keyed(
+                Quota::per_second(NonZeroU32::new(100).unwrap())
+                    .allow_burst(NonZeroU32::new(100).unwrap()),
+            )),
+        }
+    }
+
+    fn client_ip<T>(request: &Request<T>) -> String {
+        request
+            .metadata()
+            .get("x-real-ip")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| request.remote_addr().map(|addr| addr.ip().to_string()))
+            .unwrap_or_default()
+    }
+
+    fn check_rate<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        self.limiter
+            .check_key(&Self::client_ip(request))
+            .map_err(|_| Status::resource_exhausted("Too many requests."))
+    }
+}
+
+#[tonic::async_trait]
+impl MessageRelay for GrpcRelay {
+    async fn put_message(
+        &self,
+        request: Request<proto::PutMessageRequest>,
+    ) -> Result<Response<proto::PutMessageResponse>, Status> {
+        self.check_rate(&request)?;
+        let client_ip = Self::client_ip(&request);
+        if let Some(tokens) = self.state.tokens.as_ref() {
+            let presented = request
+                .metadata()
+                .get(PRIVATE_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            match tokens.check_put(presented, &client_ip).await {
+                PutAdmission::Allowed => {}
+                PutAdmission::InvalidToken => {
+                    return Err(Status::unauthenticated("Invalid private token"))
+                }
+                PutAdmission::SpentToken => {
+                    return Err(Status::unauthenticated("Private token already spent"))
+                }
+                PutAdmission::RateLimited { .. } => {
+                    return Err(Status::resource_exhausted("Too many requests."))
+                }
+                PutAdmission::Failed => return Err(Status::internal("Internal server error")),
+            }
+        }
+        let request = request.into_inner();
+        let message_id = MessageId::parse(request.message_id)
+            .map_err(|issue| Status::invalid_argument(issue.message))?;
+        if request.message.len() > max_message_size(message_id.as_str()) {
+            return Err(Status::resource_exhausted(
+                "message exceeds the size allowed for this message_id",
+            ));
+        }
+        let idempotency_key = Some(request.idempotency_key).filter(|key| !key.is_empty());
+        if idempotency_key
+            .as_ref()
+            .is_some_and(|key| key.len() > MAX_IDEMPOTENCY_KEY_LEN)
+        {
+            return Err(Status::invalid_argument("idempotency_key is too long"));
+        }
+        let (result, _) = store_put(
+            &self.state,
+            PutMessageRequest {
+                message_id,
+                message: request.message,
+                idempotency_key: None,
+                deliver_at: None,
+                ttl_seconds: None,
+                supersedes: None,
+                receipt_channel_id: None,
+                durable: false,
+                retain: false,
+            },
+            idempotency_key.as_deref(),
+            &client_ip,
+        )
+        .await?;
+        Ok(Response::new(proto::PutMessageResponse {
+            timestamp_ms: result.timestamp.timestamp_millis(),
+        }))
+    }
+
+    type GetMessagesStream = Pin<Box<dyn Stream<Item = Result<proto::Message, Status>> + Send>>;
+
+    async fn get_messages(
+        &self,
+        request: Request<proto::GetMessagesRequest>,
+    ) -> Result<Response<Self::GetMessagesStream>, Status> {
+        self.check_rate(&request)?;
+        let request = request.into_inner();
+        let message_ids = request
+            .message_ids
+            .into_iter()
+            .map(MessageId::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|issue| Status::invalid_argument(issue.message))?;
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(stream_messages(
+            self.state.clone(),
+            message_ids,
+            request.after_timestamp_ms,
+            tx,
+        ));
+        let messages = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|message| {
+                let message = proto::Message {
+                    message_id: message.message_id.into(),
+                    message: message.message,
+                    timestamp_ms: message.timestamp.timestamp_millis(),
+                };
+                (Ok(message), rx)
+            })
+        });
+        Ok(Response::new(Box::pin(messages)))
+    }
+
+    async fn ack_messages(
+        &self,
+        request: Request<proto::AckMessagesRequest>,
+    ) -> Result<Response<proto::AckMessagesResponse>, Status> {
+        self.check_rate(&request)?;
+        let acks = request
+            .into_inner()
+            .acks
+            .into_iter()
+            .map(|ack| {
+                let timestamp = DateTime::from_timestamp_millis(ack.timestamp_ms)
+                    .ok_or_else(|| Status::invalid_argument("timestamp_ms out of range"))?;
+                Ok(AckToken {
+                    message_id: MessageId::parse(ack.message_id)
+                        .map_err(|issue| Status::invalid_argument(issue.message))?,
+                    timestamp,
+                })
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        ack_messages_handler(
+            State(self.state.clone()),
+            Json(AckMessagesPayload {
+                acks,
+                consumer: None,
+            }),
+        )
+        .await?;
+        Ok(Response::new(proto::AckMessagesResponse {}))
+    }
+}
+
+pub async fn serve(state: SharedState, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    let relay = GrpcRelay::new(state);
+    let limiter = relay.limiter.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            ticker.tick().await;
+            limiter.retain_recent();
+        }
+    });
+    tracing::info!("gRPC listening on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(MessageRelayServer::new(relay))
+        .serve(addr)
+        .await
+}
@@ -0,0 +1,2102 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Json, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use futures::future::{join_all, select_all};
+use kwn_protocol::{
+    check_message_id, AckBeforeRequest, AckBeforeResponse, AckMessagesPayload, AckToken,
+    CancelMessageRequest, CancelMessageResponse, FoundMessage, GetMessagesRequest,
+    GetMessagesResponse, GetMessagesStreamLine, HasMessagesRequest, HasMessagesResponse, MessageId,
+    MessageState, MessageStateRequest, PendingCount, PollMode, PurgeChannelRequest,
+    PurgeChannelResponse, PushSubscriptionInfo, PutMessageBody, PutMessageRequest,
+    PutMessagesRequest, PutMultiRequest, PutMultiResponse, PutResult, RevokeMessageRequest,
+    RevokeMessageResponse, Signal, SortOrder, SubscribeRequest, SubscribeResponse,
+    UnsubscribeRequest, UnsubscribeResponse, ValidatePutRequest, ValidatePutResponse,
+    ValidationIssue, MIN_PATTERN_ROOT_LEN, NDJSON_CONTENT_TYPE,
+};
+use kwn_push::EndpointHash;
+use kwn_storage::{CancelOutcome, DeletionPolicy, PersistReason};
+use metrics::counter;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    capabilities,
+    error::AppError,
+    middleware::{client_ip, too_many_requests, RateLimitScope},
+    notifier::Waiters,
+    poll_sessions::PollGuard,
+    push::send_notification,
+    response_size::{self, fit_messages, json_len},
+    signals::SignalReceiver,
+    state::SharedState,
+};
+
+pub const CUSTOM_JSON_PAYLOAD_LIMIT: usize = 3000;
+// Lines buffered ahead of a slow NDJSON reader before the storage scan waits.
+const NDJSON_BUFFER_LINES: usize = 32;
+// Bytes of `{"message_id":"","message":""}` surrounding the two values in a put body.
+const PUT_ENVELOPE_OVERHEAD: usize = 30;
+pub const MAX_BATCH_ENTRIES: usize = 64;
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+// Long enough to cover a client's retries across a day of flaky connectivity.
+const IDEMPOTENCY_TTL_HOURS: i64 = 24;
+// Far enough out for reminders, near enough that scheduled messages don't pile up.
+const MAX_SCHEDULE_DAYS: i64 = 30;
+const MAX_MESSAGE_TTL_SECS: u64 = 365 * 24 * 3600;
+// Longest a poll may hide what it received before acking it.
+const MAX_LEASE_SECS: u64 = 3600;
+const MAX_CONSUMER_LEN: usize = 64;
+const MAX_PATTERNS: usize = 8;
+// Ids one pattern may expand to, so a busy subtree can't blow up a poll.
+const MAX_PATTERN_IDS: usize = 256;
+// Each batch entry is held to the single-put size, so the body may hold that many.
+pub const BATCH_PAYLOAD_LIMIT: usize = CUSTOM_JSON_PAYLOAD_LIMIT * MAX_BATCH_ENTRIES;
+
+// Largest message that still fits in a put body for this id under the JSON body limit.
+pub fn max_message_size(message_id: &str) -> usize {
+    CUSTOM_JSON_PAYLOAD_LIMIT.saturating_sub(PUT_ENVELOPE_OVERHEAD + message_id.len())
+}
+
+/// Returns the PEM public key clients need to blind and finalize private tokens.
+pub async fn token_key_handler(State(state): State<SharedState>) -> Result<String, StatusCode> {
+    let tokens = state.tokens.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    tokens.public_key_pem().map_err(|e| {
+        error!("Failed to encode private token public key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Checks a prospective put without storing anything, so clients can fail fast
+/// before uploading a large payload.
+#[instrument(skip(payload))]
+pub async fn validate_put_handler(
+    Json(payload): Json<ValidatePutRequest>,
+) -> Json<ValidatePutResponse> {
+    let mut issues = Vec::new();
+    if let Some(issue) = check_message_id(&payload.message_id) {
+        issues.push(issue);
+    }
+    let max_message_size = max_message_size(&payload.message_id);
+    if payload.message_size > max_message_size {
+        issues.push(ValidationIssue {
+            field: "message_size".to_string(),
+            error_code: "PAYLOAD_TOO_LARGE".to_string(),
+            message: format!(
+                "message of {} bytes exceeds the {} bytes allowed for this message_id",
+                payload.message_size, max_message_size
+            ),
+        });
+    }
+    Json(ValidatePutResponse {
+        valid: issues.is_empty(),
+        max_message_size,
+        issues,
+    })
+}
+
+// Like `after_put` for one message stored in every one of `message_ids`: all
+// waiters are woken first, then their pushes are queued together.
+pub fn after_put_many(state: &SharedState, message_ids: Vec<MessageId>, message_len: usize) {
+    let message_ids = message_ids
+        .into_iter()
+        .filter(|message_id| {
+            state.analytics.record_put(message_id, message_len);
+            state.lifecycle.touch(message_id);
+            needs_push(state, state.notifier.notify(message_id))
+        })
+        .collect();
+    queue_pushes(state, message_ids);
+}
+
+// Bookkeeping shared by every put path once the message is committed.
+pub fn after_put(state: &SharedState, message_id: MessageId, message_len: usize) {
+    state.analytics.record_put(&message_id, message_len);
+    state.lifecycle.touch(&message_id);
+
+    // Notify any waiting getters
+    let woken = state.notifier.notify(&message_id);
+
+    if needs_push(state, woken) {
+        queue_pushes(state, vec![message_id]);
+    }
+}
+
+// A long poll or event stream that was just woken delivers the message at
+// once, so a push would only announce it again on a device with the app open.
+// The subscription is left in place for the next put nobody is waiting for.
+fn needs_push(state: &SharedState, woken: bool) -> bool {
+    if woken && !state.push_while_polling {
+        counter!("kwn_push_skipped_polling_total").increment(1);
+        return false;
+    }
+    true
+}
+
+// Hands the pushes to the outbox worker, or sends them straight away, once
+// only, if they can't be queued.
+fn queue_pushes(state: &SharedState, message_ids: Vec<MessageId>) {
+    if message_ids.is_empty() {
+        return;
+    }
+    // Opted-in channels are texted too, whatever becomes of their push
+    if let Some(sms) = &state.sms {
+        sms.notify(message_ids.clone());
+    }
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        let outbox_state = state_clone.clone();
+        match tokio::task::spawn_blocking(move || {
+            let queued = outbox_state.outbox.enqueue(&message_ids);
+            (message_ids, queued)
+        })
+        .await
+        {
+            Ok((_, Ok(()))) => {}
+            Ok((message_ids, Err(e))) => {
+                error!("Failed to queue pushes, sending them now: {}", e);
+                let sends = message_ids.into_iter().map(|message_id| {
+                    send_notification(axum::extract::State(state_clone.clone()), message_id)
+                });
+                for result in join_all(sends).await {
+                    if let Err(e) = result {
+                        error!("Failed to send notification in background task: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => error!("Push queueing task failed: {}", e),
+        }
+    });
+}
+
+#[instrument(skip(state, body))]
+pub async fn put_message_handler(
+    State(state): State<SharedState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<PutMessageBody>,
+) -> Result<Response, AppError> {
+    let client_ip = client_ip(&headers, Some(peer));
+    let mut payload = match body {
+        PutMessageBody::Single(payload) => payload,
+        PutMessageBody::Broadcast(payload) => {
+            return Ok((
+                StatusCode::CREATED,
+                Json(store_broadcast(&state, payload, &client_ip)?),
+            )
+                .into_response())
+        }
+    };
+    let body_key = payload.idempotency_key.take();
+    let idempotency_key = idempotency_key(&headers, body_key.as_deref())?;
+    let (result, replayed) = store_put(&state, payload, idempotency_key, &client_ip).await?;
+
+    let mut response = (StatusCode::CREATED, Json(result)).into_response();
+    if replayed {
+        response.headers_mut().insert(
+            IDEMPOTENT_REPLAYED_HEADER,
+            header::HeaderValue::from_static("true"),
+        );
+    }
+    Ok(response)
+}
+
+/// Rejects a client-supplied `timestamp` further ahead of the server clock
+/// than `max_clock_skew`, or, unless `allow_past`, further behind it.
+pub fn check_clock_skew(
+    state: &SharedState,
+    field: &'static str,
+    timestamp: DateTime<Utc>,
+    allow_past: bool,
+) -> Result<(), AppError> {
+    let skew = timestamp - Utc::now();
+    if skew > state.max_clock_skew || (!allow_past && -skew > state.max_clock_skew) {
+        return Err(AppError::ClockSkew {
+            field,
+            skew_secs: skew.num_seconds(),
+            limit_secs: state.max_clock_skew.num_seconds(),
+        });
+    }
+    Ok(())
+}
+
+// Applies the operator's put heuristics, if configured, to one message.
+fn check_heuristics(
+    state: &SharedState,
+    client_ip: &str,
+    message_id: &MessageId,
+    message: &str,
+) -> Result<(), AppError> {
+    let Some(heuristics) = state.heuristics.as_ref() else {
+        return Ok(());
+    };
+    match heuristics.check(message_id, message, client_ip) {
+        Some(rule) => Err(AppError::PolicyRejected(rule.to_string())),
+        None => Ok(()),
+    }
+}
+
+fn message_ttl(ttl_seconds: Option<u64>) -> Result<Option<chrono::Duration>, AppError> {
+    match ttl_seconds {
+        None => Ok(None),
+        Some(secs) if secs == 0 || secs > MAX_MESSAGE_TTL_SECS => Err(AppError::InvalidRequest(
+            format!("ttl_seconds must be between 1 and {}", MAX_MESSAGE_TTL_SECS),
+        )),
+        Some(secs) => Ok(Some(chrono::Duration::seconds(secs as i64))),
+    }
+}
+
+// Syncs the journal for a durable put, off the async workers since it blocks
+// on an fsync.
+async fn persist_durable(state: &SharedState) -> Result<(), AppError> {
+    let messages = state.messages.clone();
+    match tokio::task::spawn_blocking(move || messages.persist(PersistReason::Durable)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute persist task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during persist: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+/// Stores one message and announces it, returning its ack token and whether
+/// `idempotency_key` matched an earlier put, in which case nothing was stored.
+pub async fn store_put(
+    state: &SharedState,
+    payload: PutMessageRequest,
+    idempotency_key: Option<&str>,
+    client_ip: &str,
+) -> Result<(PutResult, bool), AppError> {
+    let started = Instant::now();
+    let timestamp = Utc::now();
+    let ttl = message_ttl(payload.ttl_seconds)?;
+    check_heuristics(state, client_ip, &payload.message_id, &payload.message)?;
+    if let Some(deliver_at) = payload.deliver_at {
+        // Later is bounded by MAX_SCHEDULE_DAYS instead
+        if deliver_at < timestamp {
+            check_clock_skew(state, "deliver_at", deliver_at, false)?;
+        }
+    }
+    if payload.retain {
+        return store_retained(state, payload, idempotency_key, ttl, timestamp, started).await;
+    }
+    if let Some(supersedes) = payload.supersedes {
+        check_clock_skew(state, "supersedes", supersedes, true)?;
+        if payload.deliver_at.is_some() || idempotency_key.is_some() {
+            return Err(AppError::InvalidRequest(
+                "supersedes can't be combined with deliver_at or an idempotency key".to_string(),
+            ));
+        }
+    }
+    if let Some(deliver_at) = payload
+        .deliver_at
+        .filter(|deliver_at| *deliver_at > timestamp)
+    {
+        if deliver_at > timestamp + chrono::Duration::days(MAX_SCHEDULE_DAYS) {
+            return Err(AppError::InvalidRequest(format!(
+                "deliver_at must be within {} days",
+                MAX_SCHEDULE_DAYS
+            )));
+        }
+        if idempotency_key.is_some() {
+            return Err(AppError::InvalidRequest(
+                "idempotency keys can't be combined with deliver_at".to_string(),
+            ));
+        }
+        state.messages.schedule(
+            &payload.message_id,
+            &payload.message,
+            deliver_at,
+            ttl.map(|ttl| deliver_at + ttl),
+        )?;
+        if let Some(channel) = &payload.receipt_channel_id {
+            state.messages.request_receipt(
+                &AckToken {
+                    message_id: payload.message_id.clone(),
+                    timestamp: deliver_at,
+                },
+                channel,
+            )?;
+        }
+        let handle = issue_handle(
+            state,
+            &AckToken {
+                message_id: payload.message_id.clone(),
+                timestamp: deliver_at,
+            },
+        )?;
+        if payload.durable {
+            persist_durable(state).await?;
+        }
+        if state.debug.is_active() {
+            state.debug.record(
+                &payload.message_id,
+                "put",
+                Some(started.elapsed()),
+                format!(
+                    "scheduled {} bytes for {}",
+                    payload.message.len(),
+                    deliver_at
+                ),
+            );
+        }
+        // The scheduler announces it when it comes due
+        return Ok((
+            PutResult {
+                message_id: payload.message_id,
+                timestamp: deliver_at,
+                handle: Some(handle),
+            },
+            false,
+        ));
+    }
+    let stored_at = match idempotency_key {
+        Some(key) => state.messages.put_idempotent(
+            &payload.message_id,
+            &payload.message,
+            timestamp,
+            ttl.map(|ttl| timestamp + ttl),
+            key,
+            timestamp + chrono::Duration::hours(IDEMPOTENCY_TTL_HOURS),
+        )?,
+        None => {
+            match payload.supersedes {
+                Some(supersedes) => state.messages.supersede(
+                    &payload.message_id,
+                    &payload.message,
+                    timestamp,
+                    ttl.map(|ttl| timestamp + ttl),
+                    supersedes,
+                )?,
+                None => state.messages.put(
+                    &payload.message_id,
+                    &payload.message,
+                    timestamp,
+                    ttl.map(|ttl| timestamp + ttl),
+                )?,
+            }
+            timestamp
+        }
+    };
+    let replayed = stored_at != timestamp;
+    if let Some(channel) = payload.receipt_channel_id.as_ref().filter(|_| !replayed) {
+        state.messages.request_receipt(
+            &AckToken {
+                message_id: payload.message_id.clone(),
+                timestamp: stored_at,
+            },
+            channel,
+        )?;
+    }
+    // The secret behind a replay's handle only went to the original put
+    let handle = if replayed {
+        None
+    } else {
+        Some(issue_handle(
+            state,
+            &AckToken {
+                message_id: payload.message_id.clone(),
+                timestamp: stored_at,
+            },
+        )?)
+    };
+    // Also on a replay, in case the original put hasn't been synced yet
+    if payload.durable {
+        persist_durable(state).await?;
+    }
+    let message_len = payload.message.len();
+    if state.debug.is_active() {
+        state.debug.record(
+            &payload.message_id,
+            "put",
+            Some(started.elapsed()),
+            if replayed {
+                "idempotent replay, nothing stored".to_string()
+            } else {
+                format!("stored {} bytes", message_len)
+            },
+        );
+    }
+
+    // The id and timestamp form the message's ack token, so senders can refer
+    // to this exact record later
+    let result = PutResult {
+        message_id: payload.message_id,
+        timestamp: stored_at,
+        handle,
+    };
+    // A replay was already announced by the put that stored it
+    if !replayed {
+        after_put(state, result.message_id.clone(), message_len);
+    }
+    Ok((result, replayed))
+}
+
+// A retained value replaces its channel's previous one rather than queueing, so
+// there is nothing to schedule, supersede, receipt or cancel.
+async fn store_retained(
+    state: &SharedState,
+    payload: PutMessageRequest,
+    idempotency_key: Option<&str>,
+    ttl: Option<chrono::Duration>,
+    timestamp: DateTime<Utc>,
+    started: Instant,
+) -> Result<(PutResult, bool), AppError> {
+    if payload.deliver_at.is_some()
+        || payload.supersedes.is_some()
+        || payload.receipt_channel_id.is_some()
+        || idempotency_key.is_some()
+    {
+        return Err(AppError::InvalidRequest(
+            "retain can't be combined with deliver_at, supersedes, receipt_channel_id or an idempotency key"
+                .to_string(),
+        ));
+    }
+    state.messages.put_retained(
+        &payload.message_id,
+        &payload.message,
+        timestamp,
+        ttl.map(|ttl| timestamp + ttl),
+    )?;
+    if payload.durable {
+        persist_durable(state).await?;
+    }
+    let message_len = payload.message.len();
+    if state.debug.is_active() {
+        state.debug.record(
+            &payload.message_id,
+            "put",
+            Some(started.elapsed()),
+            format!("retained {} bytes", message_len),
+        );
+    }
+    let result = PutResult {
+        message_id: payload.message_id,
+        timestamp,
+        handle: None,
+    };
+    after_put(state, result.message_id.clone(), message_len);
+    Ok((result, false))
+}
+
+// Only the hash is stored, so a copy of the database can't cancel anything.
+fn issue_handle(state: &SharedState, token: &AckToken) -> Result<String, AppError> {
+    let handle = hex::encode(rand::random::<[u8; 32]>());
+    state
+        .messages
+        .save_handle(token, &Sha256::digest(handle.as_bytes()))?;
+    Ok(handle)
+}
+
+// The header wins over the body field when both are present.
+fn idempotency_key<'a>(
+    headers: &'a HeaderMap,
+    body_key: Option<&'a str>,
+) -> Result<Option<&'a str>, AppError> {
+    let key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => Some(value.to_str().map_err(|_| {
+            AppError::InvalidRequest("Idempotency-Key must be visible ASCII".to_string())
+        })?),
+        None => body_key,
+    };
+    match key {
+        Some(key) if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN => {
+            Err(AppError::InvalidRequest(format!(
+                "idempotency key must be 1 to {} bytes",
+                MAX_IDEMPOTENCY_KEY_LEN
+            )))
+        }
+        key => Ok(key),
+    }
+}
+
+/// Stores one message for several mailboxes atomically: if any insert fails,
+/// none of them are visible and no notifications are sent.
+#[instrument(skip(state, payload))]
+pub async fn put_multi_handler(
+    State(state): State<SharedState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<PutMultiRequest>,
+) -> Result<Json<PutMultiResponse>, AppError> {
+    Ok(Json(store_broadcast(
+        &state,
+        payload,
+        &client_ip(&headers, Some(peer)),
+    )?))
+}
+
+// Shared by put-multi and the `message_ids` form of put-message.
+fn store_broadcast(
+    state: &SharedState,
+    payload: PutMultiRequest,
+    client_ip: &str,
+) -> Result<PutMultiResponse, AppError> {
+    let mut message_ids = payload.message_ids;
+    message_ids.sort();
+    message_ids.dedup();
+    if message_ids.is_empty() {
+        return Err(AppError::InvalidRequest(
+            "message_ids must not be empty".to_string(),
+        ));
+    }
+    for message_id in &message_ids {
+        if payload.message.len() > max_message_size(message_id.as_str()) {
+            return Err(AppError::PayloadTooLarge(format!(
+                "message for {} exceeds the size allowed for its message_id",
+                message_id
+            )));
+        }
+        check_heuristics(state, client_ip, message_id, &payload.message)?;
+    }
+
+    let timestamp = Utc::now();
+    state
+        .messages
+        .put_many(&message_ids, &payload.message, timestamp)?;
+
+    let results = message_ids
+        .iter()
+        .map(|message_id| PutResult {
+            message_id: message_id.clone(),
+            timestamp,
+            handle: None,
+        })
+        .collect();
+    after_put_many(state, message_ids, payload.message.len());
+    Ok(PutMultiResponse { results })
+}
+
+/// Reports which mailboxes have pending messages, without bodies and without
+/// waiting, so clients can skip a full fetch when there is nothing to get.
+#[instrument(skip(state, payload))]
+pub async fn has_messages_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<HasMessagesRequest>,
+) -> Result<Json<HasMessagesResponse>, AppError> {
+    let messages = state.messages.clone();
+    let mut message_ids = payload.message_ids;
+    dedup_message_ids(&mut message_ids);
+    match tokio::task::spawn_blocking(move || {
+        let counts = messages.pending_counts(&message_ids)?;
+        let sequences = messages.latest_sequences(&message_ids)?;
+        Ok::<_, kwn_storage::StorageError>((message_ids, counts, sequences))
+    })
+    .await
+    {
+        Ok(Ok((message_ids, counts, sequences))) => Ok(Json(HasMessagesResponse {
+            results: message_ids
+                .into_iter()
+                .zip(counts)
+                .zip(sequences)
+                .map(|((message_id, count), latest_sequence)| PendingCount {
+                    message_id,
+                    has_messages: count > 0,
+                    count,
+                    latest_sequence,
+                })
+                .collect(),
+        })),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute has_messages task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during has_messages: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+/// Stores a batch of messages for distinct mailboxes in one transaction, then
+/// notifies each mailbox, so a sender fanning out pays one round trip.
+#[instrument(skip(state, payload))]
+pub async fn put_messages_handler(
+    State(state): State<SharedState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<PutMessagesRequest>,
+) -> Result<Json<PutMultiResponse>, AppError> {
+    let client_ip = client_ip(&headers, Some(peer));
+    let entries = payload.entries;
+    if entries.is_empty() || entries.len() > MAX_BATCH_ENTRIES {
+        return Err(AppError::InvalidRequest(format!(
+            "entries must hold between 1 and {} messages",
+            MAX_BATCH_ENTRIES
+        )));
+    }
+    let mut seen = HashSet::new();
+    for entry in &entries {
+        if !seen.insert(&entry.message_id) {
+            return Err(AppError::InvalidRequest(format!(
+                "message_id {} appears more than once",
+                entry.message_id
+            )));
+        }
+        message_ttl(entry.ttl_seconds)?;
+        if entry.deliver_at.is_some()
+            || entry.supersedes.is_some()
+            || entry.receipt_channel_id.is_some()
+            || entry.retain
+        {
+            return Err(AppError::InvalidRequest(
+                "deliver_at, supersedes, receipt_channel_id and retain are only supported by put-message"
+                    .to_string(),
+            ));
+        }
+        if entry.message.len() > max_message_size(entry.message_id.as_str()) {
+            return Err(AppError::PayloadTooLarge(format!(
+                "message for {} exceeds the size allowed for its message_id",
+                entry.message_id
+            )));
+        }
+        check_heuristics(&state, &client_ip, &entry.message_id, &entry.message)?;
+    }
+
+    let timestamp = Utc::now();
+    state.messages.put_batch(&entries, timestamp)?;
+    if entries.iter().any(|entry| entry.durable) {
+        persist_durable(&state).await?;
+    }
+
+    let results = entries
+        .into_iter()
+        .map(|entry| {
+            after_put(&state, entry.message_id.clone(), entry.message.len());
+            PutResult {
+                message_id: entry.message_id,
+                timestamp,
+                handle: None,
+            }
+        })
+        .collect();
+    Ok(Json(PutMultiResponse { results }))
+}
+
+// --- Handler for Acknowledging/Deleting Messages ---
+#[instrument(skip(state, payload))]
+pub async fn ack_messages_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<AckMessagesPayload>,
+) -> Result<StatusCode, AppError> {
+    if payload.acks.is_empty() {
+        return Ok(StatusCode::OK);
+    }
+    if let Some(consumer) = &payload.consumer {
+        check_consumer(consumer)?;
+    }
+    // Ack timestamps are echoed from stored messages, so only the future is suspect
+    for ack in &payload.acks {
+        check_clock_skew(&state, "timestamp", ack.timestamp, true)?;
+    }
+
+    for ack in &payload.acks {
+        state.lifecycle.touch(&ack.message_id);
+    }
+
+    let started = Instant::now();
+    let messages = state.messages.clone();
+    let acked_ids: Vec<MessageId> = if state.debug.is_active() {
+        payload.acks.iter().map(|a| a.message_id.clone()).collect()
+    } else {
+        Vec::new()
+    };
+    let acks = payload.acks; // Move acks into the blocking task
+    let consumer = payload.consumer;
+
+    // Run on the ack lane so deletes keep up even when the blocking pool is saturated
+    let result = state
+        .ack_lane
+        .run(move || {
+            // A consumer's acks only delete what every consumer has acked
+            let acks = match consumer {
+                Some(consumer) => messages.advance_cursor(&consumer, &acks)?,
+                None => acks,
+            };
+            messages
+                .ack_with_receipts(&acks, Utc::now())
+                .map(|receipts| (acks.len(), receipts))
+        })
+        .await;
+
+    let outcome = if matches!(result, Ok(Ok(_))) {
+        "deleted"
+    } else {
+        "failed"
+    };
+    for message_id in &acked_ids {
+        state
+            .debug
+            .record(message_id, "ack", Some(started.elapsed()), outcome);
+    }
+
+    match result {
+        Ok(Ok((deleted, receipts))) => {
+            state.report_stats.record_acked(deleted);
+            for (channel, receipt_len) in receipts {
+                after_put(&state, channel, receipt_len);
+            }
+            // Overwritten values survive in older segments until compacted
+            if deleted > state.compact_after_deletes
+                || state.messages.deletion_policy() == DeletionPolicy::Overwrite
+            {
+                schedule_compaction(&state, deleted);
+            }
+            Ok(StatusCode::OK)
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(lane_error) => {
+            error!("Failed to execute ack_messages task: {}", lane_error);
+            // Use a more generic error type or reuse WebPush temporarily if needed
+            Err(AppError::WebPush(format!("Ack lane error: {}", lane_error)))
+        }
+    }
+}
+
+/// Acks every message of one mailbox stored before a timestamp, so clients
+/// recovering from a large backlog needn't list each (id, timestamp) pair.
+#[instrument(skip(state, payload))]
+pub async fn ack_before_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<AckBeforeRequest>,
+) -> Result<Json<AckBeforeResponse>, AppError> {
+    // A `before` far ahead would also delete messages stored after the request
+    check_clock_skew(&state, "before", payload.before, true)?;
+    state.lifecycle.touch(&payload.message_id);
+    let started = Instant::now();
+    let messages = state.messages.clone();
+    let message_id = payload.message_id.clone();
+    let result = state
+        .ack_lane
+        .run(move || messages.ack_before(&message_id, payload.before))
+        .await;
+
+    match result {
+        Ok(Ok(deleted)) => {
+            state.report_stats.record_acked(deleted);
+            state.debug.record(
+                &payload.message_id,
+                "ack-before",
+                Some(started.elapsed()),
+                format!("deleted {} messages", deleted),
+            );
+            if deleted > state.compact_after_deletes
+                || (deleted > 0 && state.messages.deletion_policy() == DeletionPolicy::Overwrite)
+            {
+                schedule_compaction(&state, deleted);
+            }
+            Ok(Json(AckBeforeResponse { deleted }))
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(lane_error) => {
+            error!("Failed to execute ack_before task: {}", lane_error);
+            Err(AppError::WebPush(format!("Ack lane error: {}", lane_error)))
+        }
+    }
+}
+
+/// Lets a sender retract a message put by mistake, named by the message_id and
+/// timestamp its put returned. Works until the recipient acks it, including
+/// while it is still scheduled; a recipient that already fetched it keeps its
+/// copy.
+#[instrument(skip(state, payload))]
+pub async fn revoke_message_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<RevokeMessageRequest>,
+) -> Result<Json<RevokeMessageResponse>, AppError> {
+    check_clock_skew(&state, "timestamp", payload.timestamp, true)?;
+    let messages = state.messages.clone();
+    let token = AckToken {
+        message_id: payload.message_id.clone(),
+        timestamp: payload.timestamp,
+    };
+    let result = state.ack_lane.run(move || messages.revoke(&token)).await;
+
+    match result {
+        Ok(Ok(revoked)) => {
+            if revoked {
+                state.report_stats.record_acked(1);
+                info!("Revoked a message for {}", payload.message_id);
+            }
+            state.debug.record(
+                &payload.message_id,
+                "revoke",
+                None,
+                if revoked { "deleted" } else { "not found" }.to_string(),
+            );
+            Ok(Json(RevokeMessageResponse { revoked }))
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(lane_error) => {
+            error!("Failed to execute revoke_message task: {}", lane_error);
+            Err(AppError::WebPush(format!("Ack lane error: {}", lane_error)))
+        }
+    }
+}
+
+/// Lets the sender holding a put's `handle` delete the message before any poll
+/// has returned it. Unlike a revoke, no recipient ever sees a cancelled message.
+#[instrument(skip(state, payload))]
+pub async fn cancel_message_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<CancelMessageRequest>,
+) -> Result<Json<CancelMessageResponse>, AppError> {
+    check_clock_skew(&state, "timestamp", payload.timestamp, true)?;
+    let messages = state.messages.clone();
+    let token = AckToken {
+        message_id: payload.message_id.clone(),
+        timestamp: payload.timestamp,
+    };
+    let handle_hash = Sha256::digest(payload.handle.as_bytes());
+    let result = state
+        .ack_lane
+        .run(move || messages.cancel(&token, &handle_hash))
+        .await;
+
+    match result {
+        Ok(Ok(outcome)) => {
+            state.debug.record(
+                &payload.message_id,
+                "cancel",
+                None,
+                match outcome {
+                    CancelOutcome::Cancelled => "deleted",
+                    CancelOutcome::Fetched => "already fetched",
+                    CancelOutcome::NotFound => "not found",
+                }
+                .to_string(),
+            );
+            match outcome {
+                CancelOutcome::Cancelled => {
+                    state.report_stats.record_acked(1);
+                    info!("Cancelled a message for {}", payload.message_id);
+                    Ok(Json(CancelMessageResponse { cancelled: true }))
+                }
+                CancelOutcome::Fetched => Err(AppError::Conflict(
+                    "message was already fetched".to_string(),
+                )),
+                CancelOutcome::NotFound => Ok(Json(CancelMessageResponse { cancelled: false })),
+            }
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(lane_error) => {
+            error!("Failed to execute cancel_message task: {}", lane_error);
+            Err(AppError::WebPush(format!("Ack lane error: {}", lane_error)))
+        }
+    }
+}
+
+/// Tells a sender whether a message it put is still waiting, was fetched by a
+/// poll but not yet acked, or is gone.
+#[instrument(skip(state, payload))]
+pub async fn message_state_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<MessageStateRequest>,
+) -> Result<Json<MessageState>, AppError> {
+    let messages = state.messages.clone();
+    let token = AckToken {
+        message_id: payload.message_id,
+        timestamp: payload.timestamp,
+    };
+    match tokio::task::spawn_blocking(move || messages.message_state(&token)).await {
+        Ok(Ok(message_state)) => Ok(Json(message_state)),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute message_state task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during message_state: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+/// Registers a device's push subscription for the mailboxes it polls, apart
+/// from the polls themselves.
+#[instrument(skip(state, payload))]
+pub async fn subscribe_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<SubscribeRequest>,
+) -> Result<Json<SubscribeResponse>, AppError> {
+    if payload.message_ids.is_empty() {
+        return Err(AppError::InvalidRequest(
+            "message_ids must not be empty".to_string(),
+        ));
+    }
+    if let Some(problem) = check_push_subscription(&state, &payload.push_subscription) {
+        return Err(AppError::InvalidRequest(problem));
+    }
+    for message_id in &payload.message_ids {
+        state.lifecycle.touch(message_id);
+    }
+    let status =
+        save_subscription_handler(State(state), payload.message_ids, payload.push_subscription)
+            .await?;
+    Ok(Json(SubscribeResponse {
+        saved: status == StatusCode::CREATED,
+    }))
+}
+
+// Generous for a BCP 47 tag with a region and script
+const MAX_LOCALE_LEN: usize = 35;
+
+// Rejects subscriptions no registered transport could deliver, so they aren't
+// stored and pushed to.
+fn check_push_subscription(
+    state: &SharedState,
+    subscription: &PushSubscriptionInfo,
+) -> Option<String> {
+    if let Err(problem) = state.push_router.check(subscription) {
+        return Some(problem);
+    }
+    check_locale(subscription)
+}
+
+fn check_locale(subscription: &PushSubscriptionInfo) -> Option<String> {
+    if let Some(locale) = &subscription.locale {
+        let well_formed = locale.len() <= MAX_LOCALE_LEN
+            && locale
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !well_formed {
+            return Some(format!(
+                "push_subscription.locale must be a language tag of at most {} bytes",
+                MAX_LOCALE_LEN
+            ));
+        }
+    }
+    None
+}
+
+/// Removes one device's push subscription from a mailbox, so a client turning
+/// notifications off isn't pushed to until its endpoint expires.
+#[instrument(skip(state, payload))]
+pub async fn unsubscribe_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<UnsubscribeRequest>,
+) -> Result<Json<UnsubscribeResponse>, AppError> {
+    let subscriptions = state.subscriptions.clone();
+    let message_id = payload.message_id.clone();
+    match tokio::task::spawn_blocking(move || {
+        subscriptions.remove_device_subscription(&payload.message_id, &payload.endpoint)
+    })
+    .await
+    {
+        Ok(Ok(removed)) => {
+            state.debug.record(
+                &message_id,
+                "unsubscribe",
+                None,
+                if removed { "removed" } else { "not found" }.to_string(),
+            );
+            Ok(Json(UnsubscribeResponse { removed }))
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute unsubscribe task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during unsubscribe: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+/// Deletes every message, the retained value and the push subscription of one
+/// mailbox, for users rotating or abandoning a channel key. As with acks,
+/// knowing the message_id is the authorization: anyone holding it can already
+/// read and ack it all.
+#[instrument(skip(state, payload))]
+pub async fn purge_channel_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<PurgeChannelRequest>,
+) -> Result<Json<PurgeChannelResponse>, AppError> {
+    let messages = state.messages.clone();
+    let lifecycle = state.lifecycle.clone();
+    let message_id = payload.message_id.clone();
+    let result = state
+        .ack_lane
+        .run(move || {
+            let deleted = messages.purge(&message_id)?;
+            lifecycle.forget(&message_id)?;
+            Ok::<_, kwn_storage::StorageError>(deleted)
+        })
+        .await;
+
+    match result {
+        Ok(Ok(deleted_messages)) => {
+            state.report_stats.record_acked(deleted_messages);
+            info!(
+                "Purged channel {} ({} messages)",
+                payload.message_id, deleted_messages
+            );
+            state.debug.record(
+                &payload.message_id,
+                "purge",
+                None,
+                format!("deleted {} messages", deleted_messages),
+            );
+            if deleted_messages > state.compact_after_deletes
+                || (deleted_messages > 0
+                    && state.messages.deletion_policy() == DeletionPolicy::Overwrite)
+            {
+                schedule_compaction(&state, deleted_messages);
+            }
+            Ok(Json(PurgeChannelResponse { deleted_messages }))
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(lane_error) => {
+            error!("Failed to execute purge_channel task: {}", lane_error);
+            Err(AppError::WebPush(format!("Ack lane error: {}", lane_error)))
+        }
+    }
+}
+
+// Large purges leave enough tombstones to slow prefix scans until fjall's own
+// compaction catches up, so compact right away in the background.
+fn schedule_compaction(state: &SharedState, deleted: usize) {
+    info!("Ack deleted {} messages, scheduling compaction", deleted);
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || match state.messages.compact() {
+        Ok(true) => state.report_stats.record_compaction(),
+        Ok(false) => tracing::debug!("Compaction already running, skipping"),
+        Err(e) => error!("Background compaction failed: {}", e),
+    });
+}
+
+fn record_get(
+    state: &SharedState,
+    message_ids: &[MessageId],
+    results: &[FoundMessage],
+    started: Instant,
+) {
+    if !state.debug.is_active() {
+        return;
+    }
+    for message_id in message_ids {
+        let count = results
+            .iter()
+            .filter(|m| &m.message_id == message_id)
+            .count();
+        state.debug.record(
+            message_id,
+            "get",
+            Some(started.elapsed()),
+            format!("returned {} messages", count),
+        );
+    }
+}
+
+// Remembers when each message was first handed to a recipient, for
+// `/api/message-state`. A failure loses only that record, not the poll.
+pub fn record_delivered(state: &SharedState, tokens: &[AckToken]) {
+    if tokens.is_empty() {
+        return;
+    }
+    if let Err(e) = state.messages.mark_delivered(tokens, Utc::now()) {
+        error!(
+            "Failed to record delivery of {} messages: {}",
+            tokens.len(),
+            e
+        );
+    }
+}
+
+fn delivery_tokens(messages: &[FoundMessage]) -> Vec<AckToken> {
+    messages.iter().map(FoundMessage::ack_token).collect()
+}
+
+// Drops messages leased to another poll and, when this poll asked for a
+// lease, leases the rest to it. The messages are already read, so if leasing
+// fails they are returned unleased rather than failing the poll.
+fn apply_leases(
+    state: &SharedState,
+    lease_secs: Option<u64>,
+    found: Vec<FoundMessage>,
+) -> Vec<FoundMessage> {
+    if found.is_empty() {
+        return found;
+    }
+    let now = Utc::now();
+    let until = lease_secs.map(|secs| now + chrono::Duration::seconds(secs as i64));
+    match state.messages.lease(&delivery_tokens(&found), now, until) {
+        Ok(granted) => found
+            .into_iter()
+            .zip(granted)
+            .filter_map(|(message, free)| free.then_some(message))
+            .collect(),
+        Err(e) => {
+            error!(
+                "Failed to lease {} messages, returning them unleased: {}",
+                found.len(),
+                e
+            );
+            found
+        }
+    }
+}
+
+// Stable sort, so messages with equal (timestamp, message_id) keep their
+// per-id storage order in either direction.
+pub fn sort_messages(messages: &mut [FoundMessage], order: SortOrder) {
+    match order {
+        SortOrder::OldestFirst => {
+            messages.sort_by(|a, b| (a.timestamp, &a.message_id).cmp(&(b.timestamp, &b.message_id)))
+        }
+        SortOrder::NewestFirst => {
+            messages.sort_by(|a, b| (b.timestamp, &b.message_id).cmp(&(a.timestamp, &a.message_id)))
+        }
+    }
+}
+
+/// Drops repeated ids, keeping the first of each in place. A listed-twice id
+/// would otherwise be scanned twice and have its messages returned twice.
+pub fn dedup_message_ids(message_ids: &mut Vec<MessageId>) {
+    let mut seen = HashSet::with_capacity(message_ids.len());
+    message_ids.retain(|message_id| seen.insert(message_id.clone()));
+}
+
+#[instrument(skip(state, payload))]
+#[axum::debug_handler]
+pub async fn get_messages_handler(
+    State(state): State<SharedState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<GetMessagesRequest>,
+) -> Result<Response, AppError> {
+    respond_to_poll(state, &headers, peer, payload).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetMessagesQuery {
+    ids: String, // Comma separated message_ids
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    sort: SortOrder,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    mode: PollMode,
+    lease_secs: Option<u64>,
+    consumer: Option<String>,
+}
+
+/// `GET /api/messages?ids=a,b&timeout_ms=...`: the same poll as
+/// `POST /api/get-messages` for clients that can't easily send a JSON body.
+/// Push subscriptions can only be registered through the POST form.
+#[instrument(skip(state, query))]
+pub async fn get_messages_query_handler(
+    State(state): State<SharedState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<GetMessagesQuery>,
+) -> Result<Response, AppError> {
+    let message_ids = query
+        .ids
+        .split(',')
+        .map(MessageId::parse)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|issue| AppError::InvalidRequest(issue.message))?;
+    let payload = GetMessagesRequest {
+        message_ids,
+        timeout_ms: query.timeout_ms,
+        push_subscription: None,
+        sort: query.sort,
+        keepalive: false,
+        poll_session: None,
+        continue_pending: false,
+        continuation: None,
+        page_size: None,
+        cursors: Default::default(),
+        capabilities: Vec::new(),
+        since: query.since,
+        until: query.until,
+        mode: query.mode,
+        lease_secs: query.lease_secs,
+        consumer: query.consumer,
+        message_id_patterns: Vec::new(),
+        retained: false,
+        retained_seen: Default::default(),
+        signals: false,
+    };
+    respond_to_poll(state, &headers, peer, payload).await
+}
+
+async fn respond_to_poll(
+    state: SharedState,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    mut payload: GetMessagesRequest,
+) -> Result<Response, AppError> {
+    dedup_message_ids(&mut payload.message_ids);
+    if let Some(poll_limit) = &state.poll_limit {
+        if let Err(wait_secs) = poll_limit.check(
+            headers,
+            &payload.message_ids,
+            &client_ip(headers, Some(peer)),
+        ) {
+            return Ok(too_many_requests(RateLimitScope::Poll, wait_secs, None));
+        }
+    }
+    if let (Some(since), Some(until)) = (payload.since, payload.until) {
+        if since >= until {
+            return Err(AppError::InvalidRequest(
+                "since must be earlier than until".to_string(),
+            ));
+        }
+    }
+    // Drop whatever the client asked for but didn't negotiate
+    let negotiated = capabilities::negotiate(&payload.capabilities);
+    if !negotiated.allows(capabilities::CONTINUATIONS) {
+        payload.continue_pending = false;
+        payload.continuation = None;
+    }
+    if !negotiated.allows(capabilities::NOTIFY_MODE) {
+        payload.mode = PollMode::Messages;
+    }
+    if payload.mode == PollMode::Notify {
+        payload.continue_pending = false;
+        payload.page_size = None;
+    }
+    if !negotiated.allows(capabilities::CURSORS) {
+        payload.page_size = None;
+        payload.cursors.clear();
+    }
+    if !negotiated.allows(capabilities::LEASES) {
+        payload.lease_secs = None;
+    }
+    if payload
+        .lease_secs
+        .is_some_and(|secs| secs == 0 || secs > MAX_LEASE_SECS)
+    {
+        return Err(AppError::InvalidRequest(format!(
+            "lease_secs must be between 1 and {}",
+            MAX_LEASE_SECS
+        )));
+    }
+    if !negotiated.allows(capabilities::CONSUMERS) {
+        payload.consumer = None;
+    }
+    if let Some(consumer) = &payload.consumer {
+        check_consumer(consumer)?;
+    }
+    if !negotiated.allows(capabilities::PATTERNS) {
+        payload.message_id_patterns.clear();
+    }
+    if payload.message_id_patterns.len() > MAX_PATTERNS {
+        return Err(AppError::InvalidRequest(format!(
+            "at most {} message_id_patterns",
+            MAX_PATTERNS
+        )));
+    }
+    for pattern in &payload.message_id_patterns {
+        pattern_prefix(pattern)?;
+    }
+    if !negotiated.allows(capabilities::RETAINED) || payload.mode == PollMode::Notify {
+        payload.retained = false;
+    }
+    if !payload.retained {
+        payload.retained_seen.clear();
+    }
+    if !negotiated.allows(capabilities::SIGNALS) || payload.mode == PollMode::Notify {
+        payload.signals = false;
+    }
+    if let Some(token) = payload.continuation.take() {
+        let remaining = state.continuations.redeem(&token).ok_or_else(|| {
+            AppError::InvalidRequest("unknown or expired continuation".to_string())
+        })?;
+        for message_id in remaining {
+            if !payload.message_ids.contains(&message_id) {
+                payload.message_ids.push(message_id);
+            }
+        }
+        payload.continue_pending = true;
+    }
+    let guard = match payload.poll_session.as_deref() {
+        Some(session) => Some(
+            state
+                .poll_sessions
+                .begin(session, &payload.message_ids)
+                .ok_or_else(|| AppError::Conflict("already polling".to_string()))?,
+        ),
+        None => None,
+    };
+    let wants_ndjson = payload.mode == PollMode::Messages
+        && negotiated.allows(capabilities::NDJSON)
+        && headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains(NDJSON_CONTENT_TYPE));
+    if wants_ndjson {
+        return Ok(ndjson_response(state, payload, guard));
+    }
+    match state.keepalive_interval {
+        Some(interval) if payload.keepalive => {
+            Ok(keepalive_response(state, payload, interval, guard))
+        }
+        _ => {
+            let response = poll_messages(state, payload).await?;
+            drop(guard);
+            Ok(Json(response).into_response())
+        }
+    }
+}
+
+// Streams a space every `interval` while the poll waits, then the JSON response.
+// Leading whitespace is valid JSON, so clients parse the body as usual, but NATs
+// see traffic and keep the connection mapped. The status is committed with the
+// first byte, so a late error aborts the stream and the client sees a network
+// error rather than a 200 with a partial body.
+fn keepalive_response(
+    state: SharedState,
+    payload: GetMessagesRequest,
+    interval: Duration,
+    guard: Option<PollGuard>,
+) -> Response {
+    let (tx, rx) = mpsc::channel::<Result<Bytes, AppError>>(1);
+    tokio::spawn(async move {
+        let _guard = guard; // Held until the poll finishes or the client leaves
+        let poll = poll_messages(state, payload);
+        tokio::pin!(poll);
+        let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+        loop {
+            tokio::select! {
+                result = &mut poll => {
+                    let chunk = result.and_then(|response| {
+                        serde_json::to_vec(&response).map(Bytes::from).map_err(AppError::from)
+                    });
+                    if let Ok(bytes) = &chunk {
+                        // Streamed, so the middleware can't see the length
+                        response_size::record("keepalive poll".to_string(), bytes.len());
+                    }
+                    let _ = tx.send(chunk).await;
+                    return;
+                }
+                _ = ticker.tick() => {
+                    if tx.send(Ok(Bytes::from_static(b" "))).await.is_err() {
+                        return; // Client went away, stop polling
+                    }
+                }
+            }
+        }
+    });
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+// Streams each stored message as its own line as soon as it is read, so a large
+// backlog is never buffered whole; the bounded channel holds back the storage
+// scan while the client is slow to read. Waits like a normal poll when nothing
+// is stored yet. As with keepalives, a late error aborts the stream.
+fn ndjson_response(
+    state: SharedState,
+    payload: GetMessagesRequest,
+    guard: Option<PollGuard>,
+) -> Response {
+    let (tx, rx) = mpsc::channel::<Result<Bytes, AppError>>(NDJSON_BUFFER_LINES);
+    tokio::spawn(async move {
+        let _guard = guard;
+        if let Err(e) = stream_messages(state, payload, &tx).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+fn ndjson_line(line: &GetMessagesStreamLine) -> Result<Bytes, serde_json::Error> {
+    let mut bytes = serde_json::to_vec(line)?;
+    bytes.push(b'\n');
+    Ok(Bytes::from(bytes))
+}
+
+async fn stream_messages(
+    state: SharedState,
+    mut payload: GetMessagesRequest,
+    tx: &mpsc::Sender<Result<Bytes, AppError>>,
+) -> Result<(), AppError> {
+    let started = Instant::now();
+    let deadline = poll_deadline(&payload, started);
+    let check_interval = Duration::from_millis(300_000);
+    let resubscribe_ids = prepare_poll(&state, &mut payload).await;
+    let notifiers = register_notifiers(&state, &payload);
+
+    loop {
+        // Armed before the scan so a put landing in between still wakes us
+        let mut notified: Vec<_> = notifiers
+            .handles()
+            .iter()
+            .map(|n| Box::pin(n.notified()))
+            .collect();
+        for future in notified.iter_mut() {
+            future.as_mut().enable();
+        }
+        expand_patterns(&state, &mut payload)?;
+        let acked_through = consumer_cursors(&state, &payload)?;
+
+        let messages = state.messages.clone();
+        let message_ids = payload.message_ids.clone();
+        let lines = tx.clone();
+        let (since, until) = (payload.since, payload.until);
+        let lease_secs = payload.lease_secs;
+        let (streamed, scanned) = match tokio::task::spawn_blocking(move || {
+            let mut streamed = Vec::new();
+            let scanned = messages.scan(&message_ids, &mut |message| {
+                if !in_window(message.timestamp, since, until)
+                    || !past_cursor(&acked_through, &message)
+                {
+                    return true;
+                }
+                let token = message.ack_token();
+                let now = Utc::now();
+                let lease_until =
+                    lease_secs.map(|secs| now + chrono::Duration::seconds(secs as i64));
+                match messages.lease(std::slice::from_ref(&token), now, lease_until) {
+                    Ok(granted) if granted.first().copied().unwrap_or(false) => {}
+                    Ok(_) => return true, // Leased to another poll
+                    // Sent unleased, as in `apply_leases`
+                    Err(e) => error!("Failed to lease a streamed message: {}", e),
+                }
+                let sent = ndjson_line(&GetMessagesStreamLine::Message(message))
+                    .is_ok_and(|line| lines.blocking_send(Ok(line)).is_ok());
+                if sent {
+                    streamed.push(token);
+                }
+                sent
+            });
+            (streamed, scanned)
+        })
+        .await
+        {
+            Ok(result) => result,
+            Err(join_error) => {
+                error!("Failed to execute message stream task: {}", join_error);
+                return Err(AppError::WebPush(format!(
+                    "Task join error during stream: {}",
+                    join_error
+                )));
+            }
+        };
+
+        // Recorded even if the scan failed part way, for what did go out
+        record_delivered(&state, &streamed);
+        scanned?;
+        let now = Instant::now();
+        if !streamed.is_empty() || now >= deadline {
+            tracing::debug!(
+                "Streamed {} messages in {:?}",
+                streamed.len(),
+                started.elapsed()
+            );
+            let end = ndjson_line(&GetMessagesStreamLine::End {
+                resubscribe_required: !resubscribe_ids.is_empty(),
+                resubscribe_ids,
+            })?;
+            let _ = tx.send(Ok(end)).await;
+            return Ok(());
+        }
+
+        tokio::select! {
+            _ = select_all(notified) => {}
+            _ = sleep(check_interval.min(deadline - now)) => {}
+            _ = tx.closed() => return Ok(()), // Client went away, stop polling
+        }
+    }
+}
+
+// Saves the poll's push subscription, if any, and returns the requested ids
+// whose push subscription expired and hasn't been replaced yet.
+async fn prepare_poll(state: &SharedState, payload: &mut GetMessagesRequest) -> Vec<MessageId> {
+    for message_id in &payload.message_ids {
+        state.lifecycle.touch(message_id);
+    }
+    // Older clients still send their subscription with each poll. It is saved
+    // alongside the poll rather than ahead of it; newer ones use /api/subscribe.
+    if let Some(push_subscription) = payload.push_subscription.take() {
+        let state = state.clone();
+        let message_ids = payload.message_ids.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                save_subscription_handler(State(state), message_ids, push_subscription).await
+            {
+                error!("Failed to save subscription during poll: {:?}", e);
+            }
+        });
+    }
+    // Not worth failing the poll over: messages may be waiting
+    state
+        .subscriptions
+        .resubscribe_required(&payload.message_ids)
+        .unwrap_or_else(|e| {
+            error!("Failed to read resubscribe flags during poll: {}", e);
+            Vec::new()
+        })
+}
+
+pub async fn poll_messages(
+    state: SharedState,
+    mut payload: GetMessagesRequest,
+) -> Result<GetMessagesResponse, AppError> {
+    let started = Instant::now();
+    let deadline = poll_deadline(&payload, started);
+    let check_interval = Duration::from_millis(300_000); // Check DB every 5 minutes
+
+    let resubscribe_ids = prepare_poll(&state, &mut payload).await;
+    let continue_pending = payload.continue_pending;
+    // Takes the poll's ids, which grow as its patterns match new ones
+    let respond = |message_ids: &[MessageId], results: Vec<FoundMessage>| {
+        // Hand the ids that came back empty to a continuation so they keep waiting
+        let continuation = if continue_pending {
+            let answered: HashSet<&MessageId> = results.iter().map(|m| &m.message_id).collect();
+            let remaining: Vec<MessageId> = message_ids
+                .iter()
+                .filter(|id| !answered.contains(id))
+                .cloned()
+                .collect();
+            if remaining.is_empty() {
+                None
+            } else {
+                state.continuations.issue(remaining)
+            }
+        } else {
+            None
+        };
+        GetMessagesResponse {
+            results,
+            resubscribe_required: !resubscribe_ids.is_empty(),
+            resubscribe_ids: resubscribe_ids.clone(),
+            continuation,
+            cursors: BTreeMap::new(),
+            pending_ids: Vec::new(),
+            retained: Vec::new(),
+            signals: Vec::new(),
+            truncated: false,
+        }
+    };
+
+    // Get or create notifiers for the requested message IDs
+    let notifiers = register_notifiers(&state, &payload);
+    let mut signals = payload
+        .signals
+        .then(|| state.signals.subscribe(&payload.message_ids));
+
+    let mut after = HashMap::new();
+    for (message_id, cursor) in &payload.cursors {
+        after.insert(message_id.clone(), decode_cursor(cursor)?);
+    }
+    let since_after = payload
+        .since
+        .map(|since| since - chrono::Duration::milliseconds(1));
+
+    loop {
+        // Armed before the scan so a put landing in between still wakes us
+        let mut notified: Vec<_> = notifiers
+            .handles()
+            .iter()
+            .map(|n| Box::pin(n.notified()))
+            .collect();
+        for future in notified.iter_mut() {
+            future.as_mut().enable();
+        }
+        expand_patterns(&state, &mut payload)?;
+        let acked_through = consumer_cursors(&state, &payload)?;
+        if payload.mode == PollMode::Notify {
+            let pending_ids = pending_ids(&state, &payload, &acked_through)?;
+            if !pending_ids.is_empty() {
+                return Ok(GetMessagesResponse {
+                    pending_ids,
+                    ..respond(&payload.message_ids, Vec::new())
+                });
+            }
+        } else {
+            let (found_messages_this_iteration, mut cursors) =
+                read_messages(&state, &payload, &after, since_after, &acked_through)?;
+            let retained = read_retained(&state, &payload)?;
+            // Capped before leasing, so what is left out stays free for the next poll
+            let (mut found_messages_this_iteration, left_out) = fit_messages(
+                found_messages_this_iteration,
+                state.max_response_bytes,
+                json_len(&retained),
+            );
+            if !left_out.is_empty() {
+                warn!(
+                    "Left {} messages out of a poll response to stay under {} bytes",
+                    left_out.len(),
+                    state.max_response_bytes
+                );
+                if payload.page_size.is_some() {
+                    resume_cursors(
+                        &mut cursors,
+                        &found_messages_this_iteration,
+                        &left_out,
+                        |message_id| {
+                            after
+                                .get(message_id)
+                                .copied()
+                                .max(since_after)
+                                .max(acked_through.get(message_id).copied())
+                        },
+                    );
+                }
+            }
+            sort_messages(&mut found_messages_this_iteration, payload.sort);
+            let found_messages_this_iteration =
+                apply_leases(&state, payload.lease_secs, found_messages_this_iteration);
+
+            if !found_messages_this_iteration.is_empty() || !retained.is_empty() {
+                // We found messages. Return them. Frontend will ACK later.
+                tracing::debug!(
+                    "Found {} messages, returning (no deletion).",
+                    found_messages_this_iteration.len()
+                );
+                record_get(
+                    &state,
+                    &payload.message_ids,
+                    &found_messages_this_iteration,
+                    started,
+                );
+                record_delivered(&state, &delivery_tokens(&found_messages_this_iteration));
+                let mut response = respond(&payload.message_ids, found_messages_this_iteration);
+                response.cursors = cursors;
+                response.retained = retained;
+                response.truncated = !left_out.is_empty();
+                response.signals = signals
+                    .as_mut()
+                    .map(SignalReceiver::drain)
+                    .unwrap_or_default();
+                return Ok(response);
+            }
+        }
+
+        // No messages were found in this iteration. Check timeout and potentially sleep.
+        let now = Instant::now();
+        if now >= deadline {
+            tracing::debug!("Long poll timeout reached.");
+            record_get(&state, &payload.message_ids, &[], started);
+            return Ok(respond(&payload.message_ids, vec![])); // Timeout, return empty
+        }
+
+        // Wait before the next check, respecting the deadline
+        let remaining_time = deadline - now;
+        let sleep_duration = std::cmp::min(check_interval, remaining_time);
+
+        tracing::trace!(
+            "No messages found, waiting for notification or timeout ({:?})...",
+            sleep_duration
+        );
+
+        // Wait for notification or sleep timeout
+        tokio::select! {
+            // Wait for any of the notifiers to trigger
+            _ = select_all(notified) => {
+                tracing::trace!("Notification received, re-checking for messages.");
+                // No sleep, loop immediately to check DB
+            }
+            // Signals are never stored, so hand them over as they come
+            received = next_signals(&mut signals) => {
+                if !received.is_empty() {
+                    record_get(&state, &payload.message_ids, &[], started);
+                    return Ok(GetMessagesResponse {
+                        signals: received,
+                        ..respond(&payload.message_ids, Vec::new())
+                    });
+                }
+            }
+            // Wait for the calculated sleep duration
+            _ = sleep(sleep_duration) => {
+                 tracing::trace!("Slept for {:?}, checking again.", sleep_duration);
+                 // Continue loop, will check deadline at the top
+            }
+        }
+    } // End loop
+}
+
+// Points the cursor of each id that lost messages to the size cap at its last
+// kept message, or where its page started if none was kept, so the next page
+// begins with the first left out.
+fn resume_cursors(
+    cursors: &mut BTreeMap<MessageId, String>,
+    kept: &[FoundMessage],
+    left_out: &[FoundMessage],
+    page_start: impl Fn(&MessageId) -> Option<DateTime<Utc>>,
+) {
+    for message in left_out {
+        let message_id = &message.message_id;
+        let resume_after = kept
+            .iter()
+            .filter(|m| &m.message_id == message_id)
+            .map(|m| m.timestamp)
+            .max()
+            .or_else(|| page_start(message_id))
+            .unwrap_or(DateTime::UNIX_EPOCH);
+        cursors.insert(message_id.clone(), encode_cursor(resume_after));
+    }
+}
+
+// Pending forever for polls that didn't ask for signals.
+async fn next_signals(receiver: &mut Option<SignalReceiver>) -> Vec<Signal> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// The retained values of the poll's ids that the client doesn't already hold.
+fn read_retained(
+    state: &SharedState,
+    payload: &GetMessagesRequest,
+) -> Result<Vec<FoundMessage>, AppError> {
+    if !payload.retained {
+        return Ok(Vec::new());
+    }
+    let mut retained = state.messages.retained(&payload.message_ids)?;
+    retained.retain(|m| {
+        payload
+            .retained_seen
+            .get(&m.message_id)
+            .is_none_or(|seen| m.timestamp > *seen)
+    });
+    Ok(retained)
+}
+
+// One pass over the poll's ids: everything in its window past the consumer's
+// cursor or, when paging, a page per id and cursors for the ids with more.
+fn read_messages(
+    state: &SharedState,
+    payload: &GetMessagesRequest,
+    after: &HashMap<MessageId, DateTime<Utc>>,
+    since_after: Option<DateTime<Utc>>,
+    acked_through: &HashMap<MessageId, DateTime<Utc>>,
+) -> Result<(Vec<FoundMessage>, BTreeMap<MessageId, String>), AppError> {
+    let mut cursors = BTreeMap::new();
+    let Some(page_size) = payload.page_size else {
+        let mut found = state.messages.fetch(&payload.message_ids)?;
+        found.retain(|m| {
+            in_window(m.timestamp, payload.since, payload.until) && past_cursor(acked_through, m)
+        });
+        return Ok((found, cursors));
+    };
+    let mut found = Vec::new();
+    for message_id in &payload.message_ids {
+        let (mut page, mut more) = state.messages.page(
+            message_id,
+            after
+                .get(message_id)
+                .copied()
+                .max(since_after)
+                .max(acked_through.get(message_id).copied()),
+            page_size.max(1),
+        )?;
+        if let Some(until) = payload.until {
+            // Pages are oldest first, so nothing past this one is in the window
+            let before = page.len();
+            page.retain(|m| m.timestamp < until);
+            more &= page.len() == before;
+        }
+        if more {
+            if let Some(last) = page.last() {
+                cursors.insert(message_id.clone(), encode_cursor(last.timestamp));
+            }
+        }
+        found.extend(page);
+    }
+    Ok((found, cursors))
+}
+
+// The poll's ids holding messages in its window. Counts keys rather than reading
+// bodies unless a window makes timestamps matter.
+fn pending_ids(
+    state: &SharedState,
+    payload: &GetMessagesRequest,
+    acked_through: &HashMap<MessageId, DateTime<Utc>>,
+) -> Result<Vec<MessageId>, AppError> {
+    if payload.since.is_none() && payload.until.is_none() && acked_through.is_empty() {
+        let counts = state.messages.pending_counts(&payload.message_ids)?;
+        return Ok(payload
+            .message_ids
+            .iter()
+            .zip(counts)
+            .filter(|(_, count)| *count > 0)
+            .map(|(message_id, _)| message_id.clone())
+            .collect());
+    }
+    let mut pending = Vec::new();
+    for message_id in &payload.message_ids {
+        let mut any = false;
+        state
+            .messages
+            .scan(std::slice::from_ref(message_id), &mut |message| {
+                any = in_window(message.timestamp, payload.since, payload.until)
+                    && past_cursor(acked_through, &message);
+                !any
+            })?;
+        if any {
+            pending.push(message_id.clone());
+        }
+    }
+    Ok(pending)
+}
+
+// `since` is inclusive and `until` exclusive; either may be left open.
+fn in_window(
+    timestamp: DateTime<Utc>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> bool {
+    since.is_none_or(|since| timestamp >= since) && until.is_none_or(|until| timestamp < until)
+}
+
+// The part of a `<root>/*` pattern ids must start with, root and slash included.
+fn pattern_prefix(pattern: &str) -> Result<&str, AppError> {
+    let prefix = pattern
+        .strip_suffix('*')
+        .filter(|prefix| prefix.len() > MIN_PATTERN_ROOT_LEN && prefix.ends_with('/'))
+        .filter(|prefix| check_message_id(prefix).is_none());
+    prefix.ok_or_else(|| {
+        AppError::InvalidRequest(format!(
+            "message_id_patterns must look like <root>/* with a root of at least {} bytes",
+            MIN_PATTERN_ROOT_LEN
+        ))
+    })
+}
+
+// Adds the ids now holding messages under the poll's patterns to its ids.
+fn expand_patterns(state: &SharedState, payload: &mut GetMessagesRequest) -> Result<(), AppError> {
+    for pattern in &payload.message_id_patterns {
+        let prefix = pattern_prefix(pattern)?;
+        for message_id in state.messages.ids_with_prefix(prefix, MAX_PATTERN_IDS)? {
+            if !payload.message_ids.contains(&message_id) {
+                payload.message_ids.push(message_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+// One handle per listed id, plus one per pattern that wakes for ids under it
+// not yet listed.
+fn register_notifiers(state: &SharedState, payload: &GetMessagesRequest) -> Waiters {
+    let prefixes: Vec<&str> = payload
+        .message_id_patterns
+        .iter()
+        .filter_map(|pattern| pattern.strip_suffix('*'))
+        .collect();
+    Waiters::register(&state.notifier, &payload.message_ids, &prefixes)
+}
+
+// Consumer names end up in storage keys, so they are kept short and plain.
+fn check_consumer(consumer: &str) -> Result<(), AppError> {
+    let valid = !consumer.is_empty()
+        && consumer.len() <= MAX_CONSUMER_LEN
+        && consumer
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(AppError::InvalidRequest(format!(
+            "consumer must be 1 to {} letters, digits, '-' or '_'",
+            MAX_CONSUMER_LEN
+        )));
+    }
+    Ok(())
+}
+
+// The poll's consumer's cursor on each id it has acked something in, joining
+// it to the ids it hasn't read before. Empty for polls without a consumer.
+fn consumer_cursors(
+    state: &SharedState,
+    payload: &GetMessagesRequest,
+) -> Result<HashMap<MessageId, DateTime<Utc>>, AppError> {
+    let Some(consumer) = payload.consumer.as_deref() else {
+        return Ok(HashMap::new());
+    };
+    let cursors = state
+        .messages
+        .join_consumer(&payload.message_ids, consumer)?;
+    Ok(payload
+        .message_ids
+        .iter()
+        .zip(cursors)
+        .filter_map(|(message_id, cursor)| Some((message_id.clone(), cursor?)))
+        .collect())
+}
+
+fn past_cursor(acked_through: &HashMap<MessageId, DateTime<Utc>>, message: &FoundMessage) -> bool {
+    acked_through
+        .get(&message.message_id)
+        .is_none_or(|cursor| message.timestamp > *cursor)
+}
+
+// When a poll stops waiting. New messages can never fall inside a window that
+// has already closed, so such polls answer at once.
+fn poll_deadline(payload: &GetMessagesRequest, started: Instant) -> Instant {
+    if payload.until.is_some_and(|until| until <= Utc::now()) {
+        return started;
+    }
+    started + Duration::from_millis(payload.timeout_ms.unwrap_or(300_000)) // Default 5 minutes
+}
+
+// Cursors are the hex of the last returned message's millisecond timestamp.
+// Clients must treat them as opaque so the encoding can change.
+fn encode_cursor(timestamp: DateTime<Utc>) -> String {
+    hex::encode(timestamp.timestamp_millis().to_be_bytes())
+}
+
+fn decode_cursor(cursor: &str) -> Result<DateTime<Utc>, AppError> {
+    hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| <[u8; 8]>::try_from(bytes).ok())
+        .and_then(|bytes| DateTime::from_timestamp_millis(i64::from_be_bytes(bytes)))
+        .ok_or_else(|| AppError::InvalidRequest(format!("invalid cursor {}", cursor)))
+}
+
+/// Handler to receive and store a push subscription from the client
+async fn save_subscription_handler(
+    State(state): State<SharedState>, // Extract shared state
+    message_ids: Vec<MessageId>,
+    push_subscription: PushSubscriptionInfo,
+) -> Result<StatusCode, AppError> {
+    let endpoint = EndpointHash::of(&push_subscription); // For logging outside blocking task
+    info!("Received subscription request for endpoint {}", endpoint);
+
+    // Clone necessary data for the blocking task
+    let subscriptions = state.subscriptions.clone();
+
+    // Execute blocking database operations in a dedicated thread pool
+    let result = tokio::task::spawn_blocking(move || {
+        subscriptions.save_subscription(&message_ids, &push_subscription)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(true)) => {
+            // Log success after blocking task completes
+            info!(
+                "Subscription stored successfully for endpoint: {}",
+                endpoint // Use the cloned endpoint
+            );
+            Ok(StatusCode::CREATED)
+        }
+        Ok(Ok(false)) => Ok(StatusCode::OK), // Already stored as sent
+        Ok(Err(storage_error)) => Err(storage_error.into()), // Propagate error from blocking task
+        Err(join_error) => {
+            error!("Failed to execute save_subscription task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error: {}",
+                join_error
+            ))) // Or a more generic internal error
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kwn_storage::{FjallStore, MessageStore};
+
+    fn found(message_id: &str, millis: i64) -> FoundMessage {
+        FoundMessage {
+            message_id: MessageId::parse(message_id).unwrap(),
+            message: format!("{} at {}", message_id, millis),
+            timestamp: DateTime::from_timestamp_millis(millis).unwrap(),
+            sequence: None,
+        }
+    }
+
+    fn order(messages: &[FoundMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.message.as_str()).collect()
+    }
+
+    // Scans return each id's messages together, as these do
+    fn scanned() -> Vec<FoundMessage> {
+        vec![
+            found("bob", 1_000),
+            found("bob", 3_000),
+            found("alice", 2_000),
+            found("alice", 3_000),
+        ]
+    }
+
+    #[test]
+    fn oldest_first_interleaves_ids_by_timestamp() {
+        let mut messages = scanned();
+        sort_messages(&mut messages, SortOrder::OldestFirst);
+        assert_eq!(
+            order(&messages),
+            [
+                "bob at 1000",
+                "alice at 2000",
+                "alice at 3000",
+                "bob at 3000"
+            ]
+        );
+    }
+
+    #[test]
+    fn newest_first_reverses_timestamp_and_tiebreak() {
+        let mut messages = scanned();
+        sort_messages(&mut messages, SortOrder::NewestFirst);
+        assert_eq!(
+            order(&messages),
+            [
+                "bob at 3000",
+                "alice at 3000",
+                "alice at 2000",
+                "bob at 1000"
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_ids_return_each_message_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FjallStore::open(dir.path()).unwrap();
+        for message in [found("alice", 1_000), found("bob", 2_000)] {
+            store
+                .put(
+                    &message.message_id,
+                    &message.message,
+                    message.timestamp,
+                    None,
+                )
+                .unwrap();
+        }
+        let alice = MessageId::parse("alice").unwrap();
+        let bob = MessageId::parse("bob").unwrap();
+
+        let mut message_ids = vec![bob.clone(), alice.clone(), bob.clone(), alice, bob];
+        dedup_message_ids(&mut message_ids);
+        let ids: Vec<&str> = message_ids.iter().map(MessageId::as_str).collect();
+        assert_eq!(ids, ["bob", "alice"]);
+        let mut messages = store.fetch(&message_ids).unwrap();
+        sort_messages(&mut messages, SortOrder::OldestFirst);
+        assert_eq!(order(&messages), ["alice at 1000", "bob at 2000"]);
+    }
+}
@@ -0,0 +1,34 @@
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+};
+use kwn_storage::StorageHealth;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{metrics::record_storage_health, state::SharedState};
+
+#[derive(Serialize, Debug)]
+pub struct ReadinessResponse {
+    ready: bool,
+    storage: StorageHealth,
+}
+
+/// Reports not-ready while fjall is close to stalling writes, so a load
+/// balancer drains traffic before requests start timing out.
+pub async fn readyz_handler(
+    State(state): State<SharedState>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let storage = state.messages.health();
+    record_storage_health(&storage);
+    let ready = !storage.stall_risk;
+    if !ready {
+        warn!("Not ready: storage under write pressure: {:?}", storage);
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadinessResponse { ready, storage }))
+}
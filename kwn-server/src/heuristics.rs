@@ -0,0 +1,192 @@
+//! Operator-defined rules on the shape of put payloads.
+//!
+//! Bodies are end-to-end encrypted, but their size, byte entropy and how often
+//! one size repeats still set floods and unencrypted spam apart from ordinary
+//! traffic. Rules are loaded from the JSON file named by `PUT_HEURISTICS_FILE`
+//! and checked against every message a put stores:
+//!
+//! ```json
+//! {
+//!   "dry_run": true,
+//!   "rules": [
+//!     { "name": "size-burst", "kind": "identical_size", "max": 20, "window_secs": 60 },
+//!     { "name": "plaintext", "kind": "low_entropy", "min_size": 256, "min_bits_per_byte": 4.5 },
+//!     { "name": "banned-size", "kind": "size_range", "min": 1337, "max": 1337 }
+//!   ]
+//! }
+//! ```
+//!
+//! In dry-run mode matches are only logged and counted in
+//! `kwn_put_heuristic_matches_total`, so a rule can be tuned against real
+//! traffic before it rejects anything.
+
+use dashmap::DashMap;
+use kwn_protocol::MessageId;
+use metrics::counter;
+use serde::Deserialize;
+use std::{net::IpAddr, time::Instant};
+use tracing::warn;
+
+// Windows tracked before stale ones are dropped.
+const MAX_TRACKED_WINDOWS: usize = 100_000;
+
+#[derive(Deserialize)]
+struct HeuristicsConfig {
+    #[serde(default)]
+    dry_run: bool,
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    name: String,
+    #[serde(flatten)]
+    kind: RuleKind,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RuleKind {
+    /// More than `max` messages of one exact size to one mailbox from one IP
+    /// bucket within a `window_secs` window.
+    IdenticalSize { max: u32, window_secs: u64 },
+    /// Bodies of at least `min_size` bytes whose byte entropy is below
+    /// `min_bits_per_byte`. Base64 ciphertext sits near 6.
+    LowEntropy {
+        min_size: usize,
+        min_bits_per_byte: f64,
+    },
+    /// Bodies whose size falls within `min..=max`.
+    SizeRange { min: usize, max: usize },
+}
+
+// (rule index, mailbox, IP bucket, body size)
+type WindowKey = (usize, MessageId, String, usize);
+
+pub struct PutHeuristics {
+    rules: Vec<Rule>,
+    dry_run: bool,
+    windows: DashMap<WindowKey, (Instant, u32)>,
+}
+
+impl PutHeuristics {
+    /// Loads the rules file if one is configured.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(rules_file) = std::env::var("PUT_HEURISTICS_FILE") else {
+            return Ok(None);
+        };
+        let config: HeuristicsConfig = serde_json::from_str(&std::fs::read_to_string(rules_file)?)?;
+        Ok(Some(Self {
+            rules: config.rules,
+            dry_run: config.dry_run,
+            windows: DashMap::new(),
+        }))
+    }
+
+    /// Checks one message put by `client_ip`, returning the name of the first
+    /// rule it breaks. Always `None` in dry-run mode.
+    pub fn check(&self, message_id: &MessageId, message: &str, client_ip: &str) -> Option<&str> {
+        let mut broken = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            let matched = match &rule.kind {
+                RuleKind::IdenticalSize { max, window_secs } => {
+                    self.count_in_window(
+                        (
+                            index,
+                            message_id.clone(),
+                            ip_bucket(client_ip),
+                            message.len(),
+                        ),
+                        *window_secs,
+                    ) > *max
+                }
+                RuleKind::LowEntropy {
+                    min_size,
+                    min_bits_per_byte,
+                } => message.len() >= *min_size && entropy(message.as_bytes()) < *min_bits_per_byte,
+                RuleKind::SizeRange { min, max } => (*min..=*max).contains(&message.len()),
+            };
+            if !matched {
+                continue;
+            }
+            let mode = if self.dry_run { "dry_run" } else { "enforced" };
+            counter!("kwn_put_heuristic_matches_total", "rule" => rule.name.clone(), "mode" => mode)
+                .increment(1);
+            warn!(
+                "Put of {} bytes to {} matched heuristic {} ({})",
+                message.len(),
+                message_id,
+                rule.name,
+                mode
+            );
+            if !self.dry_run && broken.is_none() {
+                broken = Some(rule.name.as_str());
+            }
+        }
+        broken
+    }
+
+    // Counts this put in its key's fixed window, returning the window's total.
+    fn count_in_window(&self, key: WindowKey, window_secs: u64) -> u32 {
+        let now = Instant::now();
+        if self.windows.len() >= MAX_TRACKED_WINDOWS {
+            let longest = self
+                .rules
+                .iter()
+                .filter_map(|rule| match rule.kind {
+                    RuleKind::IdenticalSize { window_secs, .. } => Some(window_secs),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+            self.windows
+                .retain(|_, (started, _)| now.duration_since(*started).as_secs() < longest);
+        }
+        let mut window = self.windows.entry(key).or_insert((now, 0));
+        let (started, count) = window.value_mut();
+        if now.duration_since(*started).as_secs() >= window_secs {
+            *started = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count
+    }
+}
+
+// Groups neighbouring addresses: the /24 of an IPv4 address, the /64 of IPv6.
+fn ip_bucket(client_ip: &str) -> String {
+    match client_ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+        Err(_) => client_ip.to_string(),
+    }
+}
+
+// Shannon entropy of the byte distribution, in bits per byte.
+fn entropy(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
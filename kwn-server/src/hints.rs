@@ -0,0 +1,52 @@
+//! Signed operational hints delivered through each mailbox's system channel.
+//!
+//! Every mailbox has a reserved companion channel whose id is the hex SHA-256
+//! of `kwn-system\n` followed by the mailbox's id. Clients add it to their polls
+//! and read and ack hints like any other message. Anyone who knows a mailbox's
+//! id can put to its system channel too, so clients only trust a
+//! [`SignedHint`] whose signature verifies: ECDSA P-256 with SHA-256, in raw
+//! `r || s` form, base64url without padding, over `kwn-system-hint\n` followed
+//! by the `hint` string. The key is the VAPID key, whose public half clients
+//! already ship for push subscriptions; the prefix keeps these signatures
+//! apart from VAPID's JWTs.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kwn_protocol::{MessageId, SignedHint, SystemHint};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use sha2::{Digest, Sha256};
+
+const CHANNEL_PREFIX: &[u8] = b"kwn-system\n";
+const SIGNATURE_PREFIX: &[u8] = b"kwn-system-hint\n";
+
+/// The system channel belonging to `message_id`.
+pub fn system_channel(message_id: &MessageId) -> MessageId {
+    let mut hasher = Sha256::new();
+    hasher.update(CHANNEL_PREFIX);
+    hasher.update(message_id.as_bytes());
+    MessageId::parse(hex::encode(hasher.finalize()))
+        .expect("a SHA-256 hex digest is a valid message_id")
+}
+
+pub struct HintSigner {
+    key: SigningKey,
+}
+
+impl HintSigner {
+    /// Loads the VAPID private key, which startup has already checked is set.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let encoded = kwn_push::vapid_private_key()?;
+        let key = SigningKey::from_slice(&URL_SAFE_NO_PAD.decode(encoded.trim())?)?;
+        Ok(Self { key })
+    }
+
+    pub fn sign(&self, hint: &SystemHint) -> Result<SignedHint, serde_json::Error> {
+        let hint = serde_json::to_string(hint)?;
+        let mut signed = SIGNATURE_PREFIX.to_vec();
+        signed.extend_from_slice(hint.as_bytes());
+        let signature: Signature = self.key.sign(&signed);
+        Ok(SignedHint {
+            hint,
+            signature: URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        })
+    }
+}
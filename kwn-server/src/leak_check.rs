@@ -0,0 +1,104 @@
+//! Continuous verification that the delivery paths clean up after themselves.
+//!
+//! Every `LEAK_CHECK_INTERVAL_SECS` (default 300; 0 turns it off) a slice of
+//! storage starting at a random key is checked for records that shouldn't
+//! exist: messages delivered over `LEAK_CHECK_STALE_HOURS` (default 168) ago
+//! that were never acked and aren't leased, expired messages the hourly sweep
+//! missed, and delivery, lease, handle or receipt records whose message is gone.
+//! Each turns up as a `kwn_delivery_anomalies_total` count, and each pass with
+//! findings is logged to the `audit` target, naming mailboxes by hash only.
+
+use chrono::Utc;
+use kwn_storage::DeliveryAnomalies;
+use metrics::{counter, gauge};
+use rand::RngCore;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::{error, warn};
+
+use crate::{debug_capture::mailbox_hash, state::SharedState};
+
+// The sweep runs hourly, so an expired message may legitimately live this long
+const SWEEP_GRACE: chrono::Duration = chrono::Duration::hours(2);
+
+pub struct LeakCheck {
+    interval: Duration,
+    sample: usize,
+    stale_after: chrono::Duration,
+}
+
+impl LeakCheck {
+    /// None when checks are turned off.
+    pub fn from_env() -> Option<Self> {
+        let interval_secs = std::env::var("LEAK_CHECK_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(300);
+        if interval_secs == 0 {
+            return None;
+        }
+        Some(Self {
+            interval: Duration::from_secs(interval_secs),
+            sample: std::env::var("LEAK_CHECK_SAMPLE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+            stale_after: chrono::Duration::hours(
+                std::env::var("LEAK_CHECK_STALE_HOURS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(168),
+            ),
+        })
+    }
+}
+
+/// Checks a fresh sample every interval, forever.
+pub async fn run_leak_checks(state: SharedState, check: LeakCheck) {
+    let mut ticker = interval(check.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let messages = state.messages.clone();
+        let now = Utc::now();
+        let mut from = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut from);
+        let (limit, stale_before) = (check.sample, now - check.stale_after);
+        match tokio::task::spawn_blocking(move || {
+            messages.check_delivery(&from, limit, now, stale_before, now - SWEEP_GRACE)
+        })
+        .await
+        {
+            Ok(Ok(anomalies)) => report(&anomalies),
+            Ok(Err(e)) => error!("Delivery leak check failed: {}", e),
+            Err(e) => error!("Delivery leak check task failed: {}", e),
+        }
+    }
+}
+
+fn report(anomalies: &DeliveryAnomalies) {
+    gauge!("kwn_delivery_records_checked").set(anomalies.checked as f64);
+    for (kind, count) in [
+        ("stale_delivered", anomalies.stale_delivered),
+        ("unswept_expired", anomalies.unswept_expired),
+        ("orphaned_record", anomalies.orphaned_records),
+    ] {
+        counter!("kwn_delivery_anomalies_total", "kind" => kind).increment(count as u64);
+    }
+    if anomalies.is_empty() {
+        return;
+    }
+    let mailboxes: Vec<String> = anomalies
+        .mailboxes
+        .iter()
+        .map(|message_id| mailbox_hash(message_id.as_str()))
+        .collect();
+    warn!(
+        target: "audit",
+        stale_delivered = anomalies.stale_delivered,
+        unswept_expired = anomalies.unswept_expired,
+        orphaned_records = anomalies.orphaned_records,
+        checked = anomalies.checked,
+        ?mailboxes,
+        "Delivery invariant violated"
+    );
+}
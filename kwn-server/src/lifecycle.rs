@@ -0,0 +1,113 @@
+//! Mailbox lifecycle: one place that knows when each id was last active and
+//! reaps its transient state once it has been idle for `MAILBOX_IDLE_DAYS`
+//! (default 30).
+//!
+//! Handlers call [`MailboxLifecycle::touch`] on puts, polls and acks. Activity
+//! is tracked in memory, so at startup every id with stored subscription state
+//! is seeded as active "now" and reaped a full idle period later if it never
+//! shows up again. Reaping drops the in-memory notifier entry and the stored
+//! resubscribe flag, consumer cursors, leases and sequence counter. Since the
+//! activity view doesn't survive restarts, push subscriptions are kept: a
+//! mailbox that only receives pushes may never refresh them, so they go when
+//! the push service reports them gone or the owner purges the channel. Stored
+//! messages are left alone; they leave through acks.
+
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use kwn_protocol::MessageId;
+use kwn_storage::{StorageError, SubscriptionStore};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::notifier::Notifier;
+
+#[derive(Debug, Clone, Copy)]
+struct Activity {
+    #[allow(dead_code)] // Kept for debugging dumps
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+pub struct MailboxLifecycle {
+    idle_after: Duration,
+    activity: DashMap<MessageId, Activity>,
+    subscriptions: Arc<dyn SubscriptionStore>,
+    notifier: Arc<dyn Notifier>,
+}
+
+impl MailboxLifecycle {
+    pub fn new(
+        idle_after: Duration,
+        subscriptions: Arc<dyn SubscriptionStore>,
+        notifier: Arc<dyn Notifier>,
+    ) -> Self {
+        Self {
+            idle_after,
+            activity: DashMap::new(),
+            subscriptions,
+            notifier,
+        }
+    }
+
+    /// Seeds activity for ids with stored subscription state. Blocking.
+    pub fn seed(&self) -> Result<usize, StorageError> {
+        let now = Utc::now();
+        let ids = self.subscriptions.subscribed_ids()?;
+        let seeded = ids.len();
+        for id in ids {
+            self.activity.entry(id).or_insert(Activity {
+                first_seen: now,
+                last_seen: now,
+            });
+        }
+        Ok(seeded)
+    }
+
+    pub fn touch(&self, message_id: &MessageId) {
+        let now = Utc::now();
+        match self.activity.get_mut(message_id) {
+            Some(mut activity) => activity.last_seen = now,
+            None => {
+                self.activity.insert(
+                    message_id.clone(),
+                    Activity {
+                        first_seen: now,
+                        last_seen: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Drops `message_id`'s subscription state and activity right away, for
+    /// mailboxes their owners abandoned. Blocking.
+    pub fn forget(&self, message_id: &MessageId) -> Result<(), StorageError> {
+        self.subscriptions.forget_mailbox(message_id)?;
+        self.notifier.forget(message_id);
+        self.activity.remove(message_id);
+        Ok(())
+    }
+
+    /// Drops the bookkeeping and in-memory state of every mailbox idle since
+    /// before `now - idle_after`, returning how many were reaped. Blocking.
+    pub fn reap(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        let cutoff = now - self.idle_after;
+        let idle: Vec<MessageId> = self
+            .activity
+            .iter()
+            .filter(|entry| entry.last_seen < cutoff)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for message_id in &idle {
+            self.subscriptions.reap_mailbox(message_id)?;
+            self.notifier.forget(message_id);
+            // Skip ids touched again while we were reaping
+            self.activity
+                .remove_if(message_id, |_, activity| activity.last_seen < cutoff);
+        }
+        if !idle.is_empty() {
+            info!("Reaped state for {} idle mailbox(es)", idle.len());
+        }
+        Ok(idle.len())
+    }
+}
@@ -0,0 +1,234 @@
+//! HTTP listeners and the routes each one serves.
+//!
+//! By default one listener on `PORT` serves every route. `LISTENERS` splits them
+//! across several, as `addr=groups` pairs separated by `;`, for instance
+//! `0.0.0.0:3000=write;10.8.0.1:3001=read,admin` to take puts from the internet
+//! while gets and acks answer only on a VPN address. The groups are `write`
+//! (sending), `read` (polling and acking) and `admin`. Every listener builds its
+//! own middleware stack, so each has its own per-IP budget, and all of them
+//! serve `/api/info` and `/readyz`.
+
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{get, post},
+    Router,
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tower_governor::{
+    governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor, GovernorLayer,
+};
+
+use crate::{
+    admin,
+    capabilities::info_handler,
+    concurrency::limit_concurrency,
+    email::email_fallback_handler,
+    events::events_handler,
+    handlers::{
+        ack_before_handler, ack_messages_handler, cancel_message_handler, get_messages_handler,
+        get_messages_query_handler, has_messages_handler, message_state_handler,
+        purge_channel_handler, put_message_handler, put_messages_handler, put_multi_handler,
+        revoke_message_handler, subscribe_handler, token_key_handler, unsubscribe_handler,
+        validate_put_handler, BATCH_PAYLOAD_LIMIT, CUSTOM_JSON_PAYLOAD_LIMIT,
+    },
+    health::readyz_handler,
+    middleware::{payload_too_large_response, rate_limited_response, RateLimitScope},
+    response_size::record_response_size,
+    share_links::{issue_share_link_handler, redeem_share_link_handler},
+    signals::signal_handler,
+    sms::sms_alerts_handler,
+    state::SharedState,
+    tokens::private_token_gate,
+    trace_capture::trace_requests,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteGroup {
+    // Puts and everything a sender does with a message it put
+    Write,
+    // Polls, acks and everything else a mailbox owner does
+    Read,
+    Admin,
+}
+
+const ALL_GROUPS: [RouteGroup; 3] = [RouteGroup::Write, RouteGroup::Read, RouteGroup::Admin];
+
+impl RouteGroup {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim() {
+            "write" => Ok(RouteGroup::Write),
+            "read" => Ok(RouteGroup::Read),
+            "admin" => Ok(RouteGroup::Admin),
+            other => Err(format!(
+                "LISTENERS route group must be write, read or admin, not {}",
+                other
+            )),
+        }
+    }
+}
+
+pub struct Listener {
+    pub addr: SocketAddr,
+    groups: Vec<RouteGroup>,
+}
+
+impl Listener {
+    fn serves(&self, group: RouteGroup) -> bool {
+        self.groups.contains(&group)
+    }
+}
+
+/// Reads `LISTENERS`, falling back to one listener on `PORT` serving everything.
+pub fn from_env() -> Result<Vec<Listener>, Box<dyn std::error::Error>> {
+    let spec = match std::env::var("LISTENERS") {
+        Ok(spec) if !spec.trim().is_empty() => spec,
+        _ => {
+            let port = std::env::var("PORT")
+                .unwrap_or_else(|_| "3000".to_string())
+                .parse::<u16>()
+                .unwrap_or(3000);
+            return Ok(vec![Listener {
+                addr: SocketAddr::from(([0, 0, 0, 0], port)),
+                groups: ALL_GROUPS.to_vec(),
+            }]);
+        }
+    };
+    parse(&spec)
+}
+
+/// Parses a `LISTENERS` value.
+pub fn parse(spec: &str) -> Result<Vec<Listener>, Box<dyn std::error::Error>> {
+    let mut listeners = Vec::new();
+    for entry in spec.split(';').filter(|entry| !entry.trim().is_empty()) {
+        let (addr, groups) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("LISTENERS entry {} is not addr=groups", entry))?;
+        let addr: SocketAddr = addr
+            .trim()
+            .parse()
+            .map_err(|e| format!("LISTENERS address {}: {}", addr, e))?;
+        let groups = groups
+            .split(',')
+            .map(RouteGroup::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        listeners.push(Listener { addr, groups });
+    }
+    for group in ALL_GROUPS {
+        if group != RouteGroup::Admin && !listeners.iter().any(|l| l.serves(group)) {
+            tracing::warn!("No listener serves the {:?} routes", group);
+        }
+    }
+    Ok(listeners)
+}
+
+/// Builds the routes `listener` serves, under its own middleware stack.
+pub fn router(
+    state: &SharedState,
+    listener: &Listener,
+    admin_token: Option<&str>,
+    base_path: &str,
+) -> Router {
+    let governor_config = Arc::new(
+        GovernorConfigBuilder::default()
+            .key_extractor(SmartIpKeyExtractor) // Use SmartIpKeyExtractor for X-Real-IP
+            .per_millisecond(10) // 10ms period = 100 requests per second
+            .burst_size(100)
+            .use_headers()
+            .error_handler(|err| rate_limited_response(err, RateLimitScope::Ip))
+            .finish()
+            .unwrap(),
+    );
+
+    let governor_limiter = governor_config.limiter().clone();
+    let addr = listener.addr;
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(60));
+        tracing::info!(
+            "rate limiting storage size for {}: {}",
+            addr,
+            governor_limiter.len()
+        );
+        governor_limiter.retain_recent();
+    });
+
+    let mut app = Router::new()
+        .route("/api/info", get(info_handler))
+        .route("/readyz", get(readyz_handler));
+    if listener.serves(RouteGroup::Write) {
+        // Puts are where private tokens are redeemed
+        let put_routes = Router::new()
+            .route("/api/put-message", post(put_message_handler))
+            .route("/api/put-multi", post(put_multi_handler))
+            .route(
+                "/api/put-messages",
+                post(put_messages_handler).layer(DefaultBodyLimit::max(BATCH_PAYLOAD_LIMIT)),
+            )
+            .route_layer(from_fn_with_state(state.clone(), private_token_gate));
+        app = app
+            .merge(put_routes)
+            .route("/api/token-key", get(token_key_handler))
+            .route("/api/validate-put", post(validate_put_handler))
+            .route("/api/revoke-message", post(revoke_message_handler))
+            .route("/api/cancel-message", post(cancel_message_handler))
+            .route("/api/message-state", post(message_state_handler))
+            .route("/api/signal", post(signal_handler))
+            .route("/api/share-links/redeem", post(redeem_share_link_handler));
+    }
+    // Off the per-IP governor when they have budgets of their own
+    let mut separate_poll_routes = None;
+    if listener.serves(RouteGroup::Read) {
+        let poll_routes = Router::new()
+            .route("/api/get-messages", post(get_messages_handler))
+            .route("/api/messages", get(get_messages_query_handler));
+        if state.poll_limit.is_some() {
+            separate_poll_routes = Some(poll_routes);
+        } else {
+            app = app.merge(poll_routes);
+        }
+        app = app
+            .route("/api/has-messages", post(has_messages_handler))
+            .route("/api/events", get(events_handler))
+            .route("/api/ack-messages", post(ack_messages_handler))
+            .route("/api/ack-before", post(ack_before_handler))
+            .route("/api/purge-channel", post(purge_channel_handler))
+            .route("/api/subscribe", post(subscribe_handler))
+            .route("/api/unsubscribe", post(unsubscribe_handler))
+            .route("/api/email-fallback", post(email_fallback_handler))
+            .route("/api/sms-alerts", post(sms_alerts_handler))
+            .route("/api/share-links", post(issue_share_link_handler));
+    }
+    if listener.serves(RouteGroup::Admin) {
+        if let Some(admin_token) = admin_token {
+            app = app.merge(admin::router(admin_token.to_string()));
+        }
+    }
+
+    let app = app
+        .layer(from_fn_with_state(state.clone(), limit_concurrency))
+        .layer(DefaultBodyLimit::max(CUSTOM_JSON_PAYLOAD_LIMIT))
+        .layer(from_fn(payload_too_large_response))
+        .layer(from_fn(record_response_size))
+        .layer(from_fn_with_state(state.clone(), trace_requests))
+        .with_state(state.clone())
+        .layer(GovernorLayer {
+            config: governor_config,
+        });
+    let app = match separate_poll_routes {
+        Some(poll_routes) => app.merge(
+            poll_routes
+                .layer(from_fn_with_state(state.clone(), limit_concurrency))
+                .layer(DefaultBodyLimit::max(CUSTOM_JSON_PAYLOAD_LIMIT))
+                .layer(from_fn(payload_too_large_response))
+                .layer(from_fn(record_response_size))
+                .layer(from_fn_with_state(state.clone(), trace_requests))
+                .with_state(state.clone()),
+        ),
+        None => app,
+    };
+    if base_path.is_empty() {
+        app
+    } else {
+        Router::new().nest(base_path, app)
+    }
+}
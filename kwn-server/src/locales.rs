@@ -0,0 +1,98 @@
+//! Notification text in each language the client ships.
+//!
+//! A subscription may carry the device's `locale` as a BCP 47 tag such as
+//! `de` or `pt-BR`. Pushes to it use the strings for that tag, else for its
+//! language alone, else English. Bodies fill `{count}` with the messages
+//! waiting and `{time}` with when the latest arrived.
+
+pub struct NotificationText {
+    pub title: &'static str,
+    pub body: &'static str,
+}
+
+const ENGLISH: NotificationText = NotificationText {
+    title: "New Message(s)",
+    body: "{count} new message(s) at {time}",
+};
+
+// Lowercase tags, most specific first for languages with regional entries
+const TRANSLATIONS: &[(&str, NotificationText)] = &[
+    ("en", ENGLISH),
+    (
+        "de",
+        NotificationText {
+            title: "Neue Nachricht(en)",
+            body: "{count} neue Nachricht(en) um {time}",
+        },
+    ),
+    (
+        "es",
+        NotificationText {
+            title: "Mensaje(s) nuevo(s)",
+            body: "{count} mensaje(s) nuevo(s) a las {time}",
+        },
+    ),
+    (
+        "fr",
+        NotificationText {
+            title: "Nouveau(x) message(s)",
+            body: "{count} nouveau(x) message(s) à {time}",
+        },
+    ),
+    (
+        "it",
+        NotificationText {
+            title: "Nuovo/i messaggio/i",
+            body: "{count} nuovo/i messaggio/i alle {time}",
+        },
+    ),
+    (
+        "nl",
+        NotificationText {
+            title: "Nieuw(e) bericht(en)",
+            body: "{count} nieuw(e) bericht(en) om {time}",
+        },
+    ),
+    (
+        "pt",
+        NotificationText {
+            title: "Nova(s) mensagem(ns)",
+            body: "{count} nova(s) mensagem(ns) às {time}",
+        },
+    ),
+    (
+        "ja",
+        NotificationText {
+            title: "新着メッセージ",
+            body: "{time} に新着メッセージ {count} 件",
+        },
+    ),
+    (
+        "zh-tw",
+        NotificationText {
+            title: "新訊息",
+            body: "{time} 有 {count} 則新訊息",
+        },
+    ),
+    (
+        "zh",
+        NotificationText {
+            title: "新消息",
+            body: "{time} 有 {count} 条新消息",
+        },
+    ),
+];
+
+/// The strings for `locale`, falling back to its language and then English.
+pub fn notification_text(locale: Option<&str>) -> &'static NotificationText {
+    let Some(locale) = locale else {
+        return &ENGLISH;
+    };
+    let tag = locale.trim().replace('_', "-").to_ascii_lowercase();
+    let language = tag.split('-').next().unwrap_or_default();
+    TRANSLATIONS
+        .iter()
+        .find(|(key, _)| *key == tag)
+        .or_else(|| TRANSLATIONS.iter().find(|(key, _)| *key == language))
+        .map_or(&ENGLISH, |(_, text)| text)
+}
@@ -0,0 +1,215 @@
+//! Log output, configured from the environment.
+//!
+//! `LOG_FORMAT` picks `full` (the default), `compact`, `pretty`, or `json` for
+//! one object per line, as Loki and ELK ingest. Logs go to stdout unless
+//! `LOG_FILE` names a file. That file is rotated when `LOG_ROTATION` (`hourly`,
+//! `daily` or `never`, the default) says so and whenever it would pass
+//! `LOG_MAX_BYTES`, keeping the newest `LOG_MAX_FILES` (default 7) rotated files
+//! beside it. The filter starts from `RUST_LOG` and can be replaced at runtime
+//! through `/admin/log-filter`.
+
+use chrono::{DateTime, Utc};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+use tracing_subscriber::{
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+#[derive(Clone, Copy)]
+enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+#[derive(Clone, Copy)]
+enum Period {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Period {
+    // Which period `now` falls in; a change means the file is due for rotation
+    fn index(self, now: DateTime<Utc>) -> i64 {
+        match self {
+            Period::Hourly => now.timestamp().div_euclid(3600),
+            Period::Daily => now.timestamp().div_euclid(86400),
+            Period::Never => 0,
+        }
+    }
+}
+
+/// Swaps the log filter of the running server.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the filter with `directives`, in `RUST_LOG` syntax.
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+/// Installs the global subscriber described by the environment.
+pub fn init() -> Result<LogFilter, Box<dyn std::error::Error>> {
+    let format = match std::env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("") | Ok("full") => LogFormat::Full,
+        Ok("compact") => LogFormat::Compact,
+        Ok("pretty") => LogFormat::Pretty,
+        Ok("json") => LogFormat::Json,
+        Ok(other) => {
+            return Err(format!(
+                "LOG_FORMAT must be full, compact, pretty or json, not {}",
+                other
+            )
+            .into())
+        }
+    };
+    let (writer, ansi) = match std::env::var("LOG_FILE") {
+        Ok(path) if !path.is_empty() => (
+            BoxMakeWriter::new(Mutex::new(RotatingFile::open(path.into())?)),
+            false,
+        ),
+        _ => (BoxMakeWriter::new(io::stdout), true),
+    };
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let output = fmt::layer().with_writer(writer).with_ansi(ansi);
+    let output = match format {
+        LogFormat::Full => output.boxed(),
+        LogFormat::Compact => output.compact().boxed(),
+        LogFormat::Pretty => output.pretty().boxed(),
+        LogFormat::Json => output.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .try_init()?;
+    Ok(LogFilter { handle })
+}
+
+// A log file that moves itself aside, to `<path>.<UTC time>`, when its period
+// ends or it grows too large. Each event reaches it in a single write, so
+// rotation never splits a line.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    period: Period,
+    period_index: i64,
+    max_bytes: Option<u64>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let period = match std::env::var("LOG_ROTATION").as_deref() {
+            Err(_) | Ok("") | Ok("never") => Period::Never,
+            Ok("hourly") => Period::Hourly,
+            Ok("daily") => Period::Daily,
+            Ok(other) => {
+                return Err(
+                    format!("LOG_ROTATION must be hourly, daily or never, not {}", other).into(),
+                )
+            }
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            written: file.metadata()?.len(),
+            file,
+            path,
+            period,
+            period_index: period.index(Utc::now()),
+            max_bytes: std::env::var("LOG_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&bytes| bytes > 0),
+            max_files: std::env::var("LOG_MAX_FILES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(7),
+        })
+    }
+
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(now.format(".%Y%m%dT%H%M%S%.3fZ").to_string());
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        self.prune()
+    }
+
+    // Deletes all but the newest `max_files` rotated files; their names sort by age
+    fn prune(&self) -> io::Result<()> {
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            dir
+        };
+        let mut prefix = name.to_os_string();
+        prefix.push(".");
+        let prefix = prefix.to_string_lossy().into_owned();
+        let mut rotated = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                rotated.push(entry.path());
+            }
+        }
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.max_files);
+        for path in rotated.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Utc::now();
+        let period_index = self.period.index(now);
+        let oversized = self
+            .max_bytes
+            .is_some_and(|max| self.written > 0 && self.written + buf.len() as u64 > max);
+        if period_index != self.period_index || oversized {
+            self.period_index = period_index;
+            // Logging from here would recurse; keep writing to the old file
+            if let Err(e) = self.rotate(now) {
+                eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
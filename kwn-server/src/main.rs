@@ -0,0 +1,495 @@
+mod ack_lane;
+mod admin;
+mod analytics;
+mod capabilities;
+mod concurrency;
+mod continuations;
+mod debug_capture;
+mod email;
+mod error;
+mod events;
+mod grpc;
+mod handlers;
+mod health;
+mod heuristics;
+mod hints;
+mod leak_check;
+mod lifecycle;
+mod listeners;
+mod locales;
+mod logging;
+mod metrics;
+mod middleware;
+mod notifier;
+mod outbox;
+mod poll_limit;
+mod poll_sessions;
+mod push;
+mod push_chaos;
+mod push_limit;
+mod reports;
+mod response_size;
+mod scheduled;
+mod setup;
+mod shadow;
+mod share_links;
+mod signals;
+mod sms;
+mod state;
+mod subscription_cache;
+mod supervision;
+mod tokens;
+mod trace_capture;
+
+use dotenvy::dotenv;
+use futures::future::try_join_all;
+use kwn_push::{
+    ApnsProvider, FcmProvider, PushRouter, WebPushProvider, WebhookProvider, APNS_SCHEME,
+    FCM_SCHEME, WEBHOOK_SCHEME,
+};
+use kwn_storage::{
+    DeletionPolicy, FjallStore, MessageStore, PersistPolicy, PersistReason, SubscriptionStore,
+};
+use std::{future::IntoFuture, net::SocketAddr, path::Path, sync::Arc};
+use tokio::time::{interval, Duration};
+
+use ack_lane::AckLane;
+use analytics::Analytics;
+use concurrency::ConcurrencyLimits;
+use continuations::Continuations;
+use debug_capture::DebugCapture;
+use email::EmailFallback;
+use heuristics::PutHeuristics;
+use hints::HintSigner;
+use leak_check::{run_leak_checks, LeakCheck};
+use lifecycle::MailboxLifecycle;
+use notifier::WeakNotifierMap;
+use outbox::{run_outbox, PushOutbox};
+use poll_limit::PollLimiter;
+use poll_sessions::PollSessions;
+use push_chaos::ChaosPushProvider;
+use push_limit::PushLimiter;
+use reports::{run_weekly_reports, ReportStats};
+use scheduled::run_scheduler;
+use shadow::ShadowStore;
+use share_links::ShareLinks;
+use sms::SmsAlerts;
+use state::AppState;
+use subscription_cache::SubscriptionCache;
+use tokens::PrivateTokens;
+use trace_capture::TraceCapture;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let init = args.first().map(String::as_str) == Some("init");
+    // `init` writes the .env, so it mustn't pick up an old one
+    if !init {
+        dotenv().ok();
+    }
+    let log_filter = logging::init()?;
+    match args.first().map(String::as_str) {
+        Some("init") => return setup::init(&args[1..]),
+        Some("keygen-vapid") => return setup::keygen_vapid(&args[1..]),
+        _ => {}
+    }
+
+    // Checked up front rather than failing the first push
+    if let Err(e) = kwn_push::vapid_private_key() {
+        return Err(format!(
+            "{}; run `simple-message-backend init` or `keygen-vapid` to generate one",
+            e
+        )
+        .into());
+    }
+
+    let metrics_handle = metrics::install()?;
+
+    // SECURE_DELETE=1 zeroes acked values before removing them
+    let deletion_policy = match std::env::var("SECURE_DELETE").as_deref() {
+        Ok("1") | Ok("true") => DeletionPolicy::Overwrite,
+        _ => DeletionPolicy::Remove,
+    };
+    let default_persist = PersistPolicy::default();
+    let persist_policy = PersistPolicy {
+        max_pending: std::env::var("PERSIST_AFTER_MUTATIONS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_persist.max_pending),
+        interval: std::env::var("PERSIST_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(default_persist.interval),
+    };
+    let store = Arc::new(
+        FjallStore::open(Path::new("./message_db"))?
+            .with_deletion_policy(deletion_policy)
+            .with_persist_policy(persist_policy),
+    );
+
+    let mut messages: Arc<dyn MessageStore> = store.clone();
+    let mut subscriptions: Arc<dyn SubscriptionStore> = store.clone();
+    if let Ok(shadow_path) = std::env::var("SHADOW_DB_PATH") {
+        let shadow_store = Arc::new(
+            FjallStore::open(Path::new(&shadow_path))?
+                .with_deletion_policy(deletion_policy)
+                .with_persist_policy(persist_policy),
+        );
+        let shadow = Arc::new(ShadowStore::new(
+            store.clone(),
+            store.clone(),
+            shadow_store.clone(),
+            shadow_store,
+        ));
+        tracing::info!(
+            "Mirroring storage writes to shadow store at {}",
+            shadow_path
+        );
+        messages = shadow.clone();
+        subscriptions = shadow.clone();
+        // Diff recently written mailboxes between the two stores
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(60));
+            loop {
+                ticker.tick().await;
+                let shadow = shadow.clone();
+                match tokio::task::spawn_blocking(move || shadow.compare()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => tracing::error!("Shadow comparison failed: {}", e),
+                    Err(e) => tracing::error!("Shadow comparison task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Outermost, so repeated subscriptions skip the shadow store too
+    let subscriptions: Arc<dyn SubscriptionStore> = Arc::new(SubscriptionCache::new(subscriptions));
+
+    let analytics_retention_days = std::env::var("ANALYTICS_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(90);
+    let analytics = Arc::new(Analytics::new(
+        store.clone(),
+        chrono::Duration::days(analytics_retention_days),
+    ));
+
+    let web_push = Arc::new(WebPushProvider::from_env()?);
+    let mut push_router = PushRouter::new(web_push.clone());
+    if let Some(apns) = ApnsProvider::from_env()? {
+        push_router.register(APNS_SCHEME, Arc::new(apns));
+    }
+    if let Some(fcm) = FcmProvider::from_env()? {
+        push_router.register(FCM_SCHEME, Arc::new(fcm));
+    }
+    if let Some(webhook) = WebhookProvider::from_env()? {
+        push_router.register(WEBHOOK_SCHEME, Arc::new(webhook));
+    }
+    let push_router = Arc::new(push_router);
+    let push_chaos = Arc::new(ChaosPushProvider::new(push_router.clone()));
+
+    let notifier = Arc::new(WeakNotifierMap::default());
+    let mailbox_idle_days = std::env::var("MAILBOX_IDLE_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    let lifecycle = Arc::new(MailboxLifecycle::new(
+        chrono::Duration::days(mailbox_idle_days),
+        subscriptions.clone(),
+        notifier.clone(),
+    ));
+    let seeded = lifecycle.seed()?;
+    tracing::info!(
+        "Tracking {} mailbox(es) with stored subscription state",
+        seeded
+    );
+
+    let share_links = Arc::new(ShareLinks::new(store.clone()));
+
+    // BASE_PATH=/relay serves every route under /relay, for deployments behind
+    // a path-routing proxy that doesn't strip the prefix
+    let base_path = std::env::var("BASE_PATH")
+        .unwrap_or_default()
+        .trim_matches('/')
+        .to_string();
+    let base_path = if base_path.is_empty() {
+        base_path
+    } else {
+        format!("/{}", base_path)
+    };
+
+    let app_state = Arc::new(AppState {
+        messages,
+        subscriptions,
+        push: push_chaos.clone(),
+        push_router,
+        web_push,
+        push_chaos,
+        notifier,
+        signals: Arc::default(),
+        compact_after_deletes: std::env::var("COMPACT_AFTER_DELETES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000),
+        analytics: analytics.clone(),
+        debug: DebugCapture::default(),
+        traces: TraceCapture::default(),
+        tokens: PrivateTokens::from_env(store.clone())?,
+        poll_limit: PollLimiter::from_env()?,
+        push_limit: PushLimiter::from_env(),
+        push_include_message: matches!(
+            std::env::var("PUSH_INCLUDE_MESSAGE").as_deref(),
+            Ok("1") | Ok("true")
+        ),
+        push_while_polling: matches!(
+            std::env::var("PUSH_WHILE_POLLING").as_deref(),
+            Ok("1") | Ok("true")
+        ),
+        concurrency: ConcurrencyLimits::from_env()?,
+        heuristics: PutHeuristics::from_env()?,
+        hints: HintSigner::from_env()?,
+        keepalive_interval: std::env::var("LONG_POLL_KEEPALIVE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        metrics: metrics_handle,
+        poll_sessions: PollSessions::default(),
+        continuations: Continuations::default(),
+        lifecycle: lifecycle.clone(),
+        ack_lane: AckLane::new(
+            std::env::var("ACK_LANE_THREADS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2),
+        )?,
+        share_links: share_links.clone(),
+        email: EmailFallback::from_env(store.clone())?.map(Arc::new),
+        sms: SmsAlerts::from_env(store.clone())?.map(Arc::new),
+        outbox: Arc::new(PushOutbox::new(
+            store.clone(),
+            Duration::from_millis(
+                std::env::var("PUSH_COALESCE_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+            ),
+            std::env::var("PUSH_CONCURRENCY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64),
+        )),
+        report_stats: ReportStats::default(),
+        base_path: base_path.clone(),
+        max_clock_skew: chrono::Duration::seconds(
+            std::env::var("MAX_CLOCK_SKEW_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+        ),
+        log_filter,
+        max_response_bytes: std::env::var("MAX_RESPONSE_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(response_size::DEFAULT_MAX_RESPONSE_BYTES),
+    });
+
+    tokio::spawn(run_scheduler(app_state.clone()));
+    tokio::spawn(run_outbox(app_state.clone()));
+    match LeakCheck::from_env() {
+        Some(check) => {
+            tokio::spawn(run_leak_checks(app_state.clone(), check));
+        }
+        None => tracing::info!("LEAK_CHECK_INTERVAL_SECS is 0, delivery leak checks disabled"),
+    }
+    supervision::spawn_watchdog(app_state.clone());
+
+    // Sync whatever the mutation threshold hasn't already
+    let persist_messages = app_state.messages.clone();
+    tokio::spawn(async move {
+        let mut ticker = interval(persist_policy.interval);
+        loop {
+            ticker.tick().await;
+            let messages = persist_messages.clone();
+            match tokio::task::spawn_blocking(move || messages.persist(PersistReason::Interval))
+                .await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!("Failed to persist storage: {}", e),
+                Err(e) => tracing::error!("Persist task failed: {}", e),
+            }
+        }
+    });
+
+    // Reap state left behind by mailboxes that have gone quiet, unused share
+    // links, expired idempotency keys and messages past their TTL
+    let reaper_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(3600));
+        loop {
+            ticker.tick().await;
+            let lifecycle = lifecycle.clone();
+            match tokio::task::spawn_blocking(move || lifecycle.reap(chrono::Utc::now())).await {
+                Ok(Ok(reaped)) => reaper_state.report_stats.record_reaped(reaped),
+                Ok(Err(e)) => tracing::error!("Failed to reap idle mailboxes: {}", e),
+                Err(e) => tracing::error!("Mailbox reaper task failed: {}", e),
+            }
+            let share_links = share_links.clone();
+            match tokio::task::spawn_blocking(move || share_links.prune(chrono::Utc::now())).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!("Failed to prune share links: {}", e),
+                Err(e) => tracing::error!("Share link prune task failed: {}", e),
+            }
+            let outbox = reaper_state.outbox.clone();
+            match tokio::task::spawn_blocking(move || outbox.prune(chrono::Utc::now())).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!("Failed to prune push dead letters: {}", e),
+                Err(e) => tracing::error!("Dead letter prune task failed: {}", e),
+            }
+            let messages = reaper_state.messages.clone();
+            match tokio::task::spawn_blocking(move || {
+                messages.prune_idempotency_keys(chrono::Utc::now())
+            })
+            .await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!("Failed to prune idempotency keys: {}", e),
+                Err(e) => tracing::error!("Idempotency key prune task failed: {}", e),
+            }
+            let messages = reaper_state.messages.clone();
+            match tokio::task::spawn_blocking(move || messages.sweep_expired(chrono::Utc::now()))
+                .await
+            {
+                Ok(Ok(0)) => {}
+                Ok(Ok(swept)) => tracing::info!("Deleted {} expired messages", swept),
+                Ok(Err(e)) => tracing::error!("Failed to sweep expired messages: {}", e),
+                Err(e) => tracing::error!("Expired message sweep task failed: {}", e),
+            }
+        }
+    });
+
+    match std::env::var("REPORT_WEBHOOK_URL") {
+        Ok(url) if !url.is_empty() => {
+            tokio::spawn(run_weekly_reports(app_state.clone(), url));
+        }
+        _ => tracing::info!("REPORT_WEBHOOK_URL not set, weekly reports disabled"),
+    }
+
+    // Keep storage gauges fresh between readiness probes
+    let health_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(15));
+        loop {
+            ticker.tick().await;
+            metrics::record_storage_health(&health_state.messages.health());
+        }
+    });
+
+    // Write out analytics buckets shortly after each hour ends
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(60));
+        loop {
+            ticker.tick().await;
+            let analytics = analytics.clone();
+            match tokio::task::spawn_blocking(move || analytics.flush(chrono::Utc::now())).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::error!("Failed to persist analytics: {}", e),
+                Err(e) => tracing::error!("Analytics flush task failed: {}", e),
+            }
+        }
+    });
+
+    if app_state.poll_limit.is_some() {
+        let poll_state = app_state.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(60));
+            if let Some(poll_limit) = &poll_state.poll_limit {
+                poll_limit.retain_recent();
+            }
+        });
+    }
+    if app_state.push_limit.is_some() {
+        let push_state = app_state.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(600));
+            if let Some(push_limit) = &push_state.push_limit {
+                push_limit.retain_recent();
+            }
+        });
+    }
+    if app_state.email.is_some() {
+        let email_state = app_state.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(600));
+            if let Some(email) = &email_state.email {
+                email.retain_recent();
+            }
+        });
+    }
+    if app_state.sms.is_some() {
+        let sms_state = app_state.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(600));
+            if let Some(sms) = &sms_state.sms {
+                sms.retain_recent();
+            }
+        });
+    }
+
+    let admin_token = match std::env::var("ADMIN_TOKEN") {
+        Ok(admin_token) if !admin_token.is_empty() => Some(admin_token),
+        _ => {
+            tracing::info!("ADMIN_TOKEN not set, admin endpoints disabled");
+            None
+        }
+    };
+    if let Some(grpc_port) = std::env::var("GRPC_PORT")
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+    {
+        let grpc_state = app_state.clone();
+        tokio::spawn(async move {
+            let addr = SocketAddr::from(([0, 0, 0, 0], grpc_port));
+            if let Err(e) = grpc::serve(grpc_state, addr).await {
+                tracing::error!("gRPC server failed: {}", e);
+            }
+        });
+    }
+    if !base_path.is_empty() {
+        tracing::info!("Serving under base path {}", base_path);
+    }
+
+    // Bind everything before reporting ready, so a bad address fails startup
+    let mut servers = Vec::new();
+    for listener in listeners::from_env()? {
+        let app = listeners::router(&app_state, &listener, admin_token.as_deref(), &base_path);
+        tracing::info!("Listening on {}", listener.addr);
+        servers.push((tokio::net::TcpListener::bind(listener.addr).await?, app));
+    }
+
+    // Synced once more after the servers drain, since the timer won't fire again
+    let shutdown_messages = app_state.messages.clone();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        supervision::shutdown_signal().await;
+        let _ = shutdown_tx.send(());
+    });
+    supervision::notify_ready();
+    try_join_all(servers.into_iter().map(|(listener, app)| {
+        let mut shutdown_rx = shutdown_rx.clone();
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            let _ = shutdown_rx.changed().await;
+        })
+        .into_future()
+    }))
+    .await?;
+    shutdown_messages.persist(PersistReason::Shutdown)?;
+
+    Ok(())
+}
@@ -0,0 +1,17 @@
+//! Prometheus metrics, rendered at `/admin/metrics`.
+
+use kwn_storage::StorageHealth;
+use metrics::gauge;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+pub fn install() -> Result<PrometheusHandle, Box<dyn std::error::Error>> {
+    Ok(PrometheusBuilder::new().install_recorder()?)
+}
+
+pub fn record_storage_health(health: &StorageHealth) {
+    gauge!("kwn_storage_journal_count").set(health.journal_count as f64);
+    gauge!("kwn_storage_write_buffer_bytes").set(health.write_buffer_bytes as f64);
+    gauge!("kwn_storage_disk_space_bytes").set(health.disk_space_bytes as f64);
+    gauge!("kwn_storage_message_segments").set(health.message_segments as f64);
+    gauge!("kwn_storage_stall_risk").set(if health.stall_risk { 1.0 } else { 0.0 });
+}
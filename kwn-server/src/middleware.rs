@@ -0,0 +1,120 @@
+use axum::{
+    body::Body,
+    extract::Json,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use kwn_protocol::{ErrorResponse, RetryClass};
+use serde::Serialize;
+use std::net::SocketAddr;
+use tower_governor::GovernorError;
+use tracing::warn;
+
+// Which budget rejected the request.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitScope {
+    Ip,
+    // The stricter put limit for requests without a private token
+    BareIp,
+    // The get budget keyed by mailbox or poll key instead of IP
+    Poll,
+}
+
+#[derive(Serialize, Debug)]
+struct RateLimitedResponse {
+    message: &'static str,
+    error_code: &'static str,
+    retry: RetryClass,
+    scope: RateLimitScope,
+    retry_after_secs: u64,
+}
+
+// Same client address the governor keys on: X-Real-IP from nginx, else the peer.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> String {
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| peer.map(|addr| addr.ip().to_string()))
+        .unwrap_or_default()
+}
+
+pub fn too_many_requests(
+    scope: RateLimitScope,
+    wait_secs: u64,
+    headers: Option<HeaderMap>,
+) -> Response {
+    // Retry-After of 0 invites an immediate retry that will be rejected again.
+    let retry_after_secs = wait_secs.max(1);
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(RateLimitedResponse {
+            message: "Too many requests.",
+            error_code: "RATE_LIMITED",
+            retry: RetryClass::RetryAfter,
+            scope,
+            retry_after_secs,
+        }),
+    )
+        .into_response();
+    if let Some(headers) = headers {
+        response.headers_mut().extend(headers);
+    }
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        header::HeaderValue::from(retry_after_secs),
+    );
+    response
+}
+
+// Replaces tower_governor's plain-text 429 with the same JSON envelope used for
+// oversized payloads, carrying the governor's own wait time so clients back off
+// exactly as long as the key's budget needs to refill.
+pub fn rate_limited_response(err: GovernorError, scope: RateLimitScope) -> Response {
+    match err {
+        GovernorError::TooManyRequests { wait_time, headers } => {
+            too_many_requests(scope, wait_time, headers)
+        }
+        GovernorError::UnableToExtractKey => {
+            warn!("Rate limiter could not extract a client key from the request");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+        GovernorError::Other { code, msg, headers } => {
+            let mut response = (code, msg.unwrap_or_default()).into_response();
+            if let Some(headers) = headers {
+                response.headers_mut().extend(headers);
+            }
+            response
+        }
+    }
+}
+
+pub async fn payload_too_large_response(req: Request<Body>, next: Next) -> Response {
+    let response = next.run(req).await;
+
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        let is_likely_default_rejection = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|value| value.to_str().unwrap_or("").starts_with("text/plain"));
+
+        if is_likely_default_rejection {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorResponse {
+                    message: "The request payload is too large.".to_string(),
+                    error_code: "PAYLOAD_TOO_LARGE".to_string(),
+                    retry: RetryClass::Permanent,
+                    retry_after_secs: None,
+                    server_time: Some(chrono::Utc::now()),
+                    skew_secs: None,
+                }),
+            )
+                .into_response();
+        }
+    }
+
+    response
+}
@@ -0,0 +1,188 @@
+use dashmap::DashMap;
+use kwn_protocol::MessageId;
+use std::sync::{Arc, Weak};
+use tokio::sync::Notify;
+
+/// Wakes long-polling getters when a message arrives for an id they wait on.
+pub trait Notifier: Send + Sync {
+    /// Returns a handle that is woken by [`Notifier::notify`] for `message_id`
+    /// for as long as the caller holds it.
+    fn register(&self, message_id: &MessageId) -> Arc<Notify>;
+
+    /// Like [`Notifier::register`], but woken for every id starting with
+    /// `prefix`, which ends in '/'.
+    fn register_prefix(&self, prefix: &str) -> Arc<Notify>;
+
+    /// Wakes the waiters on `message_id` and its prefixes, returning whether
+    /// there were any.
+    fn notify(&self, message_id: &MessageId) -> bool;
+
+    /// Drops bookkeeping for `message_id` if nobody is waiting on it.
+    fn forget(&self, message_id: &MessageId);
+
+    /// Drops bookkeeping for `prefix` if nobody is waiting on it.
+    fn forget_prefix(&self, prefix: &str);
+}
+
+/// One poll's registrations. Dropping it releases the handles and then the
+/// entries nobody else waits on, however the poll ends: timeout, error or the
+/// client going away.
+pub struct Waiters {
+    notifier: Arc<dyn Notifier>,
+    handles: Vec<Arc<Notify>>,
+    message_ids: Vec<MessageId>,
+    prefixes: Vec<String>,
+}
+
+impl Waiters {
+    pub fn register(
+        notifier: &Arc<dyn Notifier>,
+        message_ids: &[MessageId],
+        prefixes: &[&str],
+    ) -> Self {
+        let handles = message_ids
+            .iter()
+            .map(|id| notifier.register(id))
+            .chain(
+                prefixes
+                    .iter()
+                    .map(|prefix| notifier.register_prefix(prefix)),
+            )
+            .collect();
+        Self {
+            notifier: notifier.clone(),
+            handles,
+            message_ids: message_ids.to_vec(),
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+
+    pub fn handles(&self) -> &[Arc<Notify>] {
+        &self.handles
+    }
+}
+
+impl Drop for Waiters {
+    fn drop(&mut self) {
+        self.handles.clear();
+        for message_id in &self.message_ids {
+            self.notifier.forget(message_id);
+        }
+        for prefix in &self.prefixes {
+            self.notifier.forget_prefix(prefix);
+        }
+    }
+}
+
+/// Notifier keyed by message_id holding only weak references, so an entry dies
+/// with its last waiter.
+#[derive(Default)]
+pub struct WeakNotifierMap {
+    map: DashMap<MessageId, Weak<Notify>>, // Store Weak pointers
+    // Waiters on id prefixes, keyed by the prefix including its trailing '/'
+    prefixes: DashMap<String, Weak<Notify>>,
+}
+
+// Returns the live handle under `key`, creating one if there is none.
+fn register_in<K>(map: &DashMap<K, Weak<Notify>>, key: K) -> Arc<Notify>
+where
+    K: std::hash::Hash + Eq + Clone + std::fmt::Display,
+{
+    loop {
+        // Use entry API for atomic operations
+        match map.entry(key.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(o) => {
+                if let Some(arc) = o.get().upgrade() {
+                    // Successfully upgraded Weak to Arc
+                    return arc;
+                }
+                // Stale Weak pointer found, remove it and retry loop to insert new
+                tracing::trace!(key = %key, "Removing stale notifier entry.");
+                o.remove();
+            }
+            dashmap::mapref::entry::Entry::Vacant(v) => {
+                // No entry exists, create new Arc and insert Weak
+                let new_arc = Arc::new(Notify::new());
+                v.insert(Arc::downgrade(&new_arc));
+                tracing::trace!(key = %key, "Created new notifier entry.");
+                return new_arc;
+            }
+        }
+    }
+}
+
+impl Notifier for WeakNotifierMap {
+    fn register(&self, message_id: &MessageId) -> Arc<Notify> {
+        register_in(&self.map, message_id.clone())
+    }
+
+    fn register_prefix(&self, prefix: &str) -> Arc<Notify> {
+        register_in(&self.prefixes, prefix.to_string())
+    }
+
+    fn notify(&self, message_id: &MessageId) -> bool {
+        let mut woken = false;
+        if let Some(weak_notifier_entry) = self.map.get(message_id) {
+            // Attempt to upgrade the Weak pointer
+            if let Some(notifier) = weak_notifier_entry.value().upgrade() {
+                tracing::debug!(message_id = %message_id, "Notifying waiters");
+                notifier.notify_waiters();
+                woken = true;
+            } else {
+                // The Arc was dropped, no one is waiting; register() removes the stale entry.
+                tracing::trace!(message_id = %message_id, "Notifier existed but was stale (no waiters).");
+            }
+        }
+        if self.prefixes.is_empty() {
+            return woken;
+        }
+        // Every prefix of the id ending in '/' may have waiters
+        let id = message_id.as_str();
+        for (slash, _) in id.match_indices('/') {
+            if let Some(notifier) = self
+                .prefixes
+                .get(&id[..=slash])
+                .and_then(|weak| weak.upgrade())
+            {
+                tracing::debug!(message_id = %message_id, prefix = &id[..=slash], "Notifying prefix waiters");
+                notifier.notify_waiters();
+                woken = true;
+            }
+        }
+        woken
+    }
+
+    fn forget(&self, message_id: &MessageId) {
+        self.map
+            .remove_if(message_id, |_, weak| weak.strong_count() == 0);
+    }
+
+    fn forget_prefix(&self, prefix: &str) {
+        self.prefixes
+            .remove_if(prefix, |_, weak| weak.strong_count() == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_reaches_id_and_prefix_waiters_until_they_drop() {
+        let map = Arc::new(WeakNotifierMap::default());
+        let notifier: Arc<dyn Notifier> = map.clone();
+        let inbox = MessageId::parse("team/inbox").unwrap();
+        let other = MessageId::parse("other").unwrap();
+
+        let waiters = Waiters::register(&notifier, std::slice::from_ref(&inbox), &["team/"]);
+        assert_eq!(waiters.handles().len(), 2);
+        assert!(notifier.notify(&inbox));
+        assert!(notifier.notify(&MessageId::parse("team/outbox").unwrap()));
+        assert!(!notifier.notify(&other));
+
+        drop(waiters);
+        assert!(!notifier.notify(&inbox));
+        assert!(map.map.is_empty());
+        assert!(map.prefixes.is_empty());
+    }
+}
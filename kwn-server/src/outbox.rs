@@ -0,0 +1,289 @@
+//! Durable queue of pushes announcing new messages.
+//!
+//! A put commits its message and then queues a push for each mailbox it
+//! reached in storage's `push_outbox` partition, so pushes survive restarts.
+//! [`run_outbox`] sends them as they come due. A push that fails in a way worth
+//! retrying (the push service rate limited it, timed out or answered 5xx, or
+//! storage was briefly unavailable) is queued again after a backoff doubling
+//! from 2s up to 10 minutes, or after the service's own wait. After
+//! [`MAX_ATTEMPTS`] it moves to the dead letters with its last error, where an
+//! operator can list it through `/admin/push-dead-letters` and retry or discard
+//! it. Dead letters are deleted after [`DEAD_LETTER_RETENTION`].
+//!
+//! With `PUSH_COALESCE_MS` set, a put's push waits that long before going out,
+//! and further puts to the same mailbox meanwhile queue nothing of their own:
+//! the one push covers them all, since its badge counts the messages pending
+//! when it is sent.
+//!
+//! At most `PUSH_CONCURRENCY` (default 64) requests to push services are in
+//! flight at once, however many mailboxes a pass or a burst of puts covers;
+//! the rest wait their turn, so a burst can't exhaust sockets.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashSet;
+use futures::future::join_all;
+use kwn_protocol::{MessageId, RetryClass};
+use kwn_storage::{DeadLetter, OutboxJob, OutboxStore, StorageError};
+use metrics::counter;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::{
+    sync::{Notify, Semaphore, SemaphorePermit},
+    time::{sleep, Duration},
+};
+use tracing::{error, warn};
+
+use crate::{debug_capture::mailbox_hash, push::send_notification, state::SharedState};
+
+const MAX_ATTEMPTS: u32 = 8;
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+// Pushes sent together in one pass
+const BATCH_SIZE: usize = 256;
+// How often the queue is checked without a wakeup, for retries coming due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEAD_LETTER_RETENTION: chrono::Duration = chrono::Duration::days(30);
+
+/// A dead letter as shown to operators, naming its mailbox by hash only.
+#[derive(Serialize, Debug)]
+pub struct DeadLetterView {
+    // Names the letter for retrying or discarding it
+    pub id: String,
+    pub mailbox_hash: String,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub error: String,
+}
+
+fn dead_letter_id(letter: &DeadLetter) -> String {
+    format!(
+        "{}-{}",
+        letter.failed_at.timestamp_millis(),
+        mailbox_hash(letter.message_id.as_str())
+    )
+}
+
+// What became of a job after one attempt.
+enum Outcome {
+    Done,
+    Retry(DateTime<Utc>),
+    Dead(String),
+}
+
+pub struct PushOutbox {
+    store: Arc<dyn OutboxStore>,
+    wake: Notify,
+    coalesce_window: Duration,
+    // Mailboxes with a push queued and not yet picked up for sending
+    pending: DashSet<MessageId>,
+    // One permit per request to a push service in flight
+    sends: Semaphore,
+}
+
+impl PushOutbox {
+    /// Allows `concurrency` push sends at once, at least one.
+    pub fn new(store: Arc<dyn OutboxStore>, coalesce_window: Duration, concurrency: usize) -> Self {
+        Self {
+            store,
+            wake: Notify::new(),
+            coalesce_window,
+            pending: DashSet::new(),
+            sends: Semaphore::new(concurrency.max(1)),
+        }
+    }
+
+    /// Waits for a turn to send one push, held until the permit is dropped.
+    pub async fn send_permit(&self) -> SemaphorePermit<'_> {
+        if self.sends.available_permits() == 0 {
+            counter!("kwn_push_send_waits_total").increment(1);
+        }
+        self.sends
+            .acquire()
+            .await
+            .expect("push send semaphore is never closed")
+    }
+
+    /// Queues a push to each of `message_ids` that hasn't one waiting already,
+    /// due once the coalescing window has passed.
+    pub fn enqueue(&self, message_ids: &[MessageId]) -> Result<(), StorageError> {
+        let fresh: Vec<MessageId> = message_ids
+            .iter()
+            .filter(|message_id| self.pending.insert((*message_id).clone()))
+            .cloned()
+            .collect();
+        let coalesced = message_ids.len() - fresh.len();
+        if coalesced > 0 {
+            counter!("kwn_push_coalesced_total").increment(coalesced as u64);
+        }
+        if fresh.is_empty() {
+            return Ok(());
+        }
+        let due = Utc::now() + chrono::Duration::from_std(self.coalesce_window).unwrap_or_default();
+        if let Err(e) = self.store.enqueue_pushes(&fresh, due) {
+            for message_id in &fresh {
+                self.pending.remove(message_id);
+            }
+            return Err(e);
+        }
+        if self.coalesce_window.is_zero() {
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Returns up to `limit` dead letters, oldest first.
+    pub fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetterView>, StorageError> {
+        Ok(self
+            .store
+            .dead_letters(limit)?
+            .into_iter()
+            .map(|letter| DeadLetterView {
+                id: dead_letter_id(&letter),
+                mailbox_hash: mailbox_hash(letter.message_id.as_str()),
+                failed_at: letter.failed_at,
+                attempts: letter.attempts,
+                error: letter.error,
+            })
+            .collect())
+    }
+
+    /// Removes the dead letter `id`, queuing its push again first if `retry`
+    /// is set. Returns whether there was such a letter.
+    pub fn resolve_dead_letter(&self, id: &str, retry: bool) -> Result<bool, StorageError> {
+        let Some(letter) = self
+            .store
+            .dead_letters(usize::MAX)?
+            .into_iter()
+            .find(|letter| dead_letter_id(letter) == id)
+        else {
+            return Ok(false);
+        };
+        let retry_at = retry.then(Utc::now);
+        let removed = self.store.remove_dead_letter(&letter, retry_at)?;
+        if removed && retry {
+            self.wake.notify_one();
+        }
+        Ok(removed)
+    }
+
+    /// Deletes dead letters older than [`DEAD_LETTER_RETENTION`].
+    pub fn prune(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        self.store.prune_dead_letters(now - DEAD_LETTER_RETENTION)
+    }
+}
+
+/// Sends queued pushes as they come due, forever.
+pub async fn run_outbox(state: SharedState) {
+    // Often enough that a coalesced push goes out soon after its window
+    let poll_interval = match state.outbox.coalesce_window {
+        window if window.is_zero() => POLL_INTERVAL,
+        window => window.min(POLL_INTERVAL),
+    };
+    loop {
+        tokio::select! {
+            _ = state.outbox.wake.notified() => {}
+            _ = sleep(poll_interval) => {}
+        }
+        // Drain everything due before waiting again
+        loop {
+            let store = state.outbox.store.clone();
+            let jobs =
+                match tokio::task::spawn_blocking(move || store.due_pushes(Utc::now(), BATCH_SIZE))
+                    .await
+                {
+                    Ok(Ok(jobs)) => jobs,
+                    Ok(Err(e)) => {
+                        error!("Failed to read the push outbox: {}", e);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Push outbox read task failed: {}", e);
+                        break;
+                    }
+                };
+            // Puts from here on need a push of their own
+            for job in &jobs {
+                state.outbox.pending.remove(&job.message_id);
+            }
+            let drained = jobs.len() < BATCH_SIZE;
+            // Jobs that couldn't be finished would come straight back
+            if jobs.is_empty() || !send_batch(&state, jobs).await || drained {
+                break;
+            }
+        }
+    }
+}
+
+// Sends `jobs` and records their outcomes, returning whether that worked.
+async fn send_batch(state: &SharedState, jobs: Vec<OutboxJob>) -> bool {
+    let sends = jobs
+        .iter()
+        .map(|job| send_notification(axum::extract::State(state.clone()), job.message_id.clone()));
+    let results = join_all(sends).await;
+    let mut finished = Vec::with_capacity(jobs.len());
+    for (job, result) in jobs.into_iter().zip(results) {
+        let outcome = match result {
+            Ok(_) => Outcome::Done,
+            Err(e) => match e.retry() {
+                (RetryClass::Permanent, _) => {
+                    error!("Failed to send notification: {:?}", e);
+                    Outcome::Done
+                }
+                _ if job.attempts + 1 >= MAX_ATTEMPTS => {
+                    counter!("kwn_push_outbox_dead_letters_total").increment(1);
+                    warn!(
+                        "Dead-lettering push to {} after {} attempts: {:?}",
+                        job.message_id,
+                        job.attempts + 1,
+                        e
+                    );
+                    // Unlikely to reach the device by push any time soon
+                    if let Some(email) = &state.email {
+                        email.notify(job.message_id.clone());
+                    }
+                    Outcome::Dead(e.to_string())
+                }
+                (_, retry_after_secs) => {
+                    counter!("kwn_push_outbox_retries_total").increment(1);
+                    let wait = retry_after_secs
+                        .map(Duration::from_secs)
+                        .unwrap_or_else(|| backoff(job.attempts));
+                    Outcome::Retry(
+                        Utc::now() + chrono::Duration::from_std(wait).unwrap_or_default(),
+                    )
+                }
+            },
+        };
+        finished.push((job, outcome));
+    }
+    let store = state.outbox.store.clone();
+    match tokio::task::spawn_blocking(move || {
+        for (job, outcome) in &finished {
+            match outcome {
+                Outcome::Done => store.finish_push(job, None)?,
+                Outcome::Retry(retry_at) => store.finish_push(job, Some(*retry_at))?,
+                Outcome::Dead(error) => store.dead_letter_push(job, error, Utc::now())?,
+            }
+        }
+        Ok::<_, StorageError>(())
+    })
+    .await
+    {
+        Ok(Ok(())) => true,
+        // Left in the outbox, so they're sent again on a later pass
+        Ok(Err(e)) => {
+            error!("Failed to update the push outbox: {}", e);
+            false
+        }
+        Err(e) => {
+            error!("Push outbox update task failed: {}", e);
+            false
+        }
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(1u32.checked_shl(attempts).unwrap_or(u32::MAX))
+        .min(MAX_BACKOFF)
+}
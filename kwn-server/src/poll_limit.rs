@@ -0,0 +1,101 @@
+//! Alternative rate-limit keys for long polls.
+//!
+//! Every route shares one per-IP budget by default, so many devices behind a
+//! carrier-grade NAT exhaust it together at peak times. `GET_RATE_LIMIT_KEY`
+//! moves the get path off that budget onto one keyed by the polled mailbox
+//! (`mailbox`: a hash of the sorted message_ids) or by a key the client sends in
+//! the `Poll-Key` header (`poll_key`, falling back to the IP without one). Each
+//! key gets `GET_REQUESTS_PER_MINUTE` of its own.
+
+use axum::http::HeaderMap;
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Quota, RateLimiter,
+};
+use kwn_protocol::{MessageId, POLL_KEY_HEADER};
+use sha2::{Digest, Sha256};
+use std::num::NonZeroU32;
+
+const MAX_POLL_KEY_LEN: usize = 128;
+
+// What the get path's budget is keyed on instead of the IP.
+#[derive(Clone, Copy, Debug)]
+enum PollKeying {
+    Mailbox,
+    PollKey,
+}
+
+pub struct PollLimiter {
+    keying: PollKeying,
+    limiter: DefaultKeyedRateLimiter<String>,
+}
+
+impl PollLimiter {
+    /// None when gets stay on the shared per-IP budget.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let keying = match std::env::var("GET_RATE_LIMIT_KEY").as_deref() {
+            Err(_) | Ok("") | Ok("ip") => return Ok(None),
+            Ok("mailbox") => PollKeying::Mailbox,
+            Ok("poll_key") => PollKeying::PollKey,
+            Ok(other) => {
+                return Err(format!(
+                    "GET_RATE_LIMIT_KEY must be ip, mailbox or poll_key, not {}",
+                    other
+                )
+                .into())
+            }
+        };
+        let per_minute = std::env::var("GET_REQUESTS_PER_MINUTE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .and_then(NonZeroU32::new)
+            .unwrap_or(NonZeroU32::new(120).unwrap());
+        Ok(Some(Self {
+            keying,
+            limiter: RateLimiter::keyed(Quota::per_minute(per_minute)),
+        }))
+    }
+
+    /// Charges one poll to its key, returning the seconds to wait if the key's
+    /// budget is spent.
+    pub fn check(
+        &self,
+        headers: &HeaderMap,
+        message_ids: &[MessageId],
+        client_ip: &str,
+    ) -> Result<(), u64> {
+        let key = match self.keying {
+            PollKeying::Mailbox => format!("mailbox:{}", mailbox_key(message_ids)),
+            PollKeying::PollKey => match headers
+                .get(POLL_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .filter(|key| !key.is_empty() && key.len() <= MAX_POLL_KEY_LEN)
+            {
+                Some(key) => format!("poll:{}", key),
+                None => format!("ip:{}", client_ip),
+            },
+        };
+        self.limiter.check_key(&key).map_err(|not_until| {
+            let wait = not_until.wait_time_from(DefaultClock::default().now());
+            wait.as_secs_f64().ceil() as u64
+        })
+    }
+
+    /// Drops keys whose budget has fully refilled.
+    pub fn retain_recent(&self) {
+        self.limiter.retain_recent();
+    }
+}
+
+// Order-independent, so a client listing its ids differently still shares one budget.
+fn mailbox_key(message_ids: &[MessageId]) -> String {
+    let mut sorted: Vec<&str> = message_ids.iter().map(MessageId::as_str).collect();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut hasher = Sha256::new();
+    for message_id in sorted {
+        hasher.update(message_id.as_bytes());
+        hasher.update(b"\n");
+    }
+    hex::encode(hasher.finalize())
+}
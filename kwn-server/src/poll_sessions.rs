@@ -0,0 +1,73 @@
+//! Coalescing of duplicate long polls from one client.
+//!
+//! Each open tab of the web client starts its own long poll for the same ids.
+//! Tabs share a session token (kept in localStorage), and while one poll for a
+//! given session and id set is waiting, identical polls are turned away with
+//! 409; those tabs skip that poll and leave delivery to the one already waiting.
+
+use dashmap::DashMap;
+use kwn_protocol::MessageId;
+use std::sync::Arc;
+
+#[derive(Default, Clone)]
+pub struct PollSessions {
+    active: Arc<DashMap<String, ()>>,
+}
+
+/// Marks a poll as in flight until dropped, including when the client
+/// disconnects and the handler future is cancelled.
+pub struct PollGuard {
+    active: Arc<DashMap<String, ()>>,
+    key: String,
+}
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        self.active.remove(&self.key);
+    }
+}
+
+impl PollSessions {
+    /// Returns `None` if a poll with the same session and ids is already waiting.
+    pub fn begin(&self, session: &str, message_ids: &[MessageId]) -> Option<PollGuard> {
+        let mut ids: Vec<&str> = message_ids.iter().map(MessageId::as_str).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let key = format!("{}\n{}", session, ids.join("\n"));
+        match self.active.entry(key.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(_) => None,
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(());
+                Some(PollGuard {
+                    active: self.active.clone(),
+                    key,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_polls_are_refused_until_the_first_ends() {
+        let sessions = PollSessions::default();
+        let a = MessageId::parse("a").unwrap();
+        let b = MessageId::parse("b").unwrap();
+
+        let guard = sessions.begin("tab", &[a.clone(), b.clone()]).unwrap();
+        // The same ids in another order, or repeated, are the same poll
+        assert!(sessions
+            .begin("tab", &[b.clone(), a.clone(), a.clone()])
+            .is_none());
+        assert!(sessions
+            .begin("other tab", &[a.clone(), b.clone()])
+            .is_some());
+        assert!(sessions.begin("tab", std::slice::from_ref(&a)).is_some());
+
+        drop(guard);
+        assert!(sessions.begin("tab", &[a, b]).is_some());
+    }
+}
@@ -0,0 +1,225 @@
+use axum::{extract::State, http::StatusCode};
+use futures::future::join_all;
+use kwn_protocol::{MessageId, NotificationPayload};
+use kwn_push::{PushError, PushTopic};
+use metrics::counter;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::{
+    error::AppError, locales::notification_text, response_size::json_len, state::SharedState,
+};
+
+// The 4096 bytes Web Push delivers, less aes128gcm's 86-byte header, 16-byte
+// tag and padding delimiter
+const MAX_PUSH_PLAINTEXT: usize = 4096 - 86 - 16 - 1;
+
+/// Sends a push to every device subscribed to `message_id`, if any.
+///
+/// Subscriptions are one-shot: the stored subscriptions are removed before the
+/// pushes go out and each client re-registers on its next poll. Those whose
+/// push failed in a way worth retrying are put back, for the outbox's retry,
+/// as are those skipped for being over their device's push budget and webhooks,
+/// which are kept until they fail for good.
+/// Succeeds if any device was reached.
+pub async fn send_notification(
+    State(state): State<SharedState>,
+    message_id: MessageId,
+) -> Result<StatusCode, AppError> {
+    info!("Received request to send push notification.");
+    // Look the subscriptions up and remove them in one blocking hop, moving the
+    // message_id through it rather than cloning it for each step. The pending
+    // count for the payload is read in the same hop, and the newest message
+    // too when pushes carry it.
+    let subscriptions = state.subscriptions.clone();
+    let messages = state.messages.clone();
+    let include_message = state.push_include_message;
+    let lookup = tokio::task::spawn_blocking(move || {
+        let taken = subscriptions.subscriptions(&message_id).and_then(|infos| {
+            if infos.is_empty() {
+                return Ok(None);
+            }
+            subscriptions
+                .remove_subscription(&message_id)
+                .and_then(|()| messages.count_and_latest(&message_id))
+                .and_then(|pending| {
+                    let newest = match include_message {
+                        true => messages.newest_message(&message_id)?,
+                        false => None,
+                    };
+                    Ok(Some((infos, pending, newest)))
+                })
+        });
+        (message_id, taken)
+    })
+    .await;
+
+    let (message_id, subscription_infos, (pending, latest), newest) = match lookup {
+        Ok((message_id, Ok(Some((infos, pending, newest))))) => {
+            info!(
+                "{} subscription(s) removed for message ID: {}",
+                infos.len(),
+                message_id
+            );
+            (message_id, infos, pending, newest)
+        }
+        Ok((message_id, Ok(None))) => {
+            info!("No subscription found for message ID: {}", message_id);
+            state
+                .debug
+                .record(&message_id, "push", None, "no subscription");
+            if let Some(email) = &state.email {
+                email.notify(message_id);
+            }
+            return Ok(StatusCode::NOT_FOUND);
+        }
+        Ok((_, Err(storage_error))) => return Err(storage_error.into()), // Propagate error from blocking task
+        Err(join_error) => {
+            error!("Failed to execute subscription lookup task: {}", join_error);
+            return Err(AppError::WebPush(format!(
+                "Task join error during subscription lookup: {}",
+                join_error
+            )));
+        }
+    };
+
+    let latest = latest.unwrap_or_else(chrono::Utc::now);
+    let notification_payload = |locale: Option<&str>| {
+        let text = notification_text(locale);
+        let mut payload = NotificationPayload {
+            title: text.title.to_string(),
+            body: text
+                .body
+                .replace("{count}", &pending.to_string())
+                .replace("{time}", &latest.to_string()),
+            icon: Some("android-chrome-192x192.png".to_string()), // Match service worker expectation
+            url: Some(format!("{}/", state.base_path)),           // URL to open on click
+            badge: Some(pending),
+            message: newest.clone(),
+        };
+        // Web Push caps what it carries; a message too large is fetched instead
+        if payload.message.is_some() && json_len(&payload) > MAX_PUSH_PLAINTEXT {
+            payload.message = None;
+        }
+        payload
+    };
+
+    // Devices over their push budget keep their subscription for a later push
+    let (subscription_infos, mut restore): (Vec<_>, Vec<_>) = subscription_infos
+        .into_iter()
+        .partition(|subscription_info| match &state.push_limit {
+            Some(push_limit) => push_limit.allow(&subscription_info.endpoint),
+            None => true,
+        });
+    if !restore.is_empty() {
+        counter!("kwn_push_suppressed_total").increment(restore.len() as u64);
+        state
+            .debug
+            .record(&message_id, "push", None, "over the device's push budget");
+    }
+
+    let topic = PushTopic::for_mailbox(&message_id);
+    let results = join_all(subscription_infos.iter().map(|subscription_info| async {
+        let _permit = state.outbox.send_permit().await;
+        let started = Instant::now();
+        let result = state
+            .push
+            .send(
+                subscription_info,
+                &notification_payload(subscription_info.locale.as_deref()),
+                Some(&topic),
+            )
+            .await;
+        (started.elapsed(), result)
+    }))
+    .await;
+
+    let mut delivered = false;
+    let mut gone = Vec::new();
+    let mut last_error = None;
+    for ((elapsed, result), subscription_info) in results.into_iter().zip(subscription_infos) {
+        state.debug.record(
+            &message_id,
+            "push",
+            Some(elapsed),
+            match &result {
+                Ok(()) => "sent".to_string(),
+                Err(e) => e.to_string(),
+            },
+        );
+        match result {
+            Ok(()) => {
+                state.analytics.record_push();
+                delivered = true;
+                if state.push_router.persistent(&subscription_info) {
+                    restore.push(subscription_info);
+                }
+            }
+            Err(e) => {
+                state.report_stats.record_push_failure(&e);
+                match &e {
+                    PushError::EndpointGone => gone.push(subscription_info),
+                    PushError::RateLimited | PushError::Failed(_) => {
+                        restore.push(subscription_info)
+                    }
+                    PushError::Unauthorized => {}
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if !restore.is_empty() {
+        // Before any resubscribe flag is set below, which saving would clear
+        let subscriptions = state.subscriptions.clone();
+        let restore_id = message_id.clone();
+        match tokio::task::spawn_blocking(move || {
+            for subscription_info in &restore {
+                subscriptions
+                    .save_subscription(std::slice::from_ref(&restore_id), subscription_info)?;
+            }
+            Ok::<_, kwn_storage::StorageError>(())
+        })
+        .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to restore subscriptions for {}: {}", message_id, e),
+            Err(e) => error!("Subscription restore task failed: {}", e),
+        }
+    }
+
+    let gone_count = gone.len();
+    if !gone.is_empty() {
+        // A dead endpoint is removed from every mailbox it was registered for,
+        // not just this one, and each is flagged so its clients learn on their
+        // next poll that a device needs a fresh subscription
+        let subscriptions = state.subscriptions.clone();
+        let message_id_flag = message_id.clone();
+        match tokio::task::spawn_blocking(move || {
+            let mut flagged = 0;
+            for subscription_info in &gone {
+                flagged += subscriptions.remove_endpoint(subscription_info)?.len();
+            }
+            // This mailbox's subscriptions were already taken for the push
+            subscriptions
+                .mark_resubscribe_required(&message_id_flag)
+                .map(|()| flagged)
+        })
+        .await
+        {
+            Ok(Ok(flagged)) => {
+                counter!("kwn_push_endpoints_removed_total").increment(gone_count as u64);
+                info!(
+                    "Removed {} gone endpoint(s) from {} other mailbox(es); flagged message ID {} for resubscription",
+                    gone_count, flagged, message_id
+                );
+            }
+            Ok(Err(e)) => error!("Failed to remove gone endpoints for {}: {}", message_id, e),
+            Err(e) => error!("Gone endpoint removal task failed: {}", e),
+        }
+    }
+    match last_error {
+        Some(e) if !delivered => Err(e.into()),
+        _ => Ok(StatusCode::OK),
+    }
+}
@@ -0,0 +1,170 @@
+//! Simulated push provider outages for end-to-end resilience testing.
+//!
+//! [`ChaosPushProvider`] wraps the real provider. While an operator-enabled
+//! fault is active for a push service host (the scheme, e.g. `apns`, for native ones, or `*`
+//! for all of them), sends to that host are dropped, delayed, or fail as a 429 or 410
+//! would, and each affected send is kept in a small ring buffer. Only the host
+//! and the notification title are recorded, never the subscription endpoint
+//! path or keys.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use kwn_protocol::{NotificationPayload, PushSubscriptionInfo};
+use kwn_push::{endpoint_scheme, PushError, PushProvider, PushTopic};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::info;
+
+const MAX_SIMULATED_SENDS: usize = 256;
+pub const MAX_FAULT_DURATION: Duration = Duration::from_secs(3600);
+pub const ALL_PROVIDERS: &str = "*";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SimulatedFault {
+    Drop,                    // Report success without sending
+    Delay { delay_ms: u64 }, // Send after the delay
+    RateLimited,             // Fail as if the service returned 429
+    Gone,                    // Fail as if the service returned 410
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ActiveFault {
+    pub provider: String,
+    pub fault: SimulatedFault,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SimulatedSend {
+    pub at: DateTime<Utc>,
+    pub provider: String,
+    pub fault: SimulatedFault,
+    pub title: String,
+}
+
+// Push service host of a subscription endpoint, e.g. "fcm.googleapis.com".
+fn provider_host(endpoint: &str) -> &str {
+    // The rest of a native endpoint is the device's token
+    let scheme = endpoint_scheme(endpoint);
+    if scheme != "https" {
+        return scheme;
+    }
+    let rest = endpoint
+        .split_once("://")
+        .map_or(endpoint, |(_, rest)| rest);
+    rest.split('/').next().unwrap_or(rest)
+}
+
+pub struct ChaosPushProvider {
+    inner: Arc<dyn PushProvider>,
+    faults: DashMap<String, ActiveFault>, // Keyed by provider host or ALL_PROVIDERS
+    sends: Mutex<VecDeque<SimulatedSend>>,
+}
+
+impl ChaosPushProvider {
+    pub fn new(inner: Arc<dyn PushProvider>) -> Self {
+        Self {
+            inner,
+            faults: DashMap::new(),
+            sends: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn start(
+        &self,
+        provider: String,
+        fault: SimulatedFault,
+        duration: Duration,
+    ) -> DateTime<Utc> {
+        let duration = duration.min(MAX_FAULT_DURATION);
+        let expires_at = Utc::now()
+            + chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+        info!(%provider, ?fault, %expires_at, "Simulated push fault started");
+        self.faults.insert(
+            provider.clone(),
+            ActiveFault {
+                provider,
+                fault,
+                expires_at,
+            },
+        );
+        expires_at
+    }
+
+    pub fn stop(&self, provider: &str) -> Option<ActiveFault> {
+        self.faults.remove(provider).map(|(_, fault)| fault)
+    }
+
+    pub fn faults(&self) -> Vec<ActiveFault> {
+        self.expire();
+        self.faults
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    pub fn simulated_sends(&self) -> Vec<SimulatedSend> {
+        self.sends.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn expire(&self) {
+        let now = Utc::now();
+        self.faults.retain(|_, fault| fault.expires_at > now);
+    }
+
+    fn active_fault(&self, provider: &str) -> Option<SimulatedFault> {
+        if self.faults.is_empty() {
+            return None;
+        }
+        self.expire();
+        self.faults
+            .get(provider)
+            .or_else(|| self.faults.get(ALL_PROVIDERS))
+            .map(|entry| entry.fault)
+    }
+
+    fn record(&self, provider: &str, fault: SimulatedFault, payload: &NotificationPayload) {
+        info!(provider, ?fault, "Simulated push fault applied");
+        let mut sends = self.sends.lock().unwrap();
+        if sends.len() == MAX_SIMULATED_SENDS {
+            sends.pop_front();
+        }
+        sends.push_back(SimulatedSend {
+            at: Utc::now(),
+            provider: provider.to_string(),
+            fault,
+            title: payload.title.clone(),
+        });
+    }
+}
+
+#[async_trait]
+impl PushProvider for ChaosPushProvider {
+    async fn send(
+        &self,
+        subscription: &PushSubscriptionInfo,
+        payload: &NotificationPayload,
+        topic: Option<&PushTopic>,
+    ) -> Result<(), PushError> {
+        let provider = provider_host(&subscription.endpoint);
+        let Some(fault) = self.active_fault(provider) else {
+            return self.inner.send(subscription, payload, topic).await;
+        };
+        self.record(provider, fault, payload);
+        match fault {
+            SimulatedFault::Drop => Ok(()),
+            SimulatedFault::Delay { delay_ms } => {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                self.inner.send(subscription, payload, topic).await
+            }
+            SimulatedFault::RateLimited => Err(PushError::RateLimited),
+            SimulatedFault::Gone => Err(PushError::EndpointGone),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Per-device push budget.
+//!
+//! Push services throttle, and may ban the VAPID key of, a server that pushes
+//! one device too often. Each endpoint gets `PUSHES_PER_ENDPOINT_PER_HOUR`
+//! (default 120; 0 lifts the cap) pushes, refilling evenly over the hour. A
+//! push over budget is skipped, not failed: the message is stored as usual and
+//! the subscription kept, so the device hears of it with the next push that
+//! fits. Skipped pushes are counted in `kwn_push_suppressed_total`.
+
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use sha2::{Digest, Sha256};
+use std::num::NonZeroU32;
+
+pub struct PushLimiter {
+    // Keyed by the SHA-256 of the endpoint, which can run to a few hundred bytes
+    limiter: DefaultKeyedRateLimiter<[u8; 32]>,
+}
+
+impl PushLimiter {
+    /// None when pushes are unlimited.
+    pub fn from_env() -> Option<Self> {
+        let per_hour = std::env::var("PUSHES_PER_ENDPOINT_PER_HOUR")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(120);
+        NonZeroU32::new(per_hour).map(|per_hour| Self {
+            limiter: RateLimiter::keyed(Quota::per_hour(per_hour)),
+        })
+    }
+
+    /// Charges one push to `endpoint`, returning false if its budget is spent.
+    pub fn allow(&self, endpoint: &str) -> bool {
+        let key: [u8; 32] = Sha256::digest(endpoint.as_bytes()).into();
+        self.limiter.check_key(&key).is_ok()
+    }
+
+    /// Drops endpoints whose budget has fully refilled.
+    pub fn retain_recent(&self) {
+        self.limiter.retain_recent();
+    }
+}
@@ -0,0 +1,196 @@
+//! Weekly operator reports for deployments without dashboards.
+//!
+//! With `REPORT_WEBHOOK_URL` set, every Monday at 09:00 UTC the server posts a
+//! summary of the past week to that URL: traffic from the daily analytics
+//! buckets, cleanup work (acked messages, compactions, reaped mailboxes), push
+//! failures by cause and storage pressure. The body carries a plain `text`
+//! line for chat webhooks and SMTP bridges alongside the structured `report`.
+//! Cleanup and failure counts are kept in memory and reset by each report, so
+//! the first report after a restart covers less than a week of them.
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc};
+use kwn_protocol::AnalyticsPeriod;
+use kwn_push::PushError;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{error, info};
+
+use crate::state::SharedState;
+
+const REPORT_HOUR_UTC: u32 = 9;
+
+#[derive(Default)]
+pub struct ReportStats {
+    acked_messages: AtomicU64,
+    compactions: AtomicU64,
+    reaped_mailboxes: AtomicU64,
+    push_gone: AtomicU64,
+    push_unauthorized: AtomicU64,
+    push_rate_limited: AtomicU64,
+    push_other: AtomicU64,
+}
+
+impl ReportStats {
+    pub fn record_acked(&self, deleted: usize) {
+        self.acked_messages
+            .fetch_add(deleted as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_compaction(&self) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reaped(&self, reaped: usize) {
+        self.reaped_mailboxes
+            .fetch_add(reaped as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_push_failure(&self, error: &PushError) {
+        let counter = match error {
+            PushError::EndpointGone => &self.push_gone,
+            PushError::Unauthorized => &self.push_unauthorized,
+            PushError::RateLimited => &self.push_rate_limited,
+            PushError::Failed(_) => &self.push_other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PushFailures {
+    endpoint_gone: u64,
+    unauthorized: u64,
+    rate_limited: u64,
+    other: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct WeeklyReport {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    messages: u64,
+    bytes: u64,
+    push_sends: u64,
+    busiest_day_mailboxes: u64,
+    acked_messages: u64,
+    compactions: u64,
+    reaped_mailboxes: u64,
+    push_failures: PushFailures,
+    disk_space_bytes: u64,
+    stall_risk: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct WebhookBody {
+    text: String,
+    report: WeeklyReport,
+}
+
+// The next Monday at REPORT_HOUR_UTC strictly after `now`.
+fn next_report_at(now: DateTime<Utc>) -> DateTime<Utc> {
+    let days_ahead = (7 - now.weekday().num_days_from_monday()) % 7;
+    let candidate = (now.date_naive() + Duration::days(days_ahead as i64))
+        .and_time(NaiveTime::from_hms_opt(REPORT_HOUR_UTC, 0, 0).unwrap())
+        .and_utc();
+    if candidate > now {
+        candidate
+    } else {
+        candidate + Duration::weeks(1)
+    }
+}
+
+fn build_report(state: &SharedState, to: DateTime<Utc>) -> Result<WeeklyReport, String> {
+    let from = to - Duration::weeks(1);
+    let buckets = state
+        .analytics
+        .query(AnalyticsPeriod::Day, from, to)
+        .map_err(|e| e.to_string())?;
+    let stats = &state.report_stats;
+    let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+    let health = state.messages.health();
+    Ok(WeeklyReport {
+        from,
+        to,
+        messages: buckets.iter().map(|b| b.messages).sum(),
+        bytes: buckets.iter().map(|b| b.bytes).sum(),
+        push_sends: buckets.iter().map(|b| b.push_sends).sum(),
+        busiest_day_mailboxes: buckets
+            .iter()
+            .map(|b| b.active_mailboxes)
+            .max()
+            .unwrap_or(0),
+        acked_messages: take(&stats.acked_messages),
+        compactions: take(&stats.compactions),
+        reaped_mailboxes: take(&stats.reaped_mailboxes),
+        push_failures: PushFailures {
+            endpoint_gone: take(&stats.push_gone),
+            unauthorized: take(&stats.push_unauthorized),
+            rate_limited: take(&stats.push_rate_limited),
+            other: take(&stats.push_other),
+        },
+        disk_space_bytes: health.disk_space_bytes,
+        stall_risk: health.stall_risk,
+    })
+}
+
+fn summary(report: &WeeklyReport) -> String {
+    let push_failures = report.push_failures.endpoint_gone
+        + report.push_failures.unauthorized
+        + report.push_failures.rate_limited
+        + report.push_failures.other;
+    format!(
+        "Relay weekly report: {} messages ({} bytes), {} acked, {} pushes sent, {} push failures, {} compactions, {} mailboxes reaped, {} MiB on disk{}",
+        report.messages,
+        report.bytes,
+        report.acked_messages,
+        report.push_sends,
+        push_failures,
+        report.compactions,
+        report.reaped_mailboxes,
+        report.disk_space_bytes / (1024 * 1024),
+        if report.stall_risk { ", STORAGE NEAR WRITE STALL" } else { "" },
+    )
+}
+
+/// Sends a report to `webhook_url` every week, forever.
+pub async fn run_weekly_reports(state: SharedState, webhook_url: String) {
+    let client = reqwest::Client::new();
+    loop {
+        let now = Utc::now();
+        let wait = (next_report_at(now) - now)
+            .to_std()
+            .unwrap_or(std::time::Duration::ZERO);
+        tokio::time::sleep(wait).await;
+
+        let report_state = state.clone();
+        let report = match tokio::task::spawn_blocking(move || {
+            build_report(&report_state, Utc::now())
+        })
+        .await
+        {
+            Ok(Ok(report)) => report,
+            Ok(Err(e)) => {
+                error!("Failed to build weekly report: {}", e);
+                continue;
+            }
+            Err(e) => {
+                error!("Weekly report task failed: {}", e);
+                continue;
+            }
+        };
+        let body = WebhookBody {
+            text: summary(&report),
+            report,
+        };
+        match client
+            .post(&webhook_url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => info!("Delivered weekly report"),
+            Err(e) => error!("Failed to deliver weekly report: {}", e),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Bounds on the size of poll responses, and a record of the sizes sent.
+//!
+//! A poll over many busy mailboxes could otherwise build a body of any size.
+//! `MAX_RESPONSE_BYTES` (default 4 MiB) caps the JSON of a poll's messages: the
+//! newest ones past it are left for the next poll and the response says
+//! `truncated`. Sizes are measured with a counting writer rather than by
+//! building the body. Every response of known length is recorded in the
+//! `kwn_response_bytes` histogram, by route.
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::MatchedPath,
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use kwn_protocol::FoundMessage;
+use metrics::histogram;
+use serde::Serialize;
+use std::io;
+
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+// Room for everything in a poll response besides its messages and retained values
+const RESPONSE_OVERHEAD: usize = 16 * 1024;
+
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Length of `value` as JSON, without allocating it.
+pub fn json_len<T: Serialize + ?Sized>(value: &T) -> usize {
+    let mut counter = ByteCounter(0);
+    // Only fails for maps with non-string keys, which no response has
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+/// Splits `found` into the oldest messages whose JSON fits in `max_bytes`, less
+/// `reserved` for other parts of the response, and the rest. The oldest are
+/// kept whatever the poll's order, so each id only loses its newest and paging
+/// can resume after the last kept. At least one message is always kept.
+pub fn fit_messages(
+    mut found: Vec<FoundMessage>,
+    max_bytes: usize,
+    reserved: usize,
+) -> (Vec<FoundMessage>, Vec<FoundMessage>) {
+    let budget = max_bytes.saturating_sub(RESPONSE_OVERHEAD + reserved);
+    found.sort_by(|a, b| (a.timestamp, &a.message_id).cmp(&(b.timestamp, &b.message_id)));
+    let mut used = 0;
+    let mut keep = 0;
+    for message in &found {
+        let len = json_len(message) + 1; // And its comma
+        if keep > 0 && used + len > budget {
+            break;
+        }
+        used += len;
+        keep += 1;
+    }
+    let left_out = found.split_off(keep);
+    (found, left_out)
+}
+
+/// Records the size of each response whose body length is known up front.
+/// Streamed bodies record their own.
+pub async fn record_response_size(req: Request<Body>, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let response = next.run(req).await;
+    if let (Some(route), Some(bytes)) = (route, response.body().size_hint().exact()) {
+        record(route, bytes as usize);
+    }
+    response
+}
+
+pub fn record(route: String, bytes: usize) {
+    histogram!("kwn_response_bytes", "route" => route).record(bytes as f64);
+}
@@ -0,0 +1,32 @@
+//! Delivery of messages put with a future `deliver_at`.
+//!
+//! Such messages wait in storage's scheduled partition, invisible to polls and
+//! without pushes. Once a second the scheduler moves everything that has come
+//! due into its mailbox and announces it exactly as a fresh put would.
+
+use chrono::Utc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::error;
+
+use crate::{handlers::after_put, state::SharedState};
+
+const RELEASE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Releases due messages every [`RELEASE_INTERVAL`], forever.
+pub async fn run_scheduler(state: SharedState) {
+    let mut ticker = interval(RELEASE_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let messages = state.messages.clone();
+        match tokio::task::spawn_blocking(move || messages.release_due(Utc::now())).await {
+            Ok(Ok(released)) => {
+                for (message_id, message_len) in released {
+                    after_put(&state, message_id, message_len);
+                }
+            }
+            Ok(Err(e)) => error!("Failed to release scheduled messages: {}", e),
+            Err(e) => error!("Scheduled release task failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,576 @@
+//! Shadow-write mode for trying a storage redesign on production traffic.
+//!
+//! With `SHADOW_DB_PATH` set, [`ShadowStore`] serves every read from the
+//! primary store and mirrors every mutation to a second store at that path.
+//! Shadow failures are counted and logged but never fail the request. A
+//! periodic [`ShadowStore::compare`] re-reads recently mutated mailboxes from
+//! both stores and counts those that disagree, so a new key layout or backend
+//! can run against real traffic before it takes over.
+
+use chrono::{DateTime, Utc};
+use kwn_protocol::{
+    AckToken, FoundMessage, MessageId, MessageState, PushSubscriptionInfo, PutMessageRequest,
+};
+use kwn_storage::{
+    CancelOutcome, DeletionPolicy, DeliveryAnomalies, MessageStore, PersistReason, Result,
+    StorageHealth, SubscriptionStore,
+};
+use metrics::{counter, gauge};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+// Mailboxes remembered for the next comparison; older ones are dropped unchecked.
+const MAX_PENDING_CHECKS: usize = 1024;
+
+pub struct ShadowStore {
+    primary_messages: Arc<dyn MessageStore>,
+    primary_subscriptions: Arc<dyn SubscriptionStore>,
+    shadow_messages: Arc<dyn MessageStore>,
+    shadow_subscriptions: Arc<dyn SubscriptionStore>,
+    pending: Mutex<VecDeque<MessageId>>,
+}
+
+impl ShadowStore {
+    pub fn new(
+        primary_messages: Arc<dyn MessageStore>,
+        primary_subscriptions: Arc<dyn SubscriptionStore>,
+        shadow_messages: Arc<dyn MessageStore>,
+        shadow_subscriptions: Arc<dyn SubscriptionStore>,
+    ) -> Self {
+        Self {
+            primary_messages,
+            primary_subscriptions,
+            shadow_messages,
+            shadow_subscriptions,
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn mirror(&self, operation: &'static str, result: Result<()>) {
+        if let Err(e) = result {
+            counter!("kwn_shadow_write_errors_total", "operation" => operation).increment(1);
+            warn!("Shadow {} failed: {}", operation, e);
+        }
+    }
+
+    fn queue_check<'a>(&self, message_ids: impl IntoIterator<Item = &'a MessageId>) {
+        let mut pending = self.pending.lock().unwrap();
+        for message_id in message_ids {
+            if pending.len() == MAX_PENDING_CHECKS {
+                pending.pop_front();
+            }
+            pending.push_back(message_id.clone());
+        }
+    }
+
+    /// Compares messages and subscriptions of the mailboxes mutated since the
+    /// last call, returning how many diverged. Blocking.
+    pub fn compare(&self) -> Result<usize> {
+        let message_ids: Vec<MessageId> = {
+            let mut pending = self.pending.lock().unwrap();
+            let unique: HashSet<MessageId> = pending.drain(..).collect();
+            unique.into_iter().collect()
+        };
+        let mut divergent = 0;
+        for message_id in &message_ids {
+            let ids = std::slice::from_ref(message_id);
+            let primary = message_keys(self.primary_messages.fetch(ids)?);
+            let shadow = match self.shadow_messages.fetch(ids) {
+                Ok(found) => message_keys(found),
+                Err(e) => {
+                    warn!("Shadow read failed during comparison: {}", e);
+                    divergent += 1;
+                    continue;
+                }
+            };
+            let primary_endpoints =
+                endpoints(self.primary_subscriptions.subscriptions(message_id)?);
+            let shadow_endpoints = self
+                .shadow_subscriptions
+                .subscriptions(message_id)
+                .map(endpoints)
+                .ok();
+            if primary != shadow || Some(primary_endpoints) != shadow_endpoints {
+                divergent += 1;
+            }
+        }
+        gauge!("kwn_shadow_last_checked_mailboxes").set(message_ids.len() as f64);
+        counter!("kwn_shadow_checked_mailboxes_total").increment(message_ids.len() as u64);
+        counter!("kwn_shadow_divergent_mailboxes_total").increment(divergent as u64);
+        if divergent > 0 {
+            warn!(
+                "Shadow store diverged for {} of {} mailbox(es)",
+                divergent,
+                message_ids.len()
+            );
+        } else if !message_ids.is_empty() {
+            info!("Shadow store matched for {} mailbox(es)", message_ids.len());
+        }
+        Ok(divergent)
+    }
+}
+
+// What must match between stores: which messages exist and their contents.
+fn message_keys(mut found: Vec<FoundMessage>) -> Vec<(i64, String)> {
+    found.sort_by_key(|m| m.timestamp);
+    found
+        .into_iter()
+        .map(|m| (m.timestamp.timestamp_millis(), m.message))
+        .collect()
+}
+
+// Subscriptions are per device; only the set of endpoints must match.
+fn endpoints(subscriptions: Vec<PushSubscriptionInfo>) -> Vec<String> {
+    let mut endpoints: Vec<String> = subscriptions.into_iter().map(|s| s.endpoint).collect();
+    endpoints.sort();
+    endpoints
+}
+
+impl MessageStore for ShadowStore {
+    fn put(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.primary_messages
+            .put(message_id, message, timestamp, expires_at)?;
+        self.mirror(
+            "put",
+            self.shadow_messages
+                .put(message_id, message, timestamp, expires_at),
+        );
+        self.queue_check([message_id]);
+        Ok(())
+    }
+
+    fn supersede(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        supersedes: DateTime<Utc>,
+    ) -> Result<()> {
+        self.primary_messages
+            .supersede(message_id, message, timestamp, expires_at, supersedes)?;
+        self.mirror(
+            "supersede",
+            self.shadow_messages
+                .supersede(message_id, message, timestamp, expires_at, supersedes),
+        );
+        self.queue_check([message_id]);
+        Ok(())
+    }
+
+    fn put_idempotent(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        idempotency_key: &str,
+        key_expires_at: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>> {
+        let stored_at = self.primary_messages.put_idempotent(
+            message_id,
+            message,
+            timestamp,
+            expires_at,
+            idempotency_key,
+            key_expires_at,
+        )?;
+        self.mirror(
+            "put_idempotent",
+            self.shadow_messages
+                .put_idempotent(
+                    message_id,
+                    message,
+                    timestamp,
+                    expires_at,
+                    idempotency_key,
+                    key_expires_at,
+                )
+                .map(|_| ()),
+        );
+        self.queue_check([message_id]);
+        Ok(stored_at)
+    }
+
+    fn prune_idempotency_keys(&self, now: DateTime<Utc>) -> Result<usize> {
+        let pruned = self.primary_messages.prune_idempotency_keys(now)?;
+        self.mirror(
+            "prune_idempotency_keys",
+            self.shadow_messages.prune_idempotency_keys(now).map(|_| ()),
+        );
+        Ok(pruned)
+    }
+
+    fn schedule(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        deliver_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.primary_messages
+            .schedule(message_id, message, deliver_at, expires_at)?;
+        self.mirror(
+            "schedule",
+            self.shadow_messages
+                .schedule(message_id, message, deliver_at, expires_at),
+        );
+        Ok(())
+    }
+
+    fn release_due(&self, now: DateTime<Utc>) -> Result<Vec<(MessageId, usize)>> {
+        let released = self.primary_messages.release_due(now)?;
+        self.mirror(
+            "release_due",
+            self.shadow_messages.release_due(now).map(|_| ()),
+        );
+        self.queue_check(released.iter().map(|(message_id, _)| message_id));
+        Ok(released)
+    }
+
+    fn put_batch(&self, entries: &[PutMessageRequest], timestamp: DateTime<Utc>) -> Result<()> {
+        self.primary_messages.put_batch(entries, timestamp)?;
+        self.mirror(
+            "put_batch",
+            self.shadow_messages.put_batch(entries, timestamp),
+        );
+        self.queue_check(entries.iter().map(|entry| &entry.message_id));
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        message_ids: &[MessageId],
+        visit: &mut dyn FnMut(FoundMessage) -> bool,
+    ) -> Result<usize> {
+        self.primary_messages.scan(message_ids, visit)
+    }
+
+    fn put_retained(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        timestamp: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.primary_messages
+            .put_retained(message_id, message, timestamp, expires_at)?;
+        self.mirror(
+            "put_retained",
+            self.shadow_messages
+                .put_retained(message_id, message, timestamp, expires_at),
+        );
+        Ok(())
+    }
+
+    fn retained(&self, message_ids: &[MessageId]) -> Result<Vec<FoundMessage>> {
+        self.primary_messages.retained(message_ids)
+    }
+
+    fn ids_with_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<MessageId>> {
+        self.primary_messages.ids_with_prefix(prefix, limit)
+    }
+
+    fn page(
+        &self,
+        message_id: &MessageId,
+        after: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<(Vec<FoundMessage>, bool)> {
+        self.primary_messages.page(message_id, after, limit)
+    }
+
+    fn pending_counts(&self, message_ids: &[MessageId]) -> Result<Vec<usize>> {
+        self.primary_messages.pending_counts(message_ids)
+    }
+
+    fn latest_sequences(&self, message_ids: &[MessageId]) -> Result<Vec<Option<u64>>> {
+        self.primary_messages.latest_sequences(message_ids)
+    }
+
+    fn count_and_latest(&self, message_id: &MessageId) -> Result<(usize, Option<DateTime<Utc>>)> {
+        self.primary_messages.count_and_latest(message_id)
+    }
+
+    fn ack(&self, acks: &[AckToken]) -> Result<()> {
+        self.primary_messages.ack(acks)?;
+        self.mirror("ack", self.shadow_messages.ack(acks));
+        self.queue_check(acks.iter().map(|ack| &ack.message_id));
+        Ok(())
+    }
+
+    fn request_receipt(&self, token: &AckToken, receipt_channel: &MessageId) -> Result<()> {
+        self.primary_messages
+            .request_receipt(token, receipt_channel)?;
+        self.mirror(
+            "request_receipt",
+            self.shadow_messages.request_receipt(token, receipt_channel),
+        );
+        Ok(())
+    }
+
+    fn ack_with_receipts(
+        &self,
+        acks: &[AckToken],
+        acked_at: DateTime<Utc>,
+    ) -> Result<Vec<(MessageId, usize)>> {
+        let receipts = self.primary_messages.ack_with_receipts(acks, acked_at)?;
+        self.mirror(
+            "ack_with_receipts",
+            self.shadow_messages
+                .ack_with_receipts(acks, acked_at)
+                .map(|_| ()),
+        );
+        self.queue_check(acks.iter().map(|ack| &ack.message_id));
+        self.queue_check(receipts.iter().map(|(channel, _)| channel));
+        Ok(receipts)
+    }
+
+    fn ack_before(&self, message_id: &MessageId, before: DateTime<Utc>) -> Result<usize> {
+        let deleted = self.primary_messages.ack_before(message_id, before)?;
+        self.mirror(
+            "ack_before",
+            self.shadow_messages
+                .ack_before(message_id, before)
+                .map(|_| ()),
+        );
+        self.queue_check([message_id]);
+        Ok(deleted)
+    }
+
+    fn purge(&self, message_id: &MessageId) -> Result<usize> {
+        let deleted = self.primary_messages.purge(message_id)?;
+        self.mirror("purge", self.shadow_messages.purge(message_id).map(|_| ()));
+        self.queue_check([message_id]);
+        Ok(deleted)
+    }
+
+    fn mark_delivered(&self, tokens: &[AckToken], at: DateTime<Utc>) -> Result<()> {
+        self.primary_messages.mark_delivered(tokens, at)?;
+        self.mirror(
+            "mark_delivered",
+            self.shadow_messages.mark_delivered(tokens, at),
+        );
+        Ok(())
+    }
+
+    fn message_state(&self, token: &AckToken) -> Result<MessageState> {
+        self.primary_messages.message_state(token)
+    }
+
+    fn lease(
+        &self,
+        tokens: &[AckToken],
+        now: DateTime<Utc>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<bool>> {
+        let granted = self.primary_messages.lease(tokens, now, until)?;
+        if until.is_some() {
+            self.mirror(
+                "lease",
+                self.shadow_messages.lease(tokens, now, until).map(|_| ()),
+            );
+        }
+        Ok(granted)
+    }
+
+    fn join_consumer(
+        &self,
+        message_ids: &[MessageId],
+        consumer: &str,
+    ) -> Result<Vec<Option<DateTime<Utc>>>> {
+        let cursors = self.primary_messages.join_consumer(message_ids, consumer)?;
+        self.mirror(
+            "join_consumer",
+            self.shadow_messages
+                .join_consumer(message_ids, consumer)
+                .map(|_| ()),
+        );
+        Ok(cursors)
+    }
+
+    fn advance_cursor(&self, consumer: &str, acks: &[AckToken]) -> Result<Vec<AckToken>> {
+        let done = self.primary_messages.advance_cursor(consumer, acks)?;
+        self.mirror(
+            "advance_cursor",
+            self.shadow_messages
+                .advance_cursor(consumer, acks)
+                .map(|_| ()),
+        );
+        Ok(done)
+    }
+
+    fn save_handle(&self, token: &AckToken, handle_hash: &[u8]) -> Result<()> {
+        self.primary_messages.save_handle(token, handle_hash)?;
+        self.mirror(
+            "save_handle",
+            self.shadow_messages.save_handle(token, handle_hash),
+        );
+        Ok(())
+    }
+
+    fn cancel(&self, token: &AckToken, handle_hash: &[u8]) -> Result<CancelOutcome> {
+        let outcome = self.primary_messages.cancel(token, handle_hash)?;
+        self.mirror(
+            "cancel",
+            self.shadow_messages.cancel(token, handle_hash).map(|_| ()),
+        );
+        Ok(outcome)
+    }
+
+    fn revoke(&self, token: &AckToken) -> Result<bool> {
+        let revoked = self.primary_messages.revoke(token)?;
+        self.mirror("revoke", self.shadow_messages.revoke(token).map(|_| ()));
+        self.queue_check([&token.message_id]);
+        Ok(revoked)
+    }
+
+    fn check_delivery(
+        &self,
+        from: &[u8],
+        limit: usize,
+        now: DateTime<Utc>,
+        stale_before: DateTime<Utc>,
+        swept_before: DateTime<Utc>,
+    ) -> Result<DeliveryAnomalies> {
+        self.primary_messages
+            .check_delivery(from, limit, now, stale_before, swept_before)
+    }
+
+    fn sweep_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        let swept = self.primary_messages.sweep_expired(now)?;
+        self.mirror(
+            "sweep_expired",
+            self.shadow_messages.sweep_expired(now).map(|_| ()),
+        );
+        Ok(swept)
+    }
+
+    fn deletion_policy(&self) -> DeletionPolicy {
+        self.primary_messages.deletion_policy()
+    }
+
+    fn health(&self) -> StorageHealth {
+        self.primary_messages.health()
+    }
+
+    fn persist(&self, reason: PersistReason) -> Result<u64> {
+        let persisted = self.primary_messages.persist(reason)?;
+        self.mirror("persist", self.shadow_messages.persist(reason).map(|_| ()));
+        Ok(persisted)
+    }
+
+    fn compact(&self) -> Result<bool> {
+        let started = self.primary_messages.compact()?;
+        if let Err(e) = self.shadow_messages.compact() {
+            warn!("Shadow compaction failed: {}", e);
+        }
+        Ok(started)
+    }
+}
+
+impl SubscriptionStore for ShadowStore {
+    fn save_subscription(
+        &self,
+        message_ids: &[MessageId],
+        subscription: &PushSubscriptionInfo,
+    ) -> Result<bool> {
+        let changed = self
+            .primary_subscriptions
+            .save_subscription(message_ids, subscription)?;
+        self.mirror(
+            "save_subscription",
+            self.shadow_subscriptions
+                .save_subscription(message_ids, subscription)
+                .map(|_| ()),
+        );
+        self.queue_check(message_ids);
+        Ok(changed)
+    }
+
+    fn subscriptions(&self, message_id: &MessageId) -> Result<Vec<PushSubscriptionInfo>> {
+        self.primary_subscriptions.subscriptions(message_id)
+    }
+
+    fn remove_subscription(&self, message_id: &MessageId) -> Result<()> {
+        self.primary_subscriptions.remove_subscription(message_id)?;
+        self.mirror(
+            "remove_subscription",
+            self.shadow_subscriptions.remove_subscription(message_id),
+        );
+        self.queue_check([message_id]);
+        Ok(())
+    }
+
+    fn remove_device_subscription(&self, message_id: &MessageId, endpoint: &str) -> Result<bool> {
+        let removed = self
+            .primary_subscriptions
+            .remove_device_subscription(message_id, endpoint)?;
+        self.mirror(
+            "remove_device_subscription",
+            self.shadow_subscriptions
+                .remove_device_subscription(message_id, endpoint)
+                .map(|_| ()),
+        );
+        self.queue_check([message_id]);
+        Ok(removed)
+    }
+
+    fn remove_endpoint(&self, subscription: &PushSubscriptionInfo) -> Result<Vec<MessageId>> {
+        let removed = self.primary_subscriptions.remove_endpoint(subscription)?;
+        self.mirror(
+            "remove_endpoint",
+            self.shadow_subscriptions
+                .remove_endpoint(subscription)
+                .map(|_| ()),
+        );
+        self.queue_check(&removed);
+        Ok(removed)
+    }
+
+    fn mark_resubscribe_required(&self, message_id: &MessageId) -> Result<()> {
+        self.primary_subscriptions
+            .mark_resubscribe_required(message_id)?;
+        self.mirror(
+            "mark_resubscribe_required",
+            self.shadow_subscriptions
+                .mark_resubscribe_required(message_id),
+        );
+        Ok(())
+    }
+
+    fn resubscribe_required(&self, message_ids: &[MessageId]) -> Result<Vec<MessageId>> {
+        self.primary_subscriptions.resubscribe_required(message_ids)
+    }
+
+    fn subscribed_ids(&self) -> Result<Vec<MessageId>> {
+        self.primary_subscriptions.subscribed_ids()
+    }
+
+    fn reap_mailbox(&self, message_id: &MessageId) -> Result<()> {
+        self.primary_subscriptions.reap_mailbox(message_id)?;
+        self.mirror(
+            "reap_mailbox",
+            self.shadow_subscriptions.reap_mailbox(message_id),
+        );
+        Ok(())
+    }
+
+    fn forget_mailbox(&self, message_id: &MessageId) -> Result<()> {
+        self.primary_subscriptions.forget_mailbox(message_id)?;
+        self.mirror(
+            "forget_mailbox",
+            self.shadow_subscriptions.forget_mailbox(message_id),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! One-shot share links for cross-device bootstrap.
+//!
+//! The owner of a mailbox issues a link with `POST /api/share-links` and shows
+//! it to a new device, typically as a QR code. Redeeming it with
+//! `POST /api/share-links/redeem` performs a single get-messages for that
+//! mailbox with no other credentials. Links expire after their TTL and work
+//! once: the token is a random value recorded server-side by its hash, so the
+//! message_id can't be read off the QR code and a second redemption finds
+//! nothing.
+
+use axum::extract::{Json, State};
+use chrono::{DateTime, Duration, Utc};
+use kwn_protocol::{
+    GetMessagesRequest, GetMessagesResponse, IssueShareLinkRequest, IssueShareLinkResponse,
+    MessageId, RedeemShareLinkRequest,
+};
+use kwn_storage::{ShareLinkStore, StorageError};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{error, info, instrument};
+
+use crate::{error::AppError, handlers::poll_messages, state::SharedState};
+
+const DEFAULT_TTL_SECS: u64 = 600;
+const MAX_TTL_SECS: u64 = 3600;
+
+pub struct ShareLinks {
+    store: Arc<dyn ShareLinkStore>,
+}
+
+impl ShareLinks {
+    pub fn new(store: Arc<dyn ShareLinkStore>) -> Self {
+        Self { store }
+    }
+
+    /// Records a link for `message_id` and returns its token. Blocking.
+    pub fn issue(
+        &self,
+        message_id: &MessageId,
+        expires_at: DateTime<Utc>,
+    ) -> Result<String, StorageError> {
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        self.store
+            .save_share_link(&token_hash(&token), message_id, expires_at)?;
+        Ok(token)
+    }
+
+    /// Consumes `token`, returning its mailbox unless it was unknown, already
+    /// used or expired. Blocking.
+    pub fn redeem(&self, token: &str) -> Result<Option<MessageId>, StorageError> {
+        self.store.take_share_link(&token_hash(token), Utc::now())
+    }
+
+    /// Deletes links that expired unused. Blocking.
+    pub fn prune(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        self.store.prune_share_links(now)
+    }
+}
+
+// Only hashes are stored, so a copy of the database can't redeem live links.
+fn token_hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+#[instrument(skip(state, payload))]
+pub async fn issue_share_link_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<IssueShareLinkRequest>,
+) -> Result<Json<IssueShareLinkResponse>, AppError> {
+    let ttl_secs = payload.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    if ttl_secs == 0 || ttl_secs > MAX_TTL_SECS {
+        return Err(AppError::InvalidRequest(format!(
+            "ttl_secs must be between 1 and {}",
+            MAX_TTL_SECS
+        )));
+    }
+    let expires_at = Utc::now() + Duration::seconds(ttl_secs as i64);
+    state.lifecycle.touch(&payload.message_id);
+
+    let share_links = state.share_links.clone();
+    let message_id = payload.message_id;
+    match tokio::task::spawn_blocking(move || share_links.issue(&message_id, expires_at)).await {
+        Ok(Ok(token)) => {
+            info!("Issued share link expiring at {}", expires_at);
+            Ok(Json(IssueShareLinkResponse { token, expires_at }))
+        }
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute share link issue task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during share link issue: {}",
+                join_error
+            )))
+        }
+    }
+}
+
+#[instrument(skip(state, payload))]
+pub async fn redeem_share_link_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<RedeemShareLinkRequest>,
+) -> Result<Json<GetMessagesResponse>, AppError> {
+    let share_links = state.share_links.clone();
+    let token = payload.token;
+    let message_id = match tokio::task::spawn_blocking(move || share_links.redeem(&token)).await {
+        Ok(Ok(Some(message_id))) => message_id,
+        Ok(Ok(None)) => {
+            return Err(AppError::InvalidRequest(
+                "share link is unknown, used or expired".to_string(),
+            ))
+        }
+        Ok(Err(storage_error)) => return Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute share link redeem task: {}", join_error);
+            return Err(AppError::WebPush(format!(
+                "Task join error during share link redeem: {}",
+                join_error
+            )));
+        }
+    };
+    let request = GetMessagesRequest {
+        message_ids: vec![message_id],
+        // Bootstrap material is normally waiting already
+        timeout_ms: Some(payload.timeout_ms.unwrap_or(0)),
+        ..Default::default()
+    };
+    Ok(Json(poll_messages(state, request).await?))
+}
@@ -0,0 +1,151 @@
+//! Ephemeral signals for typing indicators, call-setup pings and anything
+//! else worthless seconds later.
+//!
+//! `POST /api/signal` hands a small payload to the polls waiting on its
+//! message_id at that moment and to nobody else: nothing reaches storage, and a
+//! signal sent while nobody waits is dropped. Like the notifier, the channel
+//! for an id lives only as long as someone holds it.
+
+use axum::{extract::State, Json};
+use chrono::Utc;
+use dashmap::DashMap;
+use futures::future::select_all;
+use kwn_protocol::{MessageId, Signal, SignalRequest, SignalResponse};
+use std::sync::{Arc, Weak};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tracing::{debug, instrument};
+
+use crate::{error::AppError, state::SharedState};
+
+pub const MAX_SIGNAL_BYTES: usize = 1024;
+// Signals a slow poll may fall behind by before it misses some
+const SIGNAL_BUFFER: usize = 16;
+
+type Channel = broadcast::Sender<Signal>;
+
+#[derive(Default)]
+pub struct Signals {
+    channels: DashMap<MessageId, Weak<Channel>>,
+}
+
+impl Signals {
+    /// Starts receiving signals for `message_ids` until the result is dropped.
+    pub fn subscribe(self: &Arc<Self>, message_ids: &[MessageId]) -> SignalReceiver {
+        let mut channels = Vec::with_capacity(message_ids.len());
+        let mut receivers = Vec::with_capacity(message_ids.len());
+        for message_id in message_ids {
+            let channel = self.channel(message_id);
+            receivers.push(channel.subscribe());
+            channels.push(channel);
+        }
+        SignalReceiver {
+            signals: self.clone(),
+            message_ids: message_ids.to_vec(),
+            channels,
+            receivers,
+        }
+    }
+
+    /// Returns how many polls the signal reached.
+    pub fn send(&self, signal: Signal) -> usize {
+        let Some(channel) = self
+            .channels
+            .get(&signal.message_id)
+            .and_then(|weak| weak.upgrade())
+        else {
+            return 0;
+        };
+        channel.send(signal).unwrap_or(0)
+    }
+
+    fn channel(&self, message_id: &MessageId) -> Arc<Channel> {
+        let mut entry = self.channels.entry(message_id.clone()).or_default();
+        if let Some(channel) = entry.upgrade() {
+            return channel;
+        }
+        let channel = Arc::new(broadcast::channel(SIGNAL_BUFFER).0);
+        *entry = Arc::downgrade(&channel);
+        channel
+    }
+}
+
+/// One poll's subscriptions. Dropping it removes the channels nobody else holds.
+pub struct SignalReceiver {
+    signals: Arc<Signals>,
+    message_ids: Vec<MessageId>,
+    channels: Vec<Arc<Channel>>,
+    receivers: Vec<broadcast::Receiver<Signal>>,
+}
+
+impl SignalReceiver {
+    /// Takes every signal already received without waiting.
+    pub fn drain(&mut self) -> Vec<Signal> {
+        let mut signals = Vec::new();
+        for receiver in &mut self.receivers {
+            loop {
+                match receiver.try_recv() {
+                    Ok(signal) => signals.push(signal),
+                    Err(TryRecvError::Lagged(missed)) => {
+                        debug!("Poll missed {} signals", missed);
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+        }
+        signals.sort_by_key(|signal| signal.sent_at);
+        signals
+    }
+
+    /// Waits for the next signal, then takes any others that arrived with it.
+    /// Cancel safe. Empty only if the wait ended by falling behind.
+    pub async fn recv(&mut self) -> Vec<Signal> {
+        if self.receivers.is_empty() {
+            return std::future::pending().await;
+        }
+        let (first, _, _) = select_all(
+            self.receivers
+                .iter_mut()
+                .map(|receiver| Box::pin(receiver.recv())),
+        )
+        .await;
+        let mut signals: Vec<Signal> = first.into_iter().collect();
+        signals.extend(self.drain());
+        signals.sort_by_key(|signal| signal.sent_at);
+        signals
+    }
+}
+
+impl Drop for SignalReceiver {
+    fn drop(&mut self) {
+        self.receivers.clear();
+        self.channels.clear();
+        for message_id in &self.message_ids {
+            self.signals
+                .channels
+                .remove_if(message_id, |_, weak| weak.strong_count() == 0);
+        }
+    }
+}
+
+/// Delivers a signal to the polls waiting on its message_id right now.
+#[instrument(skip(state, payload))]
+pub async fn signal_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<SignalRequest>,
+) -> Result<Json<SignalResponse>, AppError> {
+    if payload.payload.len() > MAX_SIGNAL_BYTES {
+        return Err(AppError::PayloadTooLarge(format!(
+            "signal payload exceeds {} bytes",
+            MAX_SIGNAL_BYTES
+        )));
+    }
+    let reached = state.signals.send(Signal {
+        message_id: payload.message_id,
+        payload: payload.payload,
+        sent_at: Utc::now(),
+    });
+    debug!("Signal reached {} polls", reached);
+    Ok(Json(SignalResponse {
+        delivered: reached > 0,
+    }))
+}
@@ -0,0 +1,218 @@
+//! Opt-in SMS alerts through Twilio.
+//!
+//! For small deployments whose users would rather get a text than rely on
+//! push. A channel's owner opts in with `POST /api/sms-alerts` and a phone
+//! number. A put to the channel that no open client picked up then sends a
+//! short "new message waiting" SMS, naming no sender or content, only the link
+//! in `SMS_LINK_URL` if set. Texts cost money, so each number gets at most one
+//! per `SMS_INTERVAL_SECS` (default an hour) across all its channels.
+//!
+//! Numbers are stored under a hash of the channel's id, so the database
+//! doesn't link the two. Enabled by `TWILIO_ACCOUNT_SID`, `TWILIO_AUTH_TOKEN`
+//! and `TWILIO_FROM`, the sending number or messaging service sid.
+
+use axum::extract::{Json, State};
+use dashmap::{mapref::entry::Entry, DashMap};
+use kwn_protocol::{MessageId, SmsAlertRequest, SmsAlertResponse};
+use kwn_storage::{phone_key, SmsStore, StorageError};
+use metrics::counter;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+use tracing::{error, info, instrument};
+
+use crate::{error::AppError, locales::notification_text, state::SharedState};
+
+const TWILIO_API: &str = "https://api.twilio.com/2010-04-01";
+// E.164: a country code and subscriber number of at most 15 digits in all
+const MAX_PHONE_DIGITS: usize = 15;
+const MIN_PHONE_DIGITS: usize = 8;
+
+pub struct SmsAlerts {
+    store: Arc<dyn SmsStore>,
+    client: reqwest::Client,
+    account_sid: String,
+    auth_token: String,
+    from: String,
+    link: Option<String>,
+    interval: Duration,
+    // When each number, by hash, was last texted
+    last_sent: DashMap<[u8; 32], Instant>,
+}
+
+impl SmsAlerts {
+    /// None unless `TWILIO_ACCOUNT_SID` is set.
+    pub fn from_env(store: Arc<dyn SmsStore>) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(account_sid) = std::env::var("TWILIO_ACCOUNT_SID") else {
+            return Ok(None);
+        };
+        let required = |name: &str| {
+            std::env::var(name).map_err(|_| format!("{} must be set with TWILIO_ACCOUNT_SID", name))
+        };
+        Ok(Some(Self {
+            store,
+            client: reqwest::Client::new(),
+            account_sid,
+            auth_token: required("TWILIO_AUTH_TOKEN")?,
+            from: required("TWILIO_FROM")?,
+            link: std::env::var("SMS_LINK_URL").ok(),
+            interval: Duration::from_secs(
+                std::env::var("SMS_INTERVAL_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(3600),
+            ),
+            last_sent: DashMap::new(),
+        }))
+    }
+
+    /// Records `phone` for `message_id`, or removes it when None. Blocking.
+    pub fn register(
+        &self,
+        message_id: &MessageId,
+        phone: Option<&str>,
+    ) -> Result<(), StorageError> {
+        self.store.save_phone(&phone_key(message_id), phone)
+    }
+
+    /// Texts each of `message_ids`' numbers that hasn't been texted too
+    /// recently, in the background.
+    pub fn notify(self: &Arc<Self>, message_ids: Vec<MessageId>) {
+        let sms = self.clone();
+        tokio::spawn(async move {
+            let store = sms.store.clone();
+            let phones = match tokio::task::spawn_blocking(move || {
+                message_ids
+                    .iter()
+                    .filter_map(|message_id| store.phone(&phone_key(message_id)).transpose())
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            {
+                Ok(Ok(phones)) => phones,
+                Ok(Err(e)) => {
+                    error!("Failed to read SMS numbers: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    error!("SMS number lookup task failed: {}", e);
+                    return;
+                }
+            };
+            for phone in phones {
+                sms.send(&phone).await;
+            }
+        });
+    }
+
+    async fn send(&self, phone: &str) {
+        let phone_hash: [u8; 32] = Sha256::digest(phone.as_bytes()).into();
+        let now = Instant::now();
+        // Claimed before sending, so concurrent puts send one text
+        let claimed = match self.last_sent.entry(phone_hash) {
+            Entry::Occupied(sent) if now.duration_since(*sent.get()) < self.interval => false,
+            Entry::Occupied(mut sent) => {
+                sent.insert(now);
+                true
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(now);
+                true
+            }
+        };
+        if !claimed {
+            counter!("kwn_sms_throttled_total").increment(1);
+            return;
+        }
+
+        let title = notification_text(None).title;
+        let body = match &self.link {
+            Some(link) => format!("{} {}", title, link),
+            None => title.to_string(),
+        };
+        let sender = if self.from.starts_with("MG") {
+            "MessagingServiceSid"
+        } else {
+            "From"
+        };
+        let result = self
+            .client
+            .post(format!(
+                "{}/Accounts/{}/Messages.json",
+                TWILIO_API, self.account_sid
+            ))
+            .basic_auth(&self.account_sid, Some(&self.auth_token))
+            .form(&[
+                ("To", phone),
+                (sender, self.from.as_str()),
+                ("Body", body.as_str()),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                counter!("kwn_sms_sent_total").increment(1);
+                info!("Sent SMS alert.");
+            }
+            Err(e) => {
+                counter!("kwn_sms_failed_total").increment(1);
+                error!("Failed to send SMS alert: {}", e);
+            }
+        }
+    }
+
+    /// Forgets numbers whose interval has passed.
+    pub fn retain_recent(&self) {
+        let now = Instant::now();
+        self.last_sent
+            .retain(|_, sent| now.duration_since(*sent) < self.interval);
+    }
+}
+
+fn is_e164(phone: &str) -> bool {
+    phone.strip_prefix('+').is_some_and(|digits| {
+        (MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits.len())
+            && !digits.starts_with('0')
+            && digits.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+#[instrument(skip(state, payload))]
+pub async fn sms_alerts_handler(
+    State(state): State<SharedState>,
+    Json(payload): Json<SmsAlertRequest>,
+) -> Result<Json<SmsAlertResponse>, AppError> {
+    let Some(sms) = state.sms.clone() else {
+        return Err(AppError::InvalidRequest(
+            "this server does not send SMS".to_string(),
+        ));
+    };
+    if payload
+        .phone
+        .as_deref()
+        .is_some_and(|phone| !is_e164(phone))
+    {
+        return Err(AppError::InvalidRequest(
+            "phone must be in E.164 form, e.g. +14155550123".to_string(),
+        ));
+    }
+    state.lifecycle.touch(&payload.message_id);
+
+    let registered = payload.phone.is_some();
+    match tokio::task::spawn_blocking(move || {
+        sms.register(&payload.message_id, payload.phone.as_deref())
+    })
+    .await
+    {
+        Ok(Ok(())) => Ok(Json(SmsAlertResponse { registered })),
+        Ok(Err(storage_error)) => Err(storage_error.into()),
+        Err(join_error) => {
+            error!("Failed to execute SMS registration task: {}", join_error);
+            Err(AppError::WebPush(format!(
+                "Task join error during SMS registration: {}",
+                join_error
+            )))
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use kwn_push::{PushProvider, PushRouter, WebPushProvider};
+use kwn_storage::{MessageStore, SubscriptionStore};
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    ack_lane::AckLane, analytics::Analytics, concurrency::ConcurrencyLimits,
+    continuations::Continuations, debug_capture::DebugCapture, email::EmailFallback,
+    heuristics::PutHeuristics, hints::HintSigner, lifecycle::MailboxLifecycle, logging::LogFilter,
+    notifier::Notifier, outbox::PushOutbox, poll_limit::PollLimiter, poll_sessions::PollSessions,
+    push_chaos::ChaosPushProvider, push_limit::PushLimiter, reports::ReportStats,
+    share_links::ShareLinks, signals::Signals, sms::SmsAlerts, tokens::PrivateTokens,
+    trace_capture::TraceCapture,
+};
+
+// Structure for the shared application state. Every component sits behind its
+// trait so handlers don't depend on fjall, web-push or the waiter map directly.
+pub struct AppState {
+    pub messages: Arc<dyn MessageStore>,
+    pub subscriptions: Arc<dyn SubscriptionStore>,
+    pub push: Arc<dyn PushProvider>,
+    // The same provider as `push`, for the admin fault simulation controls.
+    pub push_chaos: Arc<ChaosPushProvider>,
+    // The transports behind `push`, to check new subscriptions against.
+    pub push_router: Arc<PushRouter>,
+    // The Web Push transport, whose VAPID keys clients and operators see.
+    pub web_push: Arc<WebPushProvider>,
+    pub notifier: Arc<dyn Notifier>,
+    pub signals: Arc<Signals>,
+    // Acks deleting more than this many messages trigger a background compaction.
+    pub compact_after_deletes: usize,
+    pub analytics: Arc<Analytics>,
+    pub debug: DebugCapture,
+    pub traces: TraceCapture,
+    pub tokens: Option<PrivateTokens>, // None unless PRIVATE_TOKEN_KEY_FILE is set
+    // Separate get budgets; None keeps gets on the shared per-IP limit.
+    pub poll_limit: Option<PollLimiter>,
+    // Whether pushes carry the newest message when it fits (PUSH_INCLUDE_MESSAGE).
+    pub push_include_message: bool,
+    // Whether puts push even when a waiting poll was woken (PUSH_WHILE_POLLING).
+    pub push_while_polling: bool,
+    // Pushes allowed per device; None when unlimited.
+    pub push_limit: Option<PushLimiter>,
+    // Per-route ceilings; None leaves every route unlimited.
+    pub concurrency: Option<ConcurrencyLimits>,
+    pub heuristics: Option<PutHeuristics>, // None unless PUT_HEURISTICS_FILE is set
+    pub hints: HintSigner,
+    // Heartbeat period for long polls that ask for keepalives; None disables them.
+    pub keepalive_interval: Option<Duration>,
+    pub metrics: PrometheusHandle,
+    pub poll_sessions: PollSessions,
+    pub continuations: Continuations,
+    pub lifecycle: Arc<MailboxLifecycle>,
+    pub ack_lane: AckLane,
+    pub share_links: Arc<ShareLinks>,
+    pub email: Option<Arc<EmailFallback>>, // None unless SMTP_URL is set
+    pub sms: Option<Arc<SmsAlerts>>,       // None unless TWILIO_ACCOUNT_SID is set
+    pub outbox: Arc<PushOutbox>,
+    pub report_stats: ReportStats,
+    // BASE_PATH normalized to "/prefix" with no trailing slash, or empty.
+    pub base_path: String,
+    // How far client-supplied timestamps may stray from the server clock.
+    pub max_clock_skew: chrono::Duration,
+    // Cap on the JSON of a poll's messages; see response_size
+    pub max_response_bytes: usize,
+    pub log_filter: LogFilter,
+}
+
+// Define the type for the shared application state
+pub type SharedState = Arc<AppState>;
@@ -0,0 +1,116 @@
+//! Skips rewriting push subscriptions that haven't changed.
+//!
+//! Clients re-send their subscription on every poll. [`SubscriptionCache`]
+//! wraps the real store and remembers, per message_id, hashes of the
+//! subscriptions saved for it, one per device, so a repeat costs a map lookup
+//! instead of a write transaction. Every other write through the cache drops the affected
+//! entries, so a removed or flagged subscription is always written again.
+
+use dashmap::DashMap;
+use kwn_protocol::{MessageId, PushSubscriptionInfo};
+use kwn_storage::{Result, SubscriptionStore, MAX_SUBSCRIPTIONS_PER_ID};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+pub struct SubscriptionCache {
+    inner: Arc<dyn SubscriptionStore>,
+    saved: DashMap<MessageId, Vec<[u8; 32]>>,
+}
+
+impl SubscriptionCache {
+    pub fn new(inner: Arc<dyn SubscriptionStore>) -> Self {
+        Self {
+            inner,
+            saved: DashMap::new(),
+        }
+    }
+}
+
+fn subscription_hash(subscription: &PushSubscriptionInfo) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    // Separators keep the field boundaries unambiguous
+    for field in [
+        subscription.endpoint.as_str(),
+        &subscription.keys.p256dh,
+        &subscription.keys.auth,
+        subscription.locale.as_deref().unwrap_or_default(),
+    ] {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize().into()
+}
+
+impl SubscriptionStore for SubscriptionCache {
+    fn save_subscription(
+        &self,
+        message_ids: &[MessageId],
+        subscription: &PushSubscriptionInfo,
+    ) -> Result<bool> {
+        let hash = subscription_hash(subscription);
+        if message_ids.iter().all(|id| {
+            self.saved
+                .get(id)
+                .is_some_and(|saved| saved.contains(&hash))
+        }) {
+            return Ok(false);
+        }
+        let changed = self.inner.save_subscription(message_ids, subscription)?;
+        for message_id in message_ids {
+            let mut saved = self.saved.entry(message_id.clone()).or_default();
+            // The store may have evicted a device; forgetting one only costs a write
+            if saved.len() >= MAX_SUBSCRIPTIONS_PER_ID {
+                saved.clear();
+            }
+            if !saved.contains(&hash) {
+                saved.push(hash);
+            }
+        }
+        Ok(changed)
+    }
+
+    fn subscriptions(&self, message_id: &MessageId) -> Result<Vec<PushSubscriptionInfo>> {
+        self.inner.subscriptions(message_id)
+    }
+
+    fn remove_subscription(&self, message_id: &MessageId) -> Result<()> {
+        self.saved.remove(message_id);
+        self.inner.remove_subscription(message_id)
+    }
+
+    fn remove_device_subscription(&self, message_id: &MessageId, endpoint: &str) -> Result<bool> {
+        self.saved.remove(message_id);
+        self.inner.remove_device_subscription(message_id, endpoint)
+    }
+
+    fn remove_endpoint(&self, subscription: &PushSubscriptionInfo) -> Result<Vec<MessageId>> {
+        let removed = self.inner.remove_endpoint(subscription)?;
+        // Flagged ids must reach storage on their next save
+        for message_id in &removed {
+            self.saved.remove(message_id);
+        }
+        Ok(removed)
+    }
+
+    fn mark_resubscribe_required(&self, message_id: &MessageId) -> Result<()> {
+        self.saved.remove(message_id);
+        self.inner.mark_resubscribe_required(message_id)
+    }
+
+    fn resubscribe_required(&self, message_ids: &[MessageId]) -> Result<Vec<MessageId>> {
+        self.inner.resubscribe_required(message_ids)
+    }
+
+    fn subscribed_ids(&self) -> Result<Vec<MessageId>> {
+        self.inner.subscribed_ids()
+    }
+
+    fn reap_mailbox(&self, message_id: &MessageId) -> Result<()> {
+        self.inner.reap_mailbox(message_id)
+    }
+
+    fn forget_mailbox(&self, message_id: &MessageId) -> Result<()> {
+        self.saved.remove(message_id);
+        self.inner.forget_mailbox(message_id)
+    }
+}
@@ -0,0 +1,80 @@
+//! systemd service notifications.
+//!
+//! Under `Type=notify` the server reports `READY=1` once storage is open and
+//! the listener is bound, and `STOPPING=1` when a shutdown signal starts the
+//! drain. With `WatchdogSec=` set it also sends `WATCHDOG=1` from a heartbeat
+//! that first makes a storage call through the blocking pool, so a wedged
+//! store or starved runtime stops the pings and systemd restarts the process.
+//! Without `NOTIFY_SOCKET` every notification is a no-op.
+
+use sd_notify::NotifyState;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::state::SharedState;
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+pub fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
+
+/// Starts the watchdog heartbeat if systemd asked for one.
+pub fn spawn_watchdog(state: SharedState) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // Ping twice per period, as systemd recommends, and give each health
+    // check the rest of its half
+    let interval = Duration::from_micros(usec) / 2;
+    info!("systemd watchdog enabled, pinging every {:?}", interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let messages = state.messages.clone();
+            let check = tokio::task::spawn_blocking(move || messages.health());
+            match tokio::time::timeout(interval, check).await {
+                Ok(Ok(_)) => notify(NotifyState::Watchdog),
+                Ok(Err(e)) => warn!("Watchdog health check failed: {}", e),
+                Err(_) => warn!("Watchdog health check timed out; withholding ping"),
+            }
+        }
+    });
+}
+
+/// Resolves on SIGINT or SIGTERM, after telling systemd the drain has begun.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, draining connections");
+    notify_stopping();
+}
@@ -0,0 +1,203 @@
+//! Privacy Pass style rate-limit tokens (RSA blind signatures, RFC 9474).
+//!
+//! An attester run by the operator obtains tokens for clients through the
+//! admin issuance endpoint; the server only ever sees blinded nonces there, so
+//! a redeemed token can't be linked back to its issuance. Puts that redeem a
+//! valid, unspent token skip the per-IP put limit; puts without one are held
+//! to `BARE_IP_PUTS_PER_MINUTE`.
+//!
+//! Enabled by pointing `PRIVATE_TOKEN_KEY_FILE` at a PEM RSA private key.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use blind_rsa_signatures::{
+    BlindedMessage, MessageRandomizer, Options, PublicKey, SecretKey, Signature,
+};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Quota, RateLimiter,
+};
+use kwn_protocol::PRIVATE_TOKEN_HEADER;
+use kwn_storage::TokenStore;
+use std::{net::SocketAddr, num::NonZeroU32, sync::Arc};
+use tracing::{error, warn};
+
+use crate::{
+    middleware::{client_ip, too_many_requests, RateLimitScope},
+    state::SharedState,
+};
+
+const NONCE_LEN: usize = 32;
+const RANDOMIZER_LEN: usize = 32;
+const MAX_TOKENS_PER_ISSUE: usize = 32;
+
+/// Outcome of [`PrivateTokens::check_put`].
+pub enum PutAdmission {
+    Allowed,
+    InvalidToken,
+    SpentToken,
+    RateLimited { wait_secs: u64 },
+    Failed,
+}
+
+pub struct PrivateTokens {
+    secret_key: SecretKey,
+    public_key: PublicKey,
+    options: Options,
+    store: Arc<dyn TokenStore>,
+    bare_ip_limiter: DefaultKeyedRateLimiter<String>,
+}
+
+impl PrivateTokens {
+    /// Loads the issuer key if token support is configured.
+    pub fn from_env(
+        store: Arc<dyn TokenStore>,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(key_file) = std::env::var("PRIVATE_TOKEN_KEY_FILE") else {
+            return Ok(None);
+        };
+        let secret_key = SecretKey::from_pem(&std::fs::read_to_string(key_file)?)?;
+        let public_key = secret_key.public_key()?;
+        let bare_ip_puts_per_minute = std::env::var("BARE_IP_PUTS_PER_MINUTE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .and_then(NonZeroU32::new)
+            .unwrap_or(NonZeroU32::new(30).unwrap());
+        Ok(Some(Self {
+            secret_key,
+            public_key,
+            options: Options::default(),
+            store,
+            bare_ip_limiter: RateLimiter::keyed(Quota::per_minute(bare_ip_puts_per_minute)),
+        }))
+    }
+
+    pub fn public_key_pem(&self) -> Result<String, blind_rsa_signatures::Error> {
+        self.public_key.to_pem()
+    }
+
+    /// Signs each base64 blinded message, returning base64 blind signatures.
+    pub fn issue(&self, blinded_messages: &[String]) -> Result<Vec<String>, String> {
+        if blinded_messages.len() > MAX_TOKENS_PER_ISSUE {
+            return Err(format!(
+                "at most {} tokens can be issued per request",
+                MAX_TOKENS_PER_ISSUE
+            ));
+        }
+        let rng = &mut rand::thread_rng();
+        blinded_messages
+            .iter()
+            .map(|encoded| {
+                let blinded = STANDARD
+                    .decode(encoded)
+                    .map_err(|e| format!("blinded message is not base64: {}", e))?;
+                let signature = self
+                    .secret_key
+                    .blind_sign(rng, BlindedMessage::new(blinded), &self.options)
+                    .map_err(|e| format!("failed to sign blinded message: {}", e))?;
+                Ok(STANDARD.encode::<&[u8]>(signature.as_ref()))
+            })
+            .collect()
+    }
+
+    // Returns the token's nonce if its signature verifies.
+    fn verify(&self, encoded: &str) -> Option<Vec<u8>> {
+        let token = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+        if token.len() <= NONCE_LEN + RANDOMIZER_LEN {
+            return None;
+        }
+        let (nonce, rest) = token.split_at(NONCE_LEN);
+        let (randomizer, signature) = rest.split_at(RANDOMIZER_LEN);
+        let randomizer = MessageRandomizer::new(randomizer.try_into().ok()?);
+        Signature::new(signature.to_vec())
+            .verify(&self.public_key, Some(randomizer), nonce, &self.options)
+            .ok()?;
+        Some(nonce.to_vec())
+    }
+
+    /// Redeems `presented` if there is one, otherwise charges `client_ip`'s
+    /// strict bare-IP put budget.
+    pub async fn check_put(&self, presented: Option<String>, client_ip: &str) -> PutAdmission {
+        if let Some(encoded) = presented {
+            let Some(nonce) = self.verify(&encoded) else {
+                warn!("Rejected put with an invalid private token");
+                return PutAdmission::InvalidToken;
+            };
+            let store = self.store.clone();
+            return match tokio::task::spawn_blocking(move || store.redeem_token(&nonce)).await {
+                Ok(Ok(true)) => PutAdmission::Allowed,
+                Ok(Ok(false)) => {
+                    warn!("Rejected put with an already spent private token");
+                    PutAdmission::SpentToken
+                }
+                Ok(Err(e)) => {
+                    error!("Failed to redeem private token: {}", e);
+                    PutAdmission::Failed
+                }
+                Err(e) => {
+                    error!("Private token redemption task failed: {}", e);
+                    PutAdmission::Failed
+                }
+            };
+        }
+
+        match self.bare_ip_limiter.check_key(&client_ip.to_string()) {
+            Ok(()) => PutAdmission::Allowed,
+            Err(not_until) => {
+                let wait = not_until.wait_time_from(DefaultClock::default().now());
+                PutAdmission::RateLimited {
+                    wait_secs: wait.as_secs_f64().ceil() as u64,
+                }
+            }
+        }
+    }
+}
+
+/// Route layer for puts: redeems a token if one is presented, otherwise applies
+/// the strict per-IP limit. A no-op when tokens aren't configured.
+pub async fn private_token_gate(
+    State(state): State<SharedState>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(tokens) = state.tokens.as_ref() else {
+        return next.run(req).await;
+    };
+
+    let presented = req
+        .headers()
+        .get(PRIVATE_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    match tokens
+        .check_put(presented, &client_ip(req.headers(), peer))
+        .await
+    {
+        PutAdmission::Allowed => next.run(req).await,
+        PutAdmission::InvalidToken => {
+            (StatusCode::UNAUTHORIZED, "Invalid private token").into_response()
+        }
+        PutAdmission::SpentToken => {
+            (StatusCode::UNAUTHORIZED, "Private token already spent").into_response()
+        }
+        PutAdmission::RateLimited { wait_secs } => {
+            too_many_requests(RateLimitScope::BareIp, wait_secs, None)
+        }
+        PutAdmission::Failed => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Time-boxed, server-wide request traces for reproducing delivery races.
+//!
+//! While an operator has a capture running, every `/api/` request is recorded
+//! as a [`TraceEvent`]: its route, arrival offset, duration, status and body
+//! length, plus the mailboxes it named as per-capture numbers. Message ids,
+//! bodies, tokens and addresses are never kept. The relay bot's `replay` mode
+//! drives a server with the same pattern of concurrent requests.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use kwn_protocol::{Trace, TraceEvent};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+use crate::{handlers::BATCH_PAYLOAD_LIMIT, state::SharedState};
+
+pub const MAX_TRACE_DURATION: Duration = Duration::from_secs(3600);
+const MAX_TRACE_EVENTS: usize = 100_000;
+
+struct Recording {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    expires: Instant,
+    // Keyed by a hash of the message_id, so raw ids never sit in memory either
+    mailboxes: HashMap<[u8; 32], u32>,
+    events: Vec<TraceEvent>,
+    dropped: u64,
+}
+
+impl Recording {
+    fn mailbox(&mut self, message_id: &str) -> u32 {
+        let next = self.mailboxes.len() as u32;
+        *self
+            .mailboxes
+            .entry(Sha256::digest(message_id.as_bytes()).into())
+            .or_insert(next)
+    }
+}
+
+// What a request looked like, gathered before it runs.
+struct Shape {
+    message_ids: Vec<String>,
+    message_len: Option<usize>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct TraceCapture {
+    active: AtomicBool,
+    recording: Mutex<Option<Recording>>,
+}
+
+impl TraceCapture {
+    /// Starts a new capture, discarding any running one.
+    pub fn start(&self, duration: Duration) -> DateTime<Utc> {
+        let duration = duration.min(MAX_TRACE_DURATION);
+        let started_at = Utc::now();
+        let expires_at = started_at
+            + chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+        let now = Instant::now();
+        *self.recording.lock().unwrap() = Some(Recording {
+            started: now,
+            started_at,
+            expires: now + duration,
+            mailboxes: HashMap::new(),
+            events: Vec::new(),
+            dropped: 0,
+        });
+        self.active.store(true, Ordering::Relaxed);
+        info!(%expires_at, "Trace capture started");
+        expires_at
+    }
+
+    /// Ends the capture, returning what it recorded. Also works after it expired.
+    pub fn stop(&self) -> Option<Trace> {
+        self.active.store(false, Ordering::Relaxed);
+        self.recording
+            .lock()
+            .unwrap()
+            .take()
+            .map(|recording| Trace {
+                started_at: recording.started_at,
+                events: recording.events,
+                dropped: recording.dropped,
+            })
+    }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn record(
+        &self,
+        arrived: Instant,
+        route: String,
+        body_len: usize,
+        shape: Shape,
+        status: StatusCode,
+    ) {
+        let mut recording = self.recording.lock().unwrap();
+        let Some(recording) = recording.as_mut() else {
+            return;
+        };
+        if arrived >= recording.expires {
+            // Kept for stop(), but nothing more is recorded
+            self.active.store(false, Ordering::Relaxed);
+            return;
+        }
+        if recording.events.len() == MAX_TRACE_EVENTS {
+            recording.dropped += 1;
+            return;
+        }
+        let mailboxes = shape
+            .message_ids
+            .iter()
+            .map(|message_id| recording.mailbox(message_id))
+            .collect();
+        let event = TraceEvent {
+            offset_ms: arrived
+                .saturating_duration_since(recording.started)
+                .as_millis() as u64,
+            route,
+            mailboxes,
+            body_len,
+            message_len: shape.message_len,
+            timeout_ms: shape.timeout_ms,
+            status: status.as_u16(),
+            duration_ms: arrived.elapsed().as_millis() as u64,
+        };
+        recording.events.push(event);
+    }
+}
+
+// Pulls the mailboxes and timing-relevant fields out of a JSON body or the
+// query string, whichever the route uses.
+fn shape(query: Option<&str>, body: &[u8]) -> Shape {
+    let mut shape = Shape {
+        message_ids: Vec::new(),
+        message_len: None,
+        timeout_ms: None,
+    };
+    if let Some(query) = query {
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match name {
+                "ids" | "message_ids" => shape
+                    .message_ids
+                    .extend(value.split(',').map(str::to_string)),
+                "timeout_ms" => shape.timeout_ms = value.parse().ok(),
+                _ => {}
+            }
+        }
+    }
+    let Ok(Value::Object(body)) = serde_json::from_slice::<Value>(body) else {
+        return shape;
+    };
+    let mut ids = |value: Option<&Value>| {
+        if let Some(Value::String(id)) = value {
+            shape.message_ids.push(id.clone());
+        }
+    };
+    ids(body.get("message_id"));
+    for list in ["message_ids", "acks", "entries"] {
+        if let Some(Value::Array(items)) = body.get(list) {
+            for item in items {
+                match item {
+                    Value::String(_) => ids(Some(item)),
+                    Value::Object(fields) => ids(fields.get("message_id")),
+                    _ => {}
+                }
+            }
+        }
+    }
+    shape.message_len = body.get("message").and_then(Value::as_str).map(str::len);
+    shape.timeout_ms = body.get("timeout_ms").and_then(Value::as_u64);
+    shape
+}
+
+/// Records each `/api/` request while a capture runs. Costs one atomic load
+/// otherwise.
+pub async fn trace_requests(
+    State(state): State<SharedState>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if !state.traces.is_active() || !req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+    let arrived = Instant::now();
+    let route = req.uri().path().to_string();
+    let query = req.uri().query().map(str::to_string);
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = to_bytes(body, BATCH_PAYLOAD_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let body_len = bytes.len();
+    let shape = shape(query.as_deref(), &bytes);
+    let response = next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await;
+    state
+        .traces
+        .record(arrived, route, body_len, shape, response.status());
+    response
+}
@@ -11,7 +11,7 @@ cleanup() {
 trap cleanup SIGINT EXIT
 
 echo "Starting Backend (Rust)..."
-cd backend
+cd kwn-server
 cargo run &
 BACKEND_PID=$!
 cd ..
@@ -3,7 +3,10 @@ import { useAuth } from "@/contexts/AuthContext"; // Import useAuth
 import { useContacts } from "@/contexts/ContactsContext";
 import { useToast } from "@/components/ui/use-toast";
 import { decryptMessage } from "@/utils/encryption"; // generateStableRequestId no longer needed here
-import { getStoredPushSubscription } from "@/utils/notifications"; // Import notification util
+import {
+  getStoredPushSubscription,
+  getStoredVapidKeyId,
+} from "@/utils/notifications"; // Import notification util
 import { Message } from "@/contexts/MessagesContext"; // Import only Message type if needed
 
 // Type for the response from /api/get-messages
@@ -16,6 +19,7 @@ interface GetMessagesApiResponse {
     timestamp: string; // ISO timestamp from backend
     group?: string;
   }[];
+  resubscribe_required?: boolean;
 }
 
 interface UseMessagePollingOptions {
@@ -28,6 +32,47 @@ interface UseMessagePollingOptions {
 
 const MIN_POLL_INTERVAL_MS = 30000; // Default minimum interval of 30 seconds
 
+// Shared by every tab of this origin so the server can coalesce their polls
+const POLL_SESSION_KEY = "pollSession";
+const getPollSession = (): string => {
+  let session = localStorage.getItem(POLL_SESSION_KEY);
+  if (!session) {
+    session = crypto.randomUUID();
+    localStorage.setItem(POLL_SESSION_KEY, session);
+  }
+  return session;
+};
+
+// The subscription and ids last registered with /api/subscribe. A push consumes
+// the registration, so it is cleared whenever a poll returns messages.
+let lastSubscribed: string | null = null;
+const subscribeForPush = async (
+  messageIds: string[],
+  pushSubscription: PushSubscription,
+): Promise<void> => {
+  const key = JSON.stringify([pushSubscription, messageIds]);
+  if (key === lastSubscribed) return;
+  const response = await fetch("/api/subscribe", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({
+      message_ids: messageIds,
+      // The locale picks the language of the notification text, and the key id
+      // which of the server's VAPID keys signs the pushes
+      push_subscription: {
+        ...pushSubscription.toJSON(),
+        locale: navigator.language,
+        vapid_key_id: getStoredVapidKeyId() ?? undefined,
+      },
+    }),
+  });
+  if (response.ok) {
+    lastSubscribed = key;
+  } else {
+    console.error(`Push subscribe failed with status ${response.status}`);
+  }
+};
+
 export const useMessagePolling = ({
   setMessages,
   activeItemId = null, // Default to null if not provided
@@ -91,8 +136,13 @@ export const useMessagePolling = ({
           return false; // No request IDs, so no messages fetched
         }
 
-        // Get stored push subscription
+        // Register for pushes apart from the poll, so a slow write can't delay it
         const pushSubscription = getStoredPushSubscription();
+        if (pushSubscription) {
+          subscribeForPush(requestIdsToSend, pushSubscription).catch((error) =>
+            console.error("Error registering push subscription:", error),
+          );
+        }
 
         // Send the list of stable request IDs (hashes) and timeout to the backend
         console.log('long poll started');
@@ -102,13 +152,17 @@ export const useMessagePolling = ({
           body: JSON.stringify({
             message_ids: requestIdsToSend,
             timeout_ms: longPollTimeoutMs, // Send timeout hint
-            // Include push subscription if available
-            ...(pushSubscription && { push_subscription: pushSubscription }),
+            poll_session: getPollSession(),
           }),
           signal: signal, // Pass the abort signal
         });
         console.log('long poll ended');
 
+        if (response.status === 409) {
+          // Another tab is already polling these ids; it will pick up the messages
+          return false;
+        }
+
         if (!response.ok) {
           // Don't throw AbortError if the request was intentionally aborted
           if (signal.aborted) {
@@ -124,6 +178,10 @@ export const useMessagePolling = ({
         // Log the received data
         // console.log('Received data.results:', data.results);
 
+        if (data.results.length > 0 || data.resubscribe_required) {
+          lastSubscribed = null;
+        }
+
         if (data.results.length > 0) {
           console.log(`Received ${data.results.length} new messages.`);
           let newMessagesAdded = false;
@@ -153,6 +153,8 @@ self.addEventListener("push", (event) => {
       data: {
         url: parsedData.url || "/", // Default URL to open on click
         ...(parsedData.data || {}),
+        // The newest message, still end-to-end encrypted, when the push carried it
+        ...(parsedData.message ? { message: parsedData.message } : {}),
       },
       tag: parsedData.tag || 'general-notification', // Allows replacing/grouping notifications
     };
@@ -3,6 +3,33 @@ import { base64ToArrayBuffer } from "./encryption";
 const VAPID_PUBLIC_KEY =
   "BBCfu1zbkYN8zMkWErBfuTfDzLZJ1-gd1hSgwydeCC3851L_7CiTy71oQtuAtx3aV3wDVk7FZVEgUMkT3ZY8RUk=";
 const SUBSCRIPTION_STORAGE_KEY = "pushSubscription";
+const VAPID_KEY_ID_STORAGE_KEY = "pushVapidKeyId";
+
+/**
+ * The VAPID public key the server wants new subscriptions made with, and its
+ * id, falling back to the built-in key when the server doesn't say.
+ */
+async function currentVapidKey(): Promise<{ publicKey: string; keyId?: string }> {
+  try {
+    const response = await fetch("/api/info");
+    if (response.ok) {
+      const info = await response.json();
+      if (info.vapid_public_key) {
+        return { publicKey: info.vapid_public_key, keyId: info.vapid_key_id };
+      }
+    }
+  } catch (error) {
+    console.warn("Failed to fetch the server's VAPID key:", error);
+  }
+  return { publicKey: VAPID_PUBLIC_KEY };
+}
+
+/**
+ * The id of the server VAPID key the stored subscription was made with.
+ */
+export function getStoredVapidKeyId(): string | null {
+  return localStorage.getItem(VAPID_KEY_ID_STORAGE_KEY);
+}
 
 /**
  * Stores the push subscription in localStorage.
@@ -18,6 +45,7 @@ export function storePushSubscription(
     console.log("Push subscription stored.");
   } else {
     localStorage.removeItem(SUBSCRIPTION_STORAGE_KEY);
+    localStorage.removeItem(VAPID_KEY_ID_STORAGE_KEY);
     console.log("Push subscription removed.");
   }
 }
@@ -93,15 +121,19 @@ export async function requestNotificationPermissionAndSubscribe(): Promise<Notif
     const subscription = await registration.pushManager.getSubscription();
 
     try {
+      const { publicKey, keyId } = await currentVapidKey();
       const subscription = await registration.pushManager.subscribe({
         userVisibleOnly: true, // Required for push notifications
-        applicationServerKey: base64ToArrayBuffer(VAPID_PUBLIC_KEY),
+        applicationServerKey: base64ToArrayBuffer(publicKey),
       });
       console.log(
         "Successfully subscribed to push notifications:",
         subscription,
       );
       storePushSubscription(subscription);
+      if (keyId) {
+        localStorage.setItem(VAPID_KEY_ID_STORAGE_KEY, keyId);
+      }
     } catch (error) {
       console.error("Failed to subscribe to push notifications:", error);
       // Handle specific errors, e.g., if VAPID key is invalid